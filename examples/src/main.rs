@@ -1,13 +1,33 @@
 use std::borrow::BorrowMut;
 use std::fmt::format;
 use schnorr_nizk;
+use schnorr_nizk::{DeviceId, ResourcePath};
 use chrono::prelude::*;
 use std::time::{Instant};
+use schnorr_nizk::key_agreement::KeyAgreement;
+
+// Exchange the messages of a key agreement in memory until both sides have nothing left to send
+fn run_key_agreement(initiator: &mut dyn KeyAgreement, receiver: &mut dyn KeyAgreement) -> (bool, bool) {
+    loop {
+        let mut sent = false;
+        if let Some(message) = initiator.next_message() {
+            receiver.receive_message(&message);
+            sent = true;
+        }
+        if let Some(message) = receiver.next_message() {
+            initiator.receive_message(&message);
+            sent = true;
+        }
+        if !sent {
+            return (initiator.is_complete(), receiver.is_complete());
+        }
+    }
+}
 
 fn main() {
     // ID's of A and B
-    let AID: u32 = 10000;
-    let BID: u32 = 20000;
+    let AID = DeviceId::Number(10000);
+    let BID = DeviceId::Number(20000);
     let iterations = 5000;
 
     /*
@@ -22,10 +42,6 @@ fn main() {
     let (pub_kA, priv_kA) = schnorr_nizk::gen_random_key_pair();
     println!("my pub key = {:?}\nmy priv key = {:?}\n", pub_kA, priv_kA);
 
-    let desc = format!("PublicKey:{}", AID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32,Some(Vec::from(pub_kA))).unwrap();
-    my_key.update_key_in_ring(Vec::from(pub_kA));
-
     let desc = format!("PrivateKey:{}", AID);
     let mut my_key = schnorr_nizk::get_key_instance(&desc, 32,Some(Vec::from(priv_kA))).unwrap();
     my_key.update_key_in_ring(Vec::from(priv_kA));
@@ -34,14 +50,54 @@ fn main() {
     let (pub_kB, priv_kB) = schnorr_nizk::gen_random_key_pair();
     println!("Server pub key = {:?}\nServer priv key = {:?}\n", pub_kB, priv_kB);
 
-    let desc = format!("PublicKey:{}", BID);
-    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(pub_kB))).unwrap();
-    my_key.update_key_in_ring(Vec::from(pub_kB));
-
     let desc = format!("PrivateKey:{}", BID);
     let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(priv_kB))).unwrap();
     my_key.update_key_in_ring(Vec::from(priv_kB));
 
+    // Public keys are only stored after the certificates of the authority are checked
+    println!("Enrolling A and B with certificates of a local authority:\n");
+    let authority_key = schnorr_nizk::enrollment::init_authority().unwrap();
+    println!("Authority pub key = {:?}\n", authority_key);
+
+    // Install a revocation list of the authority. Listed devices and keys are rejected before every verification.
+    // Once a list was accepted, every device counts as revoked while no list of at least this version is stored
+    let revocation_list = schnorr_nizk::revocation::issue_revocation_list(Utc::now().timestamp_millis() as u64, Vec::new(), Vec::new()).unwrap();
+    println!("Installed revocation list {}: {:?}", revocation_list.version, schnorr_nizk::revocation::update_revocation_list(&revocation_list));
+    println!("Is A revoked? = {}\n", schnorr_nizk::revocation::is_revoked(AID, &pub_kA));
+    let older_list = schnorr_nizk::revocation::issue_revocation_list(revocation_list.version - 1, Vec::new(), Vec::new()).unwrap();
+    println!("Installing an older revocation list, expected OutdatedVersion: {:?}\n", schnorr_nizk::revocation::update_revocation_list(&older_list));
+
+    let valid_from = Utc::now().timestamp_millis();
+    let valid_until = valid_from + 365 * 24 * 60 * 60 * 1000;
+    let cert_a = schnorr_nizk::enrollment::issue_certificate(AID, pub_kA, valid_from, valid_until, vec![String::from("sensor")]).unwrap();
+    let cert_b = schnorr_nizk::enrollment::issue_certificate(BID, pub_kB, valid_from, valid_until, vec![String::from("gateway")]).unwrap();
+    println!("Enrolled A: {:?}", schnorr_nizk::enrollment::enroll_peer(&cert_a));
+    println!("Enrolled B: {:?}\n", schnorr_nizk::enrollment::enroll_peer(&cert_b));
+
+    // Certificates with another key or an old validity period are rejected
+    let mut tampered_cert = cert_a.clone();
+    tampered_cert.public_key = pub_kB;
+    println!("Enrolling a tampered certificate, expected InvalidSignature: {:?}", schnorr_nizk::enrollment::enroll_peer(&tampered_cert));
+    let expired_cert = schnorr_nizk::enrollment::issue_certificate(AID, pub_kA, 0, 1, Vec::new()).unwrap();
+    println!("Enrolling an expired certificate, expected Expired: {:?}\n", schnorr_nizk::enrollment::enroll_peer(&expired_cert));
+
+    // IDs can also be derived from the public key of a device
+    let (pub_kC, _) = schnorr_nizk::gen_random_key_pair();
+    let CID = DeviceId::from_public_key(&pub_kC);
+    println!("Fingerprint ID of C = {}, parsed again: {:?}", CID, CID.to_string().parse::<DeviceId>());
+    let cert_c = schnorr_nizk::enrollment::issue_certificate(CID, pub_kB, valid_from, valid_until, Vec::new()).unwrap();
+    println!("Enrolling C with another key, expected DeviceIdMismatch: {:?}\n", schnorr_nizk::enrollment::enroll_peer(&cert_c));
+
+    // Enrolled devices are listed in the peer registry
+    let mut registry = schnorr_nizk::peer_registry::PeerRegistry::load().unwrap();
+    registry.set_display_name(AID, String::from("Sensor A")).unwrap();
+    registry.set_display_name(BID, String::from("Gateway B")).unwrap();
+    registry.save().unwrap();
+    for peer in registry.list_peers() {
+        println!("Peer {}: {}, status {:?}, enrolled at {}, policy {:?}", peer.device_ID, peer.display_name, peer.status, peer.enrolled_at, peer.policy);
+    }
+    println!();
+
     /*
     ************************************************************************************************
     ***************************** End Generating Key Pair For A and B ******************************
@@ -94,9 +150,17 @@ fn main() {
         // This is not included in the duration, since it's supposed to be done simultaneously
         let b_accepted = b_int_auth.verify_proof();
 
+        // Key confirmation: A sends its tag, B confirms it and answers with its own tag
+        let (a_tag, _, a_req_type) = a_int_auth.gen_next_values();
+        let _ = b_int_auth.add_recipient_values(a_req_type, a_tag, None);
+        let b_confirmed = b_int_auth.confirm_shared_secret_key();
+        let (b_tag, _, b_req_type) = b_int_auth.gen_next_values();
+        let _ = a_int_auth.add_recipient_values(b_req_type, b_tag, None);
+        let a_confirmed = a_int_auth.confirm_shared_secret_key();
+
         if i == iterations - 1 {
-            accepted_1 = a_accepted;
-            accepted_2 = b_accepted;
+            accepted_1 = a_accepted && a_confirmed;
+            accepted_2 = b_accepted && b_confirmed;
         }
     }
 
@@ -105,7 +169,35 @@ fn main() {
     let sum: f32 = all_measurements.iter().sum();
     let avg = sum / all_measurements.len() as f32;
     println!("The average execution time is: {}ms\n", avg);
-    println!("Result of last verify proofs: {}, {}\n", accepted_1, accepted_2);
+    println!("Result of last verify proofs and key confirmations: {}, {}\n", accepted_1, accepted_2);
+
+    // Pairing: the shared secret key is only stored after the codes shown on both devices were compared
+    let mut a_int_auth = schnorr_nizk::get_int_mut_auth_instance(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE);
+    let mut b_int_auth = schnorr_nizk::get_int_mut_auth_instance(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE);
+    a_int_auth.require_sas_confirmation();
+    b_int_auth.require_sas_confirmation();
+    let result = run_key_agreement(&mut a_int_auth, &mut b_int_auth);
+    println!("Key agreement completed before comparing the codes? (expected false, false) = {:?}", result);
+    let (a_code, b_code) = (a_int_auth.short_authentication_string(), b_int_auth.short_authentication_string());
+    println!("Code shown on A: {:?}, code shown on B: {:?}", a_code, b_code);
+    if a_code.is_some() && a_code == b_code {
+        println!("Technician accepted the codes: {}, {}\n", a_int_auth.accept_sas(), b_int_auth.accept_sas());
+    } else {
+        a_int_auth.reject_sas();
+        b_int_auth.reject_sas();
+    }
+
+    // Rejecting the codes on one device sends a signed abort, so the other device stops waiting for the comparison
+    let mut a_int_auth = schnorr_nizk::get_int_mut_auth_instance(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE);
+    let mut b_int_auth = schnorr_nizk::get_int_mut_auth_instance(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE);
+    a_int_auth.require_sas_confirmation();
+    b_int_auth.require_sas_confirmation();
+    run_key_agreement(&mut a_int_auth, &mut b_int_auth);
+    a_int_auth.reject_sas();
+    if let Some(abort) = a_int_auth.next_message() {
+        b_int_auth.receive_message(&abort);
+    }
+    println!("Pairing accepted by B after A rejected the codes? (expected false) = {}\n", b_int_auth.accept_sas());
 
     println!("End of Mutual Auth \n\n");
     /*
@@ -114,6 +206,179 @@ fn main() {
     ************************************************************************************************
     */
 
+    /*
+    ************************************************************************************************
+    ********************************* X25519 Key Agreement Test ************************************
+    ************************************************************************************************
+    */
+    println!("Begin of X25519 key agreement tests for generating a shared secret key:\n");
+
+    // Save different measures inside a Vec
+    let mut all_measurements: Vec<f32> = Vec::new();
+    let mut completed = (false, false);
+
+    for i in 0..iterations {
+        // Measure duration of each one
+        let start = Instant::now();
+
+        // Run the whole handshake in memory
+        let mut a_x25519 = schnorr_nizk::key_agreement::X25519KeyAgreement::new(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE, schnorr_nizk::KeySize::Bits256);
+        let mut b_x25519 = schnorr_nizk::key_agreement::X25519KeyAgreement::new(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE, schnorr_nizk::KeySize::Bits256);
+        let result = run_key_agreement(&mut a_x25519, &mut b_x25519);
+
+        // Calculate Duration
+        let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
+        all_measurements.push(duration);
+
+        if i == iterations - 1 {
+            completed = result;
+        }
+    }
+
+    // Calculate the average run duration
+    let sum: f32 = all_measurements.iter().sum();
+    let avg = sum / all_measurements.len() as f32;
+    println!("The average execution time is: {}ms\n", avg);
+    println!("Result of last key agreement: {}, {}\n", completed.0, completed.1);
+
+    // Both sides have to hold the same key, usable for NIZK proofs
+    let key_a = schnorr_nizk::get_key_instance(&format!("SharedSecretKey:{}:{}", AID, BID), 32, None).unwrap();
+    let key_b = schnorr_nizk::get_key_instance(&format!("SharedSecretKey:{}:{}", BID, AID), 32, None).unwrap();
+    println!("A and B stored the same shared secret key? = {:?}\n", key_a.get_key() == key_b.get_key());
+
+    println!("End of X25519 key agreement \n\n");
+    /*
+    ************************************************************************************************
+    ******************************* End X25519 Key Agreement Test **********************************
+    ************************************************************************************************
+    */
+
+    /*
+    ************************************************************************************************
+    ************************************** PAKE Pairing Test ***************************************
+    ************************************************************************************************
+    */
+    println!("Begin of PAKE pairing test with a new device D, which is not enrolled:\n");
+
+    // D only has its own key pair and the pairing code printed on its label
+    let DID = DeviceId::Number(40000);
+    let (_, priv_kD) = schnorr_nizk::gen_random_key_pair();
+    let desc = format!("PrivateKey:{}", DID);
+    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(priv_kD))).unwrap();
+    my_key.update_key_in_ring(Vec::from(priv_kD));
+
+    // Pairing with a wrong code fails without storing anything
+    let mut b_pake = schnorr_nizk::pake_pairing::PakePairing::new(BID, DID, schnorr_nizk::CONST_INITIATOR_ROLE, b"483920", schnorr_nizk::KeySize::Bits256);
+    let mut d_pake = schnorr_nizk::pake_pairing::PakePairing::new(DID, BID, schnorr_nizk::CONST_RECEIVER_ROLE, b"483921", schnorr_nizk::KeySize::Bits256);
+    let result = run_key_agreement(&mut b_pake, &mut d_pake);
+    println!("Pairing with a wrong code completed? (expected false, false) = {:?}\n", result);
+
+    // Pairing with the right code exchanges the public keys and the first shared secret key
+    let mut b_pake = schnorr_nizk::pake_pairing::PakePairing::new(BID, DID, schnorr_nizk::CONST_INITIATOR_ROLE, b"483920", schnorr_nizk::KeySize::Bits256);
+    let mut d_pake = schnorr_nizk::pake_pairing::PakePairing::new(DID, BID, schnorr_nizk::CONST_RECEIVER_ROLE, b"483920", schnorr_nizk::KeySize::Bits256);
+    let result = run_key_agreement(&mut b_pake, &mut d_pake);
+    println!("Pairing with the right code completed? = {:?}\n", result);
+
+    // D can authenticate with NIZK proofs right away
+    let m = format!("NIZK AUTH message of {:?}", DID);
+    let proof = schnorr_nizk::gen_nizk_proof(DID, BID, m, true);
+    let m = format!("NIZK AUTH message of {:?}", DID);
+    let result = schnorr_nizk::verify_nizk_proof(BID, DID, m, proof, true);
+    println!("NIZK proof of D accepted after pairing? = {:?}\n", result);
+
+    // A pairing code is only accepted for one pairing
+    let mut b_pake = schnorr_nizk::pake_pairing::PakePairing::new(BID, DID, schnorr_nizk::CONST_INITIATOR_ROLE, b"483920", schnorr_nizk::KeySize::Bits256);
+    let mut d_pake = schnorr_nizk::pake_pairing::PakePairing::new(DID, BID, schnorr_nizk::CONST_RECEIVER_ROLE, b"483920", schnorr_nizk::KeySize::Bits256);
+    let result = run_key_agreement(&mut b_pake, &mut d_pake);
+    println!("Pairing with a used code completed? (expected false, false) = {:?}\n", result);
+
+    // D was reset and has a new key. B only replaces the known key of D if re-pairing is allowed
+    let (_, priv_kD) = schnorr_nizk::gen_random_key_pair();
+    my_key.update_key_in_ring(Vec::from(priv_kD));
+    let mut b_pake = schnorr_nizk::pake_pairing::PakePairing::new(BID, DID, schnorr_nizk::CONST_INITIATOR_ROLE, b"650117", schnorr_nizk::KeySize::Bits256);
+    let mut d_pake = schnorr_nizk::pake_pairing::PakePairing::new(DID, BID, schnorr_nizk::CONST_RECEIVER_ROLE, b"650117", schnorr_nizk::KeySize::Bits256);
+    let result = run_key_agreement(&mut b_pake, &mut d_pake);
+    println!("Pairing with a new key of D completed without re-pairing? (expected false, false) = {:?}\n", result);
+    let mut b_pake = schnorr_nizk::pake_pairing::PakePairing::new(BID, DID, schnorr_nizk::CONST_INITIATOR_ROLE, b"650117", schnorr_nizk::KeySize::Bits256);
+    let mut d_pake = schnorr_nizk::pake_pairing::PakePairing::new(DID, BID, schnorr_nizk::CONST_RECEIVER_ROLE, b"650117", schnorr_nizk::KeySize::Bits256);
+    b_pake.allow_repair();
+    let result = run_key_agreement(&mut b_pake, &mut d_pake);
+    println!("Re-pairing with the new key of D completed? = {:?}\n", result);
+
+    println!("End of PAKE pairing test \n\n");
+    /*
+    ************************************************************************************************
+    ************************************ End PAKE Pairing Test *************************************
+    ************************************************************************************************
+    */
+
+    /*
+    ************************************************************************************************
+    ********************************** Noise Key Agreement Test ************************************
+    ************************************************************************************************
+    */
+    // Run with: cargo run --bin main --features noise
+    #[cfg(feature = "noise")]
+    {
+        println!("Begin of Noise key agreement tests for generating a shared secret key:\n");
+
+        // Save different measures inside a Vec
+        let mut all_measurements: Vec<f32> = Vec::new();
+        let mut completed = (false, false);
+
+        for i in 0..iterations {
+            // Measure duration of each one
+            let start = Instant::now();
+
+            // Run the Noise XX handshake and the identity exchange in memory
+            let mut a_noise = schnorr_nizk::noise_key_agreement::NoiseKeyAgreement::new(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE, schnorr_nizk::KeySize::Bits256);
+            let mut b_noise = schnorr_nizk::noise_key_agreement::NoiseKeyAgreement::new(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE, schnorr_nizk::KeySize::Bits256);
+            let result = run_key_agreement(&mut a_noise, &mut b_noise);
+
+            // Calculate Duration
+            let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
+            all_measurements.push(duration);
+
+            if i == iterations - 1 {
+                completed = result;
+            }
+        }
+
+        // Calculate the average run duration
+        let sum: f32 = all_measurements.iter().sum();
+        let avg = sum / all_measurements.len() as f32;
+        println!("The average execution time is: {}ms\n", avg);
+        println!("Result of last key agreement: {}, {}\n", completed.0, completed.1);
+
+        // The shared values have to work with NIZK proofs unchanged
+        let m = format!("NIZK AUTH message of {:?}", AID);
+        let proof = schnorr_nizk::gen_nizk_proof(AID, BID, m, true);
+        let m = format!("NIZK AUTH message of {:?}", AID);
+        let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, proof, true);
+        println!("NIZK proof accepted with the Noise shared secret key? = {:?}\n", result);
+
+        // A device with another identity key must not complete the key agreement
+        let (pub_k_fake, _) = schnorr_nizk::gen_random_key_pair();
+        let fake_cert = schnorr_nizk::enrollment::issue_certificate(AID, pub_k_fake, valid_from + 1, valid_until, vec![String::from("sensor")]).unwrap();
+        schnorr_nizk::enrollment::enroll_peer(&fake_cert).unwrap();
+        let mut a_noise = schnorr_nizk::noise_key_agreement::NoiseKeyAgreement::new(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE, schnorr_nizk::KeySize::Bits256);
+        let mut b_noise = schnorr_nizk::noise_key_agreement::NoiseKeyAgreement::new(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE, schnorr_nizk::KeySize::Bits256);
+        let result = run_key_agreement(&mut a_noise, &mut b_noise);
+        println!("Key agreement with an unknown identity key completed? (expected false, false) = {:?}\n", result);
+
+        // The older certificate can not roll the key back, the authority has to certify the key again
+        println!("Enrolling the older certificate of A, expected OutdatedCertificate: {:?}\n", schnorr_nizk::enrollment::enroll_peer(&cert_a));
+        let cert_a_renewed = schnorr_nizk::enrollment::issue_certificate(AID, pub_kA, valid_from + 2, valid_until, cert_a.roles.clone()).unwrap();
+        schnorr_nizk::enrollment::enroll_peer(&cert_a_renewed).unwrap();
+
+        println!("End of Noise key agreement \n\n");
+    }
+    /*
+    ************************************************************************************************
+    ******************************** End Noise Key Agreement Test **********************************
+    ************************************************************************************************
+    */
+
     /*
     ************************************************************************************************
     **************************************** Test NIZK Proof ***************************************
@@ -197,6 +462,70 @@ fn main() {
     */
 
 
+    /*
+    ************************************************************************************************
+    ***************************** Compare 128-bit and 256-bit Shared Keys **************************
+    ************************************************************************************************
+    */
+    println!("Start shared key size comparison:\n");
+
+    for key_size in [schnorr_nizk::KeySize::Bits128, schnorr_nizk::KeySize::Bits256] {
+        // Agree on a shared secret key with the chosen size
+        let mut a_int_auth = schnorr_nizk::get_int_mut_auth_instance_with_key_size(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE, key_size);
+        let mut b_int_auth = schnorr_nizk::get_int_mut_auth_instance_with_key_size(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE, schnorr_nizk::KeySize::Bits128);
+        let (val1, val2, req_type) = a_int_auth.gen_next_values();
+        let _ = b_int_auth.add_recipient_values(req_type, val1, val2);
+        let (val1, val2, req_type) = b_int_auth.gen_next_values();
+        let _ = a_int_auth.add_recipient_values(req_type, val1, val2);
+        let (val1, val2, req_type) = a_int_auth.gen_next_values();
+        let _ = b_int_auth.add_recipient_values(req_type, val1, val2);
+        let (val1, val2, req_type) = b_int_auth.gen_next_values();
+        let _ = a_int_auth.add_recipient_values(req_type, val1, val2);
+        let _ = a_int_auth.verify_proof();
+        let _ = b_int_auth.verify_proof();
+        let (val1, val2, req_type) = a_int_auth.gen_next_values();
+        let _ = b_int_auth.add_recipient_values(req_type, val1, val2);
+        let _ = b_int_auth.confirm_shared_secret_key();
+        let (val1, val2, req_type) = b_int_auth.gen_next_values();
+        let _ = a_int_auth.add_recipient_values(req_type, val1, val2);
+        let _ = a_int_auth.confirm_shared_secret_key();
+        println!("Negotiated key size: {:?}", schnorr_nizk::get_shared_key_size(AID, BID));
+
+        // Measure generating and verifying NIZK proofs with this key size
+        let mut all_measurements_gen: Vec<f32> = Vec::new();
+        let mut all_measurements_ver: Vec<f32> = Vec::new();
+        let mut res = false;
+        for i in 0..iterations {
+            let m = format!("NIZK AUTH message of {:?}", AID);
+            let start = Instant::now();
+            let proof = schnorr_nizk::gen_nizk_proof(AID, BID, m, true);
+            all_measurements_gen.push((start.elapsed().as_secs_f32()) * 1_000.0);
+
+            let m = format!("NIZK AUTH message of {:?}", AID);
+            let start = Instant::now();
+            let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, proof, true);
+            all_measurements_ver.push((start.elapsed().as_secs_f32()) * 1_000.0);
+
+            if i == iterations - 1 {
+                res = result;
+            }
+        }
+
+        let avg1 = all_measurements_gen.iter().sum::<f32>() / all_measurements_gen.len() as f32;
+        let avg2 = all_measurements_ver.iter().sum::<f32>() / all_measurements_ver.len() as f32;
+        println!("{:?}: average generation time {}ms, average verification time {}ms", key_size, avg1, avg2);
+        println!("{:?}: result of last proof is: {:?}\n", key_size, res);
+    }
+
+    println!("End of shared key size comparison \n\n");
+
+    /*
+    ************************************************************************************************
+    *************************** End Compare 128-bit and 256-bit Shared Keys ************************
+    ************************************************************************************************
+    */
+
+
     /*
     ************************************************************************************************
     ************************************ Test Mutual NIZK Auth *************************************
@@ -255,6 +584,155 @@ fn main() {
     ************************************************************************************************
     */
 
+
+    /*
+    ************************************************************************************************
+    ************************************** Key Rotation Test ***************************************
+    ************************************************************************************************
+    */
+    println!("Starting key rotation test:\n");
+
+    // Proof of A with its old key, generated before the rotation
+    let m = format!("NIZK AUTH message of {:?}", AID);
+    let old_key_proof = schnorr_nizk::gen_nizk_proof(AID, BID, m, false);
+
+    // A rotates its key and asks B for a new shared secret key. B accepts the old key for another minute
+    let mut rotation = schnorr_nizk::key_rotation::rotate_key(AID, true).unwrap();
+    let uncertified = schnorr_nizk::key_rotation::apply_key_rotation(&rotation, 60 * 1000);
+    println!("Applying the rotation without a certificate, expected CertificateRequired: {:?}\n", uncertified);
+
+    // A is enrolled by the authority, so the authority has to certify the new key as well
+    let cert_a_rotated = schnorr_nizk::enrollment::issue_certificate(AID, rotation.new_public_key, valid_from, valid_until, cert_a.roles.clone()).unwrap();
+    rotation.certificate = Some(cert_a_rotated);
+    let renew = schnorr_nizk::key_rotation::apply_key_rotation(&rotation, 60 * 1000);
+    println!("B applied key rotation of A: {:?}\n", renew);
+
+    // Rotation can only be applied once
+    let replayed = schnorr_nizk::key_rotation::apply_key_rotation(&rotation, 60 * 1000);
+    println!("Applying the same rotation again, expected OldKeyMismatch: {:?}\n", replayed);
+
+    // Proof with the old key is still accepted during the grace period
+    let m = format!("NIZK AUTH message of {:?}", AID);
+    let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, old_key_proof, false);
+    println!("Proof with the old key accepted during the grace period? = {:?}\n", result);
+
+    // A uses its new key only after B applied the rotation
+    let completed = schnorr_nizk::key_rotation::complete_key_rotation(AID, &rotation);
+    println!("A completed its key rotation: {:?}\n", completed);
+
+    // Agree on a new shared secret key with the new key
+    if let Ok(true) = renew {
+        let mut a_int = schnorr_nizk::get_int_mut_auth_instance(AID, BID, schnorr_nizk::CONST_INITIATOR_ROLE);
+        let mut b_int = schnorr_nizk::get_int_mut_auth_instance(BID, AID, schnorr_nizk::CONST_RECEIVER_ROLE);
+        let result = run_key_agreement(&mut a_int, &mut b_int);
+        println!("New shared secret key agreed with the new key of A? = {:?}\n", result);
+    }
+
+    // Proof with the new key
+    let m = format!("NIZK AUTH message of {:?}", AID);
+    let proof = schnorr_nizk::gen_nizk_proof(AID, BID, m, true);
+    let m = format!("NIZK AUTH message of {:?}", AID);
+    let result = schnorr_nizk::verify_nizk_proof(BID, AID, m, proof, true);
+    println!("Proof with the new key accepted? = {:?}\n", result);
+
+    println!("End of key rotation test \n\n");
+
+    /*
+    ************************************************************************************************
+    ************************************ End Key Rotation Test *************************************
+    ************************************************************************************************
+    */
+
+    /*
+    ************************************************************************************************
+    ************************************ Key Import/Export Test ************************************
+    ************************************************************************************************
+    */
+    println!("Begin of key import/export test:\n");
+
+    // Keys generated from an Ed25519 seed can be exported as PKCS#8 and imported on another device
+    let EID = DeviceId::Number(50000);
+    let FID = DeviceId::Number(60000);
+    let pub_kE = schnorr_nizk::key_formats::generate_exportable_key(EID).unwrap();
+    let private_pem = schnorr_nizk::key_formats::export_private_key_pkcs8_pem(EID).unwrap();
+    println!("{}", private_pem);
+    let pub_kF = schnorr_nizk::key_formats::import_private_key_pkcs8_pem(FID, &private_pem).unwrap();
+    println!("Imported PKCS#8 key has the same public key? = {:?}", pub_kE == pub_kF);
+    let private_der = schnorr_nizk::key_formats::export_private_key_pkcs8_der(FID).unwrap();
+    let pub_kF = schnorr_nizk::key_formats::import_private_key_pkcs8_der(FID, &private_der).unwrap();
+    println!("PKCS#8 DER round trip keeps the key? = {:?}\n", pub_kE == pub_kF);
+
+    // Public keys in SPKI DER/PEM and OpenSSH format
+    let public_pem = schnorr_nizk::key_formats::public_key_to_pem(&pub_kE);
+    println!("{}", public_pem);
+    let openssh = schnorr_nizk::key_formats::public_key_to_openssh(&pub_kE, "device-50000");
+    println!("{}", openssh);
+    let der = schnorr_nizk::key_formats::public_key_to_spki_der(&pub_kE);
+    println!("SPKI DER round trip keeps the key? = {:?}", schnorr_nizk::key_formats::public_key_from_spki_der(&der).unwrap() == pub_kE);
+    println!("PEM round trip keeps the key? = {:?}", schnorr_nizk::key_formats::public_key_from_pem(&public_pem).unwrap() == pub_kE);
+    println!("OpenSSH round trip keeps the key? = {:?}\n", schnorr_nizk::key_formats::public_key_from_openssh(&openssh).unwrap() == pub_kE);
+
+    // Keys generated without a seed can not be written as PKCS#8
+    let (_, priv_kE) = schnorr_nizk::gen_random_key_pair();
+    let desc = format!("PrivateKey:{}", EID);
+    let mut my_key = schnorr_nizk::get_key_instance(&desc, 32, Some(Vec::from(priv_kE))).unwrap();
+    my_key.update_key_in_ring(Vec::from(priv_kE));
+    let result = schnorr_nizk::key_formats::export_private_key_pkcs8_pem(EID);
+    println!("Export of a key without seed (expected NoSeedAvailable) = {:?}\n", result.err());
+
+    println!("End of key import/export test \n\n");
+
+    /*
+    ************************************************************************************************
+    ********************************** End Key Import/Export Test **********************************
+    ************************************************************************************************
+    */
+
+    /*
+    ************************************************************************************************
+    ************************************* Backup/Restore Test **************************************
+    ************************************************************************************************
+    */
+    println!("Begin of backup and restore test:\n");
+
+    // Back up the complete auth state of B
+    let archive = schnorr_nizk::backup::create_backup(BID, b"correct horse battery staple").unwrap();
+    println!("Backup of device {} created, {} bytes encrypted\n", archive.device_ID, archive.ciphertext.len());
+
+    // Archives can only be opened with the right passphrase and without modifications
+    let result = schnorr_nizk::backup::restore_backup(&archive, b"wrong passphrase");
+    println!("Restore with a wrong passphrase (expected DecryptionFailed) = {:?}", result.err());
+    let mut modified_archive = archive.clone();
+    modified_archive.created_at += 1;
+    let result = schnorr_nizk::backup::restore_backup(&modified_archive, b"correct horse battery staple");
+    println!("Restore of a modified archive (expected DecryptionFailed) = {:?}", result.err());
+    let result = schnorr_nizk::backup::restore_backup(&archive, b"correct horse battery staple");
+    println!("Restore with the right passphrase = {:?}\n", result);
+
+    // Once B used its shared counter with A, the backup would roll it back
+    let m = format!("NIZK AUTH message of {:?}", BID);
+    let proof = schnorr_nizk::gen_nizk_proof(BID, AID, m, true);
+    let m = format!("NIZK AUTH message of {:?}", BID);
+    let result = schnorr_nizk::verify_nizk_proof(AID, BID, m, proof, true);
+    println!("Proof of B accepted after the restore? = {:?}", result);
+    let result = schnorr_nizk::backup::restore_backup(&archive, b"correct horse battery staple");
+    println!("Restore of the older backup (expected CounterRollback) = {:?}\n", result.err());
+
+    // The counter of a new shared key starts at 1 again, so the backup is refused for the old key
+    let mut b_int = schnorr_nizk::get_int_mut_auth_instance(BID, AID, schnorr_nizk::CONST_INITIATOR_ROLE);
+    let mut a_int = schnorr_nizk::get_int_mut_auth_instance(AID, BID, schnorr_nizk::CONST_RECEIVER_ROLE);
+    run_key_agreement(&mut b_int, &mut a_int);
+    let result = schnorr_nizk::backup::restore_backup(&archive, b"correct horse battery staple");
+    println!("Restore of the backup after a new shared key (expected SharedKeyChanged) = {:?}\n", result.err());
+
+    println!("End of backup and restore test \n\n");
+
+    /*
+    ************************************************************************************************
+    *********************************** End Backup/Restore Test ************************************
+    ************************************************************************************************
+    */
+
     /*
     ************************************************************************************************
     ****************** Test of Intrusion Detection/Prevention and Access Control *******************
@@ -275,62 +753,376 @@ fn main() {
 
     // Test Access Management system
     // Test resource creation
-    let resource_id: u32 = 12345;
-    println!("Creating a resource with ID {:?}", resource_id);
-    let resp = schnorr_nizk::access_control::add_resource(resource_id, None);
-    println!("received response code {}\n", resp);
+    let resource_id = ResourcePath::from(12345);
+    println!("Creating a resource with ID {}", resource_id);
+    let resp = schnorr_nizk::access_control::add_resource(&resource_id, None);
+    println!("received response {:?}\n", resp);
 
     // Delete a resource
-    println!("Deleting resource with ID {:?}", resource_id);
-    let resp = schnorr_nizk::access_control::remove_resource(resource_id);
-    println!("received response code {}\n", resp);
+    println!("Deleting resource with ID {}", resource_id);
+    let resp = schnorr_nizk::access_control::remove_resource(&resource_id);
+    println!("received response {:?}\n", resp);
 
     // Create resource with actions
-    println!("Recreating resource with ID {:?}", resource_id);
+    println!("Recreating resource with ID {}", resource_id);
     let mut actions: Vec<Vec<u8>> = Vec::new();
     actions.push(String::from("POST").into_bytes());
     actions.push(String::from("GET").into_bytes());
     actions.push(String::from("SET").into_bytes());
-    let resp = schnorr_nizk::access_control::add_resource(resource_id, Some(actions));
-    println!("received response code {}\n", resp);
+    let resp = schnorr_nizk::access_control::add_resource(&resource_id, Some(actions));
+    println!("received response {:?}\n", resp);
 
     // Add a new action
-    println!("Adding a new action to resource with ID {:?}", resource_id);
-    let resp = schnorr_nizk::access_control::add_action_to_resource(resource_id, String::from("DEL").into_bytes());
-    println!("received response code {}\n", resp);
+    println!("Adding a new action to resource with ID {}", resource_id);
+    let resp = schnorr_nizk::access_control::add_action_to_resource(&resource_id, String::from("DEL").into_bytes());
+    println!("received response {:?}\n", resp);
 
     // Add a new action
-    println!("Deleting an action to resource with ID {:?}", resource_id);
-    let resp = schnorr_nizk::access_control::remove_action_from_resource(resource_id, String::from("POST").into_bytes());
-    println!("received response code {}\n", resp);
+    println!("Deleting an action to resource with ID {}", resource_id);
+    let resp = schnorr_nizk::access_control::remove_action_from_resource(&resource_id, String::from("POST").into_bytes());
+    println!("received response {:?}\n", resp);
 
     // Add device ID to all actions
-    println!("Adding device {} to all actions of resource with ID {:?}", AID, resource_id);
-    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, AID);
-    println!("received response code {}\n", resp);
+    println!("Adding device {} to all actions of resource with ID {}", AID, resource_id);
+    let resp = schnorr_nizk::access_control::add_device_to_all_actions(&resource_id, AID);
+    println!("received response {:?}\n", resp);
 
-    println!("Adding device {} to all actions of resource with ID {:?}", BID, resource_id);
-    let resp = schnorr_nizk::access_control::add_device_to_all_actions(resource_id, BID);
-    println!("received response code {}\n", resp);
+    println!("Adding device {} to all actions of resource with ID {}", BID, resource_id);
+    let resp = schnorr_nizk::access_control::add_device_to_all_actions(&resource_id, BID);
+    println!("received response {:?}\n", resp);
 
     // Removing device form actions
-    println!("Removing device {} from all actions of resource with ID {:?}", AID, resource_id);
-    let resp = schnorr_nizk::access_control::remove_device_from_all_actions(resource_id, AID);
-    println!("received response code {}\n", resp);
+    println!("Removing device {} from all actions of resource with ID {}", AID, resource_id);
+    let resp = schnorr_nizk::access_control::remove_device_from_all_actions(&resource_id, AID);
+    println!("received response {:?}\n", resp);
 
-    println!("Removing device {} from last action of resource with ID {:?}", BID, resource_id);
-    let resp = schnorr_nizk::access_control::remove_device_from_resource_action(resource_id, String::from("DEL").into_bytes(), BID);
-    println!("received response code {}\n", resp);
+    println!("Removing device {} from last action of resource with ID {}", BID, resource_id);
+    let resp = schnorr_nizk::access_control::remove_device_from_resource_action(&resource_id, String::from("DEL").into_bytes(), BID);
+    println!("received response {:?}\n", resp);
 
     // Check access of a device to a resource
-    println!("Check if device {} is allowed to access an action of resource with ID {:?}.\nexpected response: false.", AID, resource_id);
-    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("DEL").into_bytes(), AID);
+    println!("Check if device {} is allowed to access an action of resource with ID {}.\nexpected response: false.", AID, resource_id);
+    let resp = schnorr_nizk::access_control::check_access(&resource_id, String::from("DEL").into_bytes(), AID);
     println!("received response: {}\n", resp);
 
-    println!("Check if device {} is allowed to access an action of resource with ID {:?}.\nexpected response: true.", BID, resource_id);
-    let resp = schnorr_nizk::access_control::check_access(resource_id, String::from("GET").into_bytes(), BID);
+    println!("Check if device {} is allowed to access an action of resource with ID {}.\nexpected response: true.", BID, resource_id);
+    let resp = schnorr_nizk::access_control::check_access(&resource_id, String::from("GET").into_bytes(), BID);
+    println!("received response {}\n", resp);
+
+    // Devices are listed at most once per action
+    let resp = schnorr_nizk::access_control::add_device_to_resource_action(&resource_id, String::from("GET").into_bytes(), BID);
+    println!("Adding device {} to GET a second time (expected Err(DuplicateEntry)) = {:?}", BID, resp);
+    let resp = schnorr_nizk::access_control::add_action_to_resource(&ResourcePath::from(99999), String::from("GET").into_bytes());
+    println!("Adding an action to a missing resource (expected Err(ResourceNotFound)) = {:?}", resp);
+    println!("Resources: {:?}", schnorr_nizk::access_control::list_resources());
+    println!("Actions of resource {}: {:?}", resource_id, schnorr_nizk::access_control::list_actions(&resource_id));
+    println!("Grants of device {}: {:?}", BID, schnorr_nizk::access_control::list_device_grants(BID));
+    let grants = vec![schnorr_nizk::credentials::AccessGrant { resource_ID: resource_id.clone(), action: String::from("DEL").into_bytes() },
+                      schnorr_nizk::credentials::AccessGrant { resource_ID: resource_id.clone(), action: String::from("SET").into_bytes() }];
+    let resp = schnorr_nizk::access_control::grant_device_actions(AID, &grants);
+    println!("Granting device {} DEL and SET at once (expected Ok(())) = {:?}", AID, resp);
+    println!("Grants of device {}: {:?}", AID, schnorr_nizk::access_control::list_device_grants(AID));
+    let resp = schnorr_nizk::access_control::revoke_device_actions(AID, &grants);
+    println!("Revoking both again (expected Ok(())) = {:?}", resp);
+    println!("Grants of device {} (expected Ok([])): {:?}\n", AID, schnorr_nizk::access_control::list_device_grants(AID));
+
+    // Requests are authenticated and authorized in one step
+    let request = schnorr_nizk::authorization::new_authorization_request(BID, AID, resource_id.clone(), String::from("GET").into_bytes(), b"temperature".to_vec(), true);
+    let decision = schnorr_nizk::authorization::authorize(AID, &request, true);
+    println!("Authorization of GET by device {} (expected Allow(Device)) = {:?}", BID, decision);
+    let request = schnorr_nizk::authorization::new_authorization_request(BID, AID, resource_id.clone(), String::from("DEL").into_bytes(), Vec::new(), true);
+    let decision = schnorr_nizk::authorization::authorize(AID, &request, true);
+    println!("Authorization of DEL by device {} (expected Deny(AccessDenied(NotGranted))) = {:?}", BID, decision);
+    let mut request = schnorr_nizk::authorization::new_authorization_request(BID, AID, resource_id.clone(), String::from("GET").into_bytes(), b"temperature".to_vec(), false);
+    request.payload = b"humidity".to_vec();
+    let decision = schnorr_nizk::authorization::authorize(AID, &request, false);
+    println!("Authorization of a modified request (expected Deny(InvalidProof)) = {:?}\n", decision);
+
+    // An admin device manages the access control lists remotely with NIZK authenticated commands
+    let management_id = schnorr_nizk::acl_management::get_management_resource_path(None);
+    let protected_management_id = schnorr_nizk::acl_management::get_management_resource_path(Some(&resource_id));
+    schnorr_nizk::access_control::add_resource(&management_id, Some(vec![String::from("*").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_device_to_all_actions(&management_id, BID).unwrap();
+    schnorr_nizk::access_control::add_resource(&protected_management_id, Some(vec![String::from("*").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_denied_device_to_resource_action(&protected_management_id, String::from("*").into_bytes(), BID).unwrap();
+    let managed_resource_id = ResourcePath::from(25001);
+    let commands = vec![
+        schnorr_nizk::acl_management::ManagementCommand::AddResource { resource_ID: managed_resource_id.clone(), actions: vec![String::from("GET").into_bytes()] },
+        schnorr_nizk::acl_management::ManagementCommand::Grant { device_ID: BID, resource_ID: managed_resource_id.clone(), action: String::from("GET").into_bytes() },
+        schnorr_nizk::acl_management::ManagementCommand::ListActions { resource_ID: managed_resource_id.clone() },
+        schnorr_nizk::acl_management::ManagementCommand::RemoveResource { resource_ID: resource_id.clone() },
+    ];
+    for command in commands {
+        let request = schnorr_nizk::acl_management::new_management_request(BID, AID, command.clone(), true);
+        let resp = schnorr_nizk::acl_management::handle_management_request(AID, &request, true);
+        println!("Management command {:?} of device {} = {:?}", command, BID, resp);
+    }
+    println!("(expected Applied, Applied, Actions, AccessDenied(ExplicitlyDenied))");
+    println!("Check if device {} can access the resource created by the admin.\nexpected response: true.", BID);
+    let resp = schnorr_nizk::access_control::check_access(&managed_resource_id, String::from("GET").into_bytes(), BID);
+    println!("received response {}", resp);
+    let mut request = schnorr_nizk::acl_management::new_management_request(BID, AID, schnorr_nizk::acl_management::ManagementCommand::ListResources, false);
+    request.command = schnorr_nizk::acl_management::ManagementCommand::ListActions { resource_ID: managed_resource_id.clone() };
+    let resp = schnorr_nizk::acl_management::handle_management_request(AID, &request, false);
+    println!("Modified management request (expected Err(InvalidProof)) = {:?}", resp);
+    let audit_trail = schnorr_nizk::acl_management::read_audit_trail().unwrap();
+    println!("Audit trail has {} entries, the last one: {:?}\n", audit_trail.len(), audit_trail.last());
+    schnorr_nizk::access_control::remove_resource(&managed_resource_id).unwrap();
+    schnorr_nizk::access_control::remove_resource(&protected_management_id).unwrap();
+    schnorr_nizk::access_control::remove_resource(&management_id).unwrap();
+
+    // Changes to several resources are written together or not at all
+    let first_resource_id = ResourcePath::from(24001);
+    let second_resource_id = ResourcePath::from(24002);
+    let resp = schnorr_nizk::access_control::transaction(|acl| {
+        acl.add_resource(&first_resource_id, Some(vec![String::from("GET").into_bytes()]))?;
+        acl.add_device_to_all_actions(&first_resource_id, BID)?;
+        acl.add_resource(&resource_id, None)
+    });
+    println!("Transaction creating resource {} and the existing resource {} (expected Err(ResourceAlreadyExists)) = {:?}", first_resource_id, resource_id, resp);
+    println!("Check if the first resource of the failed transaction was discarded.\nexpected response: false.");
+    let resp = schnorr_nizk::access_control::check_access(&first_resource_id, String::from("GET").into_bytes(), BID);
+    println!("received response {}", resp);
+    let resp = schnorr_nizk::access_control::transaction(|acl| {
+        for transaction_resource_id in [&first_resource_id, &second_resource_id] {
+            acl.add_resource(transaction_resource_id, Some(vec![String::from("GET").into_bytes()]))?;
+            acl.add_device_to_all_actions(transaction_resource_id, BID)?;
+        }
+        Ok(())
+    });
+    println!("Transaction creating resources {} and {} (expected Ok(())) = {:?}", first_resource_id, second_resource_id, resp);
+    println!("Check if device {} can access both resources.\nexpected response: true, true.", BID);
+    let first_resp = schnorr_nizk::access_control::check_access(&first_resource_id, String::from("GET").into_bytes(), BID);
+    let second_resp = schnorr_nizk::access_control::check_access(&second_resource_id, String::from("GET").into_bytes(), BID);
+    println!("received response {}, {}\n", first_resp, second_resp);
+    schnorr_nizk::access_control::remove_resource(&first_resource_id).unwrap();
+    schnorr_nizk::access_control::remove_resource(&second_resource_id).unwrap();
+
+    // Roles grant actions to all their members
+    let sensor_resource_ids = [22001, 22002, 22003].map(ResourcePath::from);
+    schnorr_nizk::access_control::add_role(String::from("sensors")).unwrap();
+    schnorr_nizk::access_control::add_device_to_role(String::from("sensors"), AID).unwrap();
+    for sensor_resource_id in sensor_resource_ids.iter() {
+        schnorr_nizk::access_control::add_resource(sensor_resource_id, Some(vec![String::from("PUT").into_bytes()])).unwrap();
+        schnorr_nizk::access_control::add_role_to_all_actions(sensor_resource_id, String::from("sensors")).unwrap();
+    }
+    println!("Roles of device {}: {:?}", AID, schnorr_nizk::access_control::get_device_roles(AID));
+    println!("Members of role sensors: {:?}", schnorr_nizk::access_control::get_role_members(String::from("sensors")));
+    println!("Check if device {} can access resource {} by its role.\nexpected response: true.", AID, sensor_resource_ids[2]);
+    let resp = schnorr_nizk::access_control::check_access(&sensor_resource_ids[2], String::from("PUT").into_bytes(), AID);
+    println!("received response {}", resp);
+    println!("Check if device {} without the role can access resource {}.\nexpected response: false.", BID, sensor_resource_ids[2]);
+    let resp = schnorr_nizk::access_control::check_access(&sensor_resource_ids[2], String::from("PUT").into_bytes(), BID);
+    println!("received response {}", resp);
+    schnorr_nizk::access_control::remove_device_from_role(String::from("sensors"), AID).unwrap();
+    println!("Check again after removing device {} from the role.\nexpected response: false.", AID);
+    let resp = schnorr_nizk::access_control::check_access(&sensor_resource_ids[2], String::from("PUT").into_bytes(), AID);
+    println!("received response {}\n", resp);
+    for sensor_resource_id in sensor_resource_ids.iter() {
+        schnorr_nizk::access_control::remove_resource(sensor_resource_id).unwrap();
+    }
+    schnorr_nizk::access_control::remove_role(String::from("sensors")).unwrap();
+
+    // Conditions of an action are evaluated at the time of a clock, which can be set in tests
+    let conditional_resource_id = ResourcePath::from(23001);
+    schnorr_nizk::access_control::add_resource(&conditional_resource_id, Some(vec![String::from("PUT").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_device_to_all_actions(&conditional_resource_id, BID).unwrap();
+    let now = Utc::now().timestamp_millis();
+    let clock = schnorr_nizk::clock::ManualClock::new(now - now % 3_600_000 + 1_800_000);
+    let hour = Utc::now().hour();
+    let conditions = schnorr_nizk::access_control::AccessConditions {
+        allowed_hours: Some((hour, (hour + 1) % 24)),
+        max_invocations: Some((2, 60_000)),
+        max_auth_age: Some(60 * 60 * 1000),
+        ..Default::default()
+    };
+    schnorr_nizk::access_control::set_action_conditions(&conditional_resource_id, String::from("PUT").into_bytes(), Some(conditions)).unwrap();
+    for i in 0..3 {
+        let resp = schnorr_nizk::access_control::get_access_reason(&conditional_resource_id, b"PUT", BID, &clock);
+        println!("Invocation {} of PUT by device {} within the allowed hour (expected Ok, Ok, RateLimited) = {:?}", i + 1, BID, resp);
+    }
+    clock.advance(60_000);
+    let resp = schnorr_nizk::access_control::get_access_reason(&conditional_resource_id, b"PUT", BID, &clock);
+    println!("Invocation after the rate limit period (expected Ok) = {:?}", resp);
+    clock.advance(60 * 60 * 1000);
+    let resp = schnorr_nizk::access_control::check_access_with_clock(&conditional_resource_id, String::from("PUT").into_bytes(), BID, &clock);
+    println!("Check access one hour later.\nexpected response: false.\nreceived response {}\n", resp);
+    schnorr_nizk::access_control::remove_resource(&conditional_resource_id).unwrap();
+
+    // Credentials of the authority grant access without an entry in the local list
+    let credential_resource_id = ResourcePath::from(54321);
+    let grants = vec![schnorr_nizk::credentials::AccessGrant { resource_ID: credential_resource_id.clone(), action: String::from("GET").into_bytes() }];
+    let credential = schnorr_nizk::credentials::issue_credential(AID, grants, Utc::now().timestamp_millis() + 60 * 60 * 1000).unwrap();
+    println!("Check if device {} can access resource {} before presenting a credential.\nexpected response: false.", AID, credential_resource_id);
+    let resp = schnorr_nizk::access_control::check_access(&credential_resource_id, String::from("GET").into_bytes(), AID);
+    println!("received response {}\n", resp);
+
+    // A presents its credential together with a NIZK proof
+    let proof = schnorr_nizk::credentials::present_credential(AID, BID, &credential, true);
+    let resp = schnorr_nizk::credentials::accept_presented_credential(BID, AID, &credential, proof, true);
+    println!("Credential of {} accepted? = {:?}", AID, resp);
+    println!("Check if device {} can access resource {} with its credential.\nexpected response: true.", AID, credential_resource_id);
+    let resp = schnorr_nizk::access_control::check_access(&credential_resource_id, String::from("GET").into_bytes(), AID);
+    println!("received response {}", resp);
+    println!("Check an action that is not in the credential.\nexpected response: false.");
+    let resp = schnorr_nizk::access_control::check_access(&credential_resource_id, String::from("DEL").into_bytes(), AID);
     println!("received response {}\n", resp);
 
+    // Credentials with added rights are rejected
+    let mut tampered_credential = credential.clone();
+    tampered_credential.grants.push(schnorr_nizk::credentials::AccessGrant { resource_ID: credential_resource_id.clone(), action: String::from("DEL").into_bytes() });
+    let proof = schnorr_nizk::credentials::present_credential(AID, BID, &tampered_credential, true);
+    let resp = schnorr_nizk::credentials::accept_presented_credential(BID, AID, &tampered_credential, proof, true);
+    println!("Tampered credential accepted? (expected InvalidSignature) = {:?}\n", resp);
+
+    // Grants are inherited by all resources below, and deny entries override them anywhere in the hierarchy
+    let building_id: ResourcePath = "building".parse().unwrap();
+    let floor_id: ResourcePath = "building/floor2".parse().unwrap();
+    let door_id: ResourcePath = "building/floor2/door7".parse().unwrap();
+    schnorr_nizk::access_control::add_resource(&building_id, Some(vec![String::from("read:*").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_device_to_all_actions(&building_id, AID).unwrap();
+    schnorr_nizk::access_control::add_device_to_all_actions(&building_id, BID).unwrap();
+    schnorr_nizk::access_control::add_resource(&floor_id, Some(vec![String::from("read:temp").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_denied_device_to_resource_action(&floor_id, String::from("read:temp").into_bytes(), AID).unwrap();
+    let resp = schnorr_nizk::access_control::get_access_reason(&door_id, b"read:temp", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to read:temp of {} (expected Ok(Device)) = {:?}", BID, door_id, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&door_id, b"write:temp", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to write:temp of {} (expected Err(NotGranted)) = {:?}", BID, door_id, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&door_id, b"read:temp", AID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to read:temp of {} (expected Err(ExplicitlyDenied)) = {:?}", AID, door_id, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&door_id, b"read:humidity", AID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to read:humidity of {} (expected Ok(Device)) = {:?}\n", AID, door_id, resp);
+    schnorr_nizk::access_control::remove_resource(&floor_id).unwrap();
+    schnorr_nizk::access_control::remove_resource(&building_id).unwrap();
+
+    // A device can pass on its access to another device with a signed delegation token, which can only be narrowed down
+    let garage_id: ResourcePath = "garage".parse().unwrap();
+    let garage_door_id: ResourcePath = "garage/door1".parse().unwrap();
+    schnorr_nizk::access_control::add_resource(&garage_id, Some(vec![String::from("open:*").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_device_to_all_actions(&garage_id, AID).unwrap();
+    let valid_until = Utc::now().timestamp_millis() + 60 * 60 * 1000;
+    let token = schnorr_nizk::delegation::issue_delegation(AID, BID, garage_door_id.clone(), String::from("open:*").into_bytes(), valid_until, None).unwrap();
+    let resp = schnorr_nizk::access_control::get_access_reason(&garage_door_id, b"open:lock", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to open:lock of {} before presenting the delegation (expected Err(NotGranted)) = {:?}", BID, garage_door_id, resp);
+    let proof = schnorr_nizk::delegation::present_delegation(BID, AID, &token, true);
+    let resp = schnorr_nizk::delegation::accept_presented_delegation(AID, BID, &token, Utc::now().timestamp_millis(), proof, true);
+    println!("Delegation of {} to {} accepted? = {:?}", AID, BID, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&garage_door_id, b"open:lock", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to open:lock of {} (expected Ok(Delegation)) = {:?}", BID, garage_door_id, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&garage_id, b"open:lock", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to open:lock of {} outside the delegation (expected Err(NotGranted)) = {:?}", BID, garage_id, resp);
+    let resp = schnorr_nizk::delegation::issue_delegation(BID, AID, garage_id.clone(), String::from("open:*").into_bytes(), valid_until, Some(token.clone()));
+    println!("Derived delegation for a wider resource (expected Err(ExceedsParent)) = {:?}", resp);
+    let resp = schnorr_nizk::delegation::issue_delegation(BID, AID, garage_door_id.clone(), String::from("open:lock").into_bytes(), valid_until, Some(token.clone()));
+    println!("Derived delegation for a narrower action has depth 2? = {:?}", resp.map(|child| child.depth()));
+    // The conditions of the first issuer's entry apply to its delegates, and their invocations count for its limit
+    let conditions = schnorr_nizk::access_control::AccessConditions {
+        max_invocations: Some((1, 60_000)),
+        ..Default::default()
+    };
+    schnorr_nizk::access_control::set_action_conditions(&garage_id, String::from("open:*").into_bytes(), Some(conditions)).unwrap();
+    for invocation in 1..3 {
+        let resp = schnorr_nizk::access_control::get_access_reason(&garage_door_id, b"open:lock", BID, &schnorr_nizk::clock::SystemClock);
+        println!("Delegated invocation {} within the limit of 1 of device {} (expected Ok(Delegation), Err(NotGranted)) = {:?}", invocation, AID, resp);
+    }
+    schnorr_nizk::access_control::set_action_conditions(&garage_id, String::from("open:*").into_bytes(), None).unwrap();
+    // The issuer signs the revocation and sends it to the resource owner
+    let resp = schnorr_nizk::delegation::issue_delegation_revocation(BID, &token);
+    println!("Revocation of the token of {} by device {} (expected Err(NotAnIssuer)) = {:?}", AID, BID, resp);
+    let mut revocation = schnorr_nizk::delegation::issue_delegation_revocation(AID, &token).unwrap();
+    revocation.revoked_at += 1;
+    let resp = schnorr_nizk::delegation::accept_delegation_revocation(&revocation);
+    println!("Modified revocation accepted? (expected Err(InvalidSignature)) = {:?}", resp);
+    let revocation = schnorr_nizk::delegation::issue_delegation_revocation(AID, &token).unwrap();
+    let resp = schnorr_nizk::delegation::accept_delegation_revocation(&revocation);
+    println!("Revocation of {} accepted? = {:?}", AID, resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&garage_door_id, b"open:lock", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to open:lock of {} after revoking the delegation (expected Err(NotGranted)) = {:?}\n", BID, garage_door_id, resp);
+    schnorr_nizk::access_control::remove_resource(&garage_id).unwrap();
+
+    // Authentication outcomes, access decisions and changes of the lists are kept in a hash chained audit log,
+    // which A signs every CONST_AUDIT_CHECKPOINT_INTERVAL entries
+    schnorr_nizk::audit_log::set_audit_signer(AID).unwrap();
+    let audit_resource_id = ResourcePath::from(27001);
+    schnorr_nizk::access_control::add_resource(&audit_resource_id, Some(vec![String::from("GET").into_bytes()])).unwrap();
+    schnorr_nizk::access_control::add_device_to_resource_action(&audit_resource_id, String::from("GET").into_bytes(), BID).unwrap();
+    schnorr_nizk::access_control::check_access(&audit_resource_id, String::from("GET").into_bytes(), BID);
+    schnorr_nizk::access_control::check_access(&audit_resource_id, String::from("GET").into_bytes(), AID);
+    let checkpoint = schnorr_nizk::audit_log::append_checkpoint(AID).unwrap();
+    let pub_kA = schnorr_nizk::key_formats::get_own_public_key(AID).unwrap();
+    let audit_log = schnorr_nizk::audit_log::read_audit_log().unwrap();
+    for entry in audit_log.iter().rev().take(4).rev() {
+        println!("Audit log entry {}: {:?}", entry.sequence, entry.event);
+    }
+    let resp = schnorr_nizk::audit_log::verify_audit_log(pub_kA, Some(&checkpoint));
+    println!("Audit log verified? (expected Ok) = {:?}", resp.map(|report| report.entries));
+
+    // Changed and removed entries are detected
+    let audit_log_path = ".nizk-auth/audit/audit_log.jsonl";
+    let original_log = std::fs::read_to_string(audit_log_path).unwrap();
+    std::fs::write(audit_log_path, original_log.replacen("\"allowed\":false", "\"allowed\":true", 1)).unwrap();
+    let resp = schnorr_nizk::audit_log::verify_audit_log(pub_kA, Some(&checkpoint));
+    println!("Audit log with a changed decision verified? (expected Err(ModifiedEntry)) = {:?}", resp.map(|report| report.entries));
+    let truncated_log: Vec<&str> = original_log.lines().take(10).collect();
+    std::fs::write(audit_log_path, truncated_log.join("\n") + "\n").unwrap();
+    let resp = schnorr_nizk::audit_log::verify_audit_log(pub_kA, Some(&checkpoint));
+    println!("Truncated audit log verified? (expected Err(Truncated)) = {:?}\n", resp.map(|report| report.entries));
+    // An entry cut off by a crash is removed by the next write, which records the gap
+    std::fs::write(audit_log_path, original_log + "{\"sequence\":").unwrap();
+    schnorr_nizk::access_control::check_access(&audit_resource_id, String::from("GET").into_bytes(), BID);
+    let audit_log = schnorr_nizk::audit_log::read_audit_log().unwrap();
+    for entry in audit_log.iter().rev().take(2).rev() {
+        println!("Audit log entry {}: {:?}", entry.sequence, entry.event);
+    }
+    let resp = schnorr_nizk::audit_log::verify_audit_log(pub_kA, Some(&checkpoint));
+    println!("Audit log with a removed incomplete entry verified? (expected Ok with 1 gap) = {:?}\n", resp.map(|report| report.gaps));
+    schnorr_nizk::access_control::remove_resource(&audit_resource_id).unwrap();
+
+    // Lists and roles can be described in a TOML policy, which is applied as a whole and only changes what differs
+    let original_policy = schnorr_nizk::acl_policy::export_policy().unwrap();
+    let policy_text = r#"
+[roles]
+operators = [20000]
+
+[[resource]]
+path = "plant/line1"
+
+[[resource.action]]
+name = "read:*"
+devices = [10000]
+roles = ["operators"]
+
+[[resource.action]]
+name = "write:speed"
+devices = [20000]
+conditions = { allowed_hours = [0, 24] }
+"#;
+    let mut policy = schnorr_nizk::acl_policy::AclPolicy::from_toml(policy_text).unwrap();
+    policy.resources.extend(original_policy.resources.iter().cloned());
+    policy.roles.extend(original_policy.roles.clone());
+    let changes = schnorr_nizk::acl_policy::diff_policy(&policy).unwrap();
+    println!("Changes of the policy:");
+    for change in changes.iter() {
+        println!("{:?}", change);
+    }
+    let resp = schnorr_nizk::acl_policy::apply_policy(&policy);
+    println!("Number of applied changes (expected {}) = {:?}", changes.len(), resp.map(|changes| changes.len()));
+    let resp = schnorr_nizk::acl_policy::apply_policy(&policy);
+    println!("Changes of applying the policy again (expected []) = {:?}", resp);
+    let resp = schnorr_nizk::access_control::get_access_reason(&"plant/line1/motor".parse().unwrap(), b"read:rpm", BID, &schnorr_nizk::clock::SystemClock);
+    println!("Access of device {} to read:rpm of plant/line1/motor (expected Ok(Role(\"operators\"))) = {:?}", BID, resp);
+    // Action names that are not valid UTF-8 are exported in hex
+    schnorr_nizk::access_control::add_action_to_resource(&"plant/line1".parse().unwrap(), vec![0xff, 0x01]).unwrap();
+    schnorr_nizk::acl_policy::export_policy_file(".nizk-auth/access_control/policy.toml").unwrap();
+    let exported_policy = schnorr_nizk::acl_policy::read_policy_file(".nizk-auth/access_control/policy.toml").unwrap();
+    let exported_names: Vec<&String> = exported_policy.resources.iter().flat_map(|resource| resource.actions.iter().map(|action| &action.name)).collect();
+    println!("Exported action names (expected one \"hex:ff01\") = {:?}", exported_names);
+    println!("Exported policy describes the applied policy? (expected []) = {:?}", schnorr_nizk::acl_policy::diff_policy(&exported_policy));
+    let resp = schnorr_nizk::acl_policy::AclPolicy::from_toml("[[resource]]\npath = \"plant\"\n\n[[resource.action]]\nname = \"hex:zz\"\n");
+    println!("Policy with an invalid hex action name (expected Err(InvalidActionName)) = {:?}", resp);
+    let resp = schnorr_nizk::acl_policy::apply_policy(&original_policy);
+    println!("Changes of restoring the original policy = {:?}\n", resp.map(|changes| changes.len()));
+
     /*
     ************************************************************************************************
     *************** End of Test of Intrusion Detection/Prevention and Access Control ***************
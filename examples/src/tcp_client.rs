@@ -2,6 +2,7 @@ use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
 use std::io::prelude::*;
 use schnorr_nizk;
+use schnorr_nizk::DeviceId;
 use serde::{Deserialize, Serialize};
 use std::env;
 use chrono::prelude::*;
@@ -9,10 +10,13 @@ use std::time::{Instant};
 
 
 // ID's of client and server
-const MY_ID: u32 = 100000;
-const SERVER_ID: u32 = 200000;
+const MY_ID: DeviceId = DeviceId::Number(100000);
+const SERVER_ID: DeviceId = DeviceId::Number(200000);
 const SERVER_ADDRESS: &str = "000.000.0.00:8000";
 
+// File with the keys and certificate of the server, generated by the provision command
+const PROVISIONING_FILE: &str = "server_provisioning.json";
+
 // Data to send and receive
 #[derive(Debug, Serialize, Deserialize)]
 struct DataExchange {
@@ -24,10 +28,18 @@ struct DataExchange {
     value_3: Option<[u8; 32]>,
 }
 
-fn shared_key_agreement() {
+// Keys and certificate of a device, issued by the authority
+#[derive(Debug, Serialize, Deserialize)]
+struct Provisioning {
+    private_key: [u8; 32],
+    certificate: schnorr_nizk::enrollment::DeviceCertificate,
+    authority_public_key: [u8; 32],
+}
+
+fn shared_key_agreement(key_size: schnorr_nizk::KeySize) {
     // Prepare data to be send
     // Create an instance of Mutual auth as an initiator role and get the values to send
-    let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance(MY_ID,SERVER_ID, schnorr_nizk::CONST_INITIATOR_ROLE);
+    let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance_with_key_size(MY_ID, SERVER_ID, schnorr_nizk::CONST_INITIATOR_ROLE, key_size);
     let (my_commitment, val_2, my_req_type) = int_mut_auth.gen_next_values();
 
     // Create a data struct with all info
@@ -99,7 +111,54 @@ fn shared_key_agreement() {
             let accepted = int_mut_auth.verify_proof();
             println!("Client {} verified proof of Server {}, result: {}\n", MY_ID, SERVER_ID, accepted);
 
+            // Send key confirmation tag, or tell the server to discard the key if proof was rejected
+            let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
+            let data_to_send = DataExchange {
+                auth_type: 0,
+                request_type: my_req_type,
+                message: None,
+                value_1: val1,
+                value_2: val2,
+                value_3: None,
+            };
+            let json_string = serde_json::to_string(&data_to_send).unwrap();
+            stream.write_all(json_string.as_bytes()).expect("write failed");
+            stream.write_all(b"\n").expect("Failed to write to server");
+
             // Exist the loop since verification is complete
+            if !accepted {
+                break;
+            }
+        }
+
+        // Check the key confirmation of the server
+        if response_state == schnorr_nizk::CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED {
+            let confirmed = int_mut_auth.confirm_shared_secret_key();
+            println!("Client {} confirmed shared secret key of Server {}, result: {}\n", MY_ID, SERVER_ID, confirmed);
+
+            // Server has to roll back its stored key if confirmation failed
+            if !confirmed {
+                let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
+                let data_to_send = DataExchange {
+                    auth_type: 0,
+                    request_type: my_req_type,
+                    message: None,
+                    value_1: val1,
+                    value_2: val2,
+                    value_3: None,
+                };
+                let json_string = serde_json::to_string(&data_to_send).unwrap();
+                stream.write_all(json_string.as_bytes()).expect("write failed");
+                stream.write_all(b"\n").expect("Failed to write to server");
+            }
+
+            // Exist the loop since key agreement is complete
+            break;
+        }
+
+        // Server rejected our proof
+        if response_state == schnorr_nizk::CONST_KEY_CONFIRMATION_ROLLED_BACK {
+            println!("Server {} rejected the key agreement\n", SERVER_ID);
             break;
         }
     }
@@ -288,11 +347,13 @@ fn fake_nizk_auth(dos_attack: bool) {
 fn print_help() {
     println!("\n----------------------------------------------------------------------------\n");
     println!("Usage: ./tcp_client <auth_type> [message]");
-    println!("auth_type can be one of: exchange_keys, sharedsecretkey, nizk, sessionkey, fake, semi_fake_asymmetric, semi_fake_symmetric, dos_attack, testnizkspeed\n");
-    println!("exchange_keys: Init and Exchange Asymmetric keys between client and server for test purposes.");
-    println!("After running this, sharedsecretkey command has to be executed for a new shared secret key compatible with the current key.");
-    println!("Note: This has to be replaced by a real trusted authority in future.\n");
+    println!("auth_type can be one of: provision, exchange_keys, sharedsecretkey, nizk, sessionkey, fake, semi_fake_asymmetric, semi_fake_symmetric, dos_attack, testnizkspeed\n");
+    println!("provision: Act as the local authority. Generates key pairs and certificates for client and server.");
+    println!("The generated server_provisioning.json has to be copied to the server device before starting the server.\n");
+    println!("exchange_keys: Exchange certificates between client and server. Public keys are only stored if the authority signed them.");
+    println!("After running this, sharedsecretkey command has to be executed for a new shared secret key compatible with the current key.\n");
     println!("sharedsecretkey: will generate a secret shared key between client and server, to use for NIZK Authentication!");
+    println!("An optional key size of 128 or 256 bits can be provided, e.g. sharedsecretkey 128. Default is 256.");
     println!("nizk: will send a Non-Interactive Authentication proof to the Server.");
     println!("sessionkey: will calculate a session secret key that can be used for end-to-end secure communication.");
     println!("fake: will generate a random fake NIZK proof.");
@@ -306,33 +367,56 @@ fn print_help() {
     println!("----------------------------------------------------------------------------\n");
 }
 
-// Exchange keys for testing purposes
-fn exchange_keys() {
-    // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
-    // 192.168.0.21 for inside wlan and 127.0.0.1 for local computer
-    let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
-    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
+// Act as the local authority of the test setup: generate key pairs and certificates for client and server
+fn provision() {
+    let authority_public_key = schnorr_nizk::enrollment::init_authority().unwrap();
+    let valid_from = Utc::now().timestamp_millis();
+    let valid_until = valid_from + 365 * 24 * 60 * 60 * 1000;
 
-    // Generate random key pair
+    // Generate own key pair and certificate
     let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
-    println!("Generated Pub key: {:?}\n\nPrivKey: {:?}\n", public_key, private_key);
     let desc_priv = format!("PrivateKey:{}", MY_ID);
-    let desc_pub = format!("PublicKey:{}", MY_ID);
     let mut my_key_priv = schnorr_nizk::get_key_instance(&desc_priv, 32, Some(private_key.to_vec())).unwrap();
-    let mut my_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(public_key.to_vec())).unwrap();
-
-    // Update keys in Ring. This is important because if the key already exists
-    // then it will not be changed without this step
     my_key_priv.update_key_in_ring(private_key.to_vec());
-    my_key_pub.update_key_in_ring(public_key.to_vec());
+    let certificate = schnorr_nizk::enrollment::issue_certificate(MY_ID, public_key, valid_from, valid_until, vec![String::from("client")]).unwrap();
+    schnorr_nizk::enrollment::save_own_certificate(&certificate).unwrap();
+
+    // Generate key pair and certificate of the server
+    let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
+    let certificate = schnorr_nizk::enrollment::issue_certificate(SERVER_ID, public_key, valid_from, valid_until, vec![String::from("server")]).unwrap();
+    let provisioning = Provisioning {
+        private_key,
+        certificate,
+        authority_public_key,
+    };
+    let json_string = serde_json::to_string(&provisioning).unwrap();
+    std::fs::write(PROVISIONING_FILE, json_string).expect("Failed to write provisioning file");
+
+    println!("Client provisioned. Copy {} to the server device, it is imported when the server starts.\n", PROVISIONING_FILE);
+}
+
+// Exchange certificates with the server. Public keys are only stored if the authority signed them
+fn exchange_keys() {
+    let certificate = match schnorr_nizk::enrollment::get_own_certificate() {
+        Some(certificate) => certificate,
+        None => {
+            println!("No certificate found, run the provision command first!\n");
+            return;
+        }
+    };
+
+    // Connect to TCP Stream at port 8000 (defined in tcp_server.rs)
+    // 192.168.0.21 for inside wlan and 127.0.0.1 for local computer
+    let mut stream = TcpStream::connect(SERVER_ADDRESS).expect("connection failed");
+    let mut stream_copy = stream.try_clone().expect("Failed to clone stream\n");
 
-    // Send public key to Server
+    // Send certificate to Server
     // Prepare data to send
     let data = DataExchange {
         auth_type: 11,
         request_type: 0,
-        message: None,
-        value_1: public_key,
+        message: Some(serde_json::to_string(&certificate).unwrap()),
+        value_1: certificate.public_key,
         value_2: None,
         value_3: None,
     };
@@ -341,7 +425,7 @@ fn exchange_keys() {
     let json_string = serde_json::to_string(&data).unwrap();
 
     // Send message
-    println!("Sending NIZK Mutual Auth request:");
+    println!("Sending certificate:");
     stream.write_all(json_string.as_bytes()).expect("write failed");
     stream.write_all(b"\n").expect("Failed to write to server");
     println!("Message sent!\n");
@@ -353,13 +437,18 @@ fn exchange_keys() {
     let response_str = response.trim();
     print!("Got response from server: {}\n", response_str);
 
-    // Convert response into a DataExchange struct
-    let mut data: DataExchange = serde_json::from_str(response_str).unwrap();
-    let desc_pub = format!("PublicKey:{}", SERVER_ID);
-    let mut server_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(data.value_1.to_vec())).unwrap();
-
-    // Make sure to update key in Ring, in case an old key with same ID exists
-    server_key_pub.update_key_in_ring(data.value_1.to_vec());
+    // Convert response into a DataExchange struct and check the certificate of the server
+    let data: DataExchange = serde_json::from_str(response_str).unwrap();
+    let server_certificate: Option<schnorr_nizk::enrollment::DeviceCertificate> = data.message.and_then(|m| serde_json::from_str(&m).ok());
+    match server_certificate {
+        Some(server_certificate) if server_certificate.device_ID == SERVER_ID => {
+            let result = schnorr_nizk::enrollment::enroll_peer(&server_certificate);
+            println!("Enrolled Server {}, result: {:?}\n", SERVER_ID, result);
+        },
+        _ => {
+            println!("Server {} rejected our certificate or sent an invalid one\n", SERVER_ID);
+        }
+    }
 }
 
 // Main function
@@ -376,12 +465,25 @@ fn main() {
     // Check requested auth type
     let auth_type = &args[1];
     match auth_type.as_str() {
+        "provision" => {
+            provision();
+        }
         "exchange_keys" => {
             exchange_keys();
         }
         "sharedsecretkey" => {
+            // Read optional key size
+            let key_size = match args.get(2).map(|size| size.as_str()) {
+                Some("128") => schnorr_nizk::KeySize::Bits128,
+                Some("256") | None => schnorr_nizk::KeySize::Bits256,
+                Some(_) => {
+                    println!("Error: Key size has to be 128 or 256");
+                    return;
+                }
+            };
+
             println!("\nAuthentication type: Shared Secret Key chosen:");
-            println!("Starting interactive mutual auth...\n");
+            println!("Starting interactive mutual auth with key size {:?}...\n", key_size);
 
             let mut all_measurements: Vec<f32> = Vec::new();
 
@@ -390,7 +492,7 @@ fn main() {
                 let start = Instant::now();
 
                 // Interactive mutual auth for shared secret key agreement
-                shared_key_agreement();
+                shared_key_agreement(key_size);
 
                 // Calculate Duration
                 let duration = (start.elapsed().as_secs_f32()) * 1_000.0;
@@ -2,6 +2,7 @@ use std::net::{TcpListener, TcpStream};
 use std::io::prelude::*;
 use std::{io, thread};
 use schnorr_nizk;
+use schnorr_nizk::DeviceId;
 use serde::{Deserialize, Serialize};
 use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
@@ -10,10 +11,16 @@ use std::collections::HashMap;
 use std::net::IpAddr;
 
 // ID's of client and server
-const MY_ID: u32 = 200000;
-const CLIENT_ID: u32 = 100000;
+const MY_ID: DeviceId = DeviceId::Number(200000);
+const CLIENT_ID: DeviceId = DeviceId::Number(100000);
 const SERVER_ADDRESS: &str = "000.000.0.00:8000";
 
+// File with the keys and certificate of the server, generated by the provision command of the client
+const PROVISIONING_FILE: &str = "server_provisioning.json";
+
+// Smallest shared secret key size accepted from clients
+const MIN_KEY_SIZE: schnorr_nizk::KeySize = schnorr_nizk::KeySize::Bits128;
+
 // Block duration if a DoS attack is detected
 const BLOCK_DURATION: Duration = Duration::from_secs(10);
 
@@ -28,6 +35,36 @@ struct DataExchange {
     value_3: Option<[u8; 32]>,
 }
 
+// Keys and certificate of a device, issued by the authority
+#[derive(Debug, Serialize, Deserialize)]
+struct Provisioning {
+    private_key: [u8; 32],
+    certificate: schnorr_nizk::enrollment::DeviceCertificate,
+    authority_public_key: [u8; 32],
+}
+
+// Import keys and certificate of the server, if a provisioning file was copied to this device
+fn import_provisioning() {
+    let json_string = match std::fs::read_to_string(PROVISIONING_FILE) {
+        Ok(json_string) => json_string,
+        Err(_) => {
+            return;
+        }
+    };
+    let provisioning: Provisioning = serde_json::from_str(&json_string).unwrap();
+
+    // Save own private key, the authority public key, and the own certificate
+    let desc_priv = format!("PrivateKey:{}", MY_ID);
+    let mut my_key_priv = schnorr_nizk::get_key_instance(&desc_priv, 32, Some(provisioning.private_key.to_vec())).unwrap();
+    my_key_priv.update_key_in_ring(provisioning.private_key.to_vec());
+    schnorr_nizk::enrollment::set_authority_public_key(provisioning.authority_public_key).unwrap();
+    schnorr_nizk::enrollment::save_own_certificate(&provisioning.certificate).unwrap();
+
+    // Private key must not stay on disk
+    std::fs::remove_file(PROVISIONING_FILE).expect("Failed to delete provisioning file");
+    println!("Imported keys and certificate from {}\n", PROVISIONING_FILE);
+}
+
 // Callback function to handle an incoming connection
 fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, SystemTime>>>) {
     // Read received message
@@ -46,8 +83,8 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
         // Interactive Mutual Auth for key Agreement
         0 => {
             // Get instance of IntMutAuth and add received values
-            let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance(MY_ID,CLIENT_ID, schnorr_nizk::CONST_RECEIVER_ROLE);
-            int_mut_auth.add_recipient_values(data.request_type, data.value_1, data.value_2);
+            let mut int_mut_auth = schnorr_nizk::get_int_mut_auth_instance_with_key_size(MY_ID, CLIENT_ID, schnorr_nizk::CONST_RECEIVER_ROLE, MIN_KEY_SIZE);
+            let state = int_mut_auth.add_recipient_values(data.request_type, data.value_1, data.value_2);
 
             // Generate data to send
             let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
@@ -67,6 +104,12 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
             stream_copy.write_all(b"\n").expect("Failed to write to server");
             println!("Message sent!");
 
+            // Client was told to stop since its key size is not accepted
+            if state == schnorr_nizk::CONST_KEY_SIZE_REJECTED {
+                println!("Key size proposed by Client {} rejected\n", CLIENT_ID);
+                return;
+            }
+
             // Read last data
             message.clear();
             reader.read_line(&mut message).expect("Read server response failed!\n");
@@ -98,6 +141,45 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
             // Verify proof
             let accepted = int_mut_auth.verify_proof();
             println!("Server {} verified proof of Client {}, result: {}\n", MY_ID, CLIENT_ID, accepted);
+
+            // Read key confirmation of the client, or its request to discard the key
+            message.clear();
+            reader.read_line(&mut message).expect("Read server response failed!\n");
+            let message_str = message.trim();
+            let last_data: DataExchange = serde_json::from_str(message_str).unwrap();
+            let state = int_mut_auth.add_recipient_values(last_data.request_type, last_data.value_1, last_data.value_2);
+            if state != schnorr_nizk::CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED {
+                println!("Client {} rejected the key agreement\n", CLIENT_ID);
+                return;
+            }
+
+            // Confirm the key and send own confirmation tag, or tell the client to discard the key
+            let confirmed = int_mut_auth.confirm_shared_secret_key();
+            println!("Server {} confirmed shared secret key of Client {}, result: {}\n", MY_ID, CLIENT_ID, confirmed);
+            let (val1, val2, my_req_type) = int_mut_auth.gen_next_values();
+            let data_to_send = DataExchange {
+                auth_type: 0,
+                request_type: my_req_type,
+                message: None,
+                value_1: val1,
+                value_2: val2,
+                value_3: None,
+            };
+            let json_string = serde_json::to_string(&data_to_send).unwrap();
+            stream_copy.write_all(json_string.as_bytes()).expect("write failed");
+            stream_copy.write_all(b"\n").expect("Failed to write to server");
+
+            // Client may still reject our confirmation tag, in which case the key is rolled back
+            if confirmed {
+                message.clear();
+                if reader.read_line(&mut message).unwrap_or(0) > 0 {
+                    let last_data: DataExchange = serde_json::from_str(message.trim()).unwrap();
+                    if int_mut_auth.add_recipient_values(last_data.request_type, last_data.value_1, last_data.value_2)
+                        == schnorr_nizk::CONST_KEY_CONFIRMATION_ROLLED_BACK {
+                        println!("Client {} rejected our key confirmation, shared secret key rolled back\n", CLIENT_ID);
+                    }
+                }
+            }
         },
 
         // NIZK Authentication
@@ -174,33 +256,28 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
             println!("Server calculated session key as: {:?}\n", s_key);
         }
 
-        // Generate new keys and Exchange Public Keys
+        // Exchange certificates. The public key of the client is only stored if the authority signed it
         11 => {
-            let desc_pub = format!("PublicKey:{}", CLIENT_ID);
-            let mut client_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(data.value_1.to_vec())).unwrap();
-            client_key_pub.update_key_in_ring(data.value_1.to_vec());
-
-            // Generate own public private key pairs
-            let (public_key, private_key) = schnorr_nizk::gen_random_key_pair();
-            println!("Generated Pub key: {:?}\n\nPrivKey: {:?}\n", public_key, private_key);
-
-            let desc_priv = format!("PrivateKey:{}", MY_ID);
-            let desc_pub = format!("PublicKey:{}", MY_ID);
-            let mut my_key_priv = schnorr_nizk::get_key_instance(&desc_priv, 32, Some(private_key.to_vec())).unwrap();
-            let mut my_key_pub = schnorr_nizk::get_key_instance(&desc_pub, 32, Some(public_key.to_vec())).unwrap();
-
-            // Update keys in Ring. This is important because if the key already exists
-            // then it will not be changed without this step
-            my_key_priv.update_key_in_ring(private_key.to_vec());
-            my_key_pub.update_key_in_ring(public_key.to_vec());
-
-            // Send public key to Server
-            // Prepare data to send
+            let certificate: Option<schnorr_nizk::enrollment::DeviceCertificate> = data.message.and_then(|m| serde_json::from_str(&m).ok());
+            let accepted = match certificate {
+                Some(certificate) if certificate.device_ID == CLIENT_ID => {
+                    let result = schnorr_nizk::enrollment::enroll_peer(&certificate);
+                    println!("Enrolled Client {}, result: {:?}\n", CLIENT_ID, result);
+                    result.is_ok()
+                },
+                _ => {
+                    println!("Received no valid certificate of Client {}\n", CLIENT_ID);
+                    false
+                }
+            };
+
+            // Send own certificate, or nothing if the client was rejected
+            let my_certificate = schnorr_nizk::enrollment::get_own_certificate();
             let data = DataExchange {
                 auth_type: 11,
                 request_type: 0,
-                message: None,
-                value_1: public_key,
+                message: if accepted { my_certificate.as_ref().map(|c| serde_json::to_string(c).unwrap()) } else { None },
+                value_1: my_certificate.map(|c| c.public_key).unwrap_or([0u8; 32]),
                 value_2: None,
                 value_3: None,
             };
@@ -211,7 +288,7 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
             // Send data
             stream_copy.write_all(json_string.as_bytes()).expect("write failed");
             stream_copy.write_all(b"\n").expect("Failed to write to server");
-            println!("Public key sent!");
+            println!("Certificate sent!");
         }
 
         // For testing the speed of nizk proof. It has less checks and no prints and extra response
@@ -236,6 +313,9 @@ fn handle_connection(stream: TcpStream, block_map: Arc<Mutex<HashMap<IpAddr, Sys
 
 // Main function of the TCP Server
 fn main() {
+    // Import keys and certificate issued by the authority
+    import_provisioning();
+
     // Init intrusion data
     schnorr_nizk::init_intrusion_counters(CLIENT_ID);
     println!("\nReset intrusion values since server is restarted!\n");
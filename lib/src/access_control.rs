@@ -1,329 +1,966 @@
-use std::fs::{File, Permissions};
-use std::io::{BufReader, Write};
-use std::fs;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::cmp::Reverse;
 use serde::{Deserialize, Serialize};
+use chrono::{Datelike, TimeZone, Timelike, Utc};
+use crate::acl_store::{self, AclDatabase, AclStoreErrors};
+use crate::audit_log::{self, AuditEvent};
+use crate::credentials::AccessGrant;
+use crate::clock::{Clock, SystemClock};
+use crate::{credentials, delegation, peer_registry};
+use crate::{DeviceId, ResourcePath};
 
 #[derive(Debug, Serialize, Deserialize)]
-struct ActionsControl {
-    actionName: Vec<u8>,
-    allowedDevices: Vec<u32>,
+pub(crate) struct ActionsControl {
+    pub(crate) actionName: Vec<u8>,
+    pub(crate) allowedDevices: Vec<DeviceId>,
+    #[serde(default)]
+    pub(crate) allowedRoles: Vec<String>,
+    #[serde(default)]
+    pub(crate) deniedDevices: Vec<DeviceId>,
+    #[serde(default)]
+    pub(crate) deniedRoles: Vec<String>,
+    #[serde(default)]
+    pub(crate) conditions: Option<AccessConditions>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-struct AccessControl {
-    resourceID: u32,
-    actions: Vec<ActionsControl>,
+pub(crate) struct AccessControl {
+    pub(crate) resourceID: ResourcePath,
+    pub(crate) actions: Vec<ActionsControl>,
 }
 
-// File path of the data control detection data
-fn get_json_file_path(resourceID: u32) -> String {
-    format!(".nizk-auth/access_control/resource_{}.json", resourceID)
+// Conditions of an action, checked for every device the action is granted to
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessConditions {
+    // Hours of the day in UTC, from the first hour up to before the second one. Wraps around midnight if the first hour is larger
+    pub allowed_hours: Option<(u32, u32)>,
+    // Days of the week, starting with 0 for Monday
+    pub allowed_days: Option<Vec<u32>>,
+    pub valid_from: Option<i64>,
+    pub valid_until: Option<i64>,
+    // Max number of invocations per device within a period in ms
+    pub max_invocations: Option<(u32, i64)>,
+    // Max time in ms since the last mutual authentication with the device
+    pub max_auth_age: Option<i64>,
 }
 
-// Set the file permissions to 0o600, so that only the user can write to it
-fn shrink_file_permissions(path: String) {
-    let mut perms = Permissions::from_mode(0o600);
-    std::fs::set_permissions(&path, perms).expect("Failed to set file permissions");
+// Error of a change to the access control lists or roles
+#[derive(Debug)]
+pub enum AclError {
+    ResourceNotFound(ResourcePath),
+    ResourceAlreadyExists(ResourcePath),
+    ActionNotFound(ResourcePath, Vec<u8>),
+    ActionAlreadyExists(ResourcePath, Vec<u8>),
+    RoleNotFound(String),
+    RoleAlreadyExists(String),
+    // The device or role is already in the list of an action or role
+    DuplicateEntry,
+    // The device or role is not in the list of an action or role
+    EntryNotFound,
+    StoreFailure(AclStoreErrors),
 }
 
-// Create all parent directories for the file
-fn create_parent_dirs(file_path: String) {
-    let path = Path::new(&file_path);
+impl From<AclStoreErrors> for AclError {
+    fn from(error: AclStoreErrors) -> AclError {
+        AclError::StoreFailure(error)
+    }
+}
 
-    // Create parent directories if they don't already exist
-    if let Some(parent) = path.parent() {
-        if !parent.exists() {
-            if let Err(err) = std::fs::create_dir_all(parent) {
-                eprintln!("Failed to create parent directories: {}", err);
-            }
-        }
+// Action of a resource that is granted to a device, directly or by one of its roles
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceGrant {
+    pub resource_ID: ResourcePath,
+    pub action: Vec<u8>,
+    pub reason: AccessReason,
+}
+
+// Changes to the access control lists and roles that are written together or not at all
+pub struct AclTransaction<'a> {
+    database: &'a mut AclDatabase,
+}
+
+// Run changes to the access control lists and roles as one transaction. They are written if the
+// transaction returns Ok, and discarded if it returns Err, even if some of them already succeeded
+pub fn transaction<T>(changes: impl FnOnce(&mut AclTransaction) -> Result<T, AclError>) -> Result<T, AclError> {
+    acl_store::update_database(|database| changes(&mut AclTransaction::new(database)))?
+}
+
+// Create an action without any devices or roles
+fn new_action(actionName: Vec<u8>) -> ActionsControl {
+    ActionsControl {
+        actionName,
+        allowedDevices: Vec::new(),
+        allowedRoles: Vec::new(),
+        deniedDevices: Vec::new(),
+        deniedRoles: Vec::new(),
+        conditions: None,
     }
 }
 
-// Save json data into a json file
-fn update_resource_data(resourceID: u32, data: String) {
-    // Create File with json content
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-    let mut file = File::create(&path).unwrap();
-    file.write_all(data.as_bytes());
+// Add an entry to a list of devices or roles, which holds every entry at most once
+fn add_entry<T: PartialEq>(entries: &mut Vec<T>, entry: T) -> Result<(), AclError> {
+    if entries.contains(&entry) {
+        return Err(AclError::DuplicateEntry);
+    }
+    entries.push(entry);
+    Ok(())
 }
 
-// Create a new Resource
-pub fn add_resource(resourceID: u32, actions: Option<Vec<Vec<u8>>>) -> u8 {
-    // Get resource file path
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-
-    // Check if resource already exists
-    if path.exists() {
-        println!("ResourceID: {:?} already exists, try to delete it first!\n", resourceID);
-        return 1;
-    }
-
-    // Save Data in AccessControl struct
-    let access = match actions {
-        // Check if user included actions or not
-        Some(all_actions) => {
-            // User added actions list, convert it into ActionsStruct
-            let mut actions_vec: Vec<ActionsControl> = Vec::new();
-            for actionName in all_actions {
-                let action = ActionsControl {
-                    actionName,
-                    allowedDevices: Vec::new(),
-                };
-                actions_vec.push(action);
-            }
+// Remove an entry from a list of devices or roles. Also removes duplicates written by older versions
+fn remove_entry<T: PartialEq>(entries: &mut Vec<T>, entry: &T) -> Result<(), AclError> {
+    if !entries.contains(entry) {
+        return Err(AclError::EntryNotFound);
+    }
+    entries.retain(|listed| listed != entry);
+    Ok(())
+}
 
-            // Generate AccessControl Struct
-            AccessControl {
-                resourceID,
-                actions: actions_vec,
-            }
-        },
+impl<'a> AclTransaction<'a> {
+    // Changes to a database that is written by the caller
+    pub(crate) fn new(database: &'a mut AclDatabase) -> AclTransaction<'a> {
+        AclTransaction { database }
+    }
 
-        // User did not include actions -> Create empty AccessControl struct
-        None => {
-            AccessControl {
-                resourceID,
-                actions: Vec::new(),
+    // State of the lists and roles with the changes of the transaction so far
+    pub(crate) fn database(&self) -> &AclDatabase {
+        self.database
+    }
+
+    // Find the access control list of a resource
+    fn get_resource(&mut self, resourceID: &ResourcePath) -> Result<&mut AccessControl, AclError> {
+        self.database.resources.get_mut(&resourceID.to_string())
+            .ok_or_else(|| AclError::ResourceNotFound(resourceID.clone()))
+    }
+
+    // Find an action of a resource
+    fn get_action(&mut self, resourceID: &ResourcePath, actionName: &[u8]) -> Result<&mut ActionsControl, AclError> {
+        self.get_resource(resourceID)?.actions.iter_mut()
+            .find(|action| action.actionName == actionName)
+            .ok_or_else(|| AclError::ActionNotFound(resourceID.clone(), actionName.to_vec()))
+    }
+
+    // Find the members of a role
+    fn get_role(&mut self, roleName: &str) -> Result<&mut Vec<DeviceId>, AclError> {
+        self.database.roles.get_mut(roleName)
+            .ok_or_else(|| AclError::RoleNotFound(String::from(roleName)))
+    }
+
+    pub fn add_resource(&mut self, resourceID: &ResourcePath, actions: Option<Vec<Vec<u8>>>) -> Result<(), AclError> {
+        // Check if resource already exists
+        if self.database.resources.contains_key(&resourceID.to_string()) {
+            return Err(AclError::ResourceAlreadyExists(resourceID.clone()));
+        }
+
+        let mut access = AccessControl {
+            resourceID: resourceID.clone(),
+            actions: Vec::new(),
+        };
+        for actionName in actions.unwrap_or_default() {
+            if access.actions.iter().any(|action| action.actionName == actionName) {
+                return Err(AclError::ActionAlreadyExists(resourceID.clone(), actionName));
             }
+            access.actions.push(new_action(actionName));
         }
-    };
+        self.database.resources.insert(resourceID.to_string(), access);
+        Ok(())
+    }
 
-    // Convert struct to a JSON
-    let json_string = serde_json::to_string(&access).unwrap();
+    pub fn remove_resource(&mut self, resourceID: &ResourcePath) -> Result<(), AclError> {
+        self.database.resources.remove(&resourceID.to_string())
+            .map(|_| ())
+            .ok_or_else(|| AclError::ResourceNotFound(resourceID.clone()))
+    }
 
-    // Create parent directories if they does not exist
-    let file_path = get_json_file_path(resourceID);
-    create_parent_dirs(file_path);
+    pub fn add_action_to_resource(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>) -> Result<(), AclError> {
+        let accessData = self.get_resource(resourceID)?;
+        if accessData.actions.iter().any(|action| action.actionName == actionName) {
+            return Err(AclError::ActionAlreadyExists(resourceID.clone(), actionName));
+        }
+        accessData.actions.push(new_action(actionName));
+        Ok(())
+    }
 
-    // Create File with json content
-    update_resource_data(resourceID, json_string);
+    pub fn remove_action_from_resource(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>) -> Result<(), AclError> {
+        let accessData = self.get_resource(resourceID)?;
+        match accessData.actions.iter().position(|action| action.actionName == actionName) {
+            Some(index) => {
+                accessData.actions.remove(index);
+                Ok(())
+            },
+            None => Err(AclError::ActionNotFound(resourceID.clone(), actionName)),
+        }
+    }
 
-    // Shrink file permissions
-    let file_path = get_json_file_path(resourceID);
-    shrink_file_permissions(file_path);
+    pub fn add_device_to_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+        add_entry(&mut self.get_action(resourceID, &actionName)?.allowedDevices, deviceID)
+    }
 
-    // Return success
-    return 0;
-}
+    // Devices that already have an action keep it once
+    pub fn add_device_to_all_actions(&mut self, resourceID: &ResourcePath, deviceID: DeviceId) -> Result<(), AclError> {
+        for action in self.get_resource(resourceID)?.actions.iter_mut() {
+            let _ = add_entry(&mut action.allowedDevices, deviceID);
+        }
+        Ok(())
+    }
 
-// Delete a resource from resources list
-pub fn remove_resource(resourceID: u32) -> u8 {
-    // Check if resource already exists
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-    if path.exists() {
-        // Path exists, delete path
-        let file_path = get_json_file_path(resourceID);
-        match fs::remove_file(file_path) {
-            Ok(_) => {
-                return 0;
-            },
-            Err(e) => {
-                // Return exit code 2 since file could not be deleted
-                println!("Error deleting resource: {}", e);
-                return 2;
-            },
+    pub fn remove_device_from_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+        remove_entry(&mut self.get_action(resourceID, &actionName)?.allowedDevices, &deviceID)
+    }
+
+    pub fn remove_device_from_all_actions(&mut self, resourceID: &ResourcePath, deviceID: DeviceId) -> Result<(), AclError> {
+        for action in self.get_resource(resourceID)?.actions.iter_mut() {
+            let _ = remove_entry(&mut action.allowedDevices, &deviceID);
         }
-    }else {
-        // Return 1 because resource does not exist
-        return 1;
+        Ok(())
     }
-}
 
-// Read data from a saved json file
-fn read_access_data(resourceID: u32) -> AccessControl {
-    // Open file and read content as AccessControl struct
-    let file_path = get_json_file_path(resourceID);
-    let path = Path::new(&file_path);
-    let file = File::open(&path).unwrap();
-    let reader = BufReader::new(file);
-    let mut access: AccessControl = serde_json::from_reader(reader).unwrap();
-    access
-}
+    pub fn set_action_conditions(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, conditions: Option<AccessConditions>) -> Result<(), AclError> {
+        self.get_action(resourceID, &actionName)?.conditions = conditions;
+        Ok(())
+    }
 
-// Add a new allowed action to a certain resource
-pub fn add_action_to_resource(resourceID: u32, actionName: Vec<u8>) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-
-    // Go through all action in the access control data and find if action already exists
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Action exists
-            println!("Action already exists for resource {:?}. Please delete it first to continue.\n", resourceID);
-            return 1;
+    pub fn add_role(&mut self, roleName: String) -> Result<(), AclError> {
+        if self.database.roles.contains_key(&roleName) {
+            return Err(AclError::RoleAlreadyExists(roleName));
         }
+        self.database.roles.insert(roleName, Vec::new());
+        Ok(())
     }
 
-    // Create a struct for the action name
-    let action = ActionsControl {
-        actionName,
-        allowedDevices: Vec::new(),
-    };
+    // Actions granted or denied to the role lose their entry of it
+    pub fn remove_role(&mut self, roleName: String) -> Result<(), AclError> {
+        if self.database.roles.remove(&roleName).is_none() {
+            return Err(AclError::RoleNotFound(roleName));
+        }
+        for accessData in self.database.resources.values_mut() {
+            for action in accessData.actions.iter_mut() {
+                action.allowedRoles.retain(|allowedRole| *allowedRole != roleName);
+                action.deniedRoles.retain(|deniedRole| *deniedRole != roleName);
+            }
+        }
+        Ok(())
+    }
 
-    // Append the new action to the actions list
-    accessData.actions.push(action);
+    pub fn add_device_to_role(&mut self, roleName: String, deviceID: DeviceId) -> Result<(), AclError> {
+        add_entry(self.get_role(&roleName)?, deviceID)
+    }
 
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessData).unwrap();
-    update_resource_data(resourceID, json_string);
+    pub fn remove_device_from_role(&mut self, roleName: String, deviceID: DeviceId) -> Result<(), AclError> {
+        remove_entry(self.get_role(&roleName)?, &deviceID)
+    }
 
-    return 0;
-}
+    pub fn add_role_to_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+        add_entry(&mut self.get_action(resourceID, &actionName)?.allowedRoles, roleName)
+    }
 
-// Remove an action from a resource
-pub fn remove_action_from_resource(resourceID: u32, actionName: Vec<u8>) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Remove action and break from loop
-            accessData.actions.remove(index);
-
-            // Convert to String and write it to file
-            let json_string = serde_json::to_string(&accessData).unwrap();
-            update_resource_data(resourceID, json_string);
-
-            // Return success
-            return 0;
+    // Roles that already have an action keep it once
+    pub fn add_role_to_all_actions(&mut self, resourceID: &ResourcePath, roleName: String) -> Result<(), AclError> {
+        for action in self.get_resource(resourceID)?.actions.iter_mut() {
+            let _ = add_entry(&mut action.allowedRoles, roleName.clone());
         }
+        Ok(())
     }
 
-    // Action not removed because it does not exist
-    return 1;
-}
+    pub fn remove_role_from_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+        remove_entry(&mut self.get_action(resourceID, &actionName)?.allowedRoles, &roleName)
+    }
 
-// Add a device to an action of a resource
-pub fn add_device_to_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Add user to the allowed users for this action
-            accessData.actions[index].allowedDevices.push(deviceID);
-
-            // Convert to String and write it to file
-            let json_string = serde_json::to_string(&accessData).unwrap();
-            update_resource_data(resourceID, json_string);
-
-            // Return success
-            return 0;
+    pub fn add_denied_device_to_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+        add_entry(&mut self.get_action(resourceID, &actionName)?.deniedDevices, deviceID)
+    }
+
+    pub fn remove_denied_device_from_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+        remove_entry(&mut self.get_action(resourceID, &actionName)?.deniedDevices, &deviceID)
+    }
+
+    pub fn add_denied_role_to_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+        add_entry(&mut self.get_action(resourceID, &actionName)?.deniedRoles, roleName)
+    }
+
+    pub fn remove_denied_role_from_resource_action(&mut self, resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+        remove_entry(&mut self.get_action(resourceID, &actionName)?.deniedRoles, &roleName)
+    }
+
+    // Grant a device several actions. Fails without changes if one of the actions does not exist,
+    // grants the device already has are kept
+    pub fn grant_device_actions(&mut self, deviceID: DeviceId, grants: &[AccessGrant]) -> Result<(), AclError> {
+        for grant in grants {
+            let _ = add_entry(&mut self.get_action(&grant.resource_ID, &grant.action)?.allowedDevices, deviceID);
         }
+        Ok(())
     }
 
-    // Action not found, return 1.
-    return 1;
-}
+    // Revoke several actions from a device. Fails without changes if one of the actions does not exist
+    pub fn revoke_device_actions(&mut self, deviceID: DeviceId, grants: &[AccessGrant]) -> Result<(), AclError> {
+        for grant in grants {
+            let _ = remove_entry(&mut self.get_action(&grant.resource_ID, &grant.action)?.allowedDevices, &deviceID);
+        }
+        Ok(())
+    }
 
-// Add a device to all actions of a resource ID
-pub fn add_device_to_all_actions(resourceID: u32, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-    let mut accessDataCopy = read_access_data(resourceID);
-
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        let mut id_exists = false;
-        let mut data_copy = &action.allowedDevices;
-        for (user_index, userID) in data_copy.iter().enumerate() {
-            if *userID == deviceID {
-                id_exists = true;
+    // Remove a device from all actions of all resources and from all roles. Its deny entries are kept
+    pub fn revoke_device_everywhere(&mut self, deviceID: DeviceId) -> Result<(), AclError> {
+        for accessData in self.database.resources.values_mut() {
+            for action in accessData.actions.iter_mut() {
+                action.allowedDevices.retain(|allowedID| *allowedID != deviceID);
             }
         }
-        if !id_exists {
-            accessDataCopy.actions[index].allowedDevices.push(deviceID);
+        for members in self.database.roles.values_mut() {
+            members.retain(|memberID| *memberID != deviceID);
         }
+        Ok(())
     }
+}
+
+// Create a new Resource
+pub fn add_resource(resourceID: &ResourcePath, actions: Option<Vec<Vec<u8>>>) -> Result<(), AclError> {
+    transaction(|acl| acl.add_resource(resourceID, actions))
+}
+
+// Delete a resource from resources list
+pub fn remove_resource(resourceID: &ResourcePath) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_resource(resourceID))
+}
+
+// Add a new allowed action to a certain resource
+pub fn add_action_to_resource(resourceID: &ResourcePath, actionName: Vec<u8>) -> Result<(), AclError> {
+    transaction(|acl| acl.add_action_to_resource(resourceID, actionName))
+}
+
+// Remove an action from a resource
+pub fn remove_action_from_resource(resourceID: &ResourcePath, actionName: Vec<u8>) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_action_from_resource(resourceID, actionName))
+}
+
+// Add a device to an action of a resource
+pub fn add_device_to_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.add_device_to_resource_action(resourceID, actionName, deviceID))
+}
+
+// Add a device to all actions of a resource ID
+pub fn add_device_to_all_actions(resourceID: &ResourcePath, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.add_device_to_all_actions(resourceID, deviceID))
+}
 
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessDataCopy).unwrap();
-    update_resource_data(resourceID, json_string);
+pub fn remove_device_from_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_device_from_resource_action(resourceID, actionName, deviceID))
+}
+
+pub fn remove_device_from_all_actions(resourceID: &ResourcePath, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_device_from_all_actions(resourceID, deviceID))
+}
 
-    // Return 0 for success
-    return 0;
+// Grant a device several actions of possibly different resources at once
+pub fn grant_device_actions(deviceID: DeviceId, grants: &[AccessGrant]) -> Result<(), AclError> {
+    transaction(|acl| acl.grant_device_actions(deviceID, grants))
 }
 
-pub fn remove_device_from_resource_action(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+// Revoke several actions of possibly different resources from a device at once
+pub fn revoke_device_actions(deviceID: DeviceId, grants: &[AccessGrant]) -> Result<(), AclError> {
+    transaction(|acl| acl.revoke_device_actions(deviceID, grants))
+}
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Remove user from the allowed users for this action
-            for (user_index, userID) in action.allowedDevices.iter().enumerate() {
-                if *userID == deviceID {
-                    accessData.actions[index].allowedDevices.remove(user_index);
+// Remove a device from all access control lists and roles, e.g. after it was revoked
+pub fn revoke_device_everywhere(deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.revoke_device_everywhere(deviceID))
+}
+
+// List all resources with an access control list
+pub fn list_resources() -> Result<Vec<ResourcePath>, AclError> {
+    let database = acl_store::read_database()?;
+    Ok(database.resources.into_values().map(|accessData| accessData.resourceID).collect())
+}
 
-                    // Convert to String and write it to file
-                    let json_string = serde_json::to_string(&accessData).unwrap();
-                    update_resource_data(resourceID, json_string);
+// List the actions of a resource
+pub fn list_actions(resourceID: &ResourcePath) -> Result<Vec<Vec<u8>>, AclError> {
+    let mut database = acl_store::read_database()?;
+    match database.resources.remove(&resourceID.to_string()) {
+        Some(accessData) => Ok(accessData.actions.into_iter().map(|action| action.actionName).collect()),
+        None => Err(AclError::ResourceNotFound(resourceID.clone())),
+    }
+}
 
-                    // Return success
-                    return 0;
-                }
+// List the actions granted to a device, directly or by its roles, as they are listed in the access control lists.
+// Inherited grants and deny entries are not resolved, check_access decides on those
+pub fn list_device_grants(deviceID: DeviceId) -> Result<Vec<DeviceGrant>, AclError> {
+    let database = acl_store::read_database()?;
+    let deviceRoles = roles_of_device(&database, deviceID);
+
+    let mut grants = Vec::new();
+    for accessData in database.resources.values() {
+        for action in accessData.actions.iter() {
+            if let Some(reason) = check_local_access(action, deviceID, &deviceRoles) {
+                grants.push(DeviceGrant {
+                    resource_ID: accessData.resourceID.clone(),
+                    action: action.actionName.clone(),
+                    reason,
+                });
             }
-            // User already not allowed to use that resource
-            return 2;
         }
     }
+    Ok(grants)
+}
 
-    // Action not found, return 1
-    return 1;
+// Permission that allowed a device an action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessReason {
+    Device,
+    Role(String),
+    Credential,
+    // Delegated by the device holding the access
+    Delegation(DeviceId),
 }
 
-pub fn remove_device_from_all_actions(resourceID: u32, deviceID: u32) -> u8 {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
-    let mut accessDataCopy = read_access_data(resourceID);
+// Reason for denying a device an action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessDenial {
+    NotGranted,
+    ExplicitlyDenied,
+    OutsideAllowedHours,
+    OutsideAllowedDays,
+    NotYetValid,
+    Expired,
+    RateLimited,
+    AuthenticationTooOld,
+    // The time of the clock can not be evaluated
+    InvalidTime,
+    // The access control data could not be read, so nothing is allowed
+    AccessDataUnavailable,
+}
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        // Remove user from the allowed users for this action
-        for (user_index, userID) in action.allowedDevices.iter().enumerate() {
-            if *userID == deviceID {
-                accessDataCopy.actions[index].allowedDevices.remove(user_index);
-                break;
+// Check if a device has access to an action for a certain resource,
+// either by the local access control list or by a valid credential it presented
+pub fn check_access(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> bool {
+    check_access_with_clock(resourceID, actionName, deviceID, &SystemClock)
+}
+
+// Check access like check_access, evaluating the conditions of the action at the time of the clock
+pub fn check_access_with_clock(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId, clock: &dyn Clock) -> bool {
+    get_access_reason(resourceID, &actionName, deviceID, clock).is_ok()
+}
+
+// Return the permission that allows a device an action for a certain resource, or the reason it is denied.
+// Entries of the resource and of all resources above it are evaluated in this order:
+//  1. A deny entry for the device or one of its roles denies the action, whatever else allows it
+//  2. Otherwise the most specific entry allowing the device or one of its roles decides, see find_matching_actions
+//  3. Otherwise a valid credential of the device may allow the action, under the most specific entry
+//  4. Otherwise a valid delegation chain may allow it, under the entry that allows its first issuer by steps 1 and 2
+// The conditions of the deciding entry apply to the requesting device, and every allowed invocation of it
+// counts once for the invocation limit
+// Every decision is recorded in the audit log
+pub fn get_access_reason(resourceID: &ResourcePath, actionName: &[u8], deviceID: DeviceId, clock: &dyn Clock) -> Result<AccessReason, AccessDenial> {
+    let decision = decide_access(resourceID, actionName, deviceID, clock);
+    let reason = match &decision {
+        Ok(reason) => format!("{:?}", reason),
+        Err(denial) => format!("{:?}", denial),
+    };
+    audit_log::record_event(AuditEvent::AccessDecision {
+        peer_ID: deviceID,
+        resource_ID: resourceID.clone(),
+        action: actionName.to_vec(),
+        allowed: decision.is_ok(),
+        reason,
+    });
+    decision
+}
+
+// Decide on the access of a device, see get_access_reason
+fn decide_access(resourceID: &ResourcePath, actionName: &[u8], deviceID: DeviceId, clock: &dyn Clock) -> Result<AccessReason, AccessDenial> {
+    let database = acl_store::read_database().map_err(|e| {
+        println!("Failed to read access control data: {:?}\n", e);
+        AccessDenial::AccessDataUnavailable
+    })?;
+    let actions = find_matching_actions(&database, resourceID, actionName);
+
+    let (reason, entry) = match decide_local_access(&database, &actions, deviceID)? {
+        Some((reason, entry)) => (reason, Some(entry)),
+        None => {
+            if credentials::credential_grants_access(deviceID, resourceID, actionName, clock.now()) {
+                (AccessReason::Credential, actions.first())
+            } else {
+                // The entry allowing the first issuer of a chain applies to the delegate
+                delegation::delegation_roots(deviceID, resourceID, actionName, clock.now()).into_iter()
+                    .find_map(|rootID| match decide_local_access(&database, &actions, rootID) {
+                        Ok(Some((_, entry))) => Some((AccessReason::Delegation(rootID), Some(entry))),
+                        _ => None,
+                    })
+                    .ok_or(AccessDenial::NotGranted)?
             }
         }
+    };
+
+    // Conditions of the entry apply to all permissions, evaluated and counted for the requesting device only
+    if let Some((entryID, action)) = entry {
+        if let Some(conditions) = &action.conditions {
+            check_conditions(entryID, &action.actionName, deviceID, conditions, clock)?;
+        }
+    }
+
+    Ok(reason)
+}
+
+// Decide on the access of a device by the access control lists alone, without evaluating conditions.
+// A deny entry denies the action, otherwise the most specific allowing entry is returned with its permission.
+// None if no entry allows the device the action
+fn decide_local_access<'a>(database: &AclDatabase, actions: &'a [(ResourcePath, &'a ActionsControl)],
+                           deviceID: DeviceId) -> Result<Option<(AccessReason, &'a (ResourcePath, &'a ActionsControl))>, AccessDenial> {
+    let deviceRoles = roles_of_device(database, deviceID);
+    if actions.iter().any(|(_, action)| is_denied(action, deviceID, &deviceRoles)) {
+        return Err(AccessDenial::ExplicitlyDenied);
+    }
+
+    Ok(actions.iter().find_map(|entry| check_local_access(entry.1, deviceID, &deviceRoles).map(|reason| (reason, entry))))
+}
+
+// Check if an action name of the access control list matches an action.
+// A name ending with '*' is a wildcard matching all actions starting with the rest of the name
+pub(crate) fn action_matches(pattern: &[u8], actionName: &[u8]) -> bool {
+    match pattern.split_last() {
+        Some((b'*', prefix)) => actionName.starts_with(prefix),
+        _ => pattern == actionName,
+    }
+}
+
+// Find the actions of the access control lists matching an action on a resource, with the resource
+// each one belongs to. They are ordered from the most to the least specific: actions of the resource first,
+// then those of the resources above it. For each resource, the exact action comes before the wildcards,
+// and longer wildcards come before shorter ones
+fn find_matching_actions<'a>(database: &'a AclDatabase, resourceID: &ResourcePath, actionName: &[u8]) -> Vec<(ResourcePath, &'a ActionsControl)> {
+    let mut matchingActions = Vec::new();
+    for entryID in resourceID.ancestors() {
+        // Resources without a local list can only be accessed with a credential
+        let accessData = match database.resources.get(&entryID.to_string()) {
+            Some(accessData) => accessData,
+            None => {
+                continue;
+            }
+        };
+
+        let mut actions: Vec<&ActionsControl> = accessData.actions.iter()
+            .filter(|action| action_matches(&action.actionName, actionName))
+            .collect();
+        actions.sort_by_key(|action| (action.actionName.last() == Some(&b'*'), Reverse(action.actionName.len())));
+        matchingActions.extend(actions.into_iter().map(|action| (entryID.clone(), action)));
     }
+    matchingActions
+}
 
-    // Convert to String and write it to file
-    let json_string = serde_json::to_string(&accessDataCopy).unwrap();
-    update_resource_data(resourceID, json_string);
+// Check if an action is denied to a device or one of its roles
+fn is_denied(action: &ActionsControl, deviceID: DeviceId, deviceRoles: &[String]) -> bool {
+    action.deniedDevices.contains(&deviceID) || action.deniedRoles.iter().any(|roleName| deviceRoles.contains(roleName))
+}
 
-    // Return 0 for success
-    return 0;
+// Check if the local access control list allows a device an action
+fn check_local_access(action: &ActionsControl, deviceID: DeviceId, deviceRoles: &[String]) -> Option<AccessReason> {
+    // Check if user is allowed to use this action
+    if action.allowedDevices.contains(&deviceID) {
+        return Some(AccessReason::Device);
+    }
+
+    // Check if one of the roles of the user is allowed to use this action
+    action.allowedRoles.iter()
+        .find(|roleName| deviceRoles.contains(roleName))
+        .map(|roleName| AccessReason::Role(roleName.clone()))
 }
 
-// Check if a device has access to an action for a certain resource
-pub fn check_access(resourceID: u32, actionName: Vec<u8>, deviceID: u32) -> bool {
-    // Read access control data for the provided resource ID
-    let mut accessData = read_access_data(resourceID);
+// Evaluate the conditions of an action at the time of the clock, and count the invocation if they are met
+fn check_conditions(resourceID: &ResourcePath, actionName: &[u8], deviceID: DeviceId, conditions: &AccessConditions,
+                    clock: &dyn Clock) -> Result<(), AccessDenial> {
+    let timestamp = clock.now();
+    let time = match Utc.timestamp_millis_opt(timestamp).single() {
+        Some(time) => time,
+        None => {
+            println!("Time {} of the clock is out of range!\n", timestamp);
+            return Err(AccessDenial::InvalidTime);
+        }
+    };
+
+    if let Some((first_hour, last_hour)) = conditions.allowed_hours {
+        let hour = time.hour();
+        let allowed = if first_hour <= last_hour {
+            first_hour <= hour && hour < last_hour
+        } else {
+            first_hour <= hour || hour < last_hour
+        };
+        if !allowed {
+            return Err(AccessDenial::OutsideAllowedHours);
+        }
+    }
+    if let Some(days) = &conditions.allowed_days {
+        if !days.contains(&time.weekday().num_days_from_monday()) {
+            return Err(AccessDenial::OutsideAllowedDays);
+        }
+    }
+    if conditions.valid_from.is_some_and(|valid_from| timestamp < valid_from) {
+        return Err(AccessDenial::NotYetValid);
+    }
+    if conditions.valid_until.is_some_and(|valid_until| timestamp > valid_until) {
+        return Err(AccessDenial::Expired);
+    }
+
+    // Mutual authentication with the device has to be recent enough
+    if let Some(max_auth_age) = conditions.max_auth_age {
+        let last_mutual_auth = peer_registry::get_last_mutual_auth(deviceID);
+        if last_mutual_auth == 0 || timestamp - last_mutual_auth > max_auth_age {
+            return Err(AccessDenial::AuthenticationTooOld);
+        }
+    }
 
-    // Go through all action in the access control data and find if an action matches
-    for (index, action) in accessData.actions.iter().enumerate() {
-        if action.actionName == actionName {
-            // Action found. Check if user is allowed to use this action
-            for (user_index, userID) in action.allowedDevices.iter().enumerate(){
-                if *userID == deviceID {
-                    return true;
-                }
+    // Checked last, so that only allowed invocations are counted
+    if let Some((max_invocations, period)) = conditions.max_invocations {
+        let counted = acl_store::update_invocations(resourceID, |invocationsData| {
+            let invocations = invocationsData.entry(format!("{}:{}", deviceID, hex::encode(actionName))).or_default();
+            invocations.retain(|invocation| timestamp - *invocation < period);
+            if invocations.len() >= max_invocations as usize {
+                return Err(AccessDenial::RateLimited);
+            }
+            invocations.push(timestamp);
+            Ok(())
+        });
+        match counted {
+            Ok(result) => result?,
+            Err(e) => {
+                println!("Failed to count the invocations of {}: {:?}\n", resourceID, e);
+                return Err(AccessDenial::AccessDataUnavailable);
             }
-            // User not allowed to use that resource
-            return false;
         }
     }
 
-    // Action does not exist
-    return false;
+    Ok(())
+}
+
+// Set or remove the conditions of an action of a resource
+pub fn set_action_conditions(resourceID: &ResourcePath, actionName: Vec<u8>, conditions: Option<AccessConditions>) -> Result<(), AclError> {
+    transaction(|acl| acl.set_action_conditions(resourceID, actionName, conditions))
+}
+
+// List the roles of a device
+fn roles_of_device(database: &AclDatabase, deviceID: DeviceId) -> Vec<String> {
+    database.roles.iter()
+        .filter(|(_, members)| members.contains(&deviceID))
+        .map(|(roleName, _)| roleName.clone())
+        .collect()
+}
+
+// Define a new role without members
+pub fn add_role(roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.add_role(roleName))
+}
+
+// Delete a role together with its entries in the resource actions
+pub fn remove_role(roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_role(roleName))
+}
+
+// Assign a device to a role
+pub fn add_device_to_role(roleName: String, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.add_device_to_role(roleName, deviceID))
+}
+
+// Remove a device from a role
+pub fn remove_device_from_role(roleName: String, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_device_from_role(roleName, deviceID))
+}
+
+// List all defined roles
+pub fn list_roles() -> Result<Vec<String>, AclError> {
+    Ok(acl_store::read_database()?.roles.into_keys().collect())
+}
+
+// List the roles of a device
+pub fn get_device_roles(deviceID: DeviceId) -> Result<Vec<String>, AclError> {
+    Ok(roles_of_device(&acl_store::read_database()?, deviceID))
+}
+
+// List the members of a role
+pub fn get_role_members(roleName: String) -> Result<Vec<DeviceId>, AclError> {
+    let mut database = acl_store::read_database()?;
+    database.roles.remove(&roleName).ok_or(AclError::RoleNotFound(roleName))
+}
+
+// Grant a role an action of a resource
+pub fn add_role_to_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.add_role_to_resource_action(resourceID, actionName, roleName))
+}
+
+// Grant a role all actions of a resource
+pub fn add_role_to_all_actions(resourceID: &ResourcePath, roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.add_role_to_all_actions(resourceID, roleName))
+}
+
+// Withdraw an action of a resource from a role
+pub fn remove_role_from_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_role_from_resource_action(resourceID, actionName, roleName))
+}
+
+// Deny a device an action of a resource. Denying a wildcard action or a resource denies all actions and
+// resources it covers, even if they are allowed to the device elsewhere
+pub fn add_denied_device_to_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.add_denied_device_to_resource_action(resourceID, actionName, deviceID))
+}
+
+// Remove the deny entry of a device from an action of a resource
+pub fn remove_denied_device_from_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, deviceID: DeviceId) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_denied_device_from_resource_action(resourceID, actionName, deviceID))
+}
+
+// Deny all members of a role an action of a resource
+pub fn add_denied_role_to_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.add_denied_role_to_resource_action(resourceID, actionName, roleName))
+}
+
+// Remove the deny entry of a role from an action of a resource
+pub fn remove_denied_role_from_resource_action(resourceID: &ResourcePath, actionName: Vec<u8>, roleName: String) -> Result<(), AclError> {
+    transaction(|acl| acl.remove_denied_role_from_resource_action(resourceID, actionName, roleName))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::test_support;
+
+    fn path(resource: &str) -> ResourcePath {
+        ResourcePath::parse(resource).unwrap()
+    }
+
+    // Monday, 5 January 2026, 12:00 UTC
+    fn noon() -> ManualClock {
+        ManualClock::new(Utc.with_ymd_and_hms(2026, 1, 5, 12, 0, 0).unwrap().timestamp_millis())
+    }
+
+    fn access(resource: &str, action: &str, deviceID: u32, clock: &ManualClock) -> Result<AccessReason, AccessDenial> {
+        decide_access(&path(resource), action.as_bytes(), DeviceId::from(deviceID), clock)
+    }
+
+    // Conditions that deny every access at noon
+    fn night_only() -> Option<AccessConditions> {
+        Some(AccessConditions { allowed_hours: Some((22, 6)), ..AccessConditions::default() })
+    }
+
+    // Resource with one action granted to a device under conditions
+    fn conditional_action(resource: &str, deviceID: u32, conditions: AccessConditions) {
+        add_resource(&path(resource), Some(vec![b"open".to_vec()])).unwrap();
+        add_device_to_resource_action(&path(resource), b"open".to_vec(), DeviceId::from(deviceID)).unwrap();
+        set_action_conditions(&path(resource), b"open".to_vec(), Some(conditions)).unwrap();
+    }
+
+    #[test]
+    fn hours_wrap_around_midnight() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            conditional_action("door", 1, AccessConditions { allowed_hours: Some((22, 6)), ..AccessConditions::default() });
+            let hour = 60 * 60 * 1000;
+
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::OutsideAllowedHours));
+            clock.advance(10 * hour - 1);
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::OutsideAllowedHours));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.advance(2 * hour);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.advance(6 * hour - 1);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::OutsideAllowedHours));
+        });
+    }
+
+    #[test]
+    fn validity_period_includes_its_bounds() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            let start = clock.now();
+            conditional_action("door", 1, AccessConditions { valid_from: Some(start), valid_until: Some(start + 1000), ..AccessConditions::default() });
+
+            clock.set(start - 1);
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::NotYetValid));
+            clock.set(start);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.set(start + 1000);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.set(start + 1001);
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::Expired));
+        });
+    }
+
+    #[test]
+    fn rate_limit_window_rolls_over() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            conditional_action("door", 1, AccessConditions { max_invocations: Some((2, 60 * 1000)), ..AccessConditions::default() });
+
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::RateLimited));
+
+            // The first invocation leaves the window, denied invocations were not counted
+            clock.advance(60 * 1000 - 2);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::RateLimited));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+        });
+    }
+
+    #[test]
+    fn authentication_has_to_be_recent() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            let deviceID = DeviceId::from(43001);
+            conditional_action("door", 43001, AccessConditions { max_auth_age: Some(1000), ..AccessConditions::default() });
+            peer_registry::record_mutual_auth(deviceID, 0);
+            assert_eq!(access("door", "open", 43001, &clock), Err(AccessDenial::AuthenticationTooOld));
+
+            peer_registry::record_mutual_auth(deviceID, clock.now());
+            clock.advance(1000);
+            assert_eq!(access("door", "open", 43001, &clock), Ok(AccessReason::Device));
+            clock.advance(1);
+            assert_eq!(access("door", "open", 43001, &clock), Err(AccessDenial::AuthenticationTooOld));
+        });
+    }
+
+    #[test]
+    fn time_out_of_range_denies() {
+        test_support::in_empty_work_dir(|| {
+            let clock = ManualClock::new(i64::MAX);
+            conditional_action("door", 1, AccessConditions { allowed_days: Some(vec![0, 1, 2, 3, 4, 5, 6]), ..AccessConditions::default() });
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::InvalidTime));
+        });
+    }
+
+    #[test]
+    fn deny_beats_allow_at_same_level() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            add_resource(&path("home"), Some(vec![b"open".to_vec()])).unwrap();
+            add_role(String::from("guests")).unwrap();
+            add_device_to_role(String::from("guests"), DeviceId::from(2)).unwrap();
+            add_device_to_resource_action(&path("home"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+            add_device_to_resource_action(&path("home"), b"open".to_vec(), DeviceId::from(2)).unwrap();
+            assert_eq!(access("home", "open", 1, &clock), Ok(AccessReason::Device));
+
+            add_denied_device_to_resource_action(&path("home"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+            add_denied_role_to_resource_action(&path("home"), b"open".to_vec(), String::from("guests")).unwrap();
+            assert_eq!(access("home", "open", 1, &clock), Err(AccessDenial::ExplicitlyDenied));
+            assert_eq!(access("home", "open", 2, &clock), Err(AccessDenial::ExplicitlyDenied));
+        });
+    }
+
+    #[test]
+    fn specific_path_beats_inherited_grant() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            add_resource(&path("home"), Some(vec![b"open".to_vec()])).unwrap();
+            add_resource(&path("home/kitchen"), Some(vec![b"open".to_vec()])).unwrap();
+            add_device_to_resource_action(&path("home"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+            set_action_conditions(&path("home"), b"open".to_vec(), night_only()).unwrap();
+            add_device_to_resource_action(&path("home/kitchen"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+
+            // The entry of the kitchen decides without conditions, other rooms inherit the conditions of home
+            assert_eq!(access("home/kitchen/oven", "open", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(access("home/bath", "open", 1, &clock), Err(AccessDenial::OutsideAllowedHours));
+
+            // A deny entry above the resource still applies
+            add_denied_device_to_resource_action(&path("home"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+            assert_eq!(access("home/kitchen", "open", 1, &clock), Err(AccessDenial::ExplicitlyDenied));
+        });
+    }
+
+    #[test]
+    fn exact_action_beats_wildcard() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            add_resource(&path("sensor"), Some(vec![b"read:*".to_vec(), b"read:temp:*".to_vec(), b"read:temp:celsius".to_vec()])).unwrap();
+            for actionName in ["read:*", "read:temp:*", "read:temp:celsius"] {
+                add_device_to_resource_action(&path("sensor"), actionName.as_bytes().to_vec(), DeviceId::from(1)).unwrap();
+            }
+            set_action_conditions(&path("sensor"), b"read:*".to_vec(), night_only()).unwrap();
+            set_action_conditions(&path("sensor"), b"read:temp:*".to_vec(), Some(AccessConditions { valid_until: Some(0), ..AccessConditions::default() })).unwrap();
+
+            assert_eq!(access("sensor", "read:temp:celsius", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(access("sensor", "read:temp:kelvin", 1, &clock), Err(AccessDenial::Expired));
+            assert_eq!(access("sensor", "read:humidity", 1, &clock), Err(AccessDenial::OutsideAllowedHours));
+            assert_eq!(access("sensor", "write", 1, &clock), Err(AccessDenial::NotGranted));
+
+            // A denied wildcard covers the exact action
+            add_denied_device_to_resource_action(&path("sensor"), b"read:*".to_vec(), DeviceId::from(1)).unwrap();
+            assert_eq!(access("sensor", "read:temp:celsius", 1, &clock), Err(AccessDenial::ExplicitlyDenied));
+        });
+    }
+
+    #[test]
+    fn device_entry_beats_role_entry() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+            add_resource(&path("door/front"), Some(vec![b"open".to_vec()])).unwrap();
+            add_role(String::from("residents")).unwrap();
+            add_device_to_role(String::from("residents"), DeviceId::from(1)).unwrap();
+            add_device_to_role(String::from("residents"), DeviceId::from(2)).unwrap();
+            add_role_to_resource_action(&path("door"), b"open".to_vec(), String::from("residents")).unwrap();
+            add_device_to_resource_action(&path("door"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(access("door", "open", 2, &clock), Ok(AccessReason::Role(String::from("residents"))));
+
+            // On a more specific resource, a role entry beats an inherited device entry
+            add_role_to_resource_action(&path("door/front"), b"open".to_vec(), String::from("residents")).unwrap();
+            assert_eq!(access("door/front", "open", 1, &clock), Ok(AccessReason::Role(String::from("residents"))));
+        });
+    }
+
+    #[test]
+    fn second_grant_is_duplicate_entry() {
+        test_support::in_empty_work_dir(|| {
+            add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+            add_device_to_resource_action(&path("door"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+
+            assert!(matches!(add_device_to_resource_action(&path("door"), b"open".to_vec(), DeviceId::from(1)), Err(AclError::DuplicateEntry)));
+            assert_eq!(list_device_grants(DeviceId::from(1)).unwrap().len(), 1);
+            remove_device_from_resource_action(&path("door"), b"open".to_vec(), DeviceId::from(1)).unwrap();
+            assert!(matches!(remove_device_from_resource_action(&path("door"), b"open".to_vec(), DeviceId::from(1)), Err(AclError::EntryNotFound)));
+        });
+    }
+
+    #[test]
+    fn actions_of_unknown_resource_are_not_found() {
+        test_support::in_empty_work_dir(|| {
+            add_resource(&path("door"), Some(vec![b"open".to_vec(), b"lock".to_vec()])).unwrap();
+
+            assert_eq!(list_actions(&path("door")).unwrap(), vec![b"open".to_vec(), b"lock".to_vec()]);
+            assert!(matches!(list_actions(&path("window")), Err(AclError::ResourceNotFound(resourceID)) if resourceID == path("window")));
+            assert!(matches!(list_actions(&path("door/front")), Err(AclError::ResourceNotFound(_))));
+        });
+    }
+
+    #[test]
+    fn bulk_changes_apply_together() {
+        test_support::in_empty_work_dir(|| {
+            let clock = noon();
+            add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+            add_resource(&path("window"), Some(vec![b"open".to_vec()])).unwrap();
+            let grant = |resource: &str| AccessGrant { resource_ID: path(resource), action: b"open".to_vec() };
+            let revision = acl_store::get_revision().unwrap();
+
+            // One missing action discards the whole change
+            assert!(matches!(grant_device_actions(DeviceId::from(1), &[grant("door"), grant("gate")]), Err(AclError::ResourceNotFound(_))));
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::NotGranted));
+            assert_eq!(acl_store::get_revision().unwrap(), revision);
+
+            grant_device_actions(DeviceId::from(1), &[grant("door"), grant("window")]).unwrap();
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(access("window", "open", 1, &clock), Ok(AccessReason::Device));
+            assert_eq!(acl_store::get_revision().unwrap(), revision + 1);
+
+            assert!(revoke_device_actions(DeviceId::from(1), &[grant("door"), grant("gate")]).is_err());
+            assert_eq!(access("door", "open", 1, &clock), Ok(AccessReason::Device));
+
+            revoke_device_actions(DeviceId::from(1), &[grant("door"), grant("window")]).unwrap();
+            assert_eq!(access("door", "open", 1, &clock), Err(AccessDenial::NotGranted));
+            assert_eq!(access("window", "open", 1, &clock), Err(AccessDenial::NotGranted));
+            assert_eq!(acl_store::get_revision().unwrap(), revision + 2);
+        });
+    }
 }
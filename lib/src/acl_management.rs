@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use crate::access_control::{self, AccessDenial, AclError};
+use crate::audit_log::{self, AuditEvent, AuditLogErrors};
+use crate::{DeviceId, ResourcePath};
+
+// Resource that admin devices need grants on to manage the access control lists of a device remotely.
+// A command on a resource is checked as its action on this resource followed by the resource path,
+// so an admin can be limited to a part of the resource hierarchy
+pub const CONST_MANAGEMENT_RESOURCE: &str = "acl-management";
+
+// Change or query of the access control lists, sent by an admin device
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ManagementCommand {
+    AddResource { resource_ID: ResourcePath, actions: Vec<Vec<u8>> },
+    RemoveResource { resource_ID: ResourcePath },
+    Grant { device_ID: DeviceId, resource_ID: ResourcePath, action: Vec<u8> },
+    Revoke { device_ID: DeviceId, resource_ID: ResourcePath, action: Vec<u8> },
+    ListResources,
+    ListActions { resource_ID: ResourcePath },
+}
+
+impl ManagementCommand {
+    // Action of the command on the management resource
+    pub fn action(&self) -> &'static [u8] {
+        match self {
+            ManagementCommand::AddResource { .. } => b"add_resource",
+            ManagementCommand::RemoveResource { .. } => b"remove_resource",
+            ManagementCommand::Grant { .. } => b"grant",
+            ManagementCommand::Revoke { .. } => b"revoke",
+            ManagementCommand::ListResources | ManagementCommand::ListActions { .. } => b"list",
+        }
+    }
+
+    // Resource the command applies to, None for commands on all resources
+    pub fn resource(&self) -> Option<&ResourcePath> {
+        match self {
+            ManagementCommand::AddResource { resource_ID, .. } |
+            ManagementCommand::RemoveResource { resource_ID } |
+            ManagementCommand::Grant { resource_ID, .. } |
+            ManagementCommand::Revoke { resource_ID, .. } |
+            ManagementCommand::ListActions { resource_ID } => Some(resource_ID),
+            ManagementCommand::ListResources => None,
+        }
+    }
+
+    // Check if the command changes the access control lists
+    pub fn is_change(&self) -> bool {
+        !matches!(self, ManagementCommand::ListResources | ManagementCommand::ListActions { .. })
+    }
+}
+
+// Management command authenticated with a NIZK proof of the admin device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagementRequest {
+    pub sender_ID: DeviceId,
+    pub receiver_ID: DeviceId,
+    pub command: ManagementCommand,
+    pub proof: ([u8; 32], [u8; 32], [u8; 32]),
+}
+
+// Result of an applied command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagementOutcome {
+    Applied,
+    Resources(Vec<ResourcePath>),
+    Actions(Vec<Vec<u8>>),
+}
+
+#[derive(Debug)]
+pub enum ManagementErrors {
+    WrongReceiver,
+    // Changes have to update the shared values, see handle_management_request
+    KeyUpdateRequired,
+    InvalidProof,
+    AccessDenied(AccessDenial),
+    CommandFailed(AclError),
+}
+
+// Management command recorded in the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManagementAuditEntry {
+    pub timestamp: i64,
+    pub admin_ID: DeviceId,
+    pub command: ManagementCommand,
+    // None if the command was applied, otherwise the reason it was not
+    pub error: Option<String>,
+}
+
+// Path of the management resource an admin needs the action of a command on
+pub fn get_management_resource_path(resource_ID: Option<&ResourcePath>) -> ResourcePath {
+    let management_resource = ResourcePath::parse(CONST_MANAGEMENT_RESOURCE).unwrap();
+    match resource_ID {
+        Some(resource_ID) => management_resource.join(resource_ID),
+        None => management_resource,
+    }
+}
+
+// Message of the NIZK proof, an unambiguous encoding of the request
+fn request_transcript(sender_ID: DeviceId, receiver_ID: DeviceId, command: &ManagementCommand) -> String {
+    let command_json = serde_json::to_vec(command).unwrap();
+    let mut data = Vec::from(&b"ManagementRequest"[..]);
+    data.extend_from_slice(&sender_ID.to_bytes());
+    data.extend_from_slice(&receiver_ID.to_bytes());
+    data.extend_from_slice(&(command_json.len() as u32).to_be_bytes());
+    data.extend_from_slice(&command_json);
+    hex::encode(data)
+}
+
+// Create a management request for the receiver, authenticated with a NIZK proof.
+// Requests that change the access control lists need update_keys on both devices
+pub fn new_management_request(my_ID: DeviceId, receiver_ID: DeviceId, command: ManagementCommand, update_keys: bool) -> ManagementRequest {
+    let transcript = request_transcript(my_ID, receiver_ID, &command);
+    let proof = crate::gen_nizk_proof(my_ID, receiver_ID, transcript, update_keys);
+
+    ManagementRequest {
+        sender_ID: my_ID,
+        receiver_ID,
+        command,
+        proof,
+    }
+}
+
+// Verify the NIZK proof of a management request, check the permission of the admin on the management
+// resource and apply the command. Authenticated changes are recorded in the audit log, also if they fail.
+// Changes are only accepted with update_keys, since the proof of a request stays valid until the shared
+// values are updated, so a recorded Grant could otherwise be replayed after it was revoked
+pub fn handle_management_request(my_ID: DeviceId, request: &ManagementRequest, update_keys: bool) -> Result<ManagementOutcome, ManagementErrors> {
+    if request.receiver_ID != my_ID {
+        return Err(ManagementErrors::WrongReceiver);
+    }
+    if request.command.is_change() && !update_keys {
+        return Err(ManagementErrors::KeyUpdateRequired);
+    }
+
+    let transcript = request_transcript(request.sender_ID, request.receiver_ID, &request.command);
+    if !crate::verify_nizk_proof(my_ID, request.sender_ID, transcript, request.proof, update_keys) {
+        return Err(ManagementErrors::InvalidProof);
+    }
+
+    let management_resource = get_management_resource_path(request.command.resource());
+    let result = match access_control::get_access_reason(&management_resource, request.command.action(), request.sender_ID,
+                                                         &crate::clock::SystemClock) {
+        Ok(_) => apply_command(&request.command).map_err(ManagementErrors::CommandFailed),
+        Err(denial) => Err(ManagementErrors::AccessDenied(denial)),
+    };
+
+    if request.command.is_change() {
+        audit_log::record_event(AuditEvent::ManagementCommand {
+            admin_ID: request.sender_ID,
+            command: request.command.clone(),
+            error: result.as_ref().err().map(|error| format!("{:?}", error)),
+        });
+    }
+
+    result
+}
+
+// Apply a command to the local access control lists
+fn apply_command(command: &ManagementCommand) -> Result<ManagementOutcome, AclError> {
+    match command {
+        ManagementCommand::AddResource { resource_ID, actions } => {
+            access_control::add_resource(resource_ID, Some(actions.clone()))?;
+        },
+        ManagementCommand::RemoveResource { resource_ID } => {
+            access_control::remove_resource(resource_ID)?;
+        },
+        ManagementCommand::Grant { device_ID, resource_ID, action } => {
+            access_control::add_device_to_resource_action(resource_ID, action.clone(), *device_ID)?;
+        },
+        ManagementCommand::Revoke { device_ID, resource_ID, action } => {
+            access_control::remove_device_from_resource_action(resource_ID, action.clone(), *device_ID)?;
+        },
+        ManagementCommand::ListResources => {
+            return Ok(ManagementOutcome::Resources(access_control::list_resources()?));
+        },
+        ManagementCommand::ListActions { resource_ID } => {
+            return Ok(ManagementOutcome::Actions(access_control::list_actions(resource_ID)?));
+        },
+    }
+    Ok(ManagementOutcome::Applied)
+}
+
+// Read the management commands of the audit log, oldest entry first
+pub fn read_audit_trail() -> Result<Vec<ManagementAuditEntry>, AuditLogErrors> {
+    Ok(audit_log::read_audit_log()?.into_iter()
+        .filter_map(|entry| match entry.event {
+            AuditEvent::ManagementCommand { admin_ID, command, error } => Some(ManagementAuditEntry {
+                timestamp: entry.timestamp,
+                admin_ID,
+                command,
+                error,
+            }),
+            _ => None,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Admin device and managed device that know each other and share a key
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        crate::store_shared_values(AID, BID, &[47u8; 32]);
+        crate::store_shared_values(BID, AID, &[47u8; 32]);
+        (AID, BID)
+    }
+
+    fn path(resource: &str) -> ResourcePath {
+        ResourcePath::parse(resource).unwrap()
+    }
+
+    // Resource "door" on the managed device, and the admin allowed to grant and revoke actions below a resource
+    fn allow_admin(admin_ID: DeviceId, resource: &str) {
+        access_control::add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+        let management_resource = get_management_resource_path(Some(&path(resource)));
+        access_control::add_resource(&management_resource, Some(vec![b"grant".to_vec(), b"revoke".to_vec()])).unwrap();
+        access_control::add_device_to_all_actions(&management_resource, admin_ID).unwrap();
+    }
+
+    fn open_door(device_ID: u32) -> ManagementCommand {
+        ManagementCommand::Grant { device_ID: DeviceId::from(device_ID), resource_ID: path("door"), action: b"open".to_vec() }
+    }
+
+    fn can_open_door(device_ID: u32) -> bool {
+        access_control::check_access(&path("door"), b"open".to_vec(), DeviceId::from(device_ID))
+    }
+
+    #[test]
+    fn authorized_grant_is_applied() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47001, 47002);
+            allow_admin(BID, "door");
+
+            let request = new_management_request(BID, AID, open_door(47003), true);
+            assert_eq!(handle_management_request(AID, &request, true).unwrap(), ManagementOutcome::Applied);
+            assert!(can_open_door(47003));
+        });
+    }
+
+    #[test]
+    fn admin_without_management_grant_is_denied() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47011, 47012);
+            allow_admin(BID, "window");
+
+            let request = new_management_request(BID, AID, open_door(47013), true);
+            assert!(matches!(handle_management_request(AID, &request, true), Err(ManagementErrors::AccessDenied(AccessDenial::NotGranted))));
+            assert!(!can_open_door(47013));
+        });
+    }
+
+    #[test]
+    fn request_for_another_receiver_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47021, 47022);
+            let (CID, _) = paired_devices(47023, 47024);
+            allow_admin(BID, "door");
+
+            let request = new_management_request(BID, AID, open_door(47025), true);
+            assert!(matches!(handle_management_request(CID, &request, true), Err(ManagementErrors::WrongReceiver)));
+            assert!(!can_open_door(47025));
+        });
+    }
+
+    #[test]
+    fn tampered_command_has_invalid_proof() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47031, 47032);
+            allow_admin(BID, "door");
+
+            let mut request = new_management_request(BID, AID, open_door(47033), true);
+            request.command = open_door(47034);
+            assert!(matches!(handle_management_request(AID, &request, true), Err(ManagementErrors::InvalidProof)));
+            assert!(!can_open_door(47033) && !can_open_door(47034));
+        });
+    }
+
+    #[test]
+    fn replayed_grant_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47041, 47042);
+            allow_admin(BID, "door");
+
+            let grant = new_management_request(BID, AID, open_door(47043), true);
+            handle_management_request(AID, &grant, true).unwrap();
+            let revoke = ManagementCommand::Revoke { device_ID: DeviceId::from(47043), resource_ID: path("door"), action: b"open".to_vec() };
+            handle_management_request(AID, &new_management_request(BID, AID, revoke, true), true).unwrap();
+
+            assert!(matches!(handle_management_request(AID, &grant, true), Err(ManagementErrors::InvalidProof)));
+            assert!(matches!(handle_management_request(AID, &grant, false), Err(ManagementErrors::KeyUpdateRequired)));
+            assert!(!can_open_door(47043));
+        });
+    }
+
+    #[test]
+    fn changes_are_recorded_in_audit_trail() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(47051, 47052);
+            allow_admin(BID, "door");
+            access_control::add_resource(&get_management_resource_path(None), Some(vec![b"list".to_vec()])).unwrap();
+            access_control::add_device_to_all_actions(&get_management_resource_path(None), BID).unwrap();
+
+            handle_management_request(AID, &new_management_request(BID, AID, open_door(47053), true), true).unwrap();
+            assert!(handle_management_request(AID, &new_management_request(BID, AID, open_door(47053), true), true).is_err());
+            handle_management_request(AID, &new_management_request(BID, AID, ManagementCommand::ListResources, true), true).unwrap();
+
+            // Queries are not recorded
+            let audit_trail = read_audit_trail().unwrap();
+            assert_eq!(audit_trail.len(), 2);
+            assert!(audit_trail.iter().all(|entry| entry.admin_ID == BID && entry.command == open_door(47053)));
+            assert_eq!(audit_trail[0].error, None);
+            assert_eq!(audit_trail[1].error.as_deref(), Some("CommandFailed(DuplicateEntry)"));
+        });
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use serde::{Deserialize, Serialize};
+use crate::access_control::{self, AccessConditions, AclError, AclTransaction};
+use crate::acl_store::{self, AclDatabase};
+use crate::file_management;
+use crate::{DeviceId, ResourcePath};
+
+// Prefix of an action name in a policy that is followed by the name in hex, for names that are not valid UTF-8
+pub const CONST_HEX_ACTION_PREFIX: &str = "hex:";
+
+// Declarative description of the access control lists and roles of a device, kept in a TOML file like
+//
+//   [roles]
+//   operators = [20000, 30000]
+//
+//   [[resource]]
+//   path = "building/floor2"
+//
+//   [[resource.action]]
+//   name = "read:*"
+//   devices = [10000]
+//   roles = ["operators"]
+//
+// Applying a policy makes the lists and roles equal to it: resources, actions, roles and entries that
+// are not in the policy are removed
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AclPolicy {
+    #[serde(default)]
+    pub roles: BTreeMap<String, Vec<DeviceId>>,
+    #[serde(default, rename = "resource")]
+    pub resources: Vec<ResourcePolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourcePolicy {
+    pub path: ResourcePath,
+    #[serde(default, rename = "action")]
+    pub actions: Vec<ActionPolicy>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActionPolicy {
+    // Action or wildcard action like "read:*", see encode_action_name
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<DeviceId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_devices: Vec<DeviceId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub denied_roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<AccessConditions>,
+}
+
+#[derive(Debug)]
+pub enum PolicyErrors {
+    UnableToAccessFile(io::Error),
+    InvalidPolicy(toml::de::Error),
+    UnableToSerializePolicy(toml::ser::Error),
+    DuplicateResource(ResourcePath),
+    DuplicateAction(ResourcePath, String),
+    // A device or role is listed twice in an action or role
+    DuplicateEntry(String),
+    // An action refers to a role the policy does not define
+    UnknownRole(String),
+    // An action name of the policy with the hex prefix that is not the encoding of encode_action_name
+    InvalidActionName(ResourcePath, String),
+    ApplyFailed(AclError),
+}
+
+impl From<io::Error> for PolicyErrors {
+    fn from(error: io::Error) -> PolicyErrors {
+        PolicyErrors::UnableToAccessFile(error)
+    }
+}
+
+impl From<AclError> for PolicyErrors {
+    fn from(error: AclError) -> PolicyErrors {
+        PolicyErrors::ApplyFailed(error)
+    }
+}
+
+// Device or role entry of an action
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyEntry {
+    Device(DeviceId),
+    Role(String),
+    DeniedDevice(DeviceId),
+    DeniedRole(String),
+}
+
+// Change that brings the current lists and roles closer to a policy. A list of changes can be
+// applied in its order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyChange {
+    AddRole(String),
+    RemoveRole(String),
+    AddRoleMember { role: String, device_ID: DeviceId },
+    RemoveRoleMember { role: String, device_ID: DeviceId },
+    AddResource(ResourcePath),
+    RemoveResource(ResourcePath),
+    AddAction { resource_ID: ResourcePath, action: String },
+    RemoveAction { resource_ID: ResourcePath, action: String },
+    AddEntry { resource_ID: ResourcePath, action: String, entry: PolicyEntry },
+    RemoveEntry { resource_ID: ResourcePath, action: String, entry: PolicyEntry },
+    SetConditions { resource_ID: ResourcePath, action: String, conditions: Option<AccessConditions> },
+}
+
+impl AclPolicy {
+    // Parse and validate a policy in TOML format
+    pub fn from_toml(text: &str) -> Result<AclPolicy, PolicyErrors> {
+        let policy: AclPolicy = toml::from_str(text).map_err(PolicyErrors::InvalidPolicy)?;
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    pub fn to_toml(&self) -> Result<String, PolicyErrors> {
+        toml::to_string(self).map_err(PolicyErrors::UnableToSerializePolicy)
+    }
+
+    // Check that every resource, action and entry is listed once and that all roles are defined
+    pub fn validate(&self) -> Result<(), PolicyErrors> {
+        for (roleName, members) in self.roles.iter() {
+            check_unique(members, roleName)?;
+        }
+
+        let mut resources = BTreeSet::new();
+        for resource in self.resources.iter() {
+            if !resources.insert(&resource.path) {
+                return Err(PolicyErrors::DuplicateResource(resource.path.clone()));
+            }
+            let mut actions = BTreeSet::new();
+            for action in resource.actions.iter() {
+                if !actions.insert(&action.name) {
+                    return Err(PolicyErrors::DuplicateAction(resource.path.clone(), action.name.clone()));
+                }
+                if decode_action_name(&action.name).map(|actionName| encode_action_name(&actionName)).as_deref() != Some(action.name.as_str()) {
+                    return Err(PolicyErrors::InvalidActionName(resource.path.clone(), action.name.clone()));
+                }
+                let context = format!("{} {}", resource.path, action.name);
+                check_unique(&action.devices, &context)?;
+                check_unique(&action.roles, &context)?;
+                check_unique(&action.denied_devices, &context)?;
+                check_unique(&action.denied_roles, &context)?;
+                if let Some(roleName) = action.roles.iter().chain(action.denied_roles.iter()).find(|roleName| !self.roles.contains_key(*roleName)) {
+                    return Err(PolicyErrors::UnknownRole(roleName.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Name of an action in a policy. Names that are not valid UTF-8 or start with the hex prefix are written
+// as the prefix followed by the name in hex, so that every name has exactly one form
+pub fn encode_action_name(actionName: &[u8]) -> String {
+    match std::str::from_utf8(actionName) {
+        Ok(name) if !name.starts_with(CONST_HEX_ACTION_PREFIX) => String::from(name),
+        _ => format!("{}{}", CONST_HEX_ACTION_PREFIX, hex::encode(actionName)),
+    }
+}
+
+// Action name of a name in a policy, None if its hex is invalid
+pub fn decode_action_name(name: &str) -> Option<Vec<u8>> {
+    match name.strip_prefix(CONST_HEX_ACTION_PREFIX) {
+        Some(encoded) => hex::decode(encoded).ok(),
+        None => Some(name.as_bytes().to_vec()),
+    }
+}
+
+// Action name of a change. Names of changes are either encoded from the lists or validated with the policy
+fn change_action_name(name: String) -> Vec<u8> {
+    decode_action_name(&name).unwrap_or_else(|| name.into_bytes())
+}
+
+// Fail if a list holds an entry twice
+fn check_unique<T: Ord>(entries: &[T], context: &str) -> Result<(), PolicyErrors> {
+    let unique: BTreeSet<&T> = entries.iter().collect();
+    if unique.len() != entries.len() {
+        return Err(PolicyErrors::DuplicateEntry(String::from(context)));
+    }
+    Ok(())
+}
+
+// Read and validate a policy file
+pub fn read_policy_file(file_path: &str) -> Result<AclPolicy, PolicyErrors> {
+    AclPolicy::from_toml(&fs::read_to_string(file_path)?)
+}
+
+// Describe the lists and roles of a database as a policy
+fn policy_from_database(database: &AclDatabase) -> AclPolicy {
+    let mut resources = Vec::new();
+    for accessData in database.resources.values() {
+        let mut actions = Vec::new();
+        for action in accessData.actions.iter() {
+            actions.push(ActionPolicy {
+                name: encode_action_name(&action.actionName),
+                devices: action.allowedDevices.clone(),
+                roles: action.allowedRoles.clone(),
+                denied_devices: action.deniedDevices.clone(),
+                denied_roles: action.deniedRoles.clone(),
+                conditions: action.conditions.clone(),
+            });
+        }
+        resources.push(ResourcePolicy {
+            path: accessData.resourceID.clone(),
+            actions,
+        });
+    }
+
+    AclPolicy {
+        roles: database.roles.clone(),
+        resources,
+    }
+}
+
+// Export the current lists and roles as a policy
+pub fn export_policy() -> Result<AclPolicy, PolicyErrors> {
+    let database = acl_store::read_database().map_err(AclError::from)?;
+    Ok(policy_from_database(&database))
+}
+
+// Export the current lists and roles into a policy file. The file is replaced at once, so a crash never
+// leaves a partial policy behind. Lists that do not form a valid policy are not exported
+pub fn export_policy_file(file_path: &str) -> Result<(), PolicyErrors> {
+    let policy = export_policy()?;
+    policy.validate()?;
+    let text = policy.to_toml()?;
+    file_management::write_file_atomically(file_path, text.as_bytes())?;
+    Ok(())
+}
+
+// Changes between two lists of entries of an action, ignoring their order
+fn compare_entries<T: Clone + PartialEq>(resource_ID: &ResourcePath, action: &str, current: &[T], target: &[T],
+                                         to_entry: impl Fn(T) -> PolicyEntry, changes: &mut Vec<PolicyChange>) {
+    for removed in current.iter().filter(|entry| !target.contains(entry)) {
+        changes.push(PolicyChange::RemoveEntry { resource_ID: resource_ID.clone(), action: String::from(action), entry: to_entry(removed.clone()) });
+    }
+    for added in target.iter().filter(|entry| !current.contains(entry)) {
+        changes.push(PolicyChange::AddEntry { resource_ID: resource_ID.clone(), action: String::from(action), entry: to_entry(added.clone()) });
+    }
+}
+
+// Changes between two versions of an action
+fn compare_actions(resource_ID: &ResourcePath, current: &ActionPolicy, target: &ActionPolicy, changes: &mut Vec<PolicyChange>) {
+    let action = target.name.as_str();
+    compare_entries(resource_ID, action, &current.devices, &target.devices, PolicyEntry::Device, changes);
+    compare_entries(resource_ID, action, &current.roles, &target.roles, PolicyEntry::Role, changes);
+    compare_entries(resource_ID, action, &current.denied_devices, &target.denied_devices, PolicyEntry::DeniedDevice, changes);
+    compare_entries(resource_ID, action, &current.denied_roles, &target.denied_roles, PolicyEntry::DeniedRole, changes);
+    if current.conditions != target.conditions {
+        changes.push(PolicyChange::SetConditions { resource_ID: resource_ID.clone(), action: String::from(action), conditions: target.conditions.clone() });
+    }
+}
+
+// Changes that turn the current policy into the target policy
+fn compare_policies(current: &AclPolicy, target: &AclPolicy) -> Vec<PolicyChange> {
+    let mut changes = Vec::new();
+
+    for (roleName, members) in target.roles.iter() {
+        let current_members = match current.roles.get(roleName) {
+            Some(current_members) => current_members.as_slice(),
+            None => {
+                changes.push(PolicyChange::AddRole(roleName.clone()));
+                &[]
+            }
+        };
+        for removed in current_members.iter().filter(|memberID| !members.contains(memberID)) {
+            changes.push(PolicyChange::RemoveRoleMember { role: roleName.clone(), device_ID: *removed });
+        }
+        for added in members.iter().filter(|memberID| !current_members.contains(memberID)) {
+            changes.push(PolicyChange::AddRoleMember { role: roleName.clone(), device_ID: *added });
+        }
+    }
+    for resource in current.resources.iter().filter(|resource| !target.resources.iter().any(|wanted| wanted.path == resource.path)) {
+        changes.push(PolicyChange::RemoveResource(resource.path.clone()));
+    }
+    for resource in target.resources.iter() {
+        let current_actions = match current.resources.iter().find(|existing| existing.path == resource.path) {
+            Some(existing) => existing.actions.as_slice(),
+            None => {
+                changes.push(PolicyChange::AddResource(resource.path.clone()));
+                &[]
+            }
+        };
+        for action in current_actions.iter().filter(|action| !resource.actions.iter().any(|wanted| wanted.name == action.name)) {
+            changes.push(PolicyChange::RemoveAction { resource_ID: resource.path.clone(), action: action.name.clone() });
+        }
+        for action in resource.actions.iter() {
+            match current_actions.iter().find(|existing| existing.name == action.name) {
+                Some(existing) => compare_actions(&resource.path, existing, action, &mut changes),
+                None => {
+                    changes.push(PolicyChange::AddAction { resource_ID: resource.path.clone(), action: action.name.clone() });
+                    let empty = ActionPolicy {
+                        name: action.name.clone(),
+                        devices: Vec::new(),
+                        roles: Vec::new(),
+                        denied_devices: Vec::new(),
+                        denied_roles: Vec::new(),
+                        conditions: None,
+                    };
+                    compare_actions(&resource.path, &empty, action, &mut changes);
+                }
+            }
+        }
+    }
+
+    // Roles are removed last, after the entries of the actions that refer to them
+    for roleName in current.roles.keys().filter(|roleName| !target.roles.contains_key(*roleName)) {
+        changes.push(PolicyChange::RemoveRole(roleName.clone()));
+    }
+
+    changes
+}
+
+// Return the changes that applying a policy would make to the current lists and roles
+pub fn diff_policy(policy: &AclPolicy) -> Result<Vec<PolicyChange>, PolicyErrors> {
+    policy.validate()?;
+    Ok(compare_policies(&export_policy()?, policy))
+}
+
+// Apply a single change within a transaction
+fn apply_change(acl: &mut AclTransaction, change: &PolicyChange) -> Result<(), AclError> {
+    match change.clone() {
+        PolicyChange::AddRole(roleName) => acl.add_role(roleName),
+        PolicyChange::RemoveRole(roleName) => acl.remove_role(roleName),
+        PolicyChange::AddRoleMember { role, device_ID } => acl.add_device_to_role(role, device_ID),
+        PolicyChange::RemoveRoleMember { role, device_ID } => acl.remove_device_from_role(role, device_ID),
+        PolicyChange::AddResource(resource_ID) => acl.add_resource(&resource_ID, None),
+        PolicyChange::RemoveResource(resource_ID) => acl.remove_resource(&resource_ID),
+        PolicyChange::AddAction { resource_ID, action } => acl.add_action_to_resource(&resource_ID, change_action_name(action)),
+        PolicyChange::RemoveAction { resource_ID, action } => acl.remove_action_from_resource(&resource_ID, change_action_name(action)),
+        PolicyChange::AddEntry { resource_ID, action, entry } => match entry {
+            PolicyEntry::Device(device_ID) => acl.add_device_to_resource_action(&resource_ID, change_action_name(action), device_ID),
+            PolicyEntry::Role(roleName) => acl.add_role_to_resource_action(&resource_ID, change_action_name(action), roleName),
+            PolicyEntry::DeniedDevice(device_ID) => acl.add_denied_device_to_resource_action(&resource_ID, change_action_name(action), device_ID),
+            PolicyEntry::DeniedRole(roleName) => acl.add_denied_role_to_resource_action(&resource_ID, change_action_name(action), roleName),
+        },
+        PolicyChange::RemoveEntry { resource_ID, action, entry } => match entry {
+            PolicyEntry::Device(device_ID) => acl.remove_device_from_resource_action(&resource_ID, change_action_name(action), device_ID),
+            PolicyEntry::Role(roleName) => acl.remove_role_from_resource_action(&resource_ID, change_action_name(action), roleName),
+            PolicyEntry::DeniedDevice(device_ID) => acl.remove_denied_device_from_resource_action(&resource_ID, change_action_name(action), device_ID),
+            PolicyEntry::DeniedRole(roleName) => acl.remove_denied_role_from_resource_action(&resource_ID, change_action_name(action), roleName),
+        },
+        PolicyChange::SetConditions { resource_ID, action, conditions } => acl.set_action_conditions(&resource_ID, change_action_name(action), conditions),
+    }
+}
+
+// Make the lists and roles equal to a policy in one transaction and return the applied changes.
+// Applying the same policy again changes nothing and does not write the lists
+pub fn apply_policy(policy: &AclPolicy) -> Result<Vec<PolicyChange>, PolicyErrors> {
+    if diff_policy(policy)?.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // The changes are calculated again while holding the lock of the lists
+    let changes = access_control::transaction(|acl| {
+        let changes = compare_policies(&policy_from_database(acl.database()), policy);
+        for change in changes.iter() {
+            apply_change(acl, change)?;
+        }
+        Ok(changes)
+    })?;
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Database with roles, wildcard and exact actions, deny entries and conditions
+    fn example_database() -> AclDatabase {
+        let mut database = AclDatabase::default();
+        let floor = ResourcePath::parse("building/floor2").unwrap();
+        let mut acl = AclTransaction::new(&mut database);
+        acl.add_role(String::from("operators")).unwrap();
+        acl.add_role(String::from("guests")).unwrap();
+        acl.add_device_to_role(String::from("operators"), DeviceId::from(20000)).unwrap();
+        acl.add_device_to_role(String::from("guests"), DeviceId::from(30000)).unwrap();
+        acl.add_resource(&floor, Some(vec![b"read:*".to_vec(), b"open".to_vec(), vec![0xff, 0x00]])).unwrap();
+        acl.add_device_to_resource_action(&floor, b"read:*".to_vec(), DeviceId::from(10000)).unwrap();
+        acl.add_role_to_resource_action(&floor, b"read:*".to_vec(), String::from("operators")).unwrap();
+        acl.add_role_to_resource_action(&floor, b"open".to_vec(), String::from("guests")).unwrap();
+        acl.add_denied_role_to_resource_action(&floor, vec![0xff, 0x00], String::from("guests")).unwrap();
+        acl.add_denied_device_to_resource_action(&floor, b"open".to_vec(), DeviceId::from(10000)).unwrap();
+        acl.set_action_conditions(&floor, b"open".to_vec(), Some(AccessConditions {
+            allowed_hours: Some((22, 6)),
+            max_invocations: Some((3, 60 * 1000)),
+            ..AccessConditions::default()
+        })).unwrap();
+        database
+    }
+
+    #[test]
+    fn exported_policy_imports_without_changes() {
+        let mut database = example_database();
+        AclTransaction::new(&mut database).remove_role(String::from("guests")).unwrap();
+
+        let exported = policy_from_database(&database);
+        let imported = AclPolicy::from_toml(&exported.to_toml().unwrap()).unwrap();
+        assert_eq!(imported, exported);
+        assert!(compare_policies(&policy_from_database(&database), &imported).is_empty());
+    }
+
+    #[test]
+    fn policy_without_a_role_applies() {
+        let mut database = example_database();
+        let mut policy = policy_from_database(&database);
+        policy.roles.remove("guests");
+        for action in policy.resources.iter_mut().flat_map(|resource| resource.actions.iter_mut()) {
+            action.roles.retain(|roleName| roleName != "guests");
+            action.denied_roles.retain(|roleName| roleName != "guests");
+        }
+        policy.validate().unwrap();
+
+        let changes = compare_policies(&policy_from_database(&database), &policy);
+        let mut acl = AclTransaction::new(&mut database);
+        for change in changes.iter() {
+            apply_change(&mut acl, change).unwrap();
+        }
+        assert_eq!(policy_from_database(&database), policy);
+    }
+
+    #[test]
+    fn applied_policy_round_trips_through_files() {
+        test_support::in_empty_work_dir(|| {
+            let policy = policy_from_database(&example_database());
+            assert!(!apply_policy(&policy).unwrap().is_empty());
+
+            // The second application neither changes nor writes the lists
+            let revision = acl_store::get_revision().unwrap();
+            assert!(apply_policy(&policy).unwrap().is_empty());
+            assert_eq!(acl_store::get_revision().unwrap(), revision);
+
+            export_policy_file("policy.toml").unwrap();
+            assert_eq!(read_policy_file("policy.toml").unwrap(), policy);
+        });
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Write};
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::access_control::AccessControl;
+use crate::audit_log::{self, AuditEvent};
+use crate::file_management;
+use crate::{DeviceId, ResourcePath};
+
+// Version of the database format
+const CONST_ACL_STORE_VERSION: u32 = 1;
+
+// Directory of the access control data
+const CONST_ACL_DIR: &str = ".nizk-auth/access_control";
+
+#[derive(Debug)]
+pub enum AclStoreErrors {
+    UnableToAccessStore(io::Error),
+    InvalidStore(serde_json::Error),
+    UnsupportedVersion(u32),
+}
+
+impl From<io::Error> for AclStoreErrors {
+    fn from(error: io::Error) -> AclStoreErrors {
+        AclStoreErrors::UnableToAccessStore(error)
+    }
+}
+
+// All access control lists and roles of a device, kept in a single file
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct AclDatabase {
+    pub(crate) version: u32,
+    // Incremented by every committed transaction
+    pub(crate) revision: u64,
+    // Access control lists by resource path
+    pub(crate) resources: BTreeMap<String, AccessControl>,
+    // Member devices by role name
+    pub(crate) roles: BTreeMap<String, Vec<DeviceId>>,
+}
+
+impl Default for AclDatabase {
+    fn default() -> AclDatabase {
+        AclDatabase {
+            version: CONST_ACL_STORE_VERSION,
+            revision: 0,
+            resources: BTreeMap::new(),
+            roles: BTreeMap::new(),
+        }
+    }
+}
+
+// Timestamps of the allowed invocations of rate limited actions of a resource, by device and action
+pub(crate) type InvocationsData = BTreeMap<String, Vec<i64>>;
+
+// Roles file written before the database existed
+#[derive(Debug, Default, Deserialize)]
+struct LegacyRolesControl {
+    roles: BTreeMap<String, Vec<DeviceId>>,
+}
+
+// File path of the database
+fn get_database_file_path() -> String {
+    format!("{}/acl.json", CONST_ACL_DIR)
+}
+
+// File path of the lock serializing the writers. The database itself is replaced on every write,
+// so it can not hold the lock
+fn get_lock_file_path() -> String {
+    format!("{}/acl.lock", CONST_ACL_DIR)
+}
+
+// File path of the lock serializing the writers of the invocations. It is not the database lock,
+// since invocations are counted while an access is decided
+fn get_invocations_lock_file_path() -> String {
+    format!("{}/invocations.lock", CONST_ACL_DIR)
+}
+
+// File path of the invocations of rate limited actions of a resource. They change with every allowed
+// invocation, so they are kept beside the database and do not change its revision
+fn get_invocations_file_path(resourceID: &ResourcePath) -> String {
+    format!("{}/invocations_{}.json", CONST_ACL_DIR, resourceID.to_file_name())
+}
+
+// Check if a file of the access control directory was written before the database existed
+fn is_legacy_file(file_name: &str) -> bool {
+    file_name == "roles.json" || (file_name.starts_with("resource_") && file_name.ends_with(".json"))
+}
+
+// List the files written before the database existed
+fn list_legacy_files() -> Result<Vec<String>, AclStoreErrors> {
+    let dir = Path::new(CONST_ACL_DIR);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut legacy_files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if let Some(file_name) = entry.file_name().to_str() {
+            if is_legacy_file(file_name) {
+                legacy_files.push(format!("{}/{}", CONST_ACL_DIR, file_name));
+            }
+        }
+    }
+    legacy_files.sort();
+    Ok(legacy_files)
+}
+
+// Read the database file. Returns None if it does not exist yet
+fn read_database_file() -> Result<Option<AclDatabase>, AclStoreErrors> {
+    let file_path = get_database_file_path();
+    let path = Path::new(&file_path);
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let reader = BufReader::new(File::open(path)?);
+    let database: AclDatabase = serde_json::from_reader(reader).map_err(AclStoreErrors::InvalidStore)?;
+    if database.version != CONST_ACL_STORE_VERSION {
+        return Err(AclStoreErrors::UnsupportedVersion(database.version));
+    }
+    Ok(Some(database))
+}
+
+// Write the database into a temporary file and rename it over the database file. Readers and a
+// crash during the write only ever see the complete old or the complete new database
+fn write_database_file(database: &AclDatabase) -> Result<(), AclStoreErrors> {
+    write_json_file(get_database_file_path(), database)
+}
+
+// Write json data into a temporary file and rename it over the file
+fn write_json_file<T: Serialize>(file_path: String, data: &T) -> Result<(), AclStoreErrors> {
+    let tmp_file_path = format!("{}.tmp", file_path);
+    let json_string = serde_json::to_string(data).map_err(AclStoreErrors::InvalidStore)?;
+
+    let mut file = File::create(&tmp_file_path)?;
+    file.write_all(json_string.as_bytes())?;
+    file.sync_all()?;
+    file_management::shrink_file_permissions(tmp_file_path.clone());
+    fs::rename(&tmp_file_path, &file_path)?;
+
+    // Persist the rename itself
+    File::open(CONST_ACL_DIR)?.sync_all()?;
+    Ok(())
+}
+
+// Build the database from the json files with one resource each and the roles file
+fn read_legacy_files(legacy_files: &[String]) -> Result<AclDatabase, AclStoreErrors> {
+    let mut database = AclDatabase::default();
+    for file_path in legacy_files {
+        let reader = BufReader::new(File::open(file_path)?);
+        if file_path.ends_with("/roles.json") {
+            let rolesData: LegacyRolesControl = serde_json::from_reader(reader).map_err(AclStoreErrors::InvalidStore)?;
+            database.roles = rolesData.roles;
+        } else {
+            let accessData: AccessControl = serde_json::from_reader(reader).map_err(AclStoreErrors::InvalidStore)?;
+            database.resources.insert(accessData.resourceID.to_string(), accessData);
+        }
+    }
+    Ok(database)
+}
+
+// Serialized access control lists by resource path and serialized roles, to find the changes of a transaction
+fn snapshot_database(database: &AclDatabase) -> (BTreeMap<String, String>, String) {
+    let resources = database.resources.iter()
+        .map(|(resourceID, accessData)| (resourceID.clone(), serde_json::to_string(accessData).unwrap()))
+        .collect();
+    (resources, serde_json::to_string(&database.roles).unwrap())
+}
+
+// Return the added, changed and removed resources between two snapshots, and if the roles changed
+fn compare_snapshots(before: &(BTreeMap<String, String>, String), after: &(BTreeMap<String, String>, String)) -> (Vec<String>, bool) {
+    let mut resources: Vec<String> = before.0.keys().chain(after.0.keys())
+        .filter(|resourceID| before.0.get(*resourceID) != after.0.get(*resourceID))
+        .cloned()
+        .collect();
+    resources.sort();
+    resources.dedup();
+    (resources, before.1 != after.1)
+}
+
+// Read the current state of the database. Readers never wait for writers and never write, so files of
+// an older version are read as they are and only migrated by the next transaction
+pub(crate) fn read_database() -> Result<AclDatabase, AclStoreErrors> {
+    if let Some(database) = read_database_file()? {
+        return Ok(database);
+    }
+
+    // A writer may migrate the files meanwhile. It writes the database before it deletes them,
+    // so the database is read then, and never a part of the files
+    let legacy_database = read_legacy_files(&list_legacy_files()?);
+    match read_database_file()? {
+        Some(database) => Ok(database),
+        None => legacy_database,
+    }
+}
+
+// Run a transaction on the database while holding the writer lock. The changes are only written if
+// the transaction returns Ok, otherwise the database stays as it was
+pub(crate) fn update_database<T, E>(transaction: impl FnOnce(&mut AclDatabase) -> Result<T, E>) -> Result<Result<T, E>, AclStoreErrors> {
+    let lock_file_path = get_lock_file_path();
+    file_management::create_parent_dirs(lock_file_path.clone());
+    let lock_file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_file_path)?;
+    // Released when the lock file is closed
+    lock_file.lock()?;
+
+    // Migrate the files of an older version, which are deleted once the database is written
+    let mut legacy_files = Vec::new();
+    let mut database = match read_database_file()? {
+        Some(database) => database,
+        None => {
+            legacy_files = list_legacy_files()?;
+            read_legacy_files(&legacy_files)?
+        }
+    };
+
+    let snapshot = snapshot_database(&database);
+    let result = transaction(&mut database);
+    if result.is_ok() || !legacy_files.is_empty() {
+        if result.is_err() {
+            // Keep the migrated data without the changes of the failed transaction
+            database = read_legacy_files(&legacy_files)?;
+        }
+        database.revision += 1;
+        write_database_file(&database)?;
+        for file_path in legacy_files {
+            fs::remove_file(file_path)?;
+        }
+
+        let (resources, roles_changed) = compare_snapshots(&snapshot, &snapshot_database(&database));
+        if !resources.is_empty() || roles_changed {
+            audit_log::record_event(AuditEvent::AclChange { revision: database.revision, resources, roles_changed });
+        }
+    }
+
+    Ok(result)
+}
+
+// Lock the invocations of a resource and run an update on them. They are only written if the update
+// returns Ok. A broken invocations file is an error, so that it never resets a rate limit
+pub(crate) fn update_invocations<T, E>(resourceID: &ResourcePath, update: impl FnOnce(&mut InvocationsData) -> Result<T, E>) -> Result<Result<T, E>, AclStoreErrors> {
+    let lock_file_path = get_invocations_lock_file_path();
+    file_management::create_parent_dirs(lock_file_path.clone());
+    let lock_file = OpenOptions::new().create(true).truncate(false).write(true).open(&lock_file_path)?;
+    // Released when the lock file is closed
+    lock_file.lock()?;
+
+    let file_path = get_invocations_file_path(resourceID);
+    let mut invocationsData: InvocationsData = match File::open(&file_path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file)).map_err(AclStoreErrors::InvalidStore)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => InvocationsData::new(),
+        Err(e) => return Err(AclStoreErrors::UnableToAccessStore(e)),
+    };
+
+    let result = update(&mut invocationsData);
+    if result.is_ok() {
+        write_json_file(file_path, &invocationsData)?;
+    }
+    Ok(result)
+}
+
+// Return the revision of the database, which changes with every committed transaction
+pub fn get_revision() -> Result<u64, AclStoreErrors> {
+    Ok(read_database()?.revision)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control;
+    use crate::test_support;
+
+    // Files of the version with numeric resource IDs and without a database
+    fn write_legacy_files() {
+        fs::create_dir_all(CONST_ACL_DIR).unwrap();
+        fs::write(format!("{}/resource_7.json", CONST_ACL_DIR),
+                  r#"{"resourceID":7,"actions":[{"actionName":[111,112,101,110],"allowedDevices":[45001]}]}"#).unwrap();
+        fs::write(format!("{}/resource_8.json", CONST_ACL_DIR),
+                  r#"{"resourceID":8,"actions":[{"actionName":[111,112,101,110],"allowedDevices":[],"allowedRoles":["staff"]}]}"#).unwrap();
+        fs::write(format!("{}/roles.json", CONST_ACL_DIR), r#"{"roles":{"staff":[45002]}}"#).unwrap();
+    }
+
+    fn check_open(resourceID: u32, deviceID: u32) -> bool {
+        access_control::check_access(&ResourcePath::from(resourceID), Vec::from("open"), DeviceId::from(deviceID))
+    }
+
+    #[test]
+    fn legacy_files_are_read_and_migrated_by_first_write() {
+        test_support::in_empty_work_dir(|| {
+            write_legacy_files();
+
+            // Reading does not migrate the files
+            assert!(check_open(7, 45001) && check_open(8, 45002));
+            assert!(!check_open(7, 45002) && !check_open(8, 45001));
+            assert!(!Path::new(&get_database_file_path()).exists());
+            assert_eq!(list_legacy_files().unwrap().len(), 3);
+
+            access_control::add_resource(&ResourcePath::from(9), None).unwrap();
+            assert!(Path::new(&get_database_file_path()).exists());
+            assert!(list_legacy_files().unwrap().is_empty());
+            assert!(check_open(7, 45001) && check_open(8, 45002));
+            assert!(!check_open(7, 45002) && !check_open(8, 45001));
+            assert_eq!(access_control::get_role_members(String::from("staff")).unwrap(), vec![DeviceId::from(45002)]);
+        });
+    }
+
+    #[test]
+    fn failed_first_transaction_still_migrates() {
+        test_support::in_empty_work_dir(|| {
+            write_legacy_files();
+
+            assert!(access_control::add_resource(&ResourcePath::from(7), None).is_err());
+            assert!(list_legacy_files().unwrap().is_empty());
+            assert!(check_open(7, 45001) && check_open(8, 45002));
+        });
+    }
+}
@@ -0,0 +1,575 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::acl_management::ManagementCommand;
+use crate::{enrollment, file_management, get_key_instance, schnorr_identification};
+use crate::{DeviceId, ResourcePath};
+
+// Number of entries after which the configured signer signs a checkpoint of the log
+pub const CONST_AUDIT_CHECKPOINT_INTERVAL: u64 = 32;
+
+// Previous hash of the first entry
+const CONST_GENESIS_HASH: [u8; 32] = [0u8; 32];
+
+// Size of the blocks read from the end of the log to find its last entry
+const CONST_TAIL_BLOCK_SIZE: u64 = 1024;
+
+// Number of events of this process that could not be written to the log, recorded with the next written entry
+static LOST_EVENTS: AtomicU64 = AtomicU64::new(0);
+
+// Log file of this process with its last entry, kept between appends
+static LOG_TAIL: Mutex<Option<LogTail>> = Mutex::new(None);
+
+// Open log file and its last entry. The entry is only used while the file is unchanged since
+// this process wrote it, otherwise the end of the file is read again
+struct LogTail {
+    file: File,
+    inode: u64,
+    length: u64,
+    modified: Option<SystemTime>,
+    last: Option<AuditEntry>,
+}
+
+impl LogTail {
+    // Open or create the log file
+    fn open(file_path: &str) -> Result<LogTail, AuditLogErrors> {
+        file_management::create_parent_dirs(String::from(file_path));
+        let file = OpenOptions::new().create(true).read(true).append(true).open(file_path)?;
+        file_management::shrink_file_permissions(String::from(file_path));
+        let inode = file.metadata()?.ino();
+        Ok(LogTail {
+            file,
+            inode,
+            length: 0,
+            modified: None,
+            last: None,
+        })
+    }
+
+    // Check if the file still has the size and time of the last write of this process
+    fn is_unchanged(&self, metadata: &fs::Metadata) -> bool {
+        self.modified.is_some() && metadata.len() == self.length && metadata.modified().ok() == self.modified
+    }
+}
+
+#[derive(Debug)]
+pub enum AuditLogErrors {
+    UnableToAccessLog(io::Error),
+    PrivateKeyNotFound,
+    EmptyLog,
+    // Line of the log that is not a valid entry
+    InvalidEntry(u64),
+    InvalidLastEntry,
+    // Sequence number of an entry that does not follow the previous entry
+    BrokenChain(u64),
+    // Sequence number of an entry whose hash does not match its content
+    ModifiedEntry(u64),
+    // Sequence number of a checkpoint with a wrong signature or hash
+    InvalidCheckpoint(u64),
+    // The log ends before the entry of a trusted checkpoint
+    Truncated { expected: u64, found: u64 },
+}
+
+impl From<io::Error> for AuditLogErrors {
+    fn from(error: io::Error) -> AuditLogErrors {
+        AuditLogErrors::UnableToAccessLog(error)
+    }
+}
+
+// Signature of the signer over the hash of an entry, which covers the entry and all entries before it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditCheckpoint {
+    pub signer_ID: DeviceId,
+    pub sequence: u64,
+    pub hash: [u8; 32],
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl AuditCheckpoint {
+    fn signed_data(signer_ID: DeviceId, sequence: u64, hash: &[u8; 32]) -> Vec<u8> {
+        let mut data = Vec::from(&b"AuditCheckpoint"[..]);
+        data.extend_from_slice(&signer_ID.to_bytes());
+        data.extend_from_slice(&sequence.to_be_bytes());
+        data.extend_from_slice(hash);
+        data
+    }
+
+    // Check the signature of the checkpoint with the public key of its signer
+    pub fn verify(&self, public_key: [u8; 32]) -> bool {
+        let signed_data = AuditCheckpoint::signed_data(self.signer_ID, self.sequence, &self.hash);
+        schnorr_identification::verify_signature(public_key, &signed_data, self.signature)
+    }
+}
+
+// Event recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditEvent {
+    // Verification of a NIZK proof of a peer
+    NizkProof { peer_ID: DeviceId, accepted: bool },
+    // Verification of the proof of a peer during interactive mutual authentication
+    MutualAuth { peer_ID: DeviceId, accepted: bool },
+    // Access decision of check_access, with the permission or the reason of the denial
+    AccessDecision { peer_ID: DeviceId, resource_ID: ResourcePath, action: Vec<u8>, allowed: bool, reason: String },
+    // Committed change of the access control lists, with the changed resources
+    AclChange { revision: u64, resources: Vec<String>, roles_changed: bool },
+    // Authenticated management command changing the access control lists, with the reason it was not applied
+    ManagementCommand { admin_ID: DeviceId, command: ManagementCommand, error: Option<String> },
+    Checkpoint(AuditCheckpoint),
+    // Bytes of an incomplete last line that were removed from the log, and events that could not be written
+    Gap { discarded_bytes: u64, lost_events: u64 },
+}
+
+// Entry of the audit log, chained to the previous entry by its hash
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub timestamp: i64,
+    pub event: AuditEvent,
+    pub previous_hash: [u8; 32],
+    pub hash: [u8; 32],
+}
+
+impl AuditEntry {
+    // Hash over the previous hash and the content of the entry
+    fn calculate_hash(sequence: u64, timestamp: i64, event: &AuditEvent, previous_hash: &[u8; 32]) -> [u8; 32] {
+        let mut content = Vec::from(sequence.to_be_bytes());
+        content.extend_from_slice(&timestamp.to_be_bytes());
+        let event_json = serde_json::to_vec(event).unwrap();
+        schnorr_identification::sha3_256(b"AuditEntry", Some(previous_hash), Some(&content), Some(&event_json))
+    }
+
+    fn new(sequence: u64, event: AuditEvent, previous_hash: [u8; 32]) -> AuditEntry {
+        let timestamp = Utc::now().timestamp_millis();
+        let hash = AuditEntry::calculate_hash(sequence, timestamp, &event, &previous_hash);
+        AuditEntry {
+            sequence,
+            timestamp,
+            event,
+            previous_hash,
+            hash,
+        }
+    }
+}
+
+// Result of a successful verification of the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditLogReport {
+    pub entries: u64,
+    pub last_checkpoint: Option<AuditCheckpoint>,
+    // Entries after the last checkpoint entry, which a truncation could remove unnoticed
+    pub unsigned_entries: u64,
+    // Gap entries, each one marking lost events or a removed incomplete line
+    pub gaps: u64,
+}
+
+// File path of the log, with one json entry per line
+fn get_audit_log_file_path() -> String {
+    String::from(".nizk-auth/audit/audit_log.jsonl")
+}
+
+// File path of the ID of the device signing the checkpoints
+fn get_signer_file_path() -> String {
+    String::from(".nizk-auth/audit/signer.json")
+}
+
+// Set the device whose private key signs a checkpoint every CONST_AUDIT_CHECKPOINT_INTERVAL entries.
+// Without it, the device of the own certificate signs them
+pub fn set_audit_signer(my_ID: DeviceId) -> Result<(), AuditLogErrors> {
+    let file_path = get_signer_file_path();
+    file_management::write_file_atomically(&file_path, serde_json::to_string(&my_ID).unwrap().as_bytes())?;
+    Ok(())
+}
+
+// Return the device signing the checkpoints: the one that was set, otherwise the device of the own certificate
+fn get_audit_signer() -> Option<DeviceId> {
+    let file_path = get_signer_file_path();
+    File::open(Path::new(&file_path)).ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .or_else(|| enrollment::get_own_certificate().map(|certificate| certificate.device_ID))
+}
+
+// Read the last complete entry of the log without reading the whole file. Also returns the number of bytes
+// after the last line break, which a write interrupted by a crash left behind
+fn read_last_entry(file: &mut File) -> Result<(Option<AuditEntry>, u64), AuditLogErrors> {
+    let length = file.seek(SeekFrom::End(0))?;
+    let mut tail = Vec::new();
+    let mut start = length;
+    // Search backwards for the line break before the last complete line
+    while start > 0 {
+        let block_start = start.saturating_sub(CONST_TAIL_BLOCK_SIZE);
+        let mut block = vec![0u8; (start - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(&mut block)?;
+        block.extend_from_slice(&tail);
+        tail = block;
+        start = block_start;
+        if let Some(end) = tail.iter().rposition(|byte| *byte == b'\n') {
+            if tail[..end].contains(&b'\n') {
+                break;
+            }
+        }
+    }
+
+    let complete = tail.iter().rposition(|byte| *byte == b'\n').map_or(0, |end| end + 1);
+    let torn_bytes = (tail.len() - complete) as u64;
+    let line = match tail[..complete].split(|byte| *byte == b'\n').rfind(|line| !line.is_empty()) {
+        Some(line) => line,
+        None => {
+            return Ok((None, torn_bytes));
+        }
+    };
+    match serde_json::from_slice(line) {
+        Ok(entry) => Ok((Some(entry), torn_bytes)),
+        Err(_) => Err(AuditLogErrors::InvalidLastEntry),
+    }
+}
+
+// Sign a checkpoint over an entry with the private key of a device
+fn sign_entry(my_ID: DeviceId, entry: &AuditEntry) -> Result<AuditCheckpoint, AuditLogErrors> {
+    let private_key = match get_key_instance(&format!("PrivateKey:{}", my_ID), 32, None) {
+        Ok(key) => <[u8; 32]>::try_from(key.get_key().as_slice()).map_err(|_| AuditLogErrors::PrivateKeyNotFound)?,
+        Err(_) => {
+            return Err(AuditLogErrors::PrivateKeyNotFound);
+        }
+    };
+    let signed_data = AuditCheckpoint::signed_data(my_ID, entry.sequence, &entry.hash);
+
+    Ok(AuditCheckpoint {
+        signer_ID: my_ID,
+        sequence: entry.sequence,
+        hash: entry.hash,
+        signature: schnorr_identification::sign(private_key, &signed_data),
+    })
+}
+
+// Entry following the last entry of the log
+fn next_entry(last: Option<&AuditEntry>, event: AuditEvent) -> AuditEntry {
+    match last {
+        Some(last) => AuditEntry::new(last.sequence + 1, event, last.hash),
+        None => AuditEntry::new(0, event, CONST_GENESIS_HASH),
+    }
+}
+
+// Append an event to the log while holding its lock. The signer signs a checkpoint over the last entry
+// if one is due or forced, get_signer_ID is only called then. Returns the appended checkpoint.
+// An incomplete last line is removed, and a gap entry records it together with the events that were lost
+fn append_to_log(event: Option<AuditEvent>, get_signer_ID: impl FnOnce() -> Option<DeviceId>, force_checkpoint: bool) -> Result<Option<AuditCheckpoint>, AuditLogErrors> {
+    let file_path = get_audit_log_file_path();
+    let mut log_tail = LOG_TAIL.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    // The open file is reused as long as it is still the file at the path. It is dropped on any error,
+    // which also releases its lock
+    let path_inode = fs::metadata(&file_path).ok().map(|metadata| metadata.ino());
+    let mut tail = match log_tail.take() {
+        Some(tail) if Some(tail.inode) == path_inode => tail,
+        _ => LogTail::open(&file_path)?,
+    };
+    tail.file.lock()?;
+
+    let metadata = tail.file.metadata()?;
+    let (mut last, torn_bytes) = if tail.is_unchanged(&metadata) {
+        (tail.last.take(), 0)
+    } else {
+        read_last_entry(&mut tail.file)?
+    };
+    if torn_bytes > 0 {
+        tail.file.set_len(metadata.len() - torn_bytes)?;
+        println!("Removed an incomplete entry of {} bytes from the audit log\n", torn_bytes);
+    }
+
+    let lost_events = LOST_EVENTS.load(Ordering::SeqCst);
+    let mut events = Vec::new();
+    if torn_bytes > 0 || lost_events > 0 {
+        events.push(AuditEvent::Gap { discarded_bytes: torn_bytes, lost_events });
+    }
+    events.extend(event);
+
+    let mut entries = Vec::new();
+    for event in events {
+        let entry = next_entry(last.as_ref(), event);
+        entries.push(entry.clone());
+        last = Some(entry);
+    }
+
+    let mut checkpoint = None;
+    let signer_ID = match &last {
+        Some(entry) if force_checkpoint || (entry.sequence + 1) % CONST_AUDIT_CHECKPOINT_INTERVAL == 0 => get_signer_ID(),
+        _ => None,
+    };
+    if let (Some(signer_ID), Some(entry)) = (signer_ID, last.clone()) {
+        // A due checkpoint that can not be signed does not keep the events out of the log
+        match sign_entry(signer_ID, &entry) {
+            Ok(signed) => {
+                let checkpoint_entry = next_entry(Some(&entry), AuditEvent::Checkpoint(signed.clone()));
+                entries.push(checkpoint_entry.clone());
+                last = Some(checkpoint_entry);
+                checkpoint = Some(signed);
+            },
+            Err(error) if !force_checkpoint => println!("Failed to sign audit checkpoint: {:?}\n", error),
+            Err(error) => {
+                return Err(error);
+            }
+        }
+    }
+
+    let mut lines = String::new();
+    for entry in entries.iter() {
+        lines.push_str(&serde_json::to_string(entry).unwrap());
+        lines.push('\n');
+    }
+    tail.file.write_all(lines.as_bytes())?;
+    LOST_EVENTS.fetch_sub(lost_events, Ordering::SeqCst);
+
+    // Keep the file and its last entry for the next append
+    let metadata = tail.file.metadata()?;
+    tail.length = metadata.len();
+    tail.modified = metadata.modified().ok();
+    tail.last = last;
+    tail.file.unlock()?;
+    *log_tail = Some(tail);
+    Ok(checkpoint)
+}
+
+// Record an event in the audit log. A failure to write the log does not change the recorded decision,
+// the event is counted in the gap entry of the next write instead
+pub(crate) fn record_event(event: AuditEvent) {
+    if let Err(error) = append_to_log(Some(event), get_audit_signer, false) {
+        LOST_EVENTS.fetch_add(1, Ordering::SeqCst);
+        println!("Failed to write audit log: {:?}\n", error);
+    }
+}
+
+// Sign a checkpoint over the current end of the log, for example before handing the log to an auditor.
+// Kept outside of the device, the checkpoint reveals a later truncation of the log
+pub fn append_checkpoint(my_ID: DeviceId) -> Result<AuditCheckpoint, AuditLogErrors> {
+    append_to_log(None, || Some(my_ID), true)?.ok_or(AuditLogErrors::EmptyLog)
+}
+
+// Verify the hash chain of the log and the signatures of its checkpoints with the public key of the signer.
+// A trusted checkpoint kept outside of the log has to match an entry, otherwise the log was truncated
+// or rewritten. Without it, only the entries up to the last checkpoint in the log are protected
+pub fn verify_audit_log(signer_public_key: [u8; 32], trusted_checkpoint: Option<&AuditCheckpoint>) -> Result<AuditLogReport, AuditLogErrors> {
+    if let Some(trusted) = trusted_checkpoint {
+        if !trusted.verify(signer_public_key) {
+            return Err(AuditLogErrors::InvalidCheckpoint(trusted.sequence));
+        }
+    }
+
+    let file_path = get_audit_log_file_path();
+    let mut entries = 0u64;
+    let mut previous_hash = CONST_GENESIS_HASH;
+    let mut last_checkpoint = None;
+    let mut last_signed = None;
+    let mut trusted_found = false;
+    let mut gaps = 0u64;
+    if Path::new(&file_path).exists() {
+        let reader = BufReader::new(File::open(&file_path)?);
+        for (line_number, line) in reader.lines().enumerate() {
+            let entry: AuditEntry = serde_json::from_str(&line?).map_err(|_| AuditLogErrors::InvalidEntry(line_number as u64))?;
+            if entry.sequence != entries || entry.previous_hash != previous_hash {
+                return Err(AuditLogErrors::BrokenChain(entry.sequence));
+            }
+            if AuditEntry::calculate_hash(entry.sequence, entry.timestamp, &entry.event, &entry.previous_hash) != entry.hash {
+                return Err(AuditLogErrors::ModifiedEntry(entry.sequence));
+            }
+
+            // A checkpoint signs the entry before it
+            if let AuditEvent::Checkpoint(checkpoint) = &entry.event {
+                if checkpoint.sequence + 1 != entry.sequence || checkpoint.hash != previous_hash || !checkpoint.verify(signer_public_key) {
+                    return Err(AuditLogErrors::InvalidCheckpoint(entry.sequence));
+                }
+                last_checkpoint = Some(checkpoint.clone());
+                last_signed = Some(checkpoint.sequence);
+            }
+            if let AuditEvent::Gap { .. } = &entry.event {
+                gaps += 1;
+            }
+            if let Some(trusted) = trusted_checkpoint {
+                if trusted.sequence == entry.sequence {
+                    if trusted.hash != entry.hash {
+                        return Err(AuditLogErrors::ModifiedEntry(entry.sequence));
+                    }
+                    trusted_found = true;
+                }
+            }
+
+            previous_hash = entry.hash;
+            entries += 1;
+        }
+    }
+
+    if let Some(trusted) = trusted_checkpoint {
+        if !trusted_found {
+            return Err(AuditLogErrors::Truncated { expected: trusted.sequence + 1, found: entries });
+        }
+    }
+
+    Ok(AuditLogReport {
+        entries,
+        last_checkpoint,
+        unsigned_entries: last_signed.map_or(entries, |sequence| entries - sequence - 2),
+        gaps,
+    })
+}
+
+// Read all entries of the log, oldest entry first. Fails on the first line that is not a valid entry,
+// use verify_audit_log to check the chain of the entries
+pub fn read_audit_log() -> Result<Vec<AuditEntry>, AuditLogErrors> {
+    let file_path = get_audit_log_file_path();
+    let file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Vec::new());
+        },
+        Err(e) => {
+            return Err(AuditLogErrors::UnableToAccessLog(e));
+        }
+    };
+
+    let mut entries = Vec::new();
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let entry = serde_json::from_str(&line?).map_err(|_| AuditLogErrors::InvalidEntry(line_number as u64))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    fn record_proofs(count: u32) {
+        for number in 0..count {
+            record_event(AuditEvent::NizkProof { peer_ID: DeviceId::from(number), accepted: true });
+        }
+    }
+
+    #[test]
+    fn appended_entries_verify() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49001);
+            let public_key = test_support::store_key_pair(signer_ID);
+            set_audit_signer(signer_ID).unwrap();
+            record_proofs(40);
+
+            let checkpoint = append_checkpoint(signer_ID).unwrap();
+            let report = verify_audit_log(public_key, Some(&checkpoint)).unwrap();
+            assert_eq!(report.entries, 42);
+            assert_eq!(report.unsigned_entries, 0);
+            assert_eq!(read_audit_log().unwrap().len(), 42);
+        });
+    }
+
+    #[test]
+    fn invalid_line_is_reported() {
+        test_support::in_empty_work_dir(|| {
+            record_proofs(3);
+            let log = fs::read_to_string(get_audit_log_file_path()).unwrap();
+            let lines: Vec<&str> = log.lines().collect();
+            fs::write(get_audit_log_file_path(), format!("{}\n{{\"sequence\":\n{}\n", lines[0], lines[2])).unwrap();
+
+            assert!(matches!(read_audit_log(), Err(AuditLogErrors::InvalidEntry(1))));
+        });
+    }
+
+    #[test]
+    fn append_follows_a_changed_log() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49002);
+            let public_key = test_support::store_key_pair(signer_ID);
+            record_proofs(3);
+
+            // Log is cut back to its first entry by another process, the next entry follows that one
+            let log = fs::read_to_string(get_audit_log_file_path()).unwrap();
+            fs::write(get_audit_log_file_path(), format!("{}\n", log.lines().next().unwrap())).unwrap();
+            record_proofs(1);
+
+            let entries = read_audit_log().unwrap();
+            assert_eq!(entries.iter().map(|entry| entry.sequence).collect::<Vec<u64>>(), vec![0, 1]);
+            append_checkpoint(signer_ID).unwrap();
+            assert_eq!(verify_audit_log(public_key, None).unwrap().entries, 3);
+        });
+    }
+
+    // Lines of the log, rewritten after a change
+    fn rewrite_log(change: impl FnOnce(&mut Vec<String>)) {
+        let log = fs::read_to_string(get_audit_log_file_path()).unwrap();
+        let mut lines: Vec<String> = log.lines().map(String::from).collect();
+        change(&mut lines);
+        fs::write(get_audit_log_file_path(), lines.iter().map(|line| format!("{}\n", line)).collect::<String>()).unwrap();
+    }
+
+    #[test]
+    fn edited_event_is_detected() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49003);
+            let public_key = test_support::store_key_pair(signer_ID);
+            record_proofs(3);
+
+            // The event is changed, the stored hashes are kept
+            rewrite_log(|lines| {
+                let mut entry: AuditEntry = serde_json::from_str(&lines[1]).unwrap();
+                entry.event = AuditEvent::NizkProof { peer_ID: DeviceId::from(1), accepted: false };
+                lines[1] = serde_json::to_string(&entry).unwrap();
+            });
+
+            assert!(matches!(verify_audit_log(public_key, None), Err(AuditLogErrors::ModifiedEntry(1))));
+        });
+    }
+
+    #[test]
+    fn deleted_line_is_detected() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49004);
+            let public_key = test_support::store_key_pair(signer_ID);
+            record_proofs(4);
+
+            rewrite_log(|lines| {
+                lines.remove(1);
+            });
+
+            assert!(matches!(verify_audit_log(public_key, None), Err(AuditLogErrors::BrokenChain(2))));
+        });
+    }
+
+    #[test]
+    fn truncation_is_detected_with_trusted_checkpoint() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49005);
+            let public_key = test_support::store_key_pair(signer_ID);
+            record_proofs(4);
+            let checkpoint = append_checkpoint(signer_ID).unwrap();
+
+            // Without the checkpoint entry and the entry it signs, the remaining chain is still valid
+            rewrite_log(|lines| {
+                lines.truncate(2);
+            });
+
+            assert_eq!(verify_audit_log(public_key, None).unwrap().entries, 2);
+            assert!(matches!(verify_audit_log(public_key, Some(&checkpoint)), Err(AuditLogErrors::Truncated { expected: 4, found: 2 })));
+        });
+    }
+
+    #[test]
+    fn checkpoint_of_other_key_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let signer_ID = DeviceId::from(49006);
+            let other_ID = DeviceId::from(49007);
+            let public_key = test_support::store_key_pair(signer_ID);
+            test_support::store_key_pair(other_ID);
+            record_proofs(2);
+
+            // A trusted checkpoint signed with the wrong key, and one in the log
+            let checkpoint = append_checkpoint(other_ID).unwrap();
+            assert!(matches!(verify_audit_log(public_key, Some(&checkpoint)), Err(AuditLogErrors::InvalidCheckpoint(1))));
+            assert!(matches!(verify_audit_log(public_key, None), Err(AuditLogErrors::InvalidCheckpoint(2))));
+        });
+    }
+}
@@ -0,0 +1,168 @@
+use serde::{Deserialize, Serialize};
+use crate::access_control::{self, AccessDenial, AccessReason};
+use crate::clock::SystemClock;
+use crate::{DeviceId, ResourcePath};
+
+// Request of a device to perform an action on a resource. The resource, action and payload are
+// part of the NIZK proof, so none of them can be changed without invalidating the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthorizationRequest {
+    pub sender_ID: DeviceId,
+    pub receiver_ID: DeviceId,
+    pub resource_ID: ResourcePath,
+    pub action: Vec<u8>,
+    pub payload: Vec<u8>,
+    pub proof: ([u8; 32], [u8; 32], [u8; 32]),
+}
+
+// Reason for denying a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DenyReason {
+    WrongReceiver,
+    InvalidProof,
+    AccessDenied(AccessDenial),
+}
+
+// Decision on a request, with the permission or the reason that decided it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationDecision {
+    Allow(AccessReason),
+    Deny(DenyReason),
+}
+
+impl AuthorizationDecision {
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, AuthorizationDecision::Allow(_))
+    }
+}
+
+// Message of the NIZK proof, an unambiguous encoding of all request fields
+fn request_transcript(sender_ID: DeviceId, receiver_ID: DeviceId, resource_ID: &ResourcePath, action: &[u8], payload: &[u8]) -> String {
+    let mut data = Vec::from(&b"AuthorizationRequest"[..]);
+    data.extend_from_slice(&sender_ID.to_bytes());
+    data.extend_from_slice(&receiver_ID.to_bytes());
+    data.extend_from_slice(&resource_ID.to_bytes());
+    data.extend_from_slice(&(action.len() as u32).to_be_bytes());
+    data.extend_from_slice(action);
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(payload);
+    hex::encode(data)
+}
+
+// Create a request for an action on a resource of the receiver, authenticated with a NIZK proof
+pub fn new_authorization_request(my_ID: DeviceId, receiver_ID: DeviceId, resource_ID: ResourcePath, action: Vec<u8>,
+                                 payload: Vec<u8>, update_keys: bool) -> AuthorizationRequest {
+    let transcript = request_transcript(my_ID, receiver_ID, &resource_ID, &action, &payload);
+    let proof = crate::gen_nizk_proof(my_ID, receiver_ID, transcript, update_keys);
+
+    AuthorizationRequest {
+        sender_ID: my_ID,
+        receiver_ID,
+        resource_ID,
+        action,
+        payload,
+        proof,
+    }
+}
+
+// Verify the NIZK proof of a request and check if the sender may perform the action on the resource
+pub fn authorize(my_ID: DeviceId, request: &AuthorizationRequest, update_keys: bool) -> AuthorizationDecision {
+    if request.receiver_ID != my_ID {
+        return AuthorizationDecision::Deny(DenyReason::WrongReceiver);
+    }
+
+    let transcript = request_transcript(request.sender_ID, request.receiver_ID, &request.resource_ID,
+                                        &request.action, &request.payload);
+    if !crate::verify_nizk_proof(my_ID, request.sender_ID, transcript, request.proof, update_keys) {
+        return AuthorizationDecision::Deny(DenyReason::InvalidProof);
+    }
+
+    match access_control::get_access_reason(&request.resource_ID, &request.action, request.sender_ID, &SystemClock) {
+        Ok(reason) => AuthorizationDecision::Allow(reason),
+        Err(denial) => AuthorizationDecision::Deny(DenyReason::AccessDenied(denial)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Two devices that know each other and share a key
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        crate::store_shared_values(AID, BID, &[42u8; 32]);
+        crate::store_shared_values(BID, AID, &[42u8; 32]);
+        (AID, BID)
+    }
+
+    fn path(resource: &str) -> ResourcePath {
+        ResourcePath::parse(resource).unwrap()
+    }
+
+    // Request of B to open the door of A
+    fn open_door(AID: DeviceId, BID: DeviceId) -> AuthorizationRequest {
+        new_authorization_request(BID, AID, path("door"), Vec::from("open"), Vec::from("now"), false)
+    }
+
+    fn grant_open_door(BID: DeviceId) {
+        access_control::add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+        access_control::add_device_to_resource_action(&path("door"), b"open".to_vec(), BID).unwrap();
+    }
+
+    #[test]
+    fn granted_request_is_allowed() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(42001, 42002);
+            grant_open_door(BID);
+
+            assert_eq!(authorize(AID, &open_door(AID, BID), false), AuthorizationDecision::Allow(AccessReason::Device));
+        });
+    }
+
+    #[test]
+    fn tampered_request_has_invalid_proof() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(42011, 42012);
+            grant_open_door(BID);
+            access_control::add_action_to_resource(&path("door"), b"lock".to_vec()).unwrap();
+            access_control::add_device_to_resource_action(&path("door"), b"lock".to_vec(), BID).unwrap();
+
+            let mut request = open_door(AID, BID);
+            request.action = Vec::from("lock");
+            assert_eq!(authorize(AID, &request, false), AuthorizationDecision::Deny(DenyReason::InvalidProof));
+
+            let mut request = open_door(AID, BID);
+            request.payload = Vec::from("later");
+            assert_eq!(authorize(AID, &request, false), AuthorizationDecision::Deny(DenyReason::InvalidProof));
+
+            let mut request = open_door(AID, BID);
+            request.resource_ID = path("door/lock");
+            assert_eq!(authorize(AID, &request, false), AuthorizationDecision::Deny(DenyReason::InvalidProof));
+        });
+    }
+
+    #[test]
+    fn request_for_another_receiver_is_denied() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(42021, 42022);
+            let (CID, _) = paired_devices(42023, 42024);
+            grant_open_door(BID);
+
+            assert_eq!(authorize(CID, &open_door(AID, BID), false), AuthorizationDecision::Deny(DenyReason::WrongReceiver));
+        });
+    }
+
+    #[test]
+    fn valid_proof_without_grant_is_denied() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(42031, 42032);
+            access_control::add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+
+            assert_eq!(authorize(AID, &open_door(AID, BID), false),
+                       AuthorizationDecision::Deny(DenyReason::AccessDenied(AccessDenial::NotGranted)));
+        });
+    }
+}
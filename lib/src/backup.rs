@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use argon2::Argon2;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use chrono::Utc;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use crate::peer_registry::PeerRegistry;
+use crate::secret_management::SecretKeyErrors;
+use crate::{file_management, get_key_instance};
+use crate::DeviceId;
+
+// Version of the archive format. Archives of other versions are rejected
+const CONST_BACKUP_VERSION: u32 = 1;
+
+// Directory holding all files of the auth state
+const CONST_STATE_DIR: &str = ".nizk-auth";
+
+// Prefix of the used commitments lists, which are merged instead of replaced
+const CONST_COMMITMENTS_FILE_PREFIX: &str = "mut_comm_";
+
+#[derive(Debug)]
+pub enum BackupErrors {
+    InvalidArchive,
+    UnsupportedVersion(u32),
+    DecryptionFailed,
+    PrivateKeyNotFound,
+    CounterRollback(DeviceId),
+    SharedKeyChanged(DeviceId),
+    InvalidPeerRegistry,
+    UnableToReadState(std::io::Error),
+    UnableToStoreKey(SecretKeyErrors),
+}
+
+// Encrypted backup of the auth state of a device. The header is authenticated together with the ciphertext
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupArchive {
+    pub version: u32,
+    pub device_ID: DeviceId,
+    pub created_at: i64,
+    pub salt: [u8; 16],
+    pub nonce: [u8; 12],
+    pub ciphertext: String,
+}
+
+impl BackupArchive {
+    // Header data bound to the ciphertext
+    fn associated_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"BackupArchive"[..]);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&self.device_ID.to_bytes());
+        data.extend_from_slice(&self.created_at.to_be_bytes());
+        data.extend_from_slice(&self.salt);
+        data
+    }
+}
+
+// Content of an archive: keyring entries by description and state files by path, both hex encoded
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupState {
+    device_ID: DeviceId,
+    keys: BTreeMap<String, String>,
+    files: BTreeMap<String, String>,
+}
+
+// Derive the archive key from the passphrase with Argon2id
+fn derive_backup_key(passphrase: &[u8], salt: &[u8; 16]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Argon2::default().hash_password_into(passphrase, salt, &mut key).expect("Failed to derive backup key");
+    key
+}
+
+// Read a key from the OS, if it exists
+fn read_key(description: &str) -> Option<Vec<u8>> {
+    get_key_instance(description, 32, None).ok().map(|key| key.get_key().clone())
+}
+
+// Write the content of a file next to it, to be renamed once everything is staged
+fn stage_file(tmp_file_path: &String, content: &[u8]) -> Result<(), BackupErrors> {
+    file_management::create_parent_dirs(tmp_file_path.clone());
+    let mut file = File::create(tmp_file_path).map_err(BackupErrors::UnableToReadState)?;
+    file.write_all(content).map_err(BackupErrors::UnableToReadState)?;
+    file.sync_all().map_err(BackupErrors::UnableToReadState)?;
+    file_management::shrink_file_permissions(tmp_file_path.clone());
+    Ok(())
+}
+
+// Delete the staged files of a failed restore
+fn remove_staged_files(staged_files: &[(String, &String)]) {
+    for (tmp_file_path, _) in staged_files {
+        let _ = std::fs::remove_file(tmp_file_path);
+    }
+}
+
+// Store a key in the OS
+fn store_key(description: &str, value: Vec<u8>) -> Result<(), BackupErrors> {
+    let mut key_ins = get_key_instance(description, value.len(), Some(value.clone())).map_err(BackupErrors::UnableToStoreKey)?;
+    if key_ins.get_key() != &value {
+        key_ins.update_key_in_ring(value).map_err(BackupErrors::UnableToStoreKey)?;
+    }
+    Ok(())
+}
+
+// Put back the value a key had before the restore, or delete it if it did not exist
+fn restore_key(description: &str, previous_value: Option<Vec<u8>>) {
+    match previous_value {
+        Some(value) => {
+            let _ = store_key(description, value);
+        },
+        None => {
+            if let Ok(mut key_ins) = get_key_instance(description, 32, None) {
+                let _ = key_ins.delete_key_from_ring();
+            }
+        }
+    }
+}
+
+// Collect all files below a directory, with their paths. Temporary files and locks are no state
+fn collect_files(dir: &Path, files: &mut BTreeMap<String, String>) -> Result<(), BackupErrors> {
+    for entry in std::fs::read_dir(dir).map_err(BackupErrors::UnableToReadState)? {
+        let path = entry.map_err(BackupErrors::UnableToReadState)?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.extension() != Some(OsStr::new("tmp")) && path.extension() != Some(OsStr::new("lock")) {
+            let content = std::fs::read(&path).map_err(BackupErrors::UnableToReadState)?;
+            files.insert(path.to_string_lossy().into_owned(), hex::encode(content));
+        }
+    }
+    Ok(())
+}
+
+// Serialize the private key, the shared secret keys and counters of all registered peers and all
+// state files (peer registry, certificates, revocations, commitment lists, access control) into
+// one archive, encrypted under the passphrase
+pub fn create_backup(my_ID: DeviceId, passphrase: &[u8]) -> Result<BackupArchive, BackupErrors> {
+    let mut state = BackupState {
+        device_ID: my_ID,
+        keys: BTreeMap::new(),
+        files: BTreeMap::new(),
+    };
+
+    // Keys of this device and of the authority, if this device holds them
+    let private_key = read_key(&format!("PrivateKey:{}", my_ID)).ok_or(BackupErrors::PrivateKeyNotFound)?;
+    state.keys.insert(format!("PrivateKey:{}", my_ID), hex::encode(private_key));
+    let mut descriptions = vec![
+        format!("PrivateKeySeed:{}", my_ID),
+        String::from("AuthorityPrivateKey"),
+        String::from("AuthorityPublicKey"),
+    ];
+
+    // Shared values of all peers
+    let registry = PeerRegistry::load().map_err(|_| BackupErrors::InvalidPeerRegistry)?;
+    for peer in registry.list_peers() {
+        descriptions.push(format!("SharedSecretKey:{}:{}", my_ID, peer.device_ID));
+        descriptions.push(format!("SharedCounter:{}:{}", my_ID, peer.device_ID));
+    }
+    for description in descriptions {
+        if let Some(key) = read_key(&description) {
+            state.keys.insert(description, hex::encode(key));
+        }
+    }
+
+    let state_dir = Path::new(CONST_STATE_DIR);
+    if state_dir.exists() {
+        collect_files(state_dir, &mut state.files)?;
+    }
+
+    // Encrypt the state under a key derived from the passphrase
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut archive = BackupArchive {
+        version: CONST_BACKUP_VERSION,
+        device_ID: my_ID,
+        created_at: Utc::now().timestamp_millis(),
+        salt,
+        nonce,
+        ciphertext: String::new(),
+    };
+
+    let key = derive_backup_key(passphrase, &salt);
+    let cipher = ChaCha20Poly1305::new(&Key::from(key));
+    let plaintext = serde_json::to_vec(&state).unwrap();
+    let aad = archive.associated_data();
+    let ciphertext = cipher.encrypt(&Nonce::from(nonce), Payload { msg: &plaintext, aad: &aad })
+        .expect("Failed to encrypt backup");
+    archive.ciphertext = STANDARD.encode(ciphertext);
+
+    Ok(archive)
+}
+
+// Decrypt and check an archive, then restore all keys and files it holds. Restoring is refused before
+// anything is changed if a shared counter in the archive is behind the counter stored on this device,
+// or belongs to another shared key than the stored one, since reusing counter values would allow
+// replaying old proofs. All files are staged and the keys are undone if one of them can not be stored,
+// only a failing rename of a staged file can leave a partial restore behind. Returns the ID of the restored device
+pub fn restore_backup(archive: &BackupArchive, passphrase: &[u8]) -> Result<DeviceId, BackupErrors> {
+    if archive.version != CONST_BACKUP_VERSION {
+        return Err(BackupErrors::UnsupportedVersion(archive.version));
+    }
+
+    // Decryption fails for a wrong passphrase as well as for any modified archive
+    let ciphertext = STANDARD.decode(&archive.ciphertext).map_err(|_| BackupErrors::InvalidArchive)?;
+    let key = derive_backup_key(passphrase, &archive.salt);
+    let cipher = ChaCha20Poly1305::new(&Key::from(key));
+    let aad = archive.associated_data();
+    let plaintext = cipher.decrypt(&Nonce::from(archive.nonce), Payload { msg: &ciphertext, aad: &aad })
+        .map_err(|_| BackupErrors::DecryptionFailed)?;
+
+    let state: BackupState = serde_json::from_slice(&plaintext).map_err(|_| BackupErrors::InvalidArchive)?;
+    if state.device_ID != archive.device_ID {
+        return Err(BackupErrors::InvalidArchive);
+    }
+
+    // Decode everything first, so that an invalid archive changes nothing
+    let mut keys = Vec::new();
+    for (description, value) in state.keys.iter() {
+        keys.push((description, hex::decode(value).map_err(|_| BackupErrors::InvalidArchive)?));
+    }
+    let mut files = Vec::new();
+    for (file_path, content) in state.files.iter() {
+        // Only files of the state directory can be restored
+        if !file_path.starts_with(&format!("{}/", CONST_STATE_DIR)) || file_path.contains("..") {
+            return Err(BackupErrors::InvalidArchive);
+        }
+        files.push((file_path, hex::decode(content).map_err(|_| BackupErrors::InvalidArchive)?));
+    }
+
+    // Refuse to roll shared counters backwards. The counter of a new shared key starts at 1 again, so a
+    // higher counter in the backup only counts if the backup also holds the shared key that is stored now
+    let counter_prefix = format!("SharedCounter:{}:", state.device_ID);
+    for (description, value) in keys.iter() {
+        if let Some(other_ID) = description.strip_prefix(&counter_prefix) {
+            let backup_counter = <[u8; 4]>::try_from(value.as_slice()).map_err(|_| BackupErrors::InvalidArchive)?;
+            if let Some(current) = read_key(description) {
+                let current_counter = <[u8; 4]>::try_from(current.as_slice()).map_err(|_| BackupErrors::InvalidArchive)?;
+                if u32::from_be_bytes(current_counter) > u32::from_be_bytes(backup_counter) {
+                    println!("Backup would roll back the shared counter with device {}!\n", other_ID);
+                    return Err(BackupErrors::CounterRollback(other_ID.parse().map_err(|_| BackupErrors::InvalidArchive)?));
+                }
+            }
+
+            let key_description = format!("SharedSecretKey:{}:{}", state.device_ID, other_ID);
+            let backup_key = keys.iter().find(|(description, _)| **description == key_description).map(|(_, key)| key.clone());
+            let current_key = read_key(&key_description);
+            if current_key.is_some() && current_key != backup_key {
+                println!("Backup holds another shared key with device {} than this device!\n", other_ID);
+                return Err(BackupErrors::SharedKeyChanged(other_ID.parse().map_err(|_| BackupErrors::InvalidArchive)?));
+            }
+        }
+    }
+
+    // Stage files. Used commitments stay rejected, so existing commitment lists are merged with the backup
+    let mut staged_files = Vec::new();
+    for (file_path, mut content) in files {
+        let path = Path::new(file_path);
+        let is_commitments_list = path.file_name()
+            .is_some_and(|name| name.to_string_lossy().starts_with(CONST_COMMITMENTS_FILE_PREFIX));
+        if is_commitments_list && path.exists() {
+            let current = std::fs::read_to_string(path).map_err(BackupErrors::UnableToReadState)?;
+            let restored = String::from_utf8_lossy(&content).into_owned();
+            for line in current.lines() {
+                if !restored.lines().any(|restored_line| restored_line == line) {
+                    content.extend_from_slice(line.as_bytes());
+                    content.push(b'\n');
+                }
+            }
+        }
+
+        let tmp_file_path = format!("{}.tmp", file_path);
+        staged_files.push((tmp_file_path.clone(), file_path));
+        if let Err(e) = stage_file(&tmp_file_path, &content) {
+            remove_staged_files(&staged_files);
+            return Err(e);
+        }
+    }
+
+    // Restore keys in the OS, and undo them if one can not be stored
+    let mut previous_keys = Vec::new();
+    for (description, value) in keys {
+        previous_keys.push((description, read_key(description)));
+        if let Err(e) = store_key(description, value) {
+            for (description, previous_value) in previous_keys {
+                restore_key(description, previous_value);
+            }
+            remove_staged_files(&staged_files);
+            return Err(e);
+        }
+    }
+
+    // Replace the files with the staged ones
+    for (tmp_file_path, file_path) in staged_files {
+        std::fs::rename(tmp_file_path, file_path).map_err(BackupErrors::UnableToReadState)?;
+    }
+
+    println!("Auth state of device {} restored from the backup of {}\n", state.device_ID, archive.created_at);
+    Ok(state.device_ID)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Device with a key pair and a shared key with a registered peer
+    fn device_with_peer(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::store_key_pair(AID);
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        crate::store_shared_values(AID, BID, &[39u8; 32]);
+        (AID, BID)
+    }
+
+    fn delete_key(description: &str) {
+        get_key_instance(description, 32, None).unwrap().delete_key_from_ring().unwrap();
+    }
+
+    #[test]
+    fn restores_into_empty_keyring() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = device_with_peer(39001, 39002);
+            let descriptions = [format!("PrivateKey:{}", AID), format!("SharedSecretKey:{}:{}", AID, BID), format!("SharedCounter:{}:{}", AID, BID)];
+            let values: Vec<Option<Vec<u8>>> = descriptions.iter().map(|description| read_key(description)).collect();
+            let archive = create_backup(AID, b"passphrase").unwrap();
+
+            // Lose all keys and files of the device
+            for description in descriptions.iter() {
+                delete_key(description);
+            }
+            std::fs::remove_dir_all(CONST_STATE_DIR).unwrap();
+            assert!(read_key(&descriptions[0]).is_none());
+
+            assert_eq!(restore_backup(&archive, b"passphrase").unwrap(), AID);
+            let restored: Vec<Option<Vec<u8>>> = descriptions.iter().map(|description| read_key(description)).collect();
+            assert_eq!(restored, values);
+            assert!(PeerRegistry::load().unwrap().get_peer(BID).is_some());
+        });
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, _) = device_with_peer(39011, 39012);
+            let archive = create_backup(AID, b"passphrase").unwrap();
+
+            assert!(matches!(restore_backup(&archive, b"passphrase!"), Err(BackupErrors::DecryptionFailed)));
+        });
+    }
+
+    #[test]
+    fn tampered_archive_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, _) = device_with_peer(39021, 39022);
+            let archive = create_backup(AID, b"passphrase").unwrap();
+
+            let mut tampered = archive.clone();
+            let mut ciphertext = STANDARD.decode(&tampered.ciphertext).unwrap();
+            ciphertext[0] ^= 1;
+            tampered.ciphertext = STANDARD.encode(ciphertext);
+            assert!(matches!(restore_backup(&tampered, b"passphrase"), Err(BackupErrors::DecryptionFailed)));
+
+            // The header is authenticated as well
+            let mut tampered = archive.clone();
+            tampered.created_at += 1;
+            assert!(matches!(restore_backup(&tampered, b"passphrase"), Err(BackupErrors::DecryptionFailed)));
+            let mut tampered = archive.clone();
+            tampered.device_ID = DeviceId::from(39023);
+            assert!(matches!(restore_backup(&tampered, b"passphrase"), Err(BackupErrors::DecryptionFailed)));
+        });
+    }
+
+    #[test]
+    fn counter_rollback_is_refused() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = device_with_peer(39031, 39032);
+            let archive = create_backup(AID, b"passphrase").unwrap();
+
+            // Using the shared key moves the counter past the one in the backup
+            crate::gen_nizk_proof(AID, BID, String::from("message"), true);
+            let counter = read_key(&format!("SharedCounter:{}:{}", AID, BID));
+
+            assert!(matches!(restore_backup(&archive, b"passphrase"), Err(BackupErrors::CounterRollback(device_ID)) if device_ID == BID));
+            assert_eq!(read_key(&format!("SharedCounter:{}:{}", AID, BID)), counter);
+        });
+    }
+
+    #[test]
+    fn changed_shared_key_is_refused() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = device_with_peer(39041, 39042);
+            let archive = create_backup(AID, b"passphrase").unwrap();
+
+            // A new key agreement starts the counter at the same value again
+            crate::store_shared_values(AID, BID, &[40u8; 32]);
+
+            assert!(matches!(restore_backup(&archive, b"passphrase"), Err(BackupErrors::SharedKeyChanged(device_ID)) if device_ID == BID));
+            assert_eq!(read_key(&format!("SharedSecretKey:{}:{}", AID, BID)), Some(vec![40u8; 32]));
+        });
+    }
+}
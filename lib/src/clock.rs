@@ -0,0 +1,43 @@
+use std::cell::Cell;
+use chrono::Utc;
+
+// Source of the current time in ms, used to evaluate access conditions
+pub trait Clock {
+    fn now(&self) -> i64;
+}
+
+// Clock of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+// Clock that only moves when it is set, so that time dependent checks can be tested
+pub struct ManualClock {
+    time: Cell<i64>,
+}
+
+impl ManualClock {
+    pub fn new(time: i64) -> ManualClock {
+        ManualClock {
+            time: Cell::new(time),
+        }
+    }
+
+    pub fn set(&self, time: i64) {
+        self.time.set(time);
+    }
+
+    pub fn advance(&self, duration: i64) {
+        self.time.set(self.time.get() + duration);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        self.time.get()
+    }
+}
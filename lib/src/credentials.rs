@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::enrollment::get_authority_key;
+use crate::peer_registry::get_peer_public_key;
+use crate::{file_management, schnorr_identification};
+use crate::access_control::action_matches;
+use crate::{DeviceId, ResourcePath};
+
+#[derive(Debug)]
+pub enum CredentialErrors {
+    AuthorityKeyNotFound,
+    InvalidSignature,
+    Expired,
+    DeviceIdMismatch,
+    PeerNotActive,
+    InvalidProof,
+    OutdatedCredential,
+    UnableToWriteCredential(std::io::Error),
+}
+
+// Action a device may perform on a resource.
+// Grants on a resource include all resources below it, and wildcard actions like "read:*" all matching actions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub resource_ID: ResourcePath,
+    pub action: Vec<u8>,
+}
+
+// Credential of the authority, listing the access rights of a device until it expires.
+// Resource owners accept it without having the device in their local access control lists
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessCredential {
+    pub device_ID: DeviceId,
+    pub grants: Vec<AccessGrant>,
+    pub issued_at: i64,
+    pub valid_until: i64,
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl AccessCredential {
+    // Data signed by the authority
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"AccessCredential"[..]);
+        data.extend_from_slice(&self.device_ID.to_bytes());
+        data.extend_from_slice(&self.issued_at.to_be_bytes());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        data.extend_from_slice(&(self.grants.len() as u32).to_be_bytes());
+        for grant in self.grants.iter() {
+            data.extend_from_slice(&grant.resource_ID.to_bytes());
+            data.extend_from_slice(&(grant.action.len() as u32).to_be_bytes());
+            data.extend_from_slice(&grant.action);
+        }
+        data
+    }
+
+    // Check if the credential lists an action on a resource
+    pub fn grants_access(&self, resource_ID: &ResourcePath, action: &[u8]) -> bool {
+        self.grants.iter().any(|grant| grant.resource_ID.contains(resource_ID) && action_matches(&grant.action, action))
+    }
+}
+
+// File path of the credential presented by a peer
+fn get_peer_credential_file_path(device_ID: DeviceId) -> String {
+    format!(".nizk-auth/credentials/{}.json", device_ID)
+}
+
+// File path of the own credential
+fn get_own_credential_file_path() -> String {
+    String::from(".nizk-auth/credentials/own.json")
+}
+
+// Save a credential into a json file, see file_management::write_file_atomically
+fn write_credential(file_path: String, credential: &AccessCredential) -> Result<(), CredentialErrors> {
+    let json_string = serde_json::to_string(credential).unwrap();
+    file_management::write_file_atomically(&file_path, json_string.as_bytes()).map_err(|e| {
+        println!("Credential {} can not be written: {}\n", file_path, e);
+        CredentialErrors::UnableToWriteCredential(e)
+    })
+}
+
+// Read a credential from a json file. None if there is no credential or it can not be read
+fn read_credential(file_path: String) -> Option<AccessCredential> {
+    let file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return None;
+        },
+        Err(e) => {
+            println!("Credential {} can not be opened: {}\n", file_path, e);
+            return None;
+        }
+    };
+
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).ok()
+}
+
+// Sign a credential for a device. Only possible on the device holding the authority private key
+pub fn issue_credential(device_ID: DeviceId, grants: Vec<AccessGrant>, valid_until: i64) -> Result<AccessCredential, CredentialErrors> {
+    let authority_key = match get_authority_key("AuthorityPrivateKey") {
+        Ok(key) => key,
+        Err(_) => {
+            return Err(CredentialErrors::AuthorityKeyNotFound);
+        }
+    };
+
+    let mut credential = AccessCredential {
+        device_ID,
+        grants,
+        issued_at: Utc::now().timestamp_millis(),
+        valid_until,
+        signature: ([0u8; 32], [0u8; 32]),
+    };
+    credential.signature = schnorr_identification::sign(authority_key, &credential.signed_data());
+
+    Ok(credential)
+}
+
+// Check the signature and expiry of a credential, and that it belongs to an active, not revoked peer
+pub fn verify_credential(credential: &AccessCredential, device_ID: DeviceId) -> Result<(), CredentialErrors> {
+    verify_credential_at(credential, device_ID, Utc::now().timestamp_millis())
+}
+
+// Verify a credential like verify_credential, checking its validity at a timestamp in ms
+fn verify_credential_at(credential: &AccessCredential, device_ID: DeviceId, timestamp: i64) -> Result<(), CredentialErrors> {
+    if credential.device_ID != device_ID {
+        return Err(CredentialErrors::DeviceIdMismatch);
+    }
+
+    let authority_key = match get_authority_key("AuthorityPublicKey") {
+        Ok(key) => key,
+        Err(_) => {
+            return Err(CredentialErrors::AuthorityKeyNotFound);
+        }
+    };
+    if !schnorr_identification::verify_signature(authority_key, &credential.signed_data(), credential.signature) {
+        println!("Credential of device {} has an invalid signature!\n", credential.device_ID);
+        return Err(CredentialErrors::InvalidSignature);
+    }
+
+    if timestamp > credential.valid_until {
+        return Err(CredentialErrors::Expired);
+    }
+    if get_peer_public_key(device_ID).is_none() {
+        return Err(CredentialErrors::PeerNotActive);
+    }
+
+    Ok(())
+}
+
+// Generate a NIZK proof over the own credential, so that the receiver knows this device presented it
+pub fn present_credential(my_ID: DeviceId, receiver_ID: DeviceId, credential: &AccessCredential,
+                          update_keys: bool) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let message = serde_json::to_string(credential).unwrap();
+    crate::gen_nizk_proof(my_ID, receiver_ID, message, update_keys)
+}
+
+// Verify the NIZK proof of a presented credential and the credential itself, then keep it for check_access.
+// A credential only replaces a stored credential of the same device that was not issued later
+pub fn accept_presented_credential(my_ID: DeviceId, sender_ID: DeviceId, credential: &AccessCredential,
+                                   proof: ([u8; 32], [u8; 32], [u8; 32]), update_keys: bool) -> Result<(), CredentialErrors> {
+    let message = serde_json::to_string(credential).unwrap();
+    if !crate::verify_nizk_proof(my_ID, sender_ID, message, proof, update_keys) {
+        return Err(CredentialErrors::InvalidProof);
+    }
+    verify_credential(credential, sender_ID)?;
+
+    if let Some(stored) = read_credential(get_peer_credential_file_path(sender_ID)) {
+        if stored.issued_at > credential.issued_at {
+            println!("Device {} presented an older credential than the stored one!\n", sender_ID);
+            return Err(CredentialErrors::OutdatedCredential);
+        }
+    }
+    write_credential(get_peer_credential_file_path(sender_ID), credential)
+}
+
+// Check if the stored credential of a device is valid at a timestamp in ms and allows an action on a resource
+pub fn credential_grants_access(device_ID: DeviceId, resource_ID: &ResourcePath, action: &[u8], timestamp: i64) -> bool {
+    match read_credential(get_peer_credential_file_path(device_ID)) {
+        Some(credential) => verify_credential_at(&credential, device_ID, timestamp).is_ok() && credential.grants_access(resource_ID, action),
+        None => false,
+    }
+}
+
+// Save the credential the authority issued for this device
+pub fn save_own_credential(credential: &AccessCredential) -> Result<(), CredentialErrors> {
+    write_credential(get_own_credential_file_path(), credential)
+}
+
+// Return the credential of this device, to be presented to resource owners
+pub fn get_own_credential() -> Option<AccessCredential> {
+    read_credential(get_own_credential_file_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enrollment, test_support};
+
+    // Two devices that know each other and share a key
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        crate::store_shared_values(AID, BID, &[40u8; 32]);
+        crate::store_shared_values(BID, AID, &[40u8; 32]);
+        (AID, BID)
+    }
+
+    fn read_grant(resource_ID: &str) -> Vec<AccessGrant> {
+        vec![AccessGrant { resource_ID: resource_ID.parse().unwrap(), action: Vec::from("read") }]
+    }
+
+    fn in_one_hour() -> i64 {
+        Utc::now().timestamp_millis() + 3_600_000
+    }
+
+    #[test]
+    fn presented_credential_is_accepted() {
+        test_support::in_empty_work_dir(|| {
+            enrollment::init_authority().unwrap();
+            let (AID, BID) = paired_devices(40001, 40002);
+            let credential = issue_credential(BID, read_grant("building/door"), in_one_hour()).unwrap();
+
+            let proof = present_credential(BID, AID, &credential, false);
+            accept_presented_credential(AID, BID, &credential, proof, false).unwrap();
+
+            let now = Utc::now().timestamp_millis();
+            assert!(credential_grants_access(BID, &"building/door/lock".parse().unwrap(), b"read", now));
+            assert!(!credential_grants_access(BID, &"building/door".parse().unwrap(), b"write", now));
+            assert!(!credential_grants_access(BID, &"building/window".parse().unwrap(), b"read", now));
+        });
+    }
+
+    #[test]
+    fn expired_credential_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            enrollment::init_authority().unwrap();
+            let (AID, BID) = paired_devices(40011, 40012);
+            let valid_until = Utc::now().timestamp_millis() - 1;
+            let credential = issue_credential(BID, read_grant("building/door"), valid_until).unwrap();
+
+            assert!(matches!(verify_credential(&credential, BID), Err(CredentialErrors::Expired)));
+            let proof = present_credential(BID, AID, &credential, false);
+            assert!(matches!(accept_presented_credential(AID, BID, &credential, proof, false), Err(CredentialErrors::Expired)));
+            assert!(!credential_grants_access(BID, &"building/door".parse().unwrap(), b"read", valid_until - 1));
+        });
+    }
+
+    #[test]
+    fn credential_of_another_issuer_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            enrollment::init_authority().unwrap();
+            let (_, BID) = paired_devices(40021, 40022);
+            let credential = issue_credential(BID, read_grant("building/door"), in_one_hour()).unwrap();
+
+            enrollment::init_authority().unwrap();
+            assert!(matches!(verify_credential(&credential, BID), Err(CredentialErrors::InvalidSignature)));
+
+            let mut changed = issue_credential(BID, read_grant("building/door"), in_one_hour()).unwrap();
+            changed.grants = read_grant("building");
+            assert!(matches!(verify_credential(&changed, BID), Err(CredentialErrors::InvalidSignature)));
+        });
+    }
+
+    #[test]
+    fn credential_of_another_device_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            enrollment::init_authority().unwrap();
+            let (AID, BID) = paired_devices(40031, 40032);
+            let credential = issue_credential(AID, read_grant("building/door"), in_one_hour()).unwrap();
+
+            // B presents the credential of A with its own valid proof
+            let proof = present_credential(BID, AID, &credential, false);
+            assert!(matches!(accept_presented_credential(AID, BID, &credential, proof, false), Err(CredentialErrors::DeviceIdMismatch)));
+            assert!(!credential_grants_access(BID, &"building/door".parse().unwrap(), b"read", Utc::now().timestamp_millis()));
+        });
+    }
+}
@@ -0,0 +1,599 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::access_control::action_matches;
+use crate::peer_registry::get_peer_public_keys;
+use crate::{file_management, get_key_instance, schnorr_identification};
+use crate::{DeviceId, ResourcePath};
+
+// Max number of tokens in a delegation chain, starting with the token of the device holding the grant
+pub const CONST_MAX_DELEGATION_DEPTH: usize = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DelegationErrors {
+    PrivateKeyNotFound,
+    IssuerNotActive,
+    InvalidSignature,
+    Expired,
+    Revoked,
+    ChainTooLong,
+    // The issuer of a token is not the delegate of its parent token
+    BrokenChain,
+    // A token allows more resources, actions or time than its parent token
+    ExceedsParent,
+    DeviceIdMismatch,
+    InvalidProof,
+    UnableToWriteDelegationFile,
+    // The device revoking a token is not an issuer of its chain
+    NotAnIssuer,
+    InvalidDelegationFile,
+}
+
+// Token of a device that passes on its access to an action of a resource to another device, until it expires.
+// A token derived from a received token carries it as parent and may only narrow it down. The chain is only
+// valid as long as the first issuer holds the access in the access control list of the resource owner
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelegationToken {
+    pub issuer_ID: DeviceId,
+    pub delegate_ID: DeviceId,
+    pub resource_ID: ResourcePath,
+    // Action or wildcard action like "read:*"
+    pub action: Vec<u8>,
+    pub issued_at: i64,
+    pub valid_until: i64,
+    pub parent: Option<Box<DelegationToken>>,
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl DelegationToken {
+    // Data signed by the issuer, bound to the parent token by its ID
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"DelegationToken"[..]);
+        data.extend_from_slice(&self.issuer_ID.to_bytes());
+        data.extend_from_slice(&self.delegate_ID.to_bytes());
+        data.extend_from_slice(&self.resource_ID.to_bytes());
+        data.extend_from_slice(&(self.action.len() as u32).to_be_bytes());
+        data.extend_from_slice(&self.action);
+        data.extend_from_slice(&self.issued_at.to_be_bytes());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        match &self.parent {
+            Some(parent) => data.extend_from_slice(&parent.token_ID()),
+            None => data.extend_from_slice(&[0u8; 32]),
+        }
+        data
+    }
+
+    // ID of the token, used to revoke it
+    pub fn token_ID(&self) -> [u8; 32] {
+        schnorr_identification::sha3_256(&self.signed_data(), Some(&self.signature.0), Some(&self.signature.1), None)
+    }
+
+    // Number of tokens in the chain
+    pub fn depth(&self) -> usize {
+        1 + self.parent.as_ref().map_or(0, |parent| parent.depth())
+    }
+
+    // First token of the chain, issued by the device holding the grant
+    pub fn root(&self) -> &DelegationToken {
+        match &self.parent {
+            Some(parent) => parent.root(),
+            None => self,
+        }
+    }
+
+    // Check if the token covers an action on a resource
+    pub fn covers(&self, resource_ID: &ResourcePath, action: &[u8]) -> bool {
+        self.resource_ID.contains(resource_ID) && action_matches(&self.action, action)
+    }
+}
+
+// Revocation of a token signed by its issuer or by the issuer of one of its parent tokens, to be sent to
+// the resource owners. It also revokes all tokens derived from the token
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DelegationRevocation {
+    pub issuer_ID: DeviceId,
+    pub token_ID: [u8; 32],
+    pub revoked_at: i64,
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl DelegationRevocation {
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"DelegationRevocation"[..]);
+        data.extend_from_slice(&self.issuer_ID.to_bytes());
+        data.extend_from_slice(&self.token_ID);
+        data.extend_from_slice(&self.revoked_at.to_be_bytes());
+        data
+    }
+}
+
+// File path of the delegations presented by a device
+fn get_delegations_file_path(device_ID: DeviceId) -> String {
+    format!(".nizk-auth/delegations/{}.json", device_ID)
+}
+
+// File path of the IDs of tokens revoked by this device
+fn get_revoked_file_path() -> String {
+    String::from(".nizk-auth/delegations/revoked.json")
+}
+
+// File path of the revocations signed by issuers
+fn get_revocations_file_path() -> String {
+    String::from(".nizk-auth/delegations/revocations.json")
+}
+
+// Read a json file, or the default value if it does not exist. A file that can not be read is an error,
+// so that a broken file never drops revocations
+fn read_json_file<T: DeserializeOwned + Default>(file_path: String) -> Result<T, DelegationErrors> {
+    let file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(T::default());
+        },
+        Err(e) => {
+            println!("Delegation file {} can not be opened: {}\n", file_path, e);
+            return Err(DelegationErrors::InvalidDelegationFile);
+        }
+    };
+
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).map_err(|e| {
+        println!("Delegation file {} can not be read: {}\n", file_path, e);
+        DelegationErrors::InvalidDelegationFile
+    })
+}
+
+// Save data into a json file, see file_management::write_file_atomically
+fn write_json_file<T: Serialize>(file_path: String, data: &T) -> Result<(), DelegationErrors> {
+    let json_string = serde_json::to_string(data).unwrap();
+    file_management::write_file_atomically(&file_path, json_string.as_bytes()).map_err(|e| {
+        println!("Delegation file {} can not be written: {}\n", file_path, e);
+        DelegationErrors::UnableToWriteDelegationFile
+    })
+}
+
+// Load the private key of a device for signing
+fn get_private_key(my_ID: DeviceId) -> Result<[u8; 32], DelegationErrors> {
+    match get_key_instance(&format!("PrivateKey:{}", my_ID), 32, None) {
+        Ok(key) => <[u8; 32]>::try_from(key.get_key().as_slice()).map_err(|_| DelegationErrors::PrivateKeyNotFound),
+        Err(_) => Err(DelegationErrors::PrivateKeyNotFound),
+    }
+}
+
+// Check if a token or one of its parents is revoked. A signed revocation only counts if it comes from
+// the issuer of the token or of one of its parents
+fn is_revoked(token: &DelegationToken, revoked: &[String], revocations: &[DelegationRevocation]) -> bool {
+    let token_ID = token.token_ID();
+    if revoked.contains(&hex::encode(token_ID)) {
+        return true;
+    }
+
+    let mut issuers = Vec::new();
+    let mut current = Some(token);
+    while let Some(token) = current {
+        issuers.push(token.issuer_ID);
+        current = token.parent.as_deref();
+    }
+    revocations.iter().any(|revocation| revocation.token_ID == token_ID && issuers.contains(&revocation.issuer_ID))
+}
+
+// Check that a token does not allow more than its parent
+fn check_narrower(token: &DelegationToken, parent: &DelegationToken) -> Result<(), DelegationErrors> {
+    if parent.delegate_ID != token.issuer_ID {
+        return Err(DelegationErrors::BrokenChain);
+    }
+    // A wildcard action of the token is within a wildcard of the parent if it starts with its prefix
+    if !parent.resource_ID.contains(&token.resource_ID) || !action_matches(&parent.action, &token.action) ||
+        token.valid_until > parent.valid_until {
+        return Err(DelegationErrors::ExceedsParent);
+    }
+    Ok(())
+}
+
+// Pass on access to an action of a resource to another device. Without a parent, this device has to hold the
+// access itself. With a parent, the token is derived from a token this device received and may only narrow it down
+pub fn issue_delegation(my_ID: DeviceId, delegate_ID: DeviceId, resource_ID: ResourcePath, action: Vec<u8>,
+                        valid_until: i64, parent: Option<DelegationToken>) -> Result<DelegationToken, DelegationErrors> {
+    let private_key = get_private_key(my_ID)?;
+
+    let mut token = DelegationToken {
+        issuer_ID: my_ID,
+        delegate_ID,
+        resource_ID,
+        action,
+        issued_at: Utc::now().timestamp_millis(),
+        valid_until,
+        parent: parent.map(Box::new),
+        signature: ([0u8; 32], [0u8; 32]),
+    };
+    if let Some(parent) = &token.parent {
+        check_narrower(&token, parent)?;
+    }
+    if token.depth() > CONST_MAX_DELEGATION_DEPTH {
+        return Err(DelegationErrors::ChainTooLong);
+    }
+    token.signature = schnorr_identification::sign(private_key, &token.signed_data());
+
+    Ok(token)
+}
+
+// Verify all tokens of a chain at a time: their signatures, expiry and revocation, and that every token
+// narrows down its parent. The access of the first issuer is checked by the resource owner's check_access
+pub fn verify_delegation(token: &DelegationToken, timestamp: i64) -> Result<(), DelegationErrors> {
+    if token.depth() > CONST_MAX_DELEGATION_DEPTH {
+        return Err(DelegationErrors::ChainTooLong);
+    }
+
+    let revoked: Vec<String> = read_json_file(get_revoked_file_path())?;
+    let revocations: Vec<DelegationRevocation> = read_json_file(get_revocations_file_path())?;
+    let mut current = Some(token);
+    while let Some(token) = current {
+        let public_keys = get_peer_public_keys(token.issuer_ID);
+        if public_keys.is_empty() {
+            return Err(DelegationErrors::IssuerNotActive);
+        }
+        let signed_data = token.signed_data();
+        if !public_keys.iter().any(|public_key| schnorr_identification::verify_signature(*public_key, &signed_data, token.signature)) {
+            println!("Delegation of device {} has an invalid signature!\n", token.issuer_ID);
+            return Err(DelegationErrors::InvalidSignature);
+        }
+        if timestamp > token.valid_until {
+            return Err(DelegationErrors::Expired);
+        }
+        if is_revoked(token, &revoked, &revocations) {
+            return Err(DelegationErrors::Revoked);
+        }
+        if let Some(parent) = &token.parent {
+            check_narrower(token, parent)?;
+        }
+        current = token.parent.as_deref();
+    }
+
+    Ok(())
+}
+
+// Generate a NIZK proof over a received token, so that the resource owner knows the delegate presented it
+pub fn present_delegation(my_ID: DeviceId, receiver_ID: DeviceId, token: &DelegationToken,
+                          update_keys: bool) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let message = serde_json::to_string(token).unwrap();
+    crate::gen_nizk_proof(my_ID, receiver_ID, message, update_keys)
+}
+
+// Verify the NIZK proof of a presented token and its chain, then keep it for check_access
+pub fn accept_presented_delegation(my_ID: DeviceId, sender_ID: DeviceId, token: &DelegationToken, timestamp: i64,
+                                   proof: ([u8; 32], [u8; 32], [u8; 32]), update_keys: bool) -> Result<(), DelegationErrors> {
+    if token.delegate_ID != sender_ID {
+        return Err(DelegationErrors::DeviceIdMismatch);
+    }
+    let message = serde_json::to_string(token).unwrap();
+    if !crate::verify_nizk_proof(my_ID, sender_ID, message, proof, update_keys) {
+        return Err(DelegationErrors::InvalidProof);
+    }
+    verify_delegation(token, timestamp)?;
+
+    // Expired tokens are dropped on every update
+    let mut tokens: Vec<DelegationToken> = read_json_file(get_delegations_file_path(sender_ID))?;
+    tokens.retain(|stored| stored.valid_until >= timestamp && stored.token_ID() != token.token_ID());
+    tokens.push(token.clone());
+    write_json_file(get_delegations_file_path(sender_ID), &tokens)
+}
+
+// Revoke a token and all tokens derived from it on this device only
+pub fn revoke_delegation(token_ID: [u8; 32]) -> Result<(), DelegationErrors> {
+    let mut revoked: Vec<String> = read_json_file(get_revoked_file_path())?;
+    let token_ID = hex::encode(token_ID);
+    if !revoked.contains(&token_ID) {
+        revoked.push(token_ID);
+        write_json_file(get_revoked_file_path(), &revoked)?;
+    }
+    Ok(())
+}
+
+// Sign the revocation of a token that this device or one of the issuers of its parents issued. The revocation
+// is sent to the resource owners, which process it with accept_delegation_revocation
+pub fn issue_delegation_revocation(my_ID: DeviceId, token: &DelegationToken) -> Result<DelegationRevocation, DelegationErrors> {
+    let mut current = Some(token);
+    let mut is_issuer = false;
+    while let Some(token) = current {
+        is_issuer |= token.issuer_ID == my_ID;
+        current = token.parent.as_deref();
+    }
+    if !is_issuer {
+        return Err(DelegationErrors::NotAnIssuer);
+    }
+
+    let private_key = get_private_key(my_ID)?;
+    let mut revocation = DelegationRevocation {
+        issuer_ID: my_ID,
+        token_ID: token.token_ID(),
+        revoked_at: Utc::now().timestamp_millis(),
+        signature: ([0u8; 32], [0u8; 32]),
+    };
+    revocation.signature = schnorr_identification::sign(private_key, &revocation.signed_data());
+
+    Ok(revocation)
+}
+
+// Verify the signature of a revocation sent by an issuer and keep it. It applies to a token if the signer
+// issued the token or one of its parents
+pub fn accept_delegation_revocation(revocation: &DelegationRevocation) -> Result<(), DelegationErrors> {
+    let public_keys = get_peer_public_keys(revocation.issuer_ID);
+    if public_keys.is_empty() {
+        return Err(DelegationErrors::IssuerNotActive);
+    }
+    let signed_data = revocation.signed_data();
+    if !public_keys.iter().any(|public_key| schnorr_identification::verify_signature(*public_key, &signed_data, revocation.signature)) {
+        println!("Revocation of device {} has an invalid signature!\n", revocation.issuer_ID);
+        return Err(DelegationErrors::InvalidSignature);
+    }
+
+    let mut revocations: Vec<DelegationRevocation> = read_json_file(get_revocations_file_path())?;
+    if !revocations.iter().any(|stored| stored.issuer_ID == revocation.issuer_ID && stored.token_ID == revocation.token_ID) {
+        revocations.push(revocation.clone());
+        write_json_file(get_revocations_file_path(), &revocations)?;
+    }
+
+    Ok(())
+}
+
+// Return the first issuers of the valid chains presented by a device that cover an action on a resource.
+// The resource owner's check_access accepts a chain if its first issuer holds the access in the local
+// access control lists, not only by a credential or delegation
+pub fn delegation_roots(device_ID: DeviceId, resource_ID: &ResourcePath, action: &[u8], timestamp: i64) -> Vec<DeviceId> {
+    let tokens: Vec<DelegationToken> = match read_json_file(get_delegations_file_path(device_ID)) {
+        Ok(tokens) => tokens,
+        Err(_) => {
+            return Vec::new();
+        }
+    };
+    tokens.iter()
+        .filter(|token| token.delegate_ID == device_ID && token.covers(resource_ID, action))
+        .filter(|token| verify_delegation(token, timestamp).is_ok())
+        .map(|token| token.root().issuer_ID)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access_control::{self, AccessConditions, AccessDenial, AccessReason};
+    use crate::clock::SystemClock;
+    use crate::test_support;
+
+    // Resource owner, and devices that share a key with it
+    fn devices(ownerID: u32, deviceIDs: &[u32]) -> (DeviceId, Vec<DeviceId>) {
+        let ownerID = DeviceId::from(ownerID);
+        test_support::add_peer(ownerID, test_support::store_key_pair(ownerID));
+        let deviceIDs: Vec<DeviceId> = deviceIDs.iter().map(|deviceID| DeviceId::from(*deviceID)).collect();
+        for deviceID in deviceIDs.iter() {
+            test_support::add_peer(*deviceID, test_support::store_key_pair(*deviceID));
+            crate::store_shared_values(*deviceID, ownerID, &[48u8; 32]);
+            crate::store_shared_values(ownerID, *deviceID, &[48u8; 32]);
+        }
+        (ownerID, deviceIDs)
+    }
+
+    fn path(resource: &str) -> ResourcePath {
+        ResourcePath::parse(resource).unwrap()
+    }
+
+    fn in_one_hour() -> i64 {
+        Utc::now().timestamp_millis() + 3_600_000
+    }
+
+    // Resource "door" of the owner, with the open action granted to a device
+    fn grant_open_door(deviceID: DeviceId) {
+        access_control::add_resource(&path("door"), Some(vec![b"open".to_vec()])).unwrap();
+        access_control::add_device_to_resource_action(&path("door"), b"open".to_vec(), deviceID).unwrap();
+    }
+
+    // Token passing on the open action of a resource
+    fn delegate(issuerID: DeviceId, delegateID: DeviceId, resource: &str, parent: Option<&DelegationToken>) -> DelegationToken {
+        let valid_until = parent.map_or_else(in_one_hour, |parent| parent.valid_until);
+        issue_delegation(issuerID, delegateID, path(resource), b"open".to_vec(), valid_until, parent.cloned()).unwrap()
+    }
+
+    fn present(ownerID: DeviceId, token: &DelegationToken) -> Result<(), DelegationErrors> {
+        let proof = present_delegation(token.delegate_ID, ownerID, token, false);
+        accept_presented_delegation(ownerID, token.delegate_ID, token, Utc::now().timestamp_millis(), proof, false)
+    }
+
+    fn access(resource: &str, deviceID: DeviceId) -> Result<AccessReason, AccessDenial> {
+        access_control::get_access_reason(&path(resource), b"open", deviceID, &SystemClock)
+    }
+
+    // Revocation signed by any device, whether it issued a token of the chain or not
+    fn signed_revocation(issuerID: DeviceId, token: &DelegationToken) -> DelegationRevocation {
+        let mut revocation = DelegationRevocation {
+            issuer_ID: issuerID,
+            token_ID: token.token_ID(),
+            revoked_at: Utc::now().timestamp_millis(),
+            signature: ([0u8; 32], [0u8; 32]),
+        };
+        revocation.signature = schnorr_identification::sign(get_private_key(issuerID).unwrap(), &revocation.signed_data());
+        revocation
+    }
+
+    #[test]
+    fn two_hop_chain_is_accepted() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48001, &[48002, 48003, 48004]);
+            let (rootID, firstID, secondID) = (deviceIDs[0], deviceIDs[1], deviceIDs[2]);
+            grant_open_door(rootID);
+
+            let first = delegate(rootID, firstID, "door", None);
+            let second = delegate(firstID, secondID, "door/front", Some(&first));
+            present(ownerID, &second).unwrap();
+
+            assert_eq!(access("door/front", secondID), Ok(AccessReason::Delegation(rootID)));
+            assert_eq!(access("door", secondID), Err(AccessDenial::NotGranted));
+            assert_eq!(access("door/front", firstID), Err(AccessDenial::NotGranted));
+        });
+    }
+
+    #[test]
+    fn conditions_apply_once_to_delegate() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48011, &[48012, 48013]);
+            let (rootID, delegateID) = (deviceIDs[0], deviceIDs[1]);
+            grant_open_door(rootID);
+            let conditions = AccessConditions { max_invocations: Some((1, 3_600_000)), ..AccessConditions::default() };
+            access_control::set_action_conditions(&path("door"), b"open".to_vec(), Some(conditions)).unwrap();
+            present(ownerID, &delegate(rootID, delegateID, "door", None)).unwrap();
+
+            // The invocations of the delegate do not use up the limit of the first issuer
+            assert_eq!(access("door", delegateID), Ok(AccessReason::Delegation(rootID)));
+            assert_eq!(access("door", delegateID), Err(AccessDenial::RateLimited));
+            assert_eq!(access("door", rootID), Ok(AccessReason::Device));
+            assert_eq!(access("door", rootID), Err(AccessDenial::RateLimited));
+
+            // Authentication age is checked for the delegate, which never authenticated mutually
+            let conditions = AccessConditions { max_auth_age: Some(3_600_000), ..AccessConditions::default() };
+            access_control::set_action_conditions(&path("door"), b"open".to_vec(), Some(conditions)).unwrap();
+            crate::peer_registry::record_mutual_auth(rootID, Utc::now().timestamp_millis());
+            crate::peer_registry::record_mutual_auth(delegateID, 0);
+            assert_eq!(access("door", rootID), Ok(AccessReason::Device));
+            assert_eq!(access("door", delegateID), Err(AccessDenial::AuthenticationTooOld));
+        });
+    }
+
+    #[test]
+    fn token_exceeding_parent_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (_, deviceIDs) = devices(48021, &[48022, 48023, 48024]);
+            let (rootID, firstID, secondID) = (deviceIDs[0], deviceIDs[1], deviceIDs[2]);
+            let first = issue_delegation(rootID, firstID, path("door/front"), b"open".to_vec(), in_one_hour(), None).unwrap();
+
+            let wider_resource = issue_delegation(firstID, secondID, path("door"), b"open".to_vec(), in_one_hour(), Some(first.clone()));
+            assert_eq!(wider_resource, Err(DelegationErrors::ExceedsParent));
+            let other_action = issue_delegation(firstID, secondID, path("door/front"), b"lock".to_vec(), in_one_hour(), Some(first.clone()));
+            assert_eq!(other_action, Err(DelegationErrors::ExceedsParent));
+            let longer = issue_delegation(firstID, secondID, path("door/front"), b"open".to_vec(), first.valid_until + 1, Some(first.clone()));
+            assert_eq!(longer, Err(DelegationErrors::ExceedsParent));
+            let not_delegate = issue_delegation(secondID, firstID, path("door/front"), b"open".to_vec(), in_one_hour(), Some(first));
+            assert_eq!(not_delegate, Err(DelegationErrors::BrokenChain));
+        });
+    }
+
+    #[test]
+    fn chain_deeper_than_max_depth_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48031, &[48032, 48033, 48034, 48035, 48036]);
+            grant_open_door(deviceIDs[0]);
+            let mut token = delegate(deviceIDs[0], deviceIDs[1], "door", None);
+            for index in 1..CONST_MAX_DELEGATION_DEPTH {
+                token = delegate(deviceIDs[index], deviceIDs[index + 1], "door", Some(&token));
+            }
+            assert_eq!(token.depth(), CONST_MAX_DELEGATION_DEPTH);
+
+            let issued = issue_delegation(token.delegate_ID, deviceIDs[4], path("door"), b"open".to_vec(), token.valid_until, Some(token.clone()));
+            assert_eq!(issued, Err(DelegationErrors::ChainTooLong));
+
+            // A token signed without issue_delegation is rejected as well
+            let mut deeper = DelegationToken {
+                issuer_ID: token.delegate_ID,
+                delegate_ID: deviceIDs[4],
+                resource_ID: path("door"),
+                action: b"open".to_vec(),
+                issued_at: Utc::now().timestamp_millis(),
+                valid_until: token.valid_until,
+                parent: Some(Box::new(token)),
+                signature: ([0u8; 32], [0u8; 32]),
+            };
+            deeper.signature = schnorr_identification::sign(get_private_key(deeper.issuer_ID).unwrap(), &deeper.signed_data());
+            assert_eq!(verify_delegation(&deeper, Utc::now().timestamp_millis()), Err(DelegationErrors::ChainTooLong));
+            assert_eq!(present(ownerID, &deeper), Err(DelegationErrors::ChainTooLong));
+            assert_eq!(access("door", deviceIDs[4]), Err(AccessDenial::NotGranted));
+        });
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48041, &[48042, 48043]);
+            let (rootID, delegateID) = (deviceIDs[0], deviceIDs[1]);
+            grant_open_door(rootID);
+            let now = Utc::now().timestamp_millis();
+            let token = issue_delegation(rootID, delegateID, path("door"), b"open".to_vec(), now + 60_000, None).unwrap();
+            present(ownerID, &token).unwrap();
+
+            assert_eq!(verify_delegation(&token, now + 60_001), Err(DelegationErrors::Expired));
+            let clock = crate::clock::ManualClock::new(now + 60_001);
+            assert_eq!(access_control::get_access_reason(&path("door"), b"open", delegateID, &clock), Err(AccessDenial::NotGranted));
+            let proof = present_delegation(delegateID, ownerID, &token, false);
+            assert_eq!(accept_presented_delegation(ownerID, delegateID, &token, now + 60_001, proof, false), Err(DelegationErrors::Expired));
+        });
+    }
+
+    #[test]
+    fn revoked_parent_invalidates_child() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48051, &[48052, 48053, 48054]);
+            let (rootID, firstID, secondID) = (deviceIDs[0], deviceIDs[1], deviceIDs[2]);
+            grant_open_door(rootID);
+            let first = delegate(rootID, firstID, "door", None);
+            let second = delegate(firstID, secondID, "door", Some(&first));
+            present(ownerID, &second).unwrap();
+            assert!(access("door", secondID).is_ok());
+
+            // Revocation signed by the first issuer
+            accept_delegation_revocation(&issue_delegation_revocation(rootID, &first).unwrap()).unwrap();
+            assert_eq!(verify_delegation(&second, Utc::now().timestamp_millis()), Err(DelegationErrors::Revoked));
+            assert_eq!(access("door", secondID), Err(AccessDenial::NotGranted));
+        });
+    }
+
+    #[test]
+    fn locally_revoked_parent_invalidates_child() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48061, &[48062, 48063, 48064]);
+            let (rootID, firstID, secondID) = (deviceIDs[0], deviceIDs[1], deviceIDs[2]);
+            grant_open_door(rootID);
+            let first = delegate(rootID, firstID, "door", None);
+            let second = delegate(firstID, secondID, "door", Some(&first));
+            present(ownerID, &second).unwrap();
+
+            revoke_delegation(first.token_ID()).unwrap();
+            assert_eq!(access("door", secondID), Err(AccessDenial::NotGranted));
+        });
+    }
+
+    #[test]
+    fn removed_root_grant_invalidates_chain() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48071, &[48072, 48073]);
+            let (rootID, delegateID) = (deviceIDs[0], deviceIDs[1]);
+            grant_open_door(rootID);
+            present(ownerID, &delegate(rootID, delegateID, "door", None)).unwrap();
+            assert!(access("door", delegateID).is_ok());
+
+            access_control::remove_device_from_resource_action(&path("door"), b"open".to_vec(), rootID).unwrap();
+            assert_eq!(access("door", delegateID), Err(AccessDenial::NotGranted));
+
+            // A deny entry of the first issuer invalidates the chain as well
+            access_control::add_device_to_resource_action(&path("door"), b"open".to_vec(), rootID).unwrap();
+            access_control::add_denied_device_to_resource_action(&path("door"), b"open".to_vec(), rootID).unwrap();
+            assert_eq!(access("door", delegateID), Err(AccessDenial::NotGranted));
+        });
+    }
+
+    #[test]
+    fn revocation_of_non_issuer_is_ignored() {
+        test_support::in_empty_work_dir(|| {
+            let (ownerID, deviceIDs) = devices(48081, &[48082, 48083, 48084, 48085]);
+            let (rootID, firstID, secondID, otherID) = (deviceIDs[0], deviceIDs[1], deviceIDs[2], deviceIDs[3]);
+            grant_open_door(rootID);
+            let first = delegate(rootID, firstID, "door", None);
+            let second = delegate(firstID, secondID, "door", Some(&first));
+            present(ownerID, &second).unwrap();
+
+            assert_eq!(issue_delegation_revocation(otherID, &first), Err(DelegationErrors::NotAnIssuer));
+            // Neither a device outside the chain nor a delegate below the token can revoke it
+            accept_delegation_revocation(&signed_revocation(otherID, &first)).unwrap();
+            accept_delegation_revocation(&signed_revocation(secondID, &first)).unwrap();
+            assert_eq!(access("door", secondID), Ok(AccessReason::Delegation(rootID)));
+        });
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::schnorr_identification;
+
+// Prefix of fingerprint IDs in their text form
+const CONST_FINGERPRINT_PREFIX: &str = "fp-";
+
+// Identifier of a device. Either an assigned number, or derived from the public key of the device,
+// which ties the ID to the key. The text form is used in keyring descriptions and file names:
+// numbers keep their decimal form, fingerprints are written as "fp-" followed by 32 hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeviceId {
+    Number(u32),
+    Fingerprint([u8; 16]),
+}
+
+#[derive(Debug)]
+pub enum DeviceIdErrors {
+    InvalidNumber,
+    InvalidFingerprint,
+}
+
+impl DeviceId {
+    // Derive an ID from a public key, using the first 16 bytes of its SHA3 hash
+    pub fn from_public_key(public_key: &[u8; 32]) -> DeviceId {
+        let hash = schnorr_identification::sha3_256(b"DeviceId", Some(public_key), None, None);
+        DeviceId::Fingerprint(<[u8; 16]>::try_from(&hash[..16]).unwrap())
+    }
+
+    // Check if a fingerprint ID belongs to a public key. Number IDs are not tied to a key
+    pub fn matches_public_key(&self, public_key: &[u8; 32]) -> bool {
+        match self {
+            DeviceId::Number(_) => true,
+            DeviceId::Fingerprint(_) => *self == DeviceId::from_public_key(public_key),
+        }
+    }
+
+    // Unambiguous byte form, used in hashes and signatures
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            DeviceId::Number(number) => {
+                let mut bytes = vec![0u8];
+                bytes.extend_from_slice(&number.to_be_bytes());
+                bytes
+            },
+            DeviceId::Fingerprint(fingerprint) => {
+                let mut bytes = vec![1u8];
+                bytes.extend_from_slice(fingerprint);
+                bytes
+            }
+        }
+    }
+}
+
+impl From<u32> for DeviceId {
+    fn from(number: u32) -> DeviceId {
+        DeviceId::Number(number)
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceId::Number(number) => write!(f, "{}", number),
+            DeviceId::Fingerprint(fingerprint) => write!(f, "{}{}", CONST_FINGERPRINT_PREFIX, hex::encode(fingerprint)),
+        }
+    }
+}
+
+impl FromStr for DeviceId {
+    type Err = DeviceIdErrors;
+
+    fn from_str(s: &str) -> Result<DeviceId, DeviceIdErrors> {
+        match s.strip_prefix(CONST_FINGERPRINT_PREFIX) {
+            Some(fingerprint) => {
+                let bytes = hex::decode(fingerprint).map_err(|_| DeviceIdErrors::InvalidFingerprint)?;
+                let fingerprint = <[u8; 16]>::try_from(bytes.as_slice()).map_err(|_| DeviceIdErrors::InvalidFingerprint)?;
+                Ok(DeviceId::Fingerprint(fingerprint))
+            },
+            None => s.parse::<u32>().map(DeviceId::Number).map_err(|_| DeviceIdErrors::InvalidNumber),
+        }
+    }
+}
+
+// Numbers are stored as json numbers, so files written with u32 IDs stay readable
+impl Serialize for DeviceId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            DeviceId::Number(number) => serializer.serialize_u32(*number),
+            DeviceId::Fingerprint(_) => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+struct DeviceIdVisitor;
+
+impl<'de> Visitor<'de> for DeviceIdVisitor {
+    type Value = DeviceId;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a u32 or a device ID string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<DeviceId, E> {
+        u32::try_from(value).map(DeviceId::Number).map_err(|_| E::custom("device ID out of range"))
+    }
+
+    // Formats like TOML only have signed integers
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<DeviceId, E> {
+        u32::try_from(value).map(DeviceId::Number).map_err(|_| E::custom("device ID out of range"))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<DeviceId, E> {
+        value.parse().map_err(|_| E::custom(format!("invalid device ID {}", value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for DeviceId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DeviceId, D::Error> {
+        deserializer.deserialize_any(DeviceIdVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_round_trips() {
+        let device_ID = DeviceId::from(35001);
+        assert_eq!(device_ID.to_string(), "35001");
+        assert_eq!("35001".parse::<DeviceId>().unwrap(), device_ID);
+
+        // Numbers stay json numbers, like the u32 IDs of older files
+        assert_eq!(serde_json::to_string(&device_ID).unwrap(), "35001");
+        assert_eq!(serde_json::from_str::<DeviceId>("35001").unwrap(), device_ID);
+        assert_eq!(serde_json::from_str::<DeviceId>("\"35001\"").unwrap(), device_ID);
+    }
+
+    #[test]
+    fn fingerprint_round_trips() {
+        let device_ID = DeviceId::from_public_key(&[35u8; 32]);
+        let text = device_ID.to_string();
+        assert!(text.starts_with(CONST_FINGERPRINT_PREFIX));
+        assert_eq!(text.len(), CONST_FINGERPRINT_PREFIX.len() + 32);
+        assert_eq!(text.parse::<DeviceId>().unwrap(), device_ID);
+
+        let json = serde_json::to_string(&device_ID).unwrap();
+        assert_eq!(json, format!("\"{}\"", text));
+        assert_eq!(serde_json::from_str::<DeviceId>(&json).unwrap(), device_ID);
+        assert!(device_ID.matches_public_key(&[35u8; 32]));
+        assert!(!device_ID.matches_public_key(&[36u8; 32]));
+    }
+
+    #[test]
+    fn malformed_ids_are_rejected() {
+        assert!(matches!("".parse::<DeviceId>(), Err(DeviceIdErrors::InvalidNumber)));
+        assert!(matches!("-1".parse::<DeviceId>(), Err(DeviceIdErrors::InvalidNumber)));
+        assert!(matches!("4294967296".parse::<DeviceId>(), Err(DeviceIdErrors::InvalidNumber)));
+        assert!(matches!("device".parse::<DeviceId>(), Err(DeviceIdErrors::InvalidNumber)));
+        assert!(serde_json::from_str::<DeviceId>("4294967296").is_err());
+        assert!(serde_json::from_str::<DeviceId>("-1").is_err());
+    }
+
+    #[test]
+    fn malformed_fingerprints_are_rejected() {
+        let text = DeviceId::from_public_key(&[35u8; 32]).to_string();
+        let hex_part = &text[CONST_FINGERPRINT_PREFIX.len()..];
+
+        // Too short, too long, odd length and no hex digits
+        for fingerprint in [&hex_part[..30], &format!("{}00", hex_part)[..], &hex_part[..31], &"zz".repeat(16)[..]] {
+            let text = format!("{}{}", CONST_FINGERPRINT_PREFIX, fingerprint);
+            assert!(matches!(text.parse::<DeviceId>(), Err(DeviceIdErrors::InvalidFingerprint)));
+            assert!(serde_json::from_str::<DeviceId>(&format!("\"{}\"", text)).is_err());
+        }
+        assert!(matches!(CONST_FINGERPRINT_PREFIX.parse::<DeviceId>(), Err(DeviceIdErrors::InvalidFingerprint)));
+    }
+}
@@ -0,0 +1,219 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::secret_management::SecretKeyErrors;
+use crate::peer_registry::{PeerEntry, PeerRegistry, PeerStatus};
+use crate::{file_management, get_key_instance, revocation, schnorr_identification};
+use crate::DeviceId;
+
+#[derive(Debug)]
+pub enum EnrollmentErrors {
+    AuthorityKeyNotFound,
+    InvalidSignature,
+    NotYetValid,
+    Expired,
+    OutdatedCertificate,
+    PeerRevoked,
+    DeviceIdMismatch,
+    InvalidPeerRegistry,
+    UnableToStoreKey(SecretKeyErrors),
+    UnableToWriteCertificate(std::io::Error),
+}
+
+// Certificate of the authority, binding a device ID to its public key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceCertificate {
+    pub device_ID: DeviceId,
+    pub public_key: [u8; 32],
+    pub valid_from: i64,
+    pub valid_until: i64,
+    pub roles: Vec<String>,
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl DeviceCertificate {
+    // Data signed by the authority
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"DeviceCertificate"[..]);
+        data.extend_from_slice(&self.device_ID.to_bytes());
+        data.extend_from_slice(&self.public_key);
+        data.extend_from_slice(&self.valid_from.to_be_bytes());
+        data.extend_from_slice(&self.valid_until.to_be_bytes());
+        for role in self.roles.iter() {
+            data.extend_from_slice(&(role.len() as u32).to_be_bytes());
+            data.extend_from_slice(role.as_bytes());
+        }
+        data
+    }
+
+    // Check if the authority granted a role to the device
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+// File path of the own certificate
+fn get_own_certificate_file_path() -> String {
+    String::from(".nizk-auth/certificates/own.json")
+}
+
+// Fetch a 32 byte key of the authority from the OS
+pub(crate) fn get_authority_key(description: &str) -> Result<[u8; 32], EnrollmentErrors> {
+    match get_key_instance(description, 32, None) {
+        Ok(key) => Ok(<[u8; 32]>::try_from(key.get_key().as_slice()).unwrap()),
+        Err(_) => Err(EnrollmentErrors::AuthorityKeyNotFound),
+    }
+}
+
+// Save or replace a 32 byte key in the OS
+fn store_key(description: &str, key: [u8; 32]) -> Result<(), EnrollmentErrors> {
+    let mut key_ins = match get_key_instance(description, 32, Some(key.to_vec())) {
+        Ok(key_ins) => key_ins,
+        Err(e) => {
+            return Err(EnrollmentErrors::UnableToStoreKey(e));
+        }
+    };
+
+    // Update key in case an old key already exists
+    if key_ins.get_key() != &key.to_vec() {
+        if let Err(e) = key_ins.update_key_in_ring(key.to_vec()) {
+            return Err(EnrollmentErrors::UnableToStoreKey(e));
+        }
+    }
+    Ok(())
+}
+
+// Save a certificate into a json file, see file_management::write_file_atomically
+fn write_certificate(file_path: String, certificate: &DeviceCertificate) -> Result<(), EnrollmentErrors> {
+    let json_string = serde_json::to_string(certificate).unwrap();
+    file_management::write_file_atomically(&file_path, json_string.as_bytes()).map_err(|e| {
+        println!("Certificate {} can not be written: {}\n", file_path, e);
+        EnrollmentErrors::UnableToWriteCertificate(e)
+    })
+}
+
+// Read a certificate from a json file
+fn read_certificate(file_path: String) -> Option<DeviceCertificate> {
+    let path = Path::new(&file_path);
+    if !path.exists() {
+        return None;
+    }
+
+    let file = File::open(path).unwrap();
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).ok()
+}
+
+// Generate the key pair of a local authority. Returns the public key, which has to be installed on all devices
+pub fn init_authority() -> Result<[u8; 32], EnrollmentErrors> {
+    let (public_key, private_key) = crate::gen_random_key_pair();
+    store_key("AuthorityPrivateKey", private_key)?;
+    store_key("AuthorityPublicKey", public_key)?;
+    Ok(public_key)
+}
+
+// Install the public key of the authority, used to check all certificates
+pub fn set_authority_public_key(public_key: [u8; 32]) -> Result<(), EnrollmentErrors> {
+    store_key("AuthorityPublicKey", public_key)
+}
+
+// Sign a certificate for a device. Only possible on the device holding the authority private key
+pub fn issue_certificate(device_ID: DeviceId, public_key: [u8; 32], valid_from: i64, valid_until: i64,
+                         roles: Vec<String>) -> Result<DeviceCertificate, EnrollmentErrors> {
+    let authority_key = get_authority_key("AuthorityPrivateKey")?;
+
+    let mut certificate = DeviceCertificate {
+        device_ID,
+        public_key,
+        valid_from,
+        valid_until,
+        roles,
+        signature: ([0u8; 32], [0u8; 32]),
+    };
+    certificate.signature = schnorr_identification::sign(authority_key, &certificate.signed_data());
+
+    Ok(certificate)
+}
+
+// Check the signature of the authority and the validity period of a certificate
+pub fn verify_certificate(certificate: &DeviceCertificate) -> Result<(), EnrollmentErrors> {
+    let authority_key = get_authority_key("AuthorityPublicKey")?;
+
+    if !schnorr_identification::verify_signature(authority_key, &certificate.signed_data(), certificate.signature) {
+        println!("Certificate of device {} has an invalid signature!\n", certificate.device_ID);
+        return Err(EnrollmentErrors::InvalidSignature);
+    }
+
+    let timestamp = Utc::now().timestamp_millis();
+    if timestamp < certificate.valid_from {
+        return Err(EnrollmentErrors::NotYetValid);
+    }
+    if timestamp > certificate.valid_until {
+        return Err(EnrollmentErrors::Expired);
+    }
+
+    Ok(())
+}
+
+// Check the certificate of a peer and add it to the peer registry.
+// Display name and policy of a known peer are kept, revoked peers can not enroll again.
+// A known peer only gets a new key with a certificate issued later than its stored one
+pub fn enroll_peer(certificate: &DeviceCertificate) -> Result<(), EnrollmentErrors> {
+    verify_certificate(certificate)?;
+    if revocation::is_revoked(certificate.device_ID, &certificate.public_key) {
+        println!("Device {} or its key is revoked and can not enroll!\n", certificate.device_ID);
+        return Err(EnrollmentErrors::PeerRevoked);
+    }
+
+    // Fingerprint IDs have to be derived from the certified key, also for known peers
+    if !certificate.device_ID.matches_public_key(&certificate.public_key) {
+        println!("Device ID {} does not belong to the certified key!\n", certificate.device_ID);
+        return Err(EnrollmentErrors::DeviceIdMismatch);
+    }
+
+    let mut registry = PeerRegistry::load().map_err(|_| EnrollmentErrors::InvalidPeerRegistry)?;
+    let mut entry = match registry.get_peer(certificate.device_ID) {
+        Some(peer) if peer.status == PeerStatus::Revoked => {
+            println!("Device {} is revoked and can not enroll again!\n", certificate.device_ID);
+            return Err(EnrollmentErrors::PeerRevoked);
+        },
+        Some(peer) => {
+            // A replayed older certificate must not roll the peer back to a replaced key
+            if let Some(stored) = &peer.certificate {
+                if certificate.public_key != stored.public_key && certificate.valid_from <= stored.valid_from {
+                    println!("Certificate of device {} is not newer than its stored certificate!\n", certificate.device_ID);
+                    return Err(EnrollmentErrors::OutdatedCertificate);
+                }
+            }
+            peer.clone()
+        },
+        None => PeerEntry::new(certificate.device_ID, certificate.public_key, format!("Device {}", certificate.device_ID)),
+    };
+    entry.public_key = certificate.public_key;
+    entry.certificate = Some(certificate.clone());
+    entry.enrolled_at = Utc::now().timestamp_millis();
+
+    if registry.update_peer(entry.clone()).is_err() {
+        registry.add_peer(entry).unwrap();
+    }
+    registry.save().map_err(|_| EnrollmentErrors::InvalidPeerRegistry)?;
+
+    Ok(())
+}
+
+// Return the stored certificate of an enrolled peer
+pub fn get_peer_certificate(device_ID: DeviceId) -> Option<DeviceCertificate> {
+    PeerRegistry::load().ok()?.get_peer(device_ID).and_then(|peer| peer.certificate.clone())
+}
+
+// Save the certificate the authority issued for this device
+pub fn save_own_certificate(certificate: &DeviceCertificate) -> Result<(), EnrollmentErrors> {
+    write_certificate(get_own_certificate_file_path(), certificate)
+}
+
+// Return the certificate of this device, to be sent to peers
+pub fn get_own_certificate() -> Option<DeviceCertificate> {
+    read_certificate(get_own_certificate_file_path())
+}
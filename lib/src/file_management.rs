@@ -1,5 +1,5 @@
 use std::fs::{File, OpenOptions, Permissions};
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{self, BufReader, BufWriter, Write};
 use std::io::prelude::*;
 use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
@@ -7,6 +7,8 @@ use serde::{Deserialize, Serialize};
 use serde_json::{Result, Value};
 use hex;
 use chrono::Utc;
+use crate::revocation;
+use crate::DeviceId;
 
 // Threshold for max key guesses
 const CONST_KEY_GUESS_THRESHOLD: u8 = 5;
@@ -14,22 +16,22 @@ const CONST_MAX_AUTH_RATE: f64 = 0.01;      // 20 requests per 1000 ms
 const CONST_MIN_AUTH_RATE: f64 = 0.005;
 
 // File path of the used commitments list
-fn get_commitments_file_path(senderID: u32) -> String {
+fn get_commitments_file_path(senderID: DeviceId) -> String {
     format!(".nizk-auth/mut_comm_{}.txt", senderID)
 }
 
 // File path of the intrusion detection data
-fn get_intrusion_file_path(senderID: u32) -> String {
+fn get_intrusion_file_path(senderID: DeviceId) -> String {
     format!(".nizk-auth/intrusion_data_{}.json", senderID)
 }
 
 // Set the file permissions to 0o600, so that only the user can write to it
-fn shrink_file_permissions(path: String) {
+pub(crate) fn shrink_file_permissions(path: String) {
     let mut perms = Permissions::from_mode(0o600);
     std::fs::set_permissions(&path, perms).expect("Failed to set file permissions");
 }
 
-fn create_parent_dirs(file_path: String) {
+pub(crate) fn create_parent_dirs(file_path: String) {
     let path = Path::new(&file_path);
 
     // Create parent directories if they don't already exist
@@ -42,8 +44,20 @@ fn create_parent_dirs(file_path: String) {
     }
 }
 
+// Write data into a temporary file next to the file and rename it over the file, so that readers and
+// a crash during the write only ever see the complete old or the complete new file
+pub(crate) fn write_file_atomically(file_path: &str, data: &[u8]) -> io::Result<()> {
+    create_parent_dirs(String::from(file_path));
+    let tmp_file_path = format!("{}.tmp", file_path);
+    let mut file = File::create(&tmp_file_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::set_permissions(&tmp_file_path, Permissions::from_mode(0o600))?;
+    std::fs::rename(&tmp_file_path, file_path)
+}
+
 // Check if an old commitment is being reused again
-pub fn check_commitment(senderID: u32, commitment: [u8; 32]) -> bool {
+pub fn check_commitment(senderID: DeviceId, commitment: [u8; 32]) -> bool {
     // Get path instance
     let file_path = get_commitments_file_path(senderID);
     let file_path_copy = get_commitments_file_path(senderID);
@@ -126,7 +140,7 @@ struct Intrusion {
 }
 
 // Update the last intrusion system values
-pub fn manage_intrusion(senderID: u32, schnorr_proof: bool, mac_tag: bool) {
+pub fn manage_intrusion(senderID: DeviceId, schnorr_proof: bool, mac_tag: bool) {
     // Check if file exists and create file if it does not exist
     let file_path = get_intrusion_file_path(senderID);
     let path = Path::new(&file_path);
@@ -198,10 +212,20 @@ pub fn manage_intrusion(senderID: u32, schnorr_proof: bool, mac_tag: bool) {
         let mut file = File::create(&path).unwrap();
         file.write_all(json_string.as_bytes());
     }
+
+    // Revoke the sender if one of its keys looks compromised
+    if revocation::auto_revocation_enabled() {
+        let (asym_comp, sym_comp, _) = check_intrusion(senderID);
+        if asym_comp || sym_comp {
+            if let Err(e) = revocation::revoke_locally(senderID) {
+                println!("Device {} could not be revoked: {:?}\n", senderID, e);
+            }
+        }
+    }
 }
 
 // Check if a key is compromised or if a brute force attack is being conducted
-pub fn check_intrusion(senderID: u32) -> (bool, bool, bool) {
+pub fn check_intrusion(senderID: DeviceId) -> (bool, bool, bool) {
     // Open file and read content as Intrusion struct
     let mut intrusion = read_intrusion_data(senderID);
 
@@ -219,7 +243,7 @@ pub fn check_intrusion(senderID: u32) -> (bool, bool, bool) {
     (asym_comp, sym_comp, intrusion.dos_attack)
 }
 
-fn read_intrusion_data(senderID: u32) -> Intrusion {
+fn read_intrusion_data(senderID: DeviceId) -> Intrusion {
     // Open file and read content as Intrusion struct
     let file_path = get_intrusion_file_path(senderID);
     let path = Path::new(&file_path);
@@ -246,7 +270,7 @@ fn get_counter_values(schnorr_proof: bool, mac_tag: bool) -> (u8, u8) {
 }
 
 // Init intrusion data
-pub fn init_data(senderID: u32) {
+pub fn init_data(senderID: DeviceId) {
     // Check if file exists and create file if it does not exist
     let file_path = get_intrusion_file_path(senderID);
     let path = Path::new(&file_path);
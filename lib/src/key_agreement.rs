@@ -0,0 +1,434 @@
+use curve25519_dalek::constants::X25519_BASEPOINT;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use crate::{file_management, peer_registry, schnorr_identification, IntMutAuth, KeySize};
+use crate::{get_32byte_key, restore_shared_values, store_shared_values, sign_abort, verify_abort};
+use crate::{CONST_INITIATOR_ROLE, CONST_RECEIVER_ROLE, CONST_NEXT_STEP_REQUIRED};
+use crate::{CONST_RESPONSE_CANNOT_BE_VERIFIED, CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE};
+use crate::{CONST_RESPONSE_CAN_BE_VERIFIED, CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED};
+use crate::DeviceId;
+
+// Message types of the X25519 handshake
+const CONST_MSG_EPHEMERAL: u8 = 1;
+const CONST_MSG_EPHEMERAL_AND_CONFIRMATION: u8 = 2;
+const CONST_MSG_CONFIRMATION: u8 = 3;
+const CONST_MSG_FAILED: u8 = 4;
+
+// Stages of the X25519 handshake
+const CONST_STAGE_SEND_EPHEMERAL: u8 = 0;
+const CONST_STAGE_SEND_EPHEMERAL_AND_CONFIRMATION: u8 = 1;
+const CONST_STAGE_SEND_CONFIRMATION: u8 = 2;
+const CONST_STAGE_SEND_FAILED: u8 = 3;
+const CONST_STAGE_WAIT: u8 = 4;
+
+// Labels of the X25519 handshake
+const CONST_X25519_TRANSCRIPT_LABEL: &[u8] = b"X25519KeyAgreement transcript";
+const CONST_X25519_CONFIRMATION_LABEL: &[u8] = b"X25519KeyAgreement key confirmation";
+
+// A protocol that agrees on a SharedSecretKey and SharedCounter with another device.
+// Messages are opaque bytes, so any transport can carry them.
+pub trait KeyAgreement {
+    // Next message to send to the other device, None if we have to wait for a message or are done
+    fn next_message(&mut self) -> Option<Vec<u8>>;
+
+    // Process a message of the other device. Returns false if the key agreement failed
+    fn receive_message(&mut self, message: &[u8]) -> bool;
+
+    // True once the shared secret key and counter are stored in the OS
+    fn is_complete(&self) -> bool;
+}
+
+// Interactive Schnorr mutual authentication. Messages are the request type, a flag for the
+// second value, and both 32-byte values
+impl KeyAgreement for IntMutAuth {
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let (val1, val2, request_type) = self.gen_next_values();
+        if request_type == CONST_NEXT_STEP_REQUIRED {
+            return None;
+        }
+
+        let mut message = Vec::with_capacity(66);
+        message.push(request_type);
+        message.push(val2.is_some() as u8);
+        message.extend_from_slice(&val1);
+        message.extend_from_slice(&val2.unwrap_or([0u8; 32]));
+        Some(message)
+    }
+
+    fn receive_message(&mut self, message: &[u8]) -> bool {
+        if message.len() != 66 {
+            return false;
+        }
+
+        // Decode values
+        let val1 = <[u8; 32]>::try_from(&message[2..34]).unwrap();
+        let val2 = if message[1] == 1 {
+            Some(<[u8; 32]>::try_from(&message[34..66]).unwrap())
+        } else {
+            None
+        };
+
+        // Verify whatever the received values allow us to verify
+        match self.add_recipient_values(message[0], val1, val2) {
+            CONST_RESPONSE_CANNOT_BE_VERIFIED => true,
+            CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE | CONST_RESPONSE_CAN_BE_VERIFIED => {
+                self.verify_proof()
+            },
+            CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED => self.confirm_shared_secret_key(),
+            _ => false,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.key_committed
+    }
+}
+
+// Static-ephemeral X25519 handshake. Both devices send an ephemeral key, and the shared secret key is
+// derived from the ephemeral-ephemeral and both static-ephemeral Diffie-Hellman values. The static keys
+// are the Ed25519 identity keys in Montgomery form, so only the owners of the identity keys can derive
+// the key. Key confirmation tags prove this to the other device.
+pub struct X25519KeyAgreement {
+    pub my_ID: DeviceId,
+    pub other_ID: DeviceId,
+    pub role: u8,
+    pub key_size: KeySize,
+    stage: u8,
+    my_ephemeral: Scalar,
+    my_ephemeral_public: [u8; 32],
+    other_ephemeral_public: [u8; 32],
+    pending_shared_key: Option<[u8; 32]>,
+    key_committed: bool,
+    previous_shared_values: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl X25519KeyAgreement {
+    // Create a new instance. For the receiver, key_size is the smallest accepted key size
+    pub fn new(my_ID: DeviceId, other_ID: DeviceId, role: u8, key_size: KeySize) -> X25519KeyAgreement {
+        // Generate ephemeral key pair
+        let my_ephemeral = schnorr_identification::generate_random_scalar();
+        let my_ephemeral_public = (X25519_BASEPOINT * my_ephemeral).to_bytes();
+
+        // Initiator starts, the receiver waits for the ephemeral key of the initiator
+        let mut stage = CONST_STAGE_SEND_EPHEMERAL;
+        if role == CONST_RECEIVER_ROLE {
+            stage = CONST_STAGE_WAIT;
+        }
+
+        X25519KeyAgreement {
+            my_ID,
+            other_ID,
+            role,
+            key_size,
+            stage,
+            my_ephemeral,
+            my_ephemeral_public,
+            other_ephemeral_public: [0u8; 32],
+            pending_shared_key: None,
+            key_committed: false,
+            previous_shared_values: None,
+        }
+    }
+
+    // Undo storing the shared secret key and counter
+    pub fn rollback_shared_secret_key(&mut self) {
+        if !self.key_committed {
+            return;
+        }
+
+        restore_shared_values(self.my_ID, self.other_ID, self.previous_shared_values.take());
+        self.key_committed = false;
+    }
+
+    // Hash of both IDs, the key size and both ephemeral keys, starting with the ones of the initiator
+    pub fn transcript_hash(&self) -> [u8; 32] {
+        let (init_values, rec_values) = if self.role == CONST_INITIATOR_ROLE {
+            ((self.my_ID, self.my_ephemeral_public), (self.other_ID, self.other_ephemeral_public))
+        } else {
+            ((self.other_ID, self.other_ephemeral_public), (self.my_ID, self.my_ephemeral_public))
+        };
+
+        let mut ids = Vec::with_capacity(9);
+        let mut ephemerals = Vec::with_capacity(64);
+        for (id, ephemeral) in [init_values, rec_values] {
+            ids.extend_from_slice(&id.to_bytes());
+            ephemerals.extend_from_slice(&ephemeral);
+        }
+        ids.push(self.key_size.bytes() as u8);
+
+        schnorr_identification::sha3_256(CONST_X25519_TRANSCRIPT_LABEL, Some(&ids), Some(&ephemerals), None)
+    }
+
+    // Calculate the shared secret key from the received ephemeral key. Returns None for invalid keys or unaccepted peers
+    fn calculate_shared_secret_key(&self) -> Option<[u8; 32]> {
+        // Fetch own identity key and the identity key of the other device in Montgomery form
+        let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.my_ID));
+        let pubkey = peer_registry::get_key_agreement_public_key(self.other_ID,
+                                                                 peer_registry::KeyAgreementProtocol::X25519,
+                                                                 self.key_size)?;
+        let my_static = Scalar::from_bytes_mod_order(privkey);
+        let other_static = schnorr_identification::bytes_to_edwards(&pubkey).to_montgomery();
+        let other_ephemeral = MontgomeryPoint(self.other_ephemeral_public);
+
+        // Diffie-Hellman values in the same order on both sides:
+        // ephemeral-ephemeral, initiator ephemeral with receiver static, initiator static with receiver ephemeral
+        let ee = (other_ephemeral * self.my_ephemeral).to_bytes();
+        let (es, se) = if self.role == CONST_INITIATOR_ROLE {
+            ((other_static * self.my_ephemeral).to_bytes(), (other_ephemeral * my_static).to_bytes())
+        } else {
+            ((other_ephemeral * my_static).to_bytes(), (other_static * self.my_ephemeral).to_bytes())
+        };
+
+        // Reject low order points, which result in an all-zero value
+        if ee == [0u8; 32] || es == [0u8; 32] || se == [0u8; 32] {
+            println!("Received an invalid ephemeral key from {}!\n", self.other_ID);
+            return None;
+        }
+
+        let transcript_hash = self.transcript_hash();
+        Some(schnorr_identification::sha3_256(&ee, Some(&es), Some(&se), Some(&transcript_hash)))
+    }
+
+    // Calculate the tag that proves that a role derived the shared secret key
+    fn gen_key_confirmation(&self, key: [u8; 32], role: u8) -> [u8; 32] {
+        schnorr_identification::kmac_256(key,
+                                         CONST_X25519_CONFIRMATION_LABEL,
+                                         Some(&[role]),
+                                         Some(&self.transcript_hash()))
+    }
+
+    // Check the received ephemeral key and derive the shared secret key from it
+    fn add_other_ephemeral(&mut self, ephemeral: &[u8]) -> bool {
+        self.other_ephemeral_public = <[u8; 32]>::try_from(ephemeral).unwrap();
+
+        // An ephemeral key must never be used twice, to protect against replay attacks
+        if !file_management::check_commitment(self.other_ID, self.other_ephemeral_public) {
+            return false;
+        }
+
+        self.pending_shared_key = self.calculate_shared_secret_key();
+        self.pending_shared_key.is_some()
+    }
+
+    // Verify the confirmation tag of the other device and store the shared secret key if it matches
+    fn confirm_shared_secret_key(&mut self, tag: &[u8]) -> bool {
+        let key = match self.pending_shared_key {
+            Some(key) => key,
+            None => {
+                return false;
+            }
+        };
+
+        let other_role = if self.role == CONST_INITIATOR_ROLE {
+            CONST_RECEIVER_ROLE
+        } else {
+            CONST_INITIATOR_ROLE
+        };
+        if self.gen_key_confirmation(key, other_role)[..] != tag[..] {
+            println!("Key confirmation of {} failed, shared secret key is discarded!\n", self.other_ID);
+            self.pending_shared_key = None;
+            return false;
+        }
+
+        // Both sides hold the same key, commit it to the OS
+        let key_len = self.key_size.bytes();
+        self.previous_shared_values = store_shared_values(self.my_ID, self.other_ID, &key[..key_len]);
+        self.key_committed = true;
+        true
+    }
+}
+
+impl KeyAgreement for X25519KeyAgreement {
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let mut message = Vec::with_capacity(66);
+        match self.stage {
+            CONST_STAGE_SEND_EPHEMERAL => {
+                // Propose the key size together with the ephemeral key
+                message.push(CONST_MSG_EPHEMERAL);
+                message.push(self.key_size.bytes() as u8);
+                message.extend_from_slice(&self.my_ephemeral_public);
+            },
+            CONST_STAGE_SEND_EPHEMERAL_AND_CONFIRMATION => {
+                message.push(CONST_MSG_EPHEMERAL_AND_CONFIRMATION);
+                message.extend_from_slice(&self.my_ephemeral_public);
+                message.extend_from_slice(&self.gen_key_confirmation(self.pending_shared_key.unwrap(), self.role));
+            },
+            CONST_STAGE_SEND_CONFIRMATION => {
+                message.push(CONST_MSG_CONFIRMATION);
+                message.extend_from_slice(&self.gen_key_confirmation(self.pending_shared_key.unwrap(), self.role));
+            },
+            CONST_STAGE_SEND_FAILED => {
+                // Tell the other device to discard the shared secret key, signed over its ephemeral key
+                let signature = sign_abort(self.my_ID, self.other_ID, &self.other_ephemeral_public);
+                message.push(CONST_MSG_FAILED);
+                message.extend_from_slice(&signature.0);
+                message.extend_from_slice(&signature.1);
+            },
+            _ => {
+                return None;
+            }
+        }
+
+        self.stage = CONST_STAGE_WAIT;
+        Some(message)
+    }
+
+    fn receive_message(&mut self, message: &[u8]) -> bool {
+        let accepted = match (self.role, message.first().copied(), message.len()) {
+            // Receiver got the ephemeral key and the proposed key size of the initiator
+            (CONST_RECEIVER_ROLE, Some(CONST_MSG_EPHEMERAL), 34) => {
+                // Keep the ephemeral key even if the key size is rejected, so that the abort can be bound to it
+                self.other_ephemeral_public = <[u8; 32]>::try_from(&message[2..34]).unwrap();
+                match KeySize::from_bytes(message[1] as usize) {
+                    Some(key_size) if key_size >= self.key_size => {
+                        self.key_size = key_size;
+                        self.add_other_ephemeral(&message[2..34])
+                    },
+                    _ => {
+                        println!("Key size proposed by {} is not accepted!\n", self.other_ID);
+                        false
+                    }
+                }
+            },
+            // Initiator got the ephemeral key and the confirmation tag of the receiver
+            (CONST_INITIATOR_ROLE, Some(CONST_MSG_EPHEMERAL_AND_CONFIRMATION), 65) => {
+                self.add_other_ephemeral(&message[1..33]) && self.confirm_shared_secret_key(&message[33..65])
+            },
+            // Receiver got the confirmation tag of the initiator
+            (CONST_RECEIVER_ROLE, Some(CONST_MSG_CONFIRMATION), 33) => {
+                self.confirm_shared_secret_key(&message[1..33])
+            },
+            // Other device discarded the key, so do the same if it signed the abort
+            (_, Some(CONST_MSG_FAILED), 65) => {
+                let signature = (<[u8; 32]>::try_from(&message[1..33]).unwrap(), <[u8; 32]>::try_from(&message[33..65]).unwrap());
+                if !verify_abort(self.my_ID, self.other_ID, &self.my_ephemeral_public, signature) {
+                    println!("Abort of {} is not authenticated and is ignored!\n", self.other_ID);
+                    return false;
+                }
+                self.rollback_shared_secret_key();
+                self.stage = CONST_STAGE_WAIT;
+                return false;
+            },
+            _ => false,
+        };
+
+        // Define the next message
+        self.stage = match (accepted, self.role, self.key_committed) {
+            // A committed key is only discarded by a signed abort, never by an unexpected message
+            (false, _, true) => CONST_STAGE_WAIT,
+            (false, _, false) => CONST_STAGE_SEND_FAILED,
+            (true, CONST_RECEIVER_ROLE, false) => CONST_STAGE_SEND_EPHEMERAL_AND_CONFIRMATION,
+            (true, CONST_INITIATOR_ROLE, true) => CONST_STAGE_SEND_CONFIRMATION,
+            _ => CONST_STAGE_WAIT,
+        };
+
+        accepted
+    }
+
+    fn is_complete(&self) -> bool {
+        self.key_committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Run the key agreement between two instances in memory. Messages are passed through tamper before delivery
+    fn run(initiator: &mut dyn KeyAgreement, receiver: &mut dyn KeyAgreement, tamper: &mut dyn FnMut(&mut Vec<u8>)) {
+        loop {
+            let mut sent = false;
+            if let Some(mut message) = initiator.next_message() {
+                tamper(&mut message);
+                receiver.receive_message(&message);
+                sent = true;
+            }
+            if let Some(mut message) = receiver.next_message() {
+                tamper(&mut message);
+                initiator.receive_message(&message);
+                sent = true;
+            }
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    // Two devices that know the public key of each other
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        (AID, BID)
+    }
+
+    fn shared_values(my_ID: DeviceId, other_ID: DeviceId) -> (Vec<u8>, Vec<u8>) {
+        (crate::get_shared_key(my_ID, other_ID).0, crate::get_shared_counter(my_ID, other_ID).0.to_vec())
+    }
+
+    // Flip a bit of the confirmation tag in messages of a type
+    fn tamper_tag(message_type: u8) -> impl FnMut(&mut Vec<u8>) {
+        move |message| {
+            if message[0] == message_type {
+                let last = message.len() - 1;
+                message[last] ^= 1;
+            }
+        }
+    }
+
+    #[test]
+    fn agrees_on_shared_values_usable_for_proofs() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(29001, 29002);
+            let mut a_x25519 = X25519KeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits128);
+            let mut b_x25519 = X25519KeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits128);
+            run(&mut a_x25519, &mut b_x25519, &mut |_| {});
+
+            assert!(a_x25519.is_complete() && b_x25519.is_complete());
+            let (a_key, a_counter) = shared_values(AID, BID);
+            assert_eq!(a_key.len(), 16);
+            assert_eq!((a_key, a_counter), shared_values(BID, AID));
+
+            let proof = crate::gen_nizk_proof(AID, BID, String::from("message"), true);
+            assert!(crate::verify_nizk_proof(BID, AID, String::from("message"), proof, true));
+        });
+    }
+
+    #[test]
+    fn tampered_initiator_confirmation_rolls_back() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(29003, 29004);
+            let mut a_x25519 = X25519KeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits256);
+            let mut b_x25519 = X25519KeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits256);
+
+            // The initiator stores the key before it sends its tag, the signed abort of the receiver removes it again
+            run(&mut a_x25519, &mut b_x25519, &mut tamper_tag(CONST_MSG_CONFIRMATION));
+
+            assert!(!a_x25519.is_complete() && !b_x25519.is_complete());
+            assert!(crate::get_key_instance(&format!("SharedSecretKey:{}:{}", AID, BID), 32, None).is_err());
+            assert!(crate::get_key_instance(&format!("SharedSecretKey:{}:{}", BID, AID), 32, None).is_err());
+        });
+    }
+
+    #[test]
+    fn tampered_confirmation_keeps_previous_key() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(29005, 29006);
+            let mut a_x25519 = X25519KeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits256);
+            let mut b_x25519 = X25519KeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits256);
+            run(&mut a_x25519, &mut b_x25519, &mut |_| {});
+            let (a_values, b_values) = (shared_values(AID, BID), shared_values(BID, AID));
+
+            for message_type in [CONST_MSG_EPHEMERAL_AND_CONFIRMATION, CONST_MSG_CONFIRMATION] {
+                let mut a_x25519 = X25519KeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits256);
+                let mut b_x25519 = X25519KeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits256);
+                run(&mut a_x25519, &mut b_x25519, &mut tamper_tag(message_type));
+
+                assert!(!a_x25519.is_complete() && !b_x25519.is_complete());
+                assert_eq!(shared_values(AID, BID), a_values);
+                assert_eq!(shared_values(BID, AID), b_values);
+            }
+        });
+    }
+}
@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::enrollment::{self, DeviceCertificate, EnrollmentErrors};
+use crate::peer_registry::{PeerRegistry, PeerStatus};
+use crate::{get_32byte_key, get_key_instance, revocation, schnorr_identification};
+use crate::DeviceId;
+
+#[derive(Debug)]
+pub enum KeyRotationErrors {
+    PeerNotFound,
+    PeerNotActive,
+    OldKeyMismatch,
+    InvalidSignature,
+    NewKeyRevoked,
+    DeviceIdMismatch,
+    CertificateRequired,
+    InvalidCertificate(EnrollmentErrors),
+    InvalidPeerRegistry,
+    NoPendingRotation,
+    UnableToStoreKey,
+}
+
+// Announcement of a new public key. It is signed with the old and the new private key,
+// so it proves possession of both in one message. Devices enrolled by the authority also
+// have to attach a certificate of the authority for the new key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeyRotation {
+    pub device_ID: DeviceId,
+    pub old_public_key: [u8; 32],
+    pub new_public_key: [u8; 32],
+    pub renew_shared_key: bool,
+    pub old_key_signature: ([u8; 32], [u8; 32]),
+    pub new_key_signature: ([u8; 32], [u8; 32]),
+    #[serde(default)]
+    pub certificate: Option<DeviceCertificate>,
+}
+
+impl KeyRotation {
+    // Data signed with both keys
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"KeyRotation"[..]);
+        data.extend_from_slice(&self.device_ID.to_bytes());
+        data.extend_from_slice(&self.old_public_key);
+        data.extend_from_slice(&self.new_public_key);
+        data.push(self.renew_shared_key as u8);
+        data
+    }
+}
+
+// Generate a new key pair and return the announcement for all peers.
+// If renew_shared_key is set, peers are asked to run a new shared key agreement.
+// The new private key is kept aside and the old one stays in use, so that peers which have not applied
+// the announcement yet still accept the proofs of this device. Call complete_key_rotation once all peers
+// applied it, before their grace period ends, and renew the shared keys only after that
+pub fn rotate_key(my_ID: DeviceId, renew_shared_key: bool) -> Result<KeyRotation, KeyRotationErrors> {
+    // Fetch old private key and generate the new key pair
+    let (old_private_key, _) = get_32byte_key(format!("PrivateKey:{}", my_ID));
+    let old_public_key = schnorr_identification::public_key(old_private_key);
+    let (new_public_key, new_private_key) = schnorr_identification::key_gen();
+    if revocation::is_revoked(my_ID, &new_public_key) {
+        println!("New key of device {} is revoked!\n", my_ID);
+        return Err(KeyRotationErrors::NewKeyRevoked);
+    }
+
+    // Sign announcement with both keys
+    let mut rotation = KeyRotation {
+        device_ID: my_ID,
+        old_public_key,
+        new_public_key,
+        renew_shared_key,
+        old_key_signature: ([0u8; 32], [0u8; 32]),
+        new_key_signature: ([0u8; 32], [0u8; 32]),
+        certificate: None,
+    };
+    let data = rotation.signed_data();
+    rotation.old_key_signature = schnorr_identification::sign(old_private_key, &data);
+    rotation.new_key_signature = schnorr_identification::sign(new_private_key, &data);
+
+    // Keep the new private key until the rotation is completed. A pending key of an earlier rotation is replaced
+    let description = format!("PendingPrivateKey:{}", my_ID);
+    let mut pending_key = get_key_instance(&description, 32, Some(new_private_key.to_vec()))
+        .map_err(|_| KeyRotationErrors::UnableToStoreKey)?;
+    if pending_key.get_key() != &new_private_key.to_vec() {
+        pending_key.update_key_in_ring(new_private_key.to_vec()).map_err(|_| KeyRotationErrors::UnableToStoreKey)?;
+    }
+
+    Ok(rotation)
+}
+
+// Use the new private key of a rotation from now on. The peers have to have applied the rotation,
+// otherwise they reject all proofs of this device
+pub fn complete_key_rotation(my_ID: DeviceId, rotation: &KeyRotation) -> Result<(), KeyRotationErrors> {
+    let pending_key = get_key_instance(&format!("PendingPrivateKey:{}", my_ID), 32, None)
+        .map_err(|_| KeyRotationErrors::NoPendingRotation)?;
+    let new_private_key = <[u8; 32]>::try_from(pending_key.get_key().as_slice())
+        .map_err(|_| KeyRotationErrors::NoPendingRotation)?;
+    let (old_private_key, mut key_instance) = get_32byte_key(format!("PrivateKey:{}", my_ID));
+
+    // Only the pending rotation of the current key can be completed
+    if rotation.device_ID != my_ID || schnorr_identification::public_key(new_private_key) != rotation.new_public_key {
+        println!("Key rotation of device {} is not the pending one!\n", my_ID);
+        return Err(KeyRotationErrors::NoPendingRotation);
+    }
+    if schnorr_identification::public_key(old_private_key) != rotation.old_public_key {
+        return Err(KeyRotationErrors::OldKeyMismatch);
+    }
+    if revocation::is_revoked(my_ID, &rotation.new_public_key) {
+        return Err(KeyRotationErrors::NewKeyRevoked);
+    }
+
+    key_instance.update_key_in_ring(new_private_key.to_vec()).map_err(|_| KeyRotationErrors::UnableToStoreKey)?;
+    let _ = pending_key.delete_key_from_ring();
+    Ok(())
+}
+
+// Check a key rotation of a peer and replace its public key in the peer registry.
+// The old key is still accepted for NIZK proofs during the grace period in ms.
+// Returns true if the peer asked for a new shared key agreement
+pub fn apply_key_rotation(rotation: &KeyRotation, grace_period: i64) -> Result<bool, KeyRotationErrors> {
+    let mut registry = PeerRegistry::load().map_err(|_| KeyRotationErrors::InvalidPeerRegistry)?;
+    let mut entry = match registry.get_peer(rotation.device_ID) {
+        Some(peer) => peer.clone(),
+        None => {
+            return Err(KeyRotationErrors::PeerNotFound);
+        }
+    };
+    if entry.status != PeerStatus::Active {
+        return Err(KeyRotationErrors::PeerNotActive);
+    }
+
+    // Only the current key can be rotated, which also rejects replayed rotations
+    if entry.public_key != rotation.old_public_key {
+        println!("Key rotation of device {} does not start from its current key!\n", rotation.device_ID);
+        return Err(KeyRotationErrors::OldKeyMismatch);
+    }
+
+    // Device has to prove possession of both private keys
+    let data = rotation.signed_data();
+    if !schnorr_identification::verify_signature(rotation.old_public_key, &data, rotation.old_key_signature) ||
+        !schnorr_identification::verify_signature(rotation.new_public_key, &data, rotation.new_key_signature) {
+        println!("Key rotation of device {} has an invalid signature!\n", rotation.device_ID);
+        return Err(KeyRotationErrors::InvalidSignature);
+    }
+    if revocation::is_revoked(rotation.device_ID, &rotation.new_public_key) {
+        return Err(KeyRotationErrors::NewKeyRevoked);
+    }
+
+    // Fingerprint IDs are derived from a key, so they can not move to another key
+    if !rotation.device_ID.matches_public_key(&rotation.new_public_key) {
+        println!("Device ID {} does not belong to the new key!\n", rotation.device_ID);
+        return Err(KeyRotationErrors::DeviceIdMismatch);
+    }
+
+    // Keys certified by the authority can only be replaced by another certified key
+    match &rotation.certificate {
+        Some(certificate) => {
+            if certificate.device_ID != rotation.device_ID || certificate.public_key != rotation.new_public_key {
+                println!("Certificate of the key rotation of device {} is not issued for the new key!\n", rotation.device_ID);
+                return Err(KeyRotationErrors::InvalidCertificate(EnrollmentErrors::DeviceIdMismatch));
+            }
+            enrollment::verify_certificate(certificate).map_err(KeyRotationErrors::InvalidCertificate)?;
+        },
+        None if entry.certificate.is_some() => {
+            println!("Key rotation of the enrolled device {} has no certificate for the new key!\n", rotation.device_ID);
+            return Err(KeyRotationErrors::CertificateRequired);
+        },
+        None => {},
+    }
+
+    // Replace the key and its certificate in one registry update
+    entry.previous_public_key = Some(entry.public_key);
+    entry.previous_key_valid_until = Utc::now().timestamp_millis() + grace_period;
+    entry.public_key = rotation.new_public_key;
+    entry.certificate = rotation.certificate.clone();
+    registry.update_peer(entry).unwrap();
+    registry.save().map_err(|_| KeyRotationErrors::InvalidPeerRegistry)?;
+
+    Ok(rotation.renew_shared_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Device whose own key pair and peer entry are stored in the working directory
+    fn device(ID: u32) -> DeviceId {
+        let device_ID = DeviceId::from(ID);
+        test_support::add_peer(device_ID, test_support::store_key_pair(device_ID));
+        device_ID
+    }
+
+    fn peer_public_key(device_ID: DeviceId) -> [u8; 32] {
+        PeerRegistry::load().unwrap().get_peer(device_ID).unwrap().public_key
+    }
+
+    #[test]
+    fn rotation_signed_by_both_keys_is_accepted() {
+        test_support::in_empty_work_dir(|| {
+            let AID = device(34001);
+            let rotation = rotate_key(AID, true).unwrap();
+
+            assert!(apply_key_rotation(&rotation, 60_000).unwrap());
+            assert_eq!(peer_public_key(AID), rotation.new_public_key);
+            complete_key_rotation(AID, &rotation).unwrap();
+            let (private_key, _) = get_32byte_key(format!("PrivateKey:{}", AID));
+            assert_eq!(schnorr_identification::public_key(private_key), rotation.new_public_key);
+        });
+    }
+
+    #[test]
+    fn rotation_without_old_key_signature_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let AID = device(34011);
+            let mut rotation = rotate_key(AID, false).unwrap();
+            let old_public_key = rotation.old_public_key;
+
+            // Signed with the new key only
+            rotation.old_key_signature = rotation.new_key_signature;
+            assert!(matches!(apply_key_rotation(&rotation, 60_000), Err(KeyRotationErrors::InvalidSignature)));
+            assert_eq!(peer_public_key(AID), old_public_key);
+        });
+    }
+
+    #[test]
+    fn replayed_rotation_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let AID = device(34021);
+            let first = rotate_key(AID, false).unwrap();
+            apply_key_rotation(&first, 60_000).unwrap();
+            assert!(matches!(apply_key_rotation(&first, 60_000), Err(KeyRotationErrors::OldKeyMismatch)));
+
+            // Also after the next rotation, the first one must not bring back its key
+            complete_key_rotation(AID, &first).unwrap();
+            let second = rotate_key(AID, false).unwrap();
+            apply_key_rotation(&second, 60_000).unwrap();
+            assert!(matches!(apply_key_rotation(&first, 60_000), Err(KeyRotationErrors::OldKeyMismatch)));
+            assert_eq!(peer_public_key(AID), second.new_public_key);
+        });
+    }
+}
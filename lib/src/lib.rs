@@ -5,8 +5,34 @@ use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
 use curve25519_dalek::scalar::Scalar;
 use std::thread;
 use crate::secret_management::MyKey;
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
 pub mod file_management;
 pub mod access_control;
+pub mod acl_store;
+pub mod key_agreement;
+pub mod enrollment;
+pub mod peer_registry;
+pub mod revocation;
+pub mod key_rotation;
+pub mod pake_pairing;
+pub mod device_id;
+pub mod key_formats;
+pub mod backup;
+pub mod credentials;
+pub mod authorization;
+pub mod acl_management;
+pub mod acl_policy;
+pub mod delegation;
+pub mod audit_log;
+pub mod clock;
+pub mod resource_path;
+pub use device_id::DeviceId;
+pub use resource_path::ResourcePath;
+#[cfg(feature = "noise")]
+pub mod noise_key_agreement;
+#[cfg(test)]
+mod test_support;
 
 // Constants for defining a role of a protocol initiator or a receiver.
 pub const CONST_INITIATOR_ROLE: u8 = 0;
@@ -18,34 +44,80 @@ const CONST_COMMITMENT: u8 = 1;
 const CONST_COMMITMENT_AND_CHALLENGE: u8 = 2;
 const CONST_CHALLENGE_AND_RESPONSE: u8 = 3;
 const CONST_RESPONSE: u8 = 4;
+const CONST_KEY_CONFIRMATION: u8 = 5;
+const CONST_KEY_CONFIRMATION_FAILED: u8 = 6;
 
 // For knowing that the response can be verified or not
 pub const CONST_RESPONSE_CANNOT_BE_VERIFIED: u8 = 0;
 pub const CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE: u8 = 1;
 pub const CONST_RESPONSE_CAN_BE_VERIFIED: u8 = 2;
 pub const CONST_RECEIVED_WRONG_REQUEST_ID: u8 = 3;
+pub const CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED: u8 = 4;
+pub const CONST_KEY_CONFIRMATION_ROLLED_BACK: u8 = 5;
+pub const CONST_ABORT_NOT_AUTHENTICATED: u8 = 6;
+pub const CONST_KEY_SIZE_REJECTED: u8 = 7;
+
+// Labels of the key confirmation tags and of the transcript hash
+const CONST_KEY_CONFIRMATION_LABEL: &[u8] = b"IntMutAuth key confirmation";
+const CONST_TRANSCRIPT_LABEL: &[u8] = b"IntMutAuth transcript";
+const CONST_SAS_LABEL: &[u8] = b"IntMutAuth short authentication string";
+const CONST_CHALLENGE_COMMITMENT_LABEL: &[u8] = b"IntMutAuth challenge commitment";
+
+// Label of the signed message that aborts a key agreement
+const CONST_ABORT_LABEL: &[u8] = b"KeyAgreement abort";
 
 // For checking if no other values needs to be generated
 pub const CONST_NO_OTHER_VALUES_TO_GENERATE: u8 = 0;
 pub const CONST_NEXT_VALUES_HAS_TO_BE_GENERATED: u8 = 1;
 
+// Size of the shared secret key. It defines the KMAC variant of the challenge and the ratchet output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum KeySize {
+    Bits128,
+    Bits256,
+}
+
+impl KeySize {
+    // Number of bytes of a key with this size
+    pub fn bytes(&self) -> usize {
+        match self {
+            KeySize::Bits128 => 16,
+            KeySize::Bits256 => 32,
+        }
+    }
+
+    // Key size of a stored key, derived from its length
+    pub fn from_bytes(bytes: usize) -> Option<KeySize> {
+        match bytes {
+            16 => Some(KeySize::Bits128),
+            32 => Some(KeySize::Bits256),
+            _ => None,
+        }
+    }
+}
+
 // Return an instance of MyKey of the key corresponding to the key description
 pub fn get_key_instance(key_description: &str, key_size: usize, key: Option<Vec<u8>>) -> Result<secret_management::MyKey, secret_management::SecretKeyErrors> {
-    let my_key = secret_management::MyKey::new(key_description, 32, key);
+    let my_key = secret_management::MyKey::new(key_description, key_size, key);
     my_key
 }
 
-pub fn get_int_mut_auth_instance(sender_ID: u32, recipient_ID: u32, role: u8) -> IntMutAuth {
+pub fn get_int_mut_auth_instance(sender_ID: DeviceId, recipient_ID: DeviceId, role: u8) -> IntMutAuth {
     let mut ins = IntMutAuth::new(sender_ID, recipient_ID, role);
     ins
 }
 
-pub fn get_int_schnorr_prover_instance(my_ID: u32, recipient_ID: u32) -> IntSchnorrProver {
+// The initiator proposes key_size, the receiver accepts any proposed size not smaller than key_size
+pub fn get_int_mut_auth_instance_with_key_size(sender_ID: DeviceId, recipient_ID: DeviceId, role: u8, key_size: KeySize) -> IntMutAuth {
+    IntMutAuth::new_with_key_size(sender_ID, recipient_ID, role, key_size)
+}
+
+pub fn get_int_schnorr_prover_instance(my_ID: DeviceId, recipient_ID: DeviceId) -> IntSchnorrProver {
     let mut ins = IntSchnorrProver::new(my_ID, recipient_ID);
     ins
 }
 
-pub fn get_int_schnorr_verifier_instance(my_ID: u32, sender_ID: u32, commitment: [u8; 32]) -> IntSchnorrVerifier {
+pub fn get_int_schnorr_verifier_instance(my_ID: DeviceId, sender_ID: DeviceId, commitment: [u8; 32]) -> IntSchnorrVerifier {
     let mut ins = IntSchnorrVerifier::new(my_ID, sender_ID, commitment);
     ins
 }
@@ -71,9 +143,10 @@ pub fn gen_random_key_pair() -> ([u8; 32], [u8; 32]) {
 
 // Struct for interactive mutual authentication for secret key sharing
 pub struct IntMutAuth {
-    pub sender_ID: u32,
-    pub recipient_ID: u32,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
     pub role: u8,
+    pub key_size: KeySize,
     stage: u8,
     my_random_int: Scalar,
     my_commitment: [u8; 32],
@@ -82,11 +155,24 @@ pub struct IntMutAuth {
     recipient_commitment: [u8; 32],
     recipient_challenge: Scalar,
     recipient_response: [u8; 32],
+    recipient_confirmation: [u8; 32],
+    recipient_challenge_commitment: Option<[u8; 32]>,
+    pending_shared_key: Option<[u8; 32]>,
+    confirmation_sent: bool,
+    key_committed: bool,
+    previous_shared_values: Option<(Vec<u8>, Vec<u8>)>,
+    sas_required: bool,
+    sas_pending: bool,
 }
 
 impl IntMutAuth {
-    // Create a new instance of Int_mut_auth
-    pub fn new(sender_ID: u32, recipient_ID: u32, role: u8) -> IntMutAuth {
+    // Create a new instance of Int_mut_auth with a 256-bit shared secret key
+    pub fn new(sender_ID: DeviceId, recipient_ID: DeviceId, role: u8) -> IntMutAuth {
+        IntMutAuth::new_with_key_size(sender_ID, recipient_ID, role, KeySize::Bits256)
+    }
+
+    // Create a new instance of Int_mut_auth. For the receiver, key_size is the smallest accepted key size
+    pub fn new_with_key_size(sender_ID: DeviceId, recipient_ID: DeviceId, role: u8, key_size: KeySize) -> IntMutAuth {
         // Generate random secret scalar and Commitment
         let my_random_int = schnorr_identification::generate_random_scalar();
         let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
@@ -109,6 +195,7 @@ impl IntMutAuth {
             sender_ID,
             recipient_ID,
             role,
+            key_size,
             stage,
             my_random_int,
             my_commitment,
@@ -117,6 +204,14 @@ impl IntMutAuth {
             recipient_commitment,
             recipient_challenge,
             recipient_response,
+            recipient_confirmation: [0u8; 32],
+            recipient_challenge_commitment: None,
+            pending_shared_key: None,
+            confirmation_sent: false,
+            key_committed: false,
+            previous_shared_values: None,
+            sas_required: false,
+            sas_pending: false,
         };
 
         // Return
@@ -131,7 +226,24 @@ impl IntMutAuth {
             },
             CONST_COMMITMENT => {
                 self.recipient_commitment = val1;
-                CONST_RESPONSE_CANNOT_BE_VERIFIED
+                self.recipient_challenge_commitment = val2;
+
+                // Initiator proposes the key size with its commitment. Initiators without a proposal use 256 bits
+                let proposed = match val2 {
+                    Some(size) => KeySize::from_bytes(size[0] as usize),
+                    None => Some(KeySize::Bits256),
+                };
+                match proposed {
+                    Some(key_size) if key_size >= self.key_size => {
+                        self.key_size = key_size;
+                        CONST_RESPONSE_CANNOT_BE_VERIFIED
+                    },
+                    _ => {
+                        println!("Key size proposed by {} is not accepted!\n", self.recipient_ID);
+                        self.stage = CONST_KEY_CONFIRMATION_FAILED;
+                        CONST_KEY_SIZE_REJECTED
+                    }
+                }
             },
             CONST_COMMITMENT_AND_CHALLENGE => {
                 self.recipient_commitment = val1;
@@ -147,6 +259,23 @@ impl IntMutAuth {
                 self.recipient_response = val1;
                 CONST_RESPONSE_CAN_BE_VERIFIED
             },
+            CONST_KEY_CONFIRMATION => {
+                self.recipient_confirmation = val1;
+                CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED
+            },
+            CONST_KEY_CONFIRMATION_FAILED => {
+                // Only the recipient itself can make us discard the key, the abort is signed over our commitment
+                if !verify_abort(self.sender_ID, self.recipient_ID, &self.my_commitment, (val1, val2.unwrap_or([0u8; 32]))) {
+                    println!("Abort of {} is not authenticated and is ignored!\n", self.recipient_ID);
+                    return CONST_ABORT_NOT_AUTHENTICATED;
+                }
+
+                // Recipient could not confirm our key or rejected the codes, so undo everything we stored
+                self.rollback_shared_secret_key();
+                self.pending_shared_key = None;
+                self.sas_pending = false;
+                CONST_KEY_CONFIRMATION_ROLLED_BACK
+            },
             _ => {
                 CONST_RECEIVED_WRONG_REQUEST_ID
             },
@@ -157,9 +286,19 @@ impl IntMutAuth {
     pub fn gen_next_values(&mut self) -> ([u8; 32], Option<[u8; 32]>, u8) {
         match self.stage {
             CONST_COMMITMENT => {
+                // Our challenge is chosen now, so that it does not depend on the values of the recipient
+                let challenge = schnorr_identification::generate_random_32bytes();
+                self.my_challenge = Scalar::from_bytes_mod_order(challenge);
+
+                // Propose the key size in the first byte of the second value. The other bytes commit to
+                // our commitment and challenge, so that nobody can choose them to get a certain code
+                let mut key_size = [0u8; 32];
+                key_size[0] = self.key_size.bytes() as u8;
+                key_size[1..].copy_from_slice(&challenge_commitment(&self.my_commitment, self.my_challenge.as_bytes())[..31]);
+
                 // Define next stage and return Commitment
                 self.stage = CONST_CHALLENGE_AND_RESPONSE;
-                (self.my_commitment, None, CONST_COMMITMENT)
+                (self.my_commitment, Some(key_size), CONST_COMMITMENT)
             },
             CONST_COMMITMENT_AND_CHALLENGE => {
                 // Generate Challenge
@@ -171,20 +310,43 @@ impl IntMutAuth {
                 (self.my_commitment, Some(challenge), CONST_COMMITMENT_AND_CHALLENGE)
             },
             CONST_CHALLENGE_AND_RESPONSE => {
-                // Generate Challenge
-                let challenge = schnorr_identification::generate_random_32bytes();
-                self.my_challenge = Scalar::from_bytes_mod_order(challenge);
+                // Reveal the challenge we committed to with our commitment
+                let challenge = self.my_challenge.to_bytes();
 
                 // Calculate response
                 let response = self.gen_proof();
                 self.my_response = response;
+
+                // Key confirmation follows once the proof of the recipient is verified
+                self.stage = CONST_KEY_CONFIRMATION;
                 (challenge, Some(response), CONST_CHALLENGE_AND_RESPONSE)
             },
             CONST_RESPONSE => {
                 let response = self.gen_proof();
                 self.my_response = response;
+
+                // Key confirmation follows once the proof of the recipient is verified
+                self.stage = CONST_KEY_CONFIRMATION;
                 (response, None, CONST_RESPONSE)
             },
+            CONST_KEY_CONFIRMATION => {
+                // Confirmation tag can only be sent once, after the proof of the recipient is accepted
+                match self.pending_shared_key {
+                    Some(key) if !self.confirmation_sent => {
+                        self.confirmation_sent = true;
+                        (self.gen_key_confirmation(key, self.role), None, CONST_KEY_CONFIRMATION)
+                    },
+                    _ => {
+                        (self.my_commitment, None, CONST_NEXT_STEP_REQUIRED)
+                    },
+                }
+            },
+            CONST_KEY_CONFIRMATION_FAILED => {
+                // Inform the recipient that it has to discard the shared secret key
+                self.stage = CONST_NEXT_STEP_REQUIRED;
+                let signature = sign_abort(self.sender_ID, self.recipient_ID, &self.recipient_commitment);
+                (signature.0, Some(signature.1), CONST_KEY_CONFIRMATION_FAILED)
+            },
             _ => {
                 (self.my_commitment, None, CONST_NEXT_STEP_REQUIRED)
             },
@@ -200,79 +362,328 @@ impl IntMutAuth {
         let secret_key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
         let secret_key_sc = Scalar::from_bytes_mod_order(secret_key_bytes);
 
+        // Bind the challenge of the recipient to this session
+        let challenge = schnorr_identification::bind_challenge(self.sender_ID,
+                                                               self.recipient_ID,
+                                                               &self.my_commitment,
+                                                               &self.recipient_commitment,
+                                                               &self.recipient_challenge);
+
         // Generate Proof
         let proof = schnorr_identification::generate_proof_response(self.my_random_int,
                                                                     secret_key_sc,
-                                                                    self.recipient_challenge);
+                                                                    challenge);
         // Return Proof
         proof
     }
 
-    // Verify proof
-    pub fn verify_proof(&self) -> bool {
+    // Verify proof. The shared secret key is only calculated here, it is stored after key confirmation
+    pub fn verify_proof(&mut self) -> bool {
         // Check if commitment is never used to protect against replay attacks
         if !file_management::check_commitment(self.recipient_ID, self.recipient_commitment) {
+            self.stage = CONST_KEY_CONFIRMATION_FAILED;
+            audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted: false });
             return false;
         }
 
-        // Fetch Public Key of the recipient
-        let desciption = format!("PublicKey:{}", &self.recipient_ID);
-        let recipient_pubkey = get_key_instance(&desciption, 32, None).unwrap();
+        // Fetch Public Key of the recipient, if its policy allows this key agreement
+        let key_bytes = match peer_registry::get_key_agreement_public_key(self.recipient_ID,
+                                                                          peer_registry::KeyAgreementProtocol::IntMutAuth,
+                                                                          self.key_size) {
+            Some(key_bytes) => key_bytes,
+            None => {
+                self.stage = CONST_KEY_CONFIRMATION_FAILED;
+                audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted: false });
+                return false;
+            }
+        };
 
-        // Convert Key into [u8; 32] format
-        let key: &[u8] = recipient_pubkey.get_key();
-        let key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
+        // During pairing, the initiator has to reveal the challenge it committed to in its first message
+        if self.sas_required && self.role == CONST_RECEIVER_ROLE && !self.check_challenge_commitment() {
+            println!("Challenge of {} does not match its commitment!\n", self.recipient_ID);
+            self.stage = CONST_KEY_CONFIRMATION_FAILED;
+            audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted: false });
+            return false;
+        }
 
-        // Verify proof
-        let proof = (self.recipient_commitment, self.my_challenge, self.recipient_response);
+        // Verify proof against our challenge, bound to this session
+        let challenge = schnorr_identification::bind_challenge(self.recipient_ID,
+                                                               self.sender_ID,
+                                                               &self.recipient_commitment,
+                                                               &self.my_commitment,
+                                                               &self.my_challenge);
+        let proof = (self.recipient_commitment, challenge, self.recipient_response);
         let accepted = schnorr_identification::verify_int_proof(key_bytes, proof);
 
-        // Calculate the shared secret key
+        // Calculate the shared secret key, or tell the recipient to discard it
         if accepted == true {
-            self.calculate_shared_secret_key();
+            self.pending_shared_key = Some(self.calculate_shared_secret_key());
+        } else {
+            self.stage = CONST_KEY_CONFIRMATION_FAILED;
         }
+        audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted });
 
         // Return verification results
         accepted
     }
 
-    fn calculate_shared_secret_key(&self) {
+    // Check the challenge of the initiator against the commitment of its first message
+    fn check_challenge_commitment(&self) -> bool {
+        match self.recipient_challenge_commitment {
+            Some(commitment) => {
+                let expected = challenge_commitment(&self.recipient_commitment, self.recipient_challenge.as_bytes());
+                commitment[1..] == expected[..31]
+            },
+            None => false,
+        }
+    }
+
+    // Verify the key confirmation tag of the recipient and store the shared secret key if it matches
+    pub fn confirm_shared_secret_key(&mut self) -> bool {
+        // Proof of the recipient has to be accepted first
+        let key = match self.pending_shared_key {
+            Some(key) => key,
+            None => {
+                return false;
+            }
+        };
+
+        // Recipient tag is calculated with the role of the recipient
+        let recipient_role = if self.role == CONST_INITIATOR_ROLE {
+            CONST_RECEIVER_ROLE
+        } else {
+            CONST_INITIATOR_ROLE
+        };
+        let expected_tag = self.gen_key_confirmation(key, recipient_role);
+
+        // Recipient derived another key. Undo any stored value and tell the recipient to do the same
+        if expected_tag != self.recipient_confirmation {
+            println!("Key confirmation of {} failed, shared secret key is discarded!\n", self.recipient_ID);
+            self.rollback_shared_secret_key();
+            self.pending_shared_key = None;
+            self.stage = CONST_KEY_CONFIRMATION_FAILED;
+            return false;
+        }
+
+        // During pairing, the key is only stored after the short authentication strings were compared
+        if self.sas_required {
+            self.sas_pending = true;
+            return true;
+        }
+
+        // Both sides hold the same key, commit it to the OS
+        self.commit_shared_secret_key(key);
+        true
+    }
+
+    // Pairing mode: the shared secret key is only stored after accept_sas is called
+    pub fn require_sas_confirmation(&mut self) {
+        self.sas_required = true;
+    }
+
+    // Six digit code derived from both public keys and the transcript, available after key confirmation.
+    // Both devices show it, and a technician compares them before accepting the pairing. The initiator
+    // commits to its values before the receiver sends its values, so nobody can choose a session with a certain code
+    pub fn short_authentication_string(&self) -> Option<String> {
+        if !self.sas_pending {
+            return None;
+        }
+
+        // Public keys of both devices, starting with the initiator
+        let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.sender_ID));
+        let my_pubkey = schnorr_identification::public_key(privkey);
+        let recipient_pubkey = peer_registry::get_peer_public_key(self.recipient_ID)?;
+        let mut pubkeys = Vec::with_capacity(64);
+        if self.role == CONST_INITIATOR_ROLE {
+            pubkeys.extend_from_slice(&my_pubkey);
+            pubkeys.extend_from_slice(&recipient_pubkey);
+        } else {
+            pubkeys.extend_from_slice(&recipient_pubkey);
+            pubkeys.extend_from_slice(&my_pubkey);
+        }
+
+        let hash = schnorr_identification::sha3_256(CONST_SAS_LABEL, Some(&pubkeys), Some(&self.transcript_hash()), None);
+        let code = u32::from_be_bytes(<[u8; 4]>::try_from(&hash[..4]).unwrap()) % 1_000_000;
+        Some(format!("{:06}", code))
+    }
+
+    // Codes matched, store the shared secret key
+    pub fn accept_sas(&mut self) -> bool {
+        match self.pending_shared_key {
+            Some(key) if self.sas_pending => {
+                self.sas_pending = false;
+                self.commit_shared_secret_key(key);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Codes did not match, discard the shared secret key. The next message is a signed abort,
+    // so that the recipient does not keep waiting for the comparison
+    pub fn reject_sas(&mut self) {
+        println!("Short authentication string rejected, shared secret key with {} is discarded!\n", self.recipient_ID);
+        self.sas_pending = false;
+        self.pending_shared_key = None;
+        self.stage = CONST_KEY_CONFIRMATION_FAILED;
+    }
+
+    // Undo storing the shared secret key and counter, e.g. if the recipient failed to confirm them
+    pub fn rollback_shared_secret_key(&mut self) {
+        if !self.key_committed {
+            return;
+        }
+
+        restore_shared_values(self.sender_ID, self.recipient_ID, self.previous_shared_values.take());
+        self.key_committed = false;
+    }
+
+    // Calculate the tag that proves that a role derived the shared secret key
+    fn gen_key_confirmation(&self, key: [u8; 32], role: u8) -> [u8; 32] {
+        schnorr_identification::kmac_256(key,
+                                         CONST_KEY_CONFIRMATION_LABEL,
+                                         Some(&[role]),
+                                         Some(&self.transcript_hash()))
+    }
+
+    // Hash of both IDs, the key size, both commitments and both challenges, starting with the ones of the initiator
+    pub fn transcript_hash(&self) -> [u8; 32] {
+        let (init_values, rec_values) = if self.role == CONST_INITIATOR_ROLE {
+            ((self.sender_ID, self.my_commitment, self.my_challenge),
+             (self.recipient_ID, self.recipient_commitment, self.recipient_challenge))
+        } else {
+            ((self.recipient_ID, self.recipient_commitment, self.recipient_challenge),
+             (self.sender_ID, self.my_commitment, self.my_challenge))
+        };
+
+        let mut ids = Vec::with_capacity(8);
+        let mut commitments = Vec::with_capacity(64);
+        let mut challenges = Vec::with_capacity(64);
+        for (id, commitment, challenge) in [init_values, rec_values] {
+            ids.extend_from_slice(&id.to_bytes());
+            commitments.extend_from_slice(&commitment);
+            challenges.extend_from_slice(challenge.as_bytes());
+        }
+        ids.push(self.key_size.bytes() as u8);
+
+        schnorr_identification::sha3_256(CONST_TRANSCRIPT_LABEL, Some(&ids), Some(&commitments), Some(&challenges))
+    }
+
+    fn calculate_shared_secret_key(&self) -> [u8; 32] {
         // Calculate shared secret key
         let commitment = schnorr_identification::bytes_to_edwards(&self.recipient_commitment);
         let shared_secret_key = (self.my_random_int * commitment).compress().to_bytes();
 
-        // Hash the shared secret key
-        let hashed_shared_Secret = schnorr_identification::sha3_256(&shared_secret_key, None, None, None);
-        let key_vec = Vec::from(hashed_shared_Secret);
-        let key_vec_copy = Vec::from(hashed_shared_Secret);
+        // Hash the shared secret key together with the transcript, so the key is bound to this session
+        let transcript_hash = self.transcript_hash();
+        schnorr_identification::sha3_256(&shared_secret_key, Some(&transcript_hash), None, None)
+    }
 
-        // Save the shared key in the OS
-        let desciption = format!("SharedSecretKey:{}:{}",&self.sender_ID, &self.recipient_ID);
-        let mut mykey = get_key_instance(&desciption, 32, Some(key_vec)).unwrap();
+    fn commit_shared_secret_key(&mut self, hashed_shared_secret: [u8; 32]) {
+        // Save the shared key in the OS. Its length records the negotiated key size
+        let key_len = self.key_size.bytes();
+        self.previous_shared_values = store_shared_values(self.sender_ID,
+                                                          self.recipient_ID,
+                                                          &hashed_shared_secret[..key_len]);
+        self.key_committed = true;
+    }
+}
 
-        // Check if key value was changed during initiation of Mykey instance or not. Change it if not
-        if mykey.get_key() != &key_vec_copy {
-            mykey.update_key_in_ring(key_vec_copy).unwrap();
-        }
+// Store a new shared secret key and reset the shared counter. Returns the replaced values for a rollback
+fn store_shared_values(my_ID: DeviceId, other_ID: DeviceId, key: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let key_desc = format!("SharedSecretKey:{}:{}", my_ID, other_ID);
+    let counter_desc = format!("SharedCounter:{}:{}", my_ID, other_ID);
+
+    // Keep the values of the last agreement for a rollback
+    let mut previous_values = None;
+    if let (Ok(old_key), Ok(old_counter)) = (get_key_instance(&key_desc, 32, None),
+                                             get_key_instance(&counter_desc, 4, None)) {
+        previous_values = Some((old_key.get_key().clone(), old_counter.get_key().clone()));
+    }
 
-        // Initiate the shared counter and save it in the OS
-        let mut shared_counter: u32 = 1;
-        let shared_counter_vec = Vec::from(shared_counter.to_be_bytes());
-        let shared_counter_vec_copy = Vec::from(shared_counter.to_be_bytes());
-        let desciption = format!("SharedCounter:{}:{}",&self.sender_ID, &self.recipient_ID);
-        let mut mykey = get_key_instance(&desciption, 4, Some(shared_counter_vec)).unwrap();
+    // Save the shared key in the OS
+    let key_vec = Vec::from(key);
+    let key_vec_copy = Vec::from(key);
+    let mut mykey = get_key_instance(&key_desc, key.len(), Some(key_vec)).unwrap();
+
+    // Check if key value was changed during initiation of Mykey instance or not. Change it if not
+    if mykey.get_key() != &key_vec_copy {
+        mykey.update_key_in_ring(key_vec_copy).unwrap();
+    }
+
+    // Initiate the shared counter and save it in the OS
+    let mut shared_counter: u32 = 1;
+    let shared_counter_vec = Vec::from(shared_counter.to_be_bytes());
+    let shared_counter_vec_copy = Vec::from(shared_counter.to_be_bytes());
+    let mut mykey = get_key_instance(&counter_desc, 4, Some(shared_counter_vec)).unwrap();
+
+    // Check if key value was changed during initiation of Mykey instance or not. Change it if not
+    if mykey.get_key() != &shared_counter_vec_copy {
+        mykey.update_key_in_ring(shared_counter_vec_copy).unwrap();
+    }
+
+    // A new shared key is only stored after both devices authenticated each other
+    peer_registry::record_mutual_auth(other_ID, Utc::now().timestamp_millis());
+
+    previous_values
+}
+
+// Hash commitment of the initiator to its commitment and challenge
+fn challenge_commitment(commitment: &[u8; 32], challenge: &[u8; 32]) -> [u8; 32] {
+    schnorr_identification::sha3_256(CONST_CHALLENGE_COMMITMENT_LABEL, Some(commitment), Some(challenge), None)
+}
 
-        // Check if key value was changed during initiation of Mykey instance or not. Change it if not
-        if mykey.get_key() != &shared_counter_vec_copy {
-            mykey.update_key_in_ring(shared_counter_vec_copy).unwrap();
+// Signed data of an abort, bound to the fresh value the receiver of the abort sent in this session
+fn abort_data(sender_ID: DeviceId, receiver_ID: DeviceId, receiver_nonce: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::from(CONST_ABORT_LABEL);
+    data.extend_from_slice(&sender_ID.to_bytes());
+    data.extend_from_slice(&receiver_ID.to_bytes());
+    data.extend_from_slice(receiver_nonce);
+    data
+}
+
+// Sign the abort of a key agreement, so that nobody else can make the other device discard its shared secret key.
+// The other device may hold another key than we do, so the abort can not be authenticated with the pending key
+pub(crate) fn sign_abort(my_ID: DeviceId, other_ID: DeviceId, other_nonce: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", my_ID));
+    schnorr_identification::sign(privkey, &abort_data(my_ID, other_ID, other_nonce))
+}
+
+// Verify that the other device signed the abort of the key agreement over our fresh value of this session
+pub(crate) fn verify_abort(my_ID: DeviceId, other_ID: DeviceId, my_nonce: &[u8; 32], signature: ([u8; 32], [u8; 32])) -> bool {
+    let data = abort_data(other_ID, my_ID, my_nonce);
+    peer_registry::get_peer_public_keys(other_ID).iter()
+        .any(|public_key| schnorr_identification::verify_signature(*public_key, &data, signature))
+}
+
+// Undo store_shared_values with the values it returned
+fn restore_shared_values(my_ID: DeviceId, other_ID: DeviceId, previous_values: Option<(Vec<u8>, Vec<u8>)>) {
+    let key_desc = format!("SharedSecretKey:{}:{}", my_ID, other_ID);
+    let counter_desc = format!("SharedCounter:{}:{}", my_ID, other_ID);
+    let mut key_ins = get_key_instance(&key_desc, 32, None).unwrap();
+    let mut counter_ins = get_key_instance(&counter_desc, 4, None).unwrap();
+
+    match previous_values {
+        // Restore the values of the last agreement
+        Some((old_key, old_counter)) => {
+            key_ins.update_key_in_ring(old_key).unwrap();
+            counter_ins.update_key_in_ring(old_counter).unwrap();
+        },
+        // No values existed before, delete the new ones
+        None => {
+            key_ins.delete_key_from_ring().unwrap();
+            counter_ins.delete_key_from_ring().unwrap();
         }
     }
+
+    // The agreement was not completed, so it does not count as a fresh mutual authentication
+    peer_registry::record_mutual_auth(other_ID, 0);
 }
 
 // Struct for mutual authentication using the NIZKP
 pub struct NIZKMutAuth {
-    pub sender_ID: u32,
-    pub recipient_ID: u32,
+    pub sender_ID: DeviceId,
+    pub recipient_ID: DeviceId,
     initiator: bool,
     my_random_int: Scalar,
     my_commitment: [u8; 32],
@@ -286,7 +697,7 @@ pub struct NIZKMutAuth {
 
 impl NIZKMutAuth {
     // Create a new instance of Int_mut_auth
-    pub fn new(sender_ID: u32, recipient_ID: u32, sender_proof: Option<([u8; 32], [u8; 32], [u8; 32])>) -> (NIZKMutAuth, ([u8; 32], [u8; 32], [u8; 32])) {
+    pub fn new(sender_ID: DeviceId, recipient_ID: DeviceId, sender_proof: Option<([u8; 32], [u8; 32], [u8; 32])>) -> (NIZKMutAuth, ([u8; 32], [u8; 32], [u8; 32])) {
         // Generate random secret scalar and Commitment
         let my_random_int = schnorr_identification::generate_random_scalar();
         let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
@@ -340,14 +751,14 @@ impl NIZKMutAuth {
     fn nizk_proof(&mut self) -> ([u8; 32], [u8; 32], [u8; 32]){
         // Fetch secret key and shared secret key
         let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.sender_ID));
-        let (sharedkey, mut sk) = get_32byte_key(format!("SharedSecretKey:{}:{}", self.sender_ID, self.recipient_ID));
+        let (sharedkey, mut sk) = get_shared_key(self.sender_ID, self.recipient_ID);
 
         // Fetch shared counter value
         let (shared_counter, mut sc) = get_shared_counter(self.sender_ID, self.recipient_ID);
 
         // Calculate proof
         let (r, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey,
-                                                                                      sharedkey,
+                                                                                      &sharedkey,
                                                                                       shared_counter,
                                                                                       None);
 
@@ -371,18 +782,23 @@ impl NIZKMutAuth {
     // Verif recipient's proof
     pub fn verify_proof(&mut self) -> bool {
         // Fetch Public key of the sender, shared secret key, and shared counter
-        let (pubkey, _) = get_32byte_key(format!("PublicKey:{}", self.recipient_ID));
-        let (sharedkey, _) = get_32byte_key(format!("SharedSecretKey:{}:{}", self.sender_ID, self.recipient_ID));
+        let pubkeys = peer_registry::get_peer_public_keys(self.recipient_ID);
+        if pubkeys.is_empty() {
+            self.proof_accepted = false;
+            audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted: false });
+            return false;
+        }
+        let (sharedkey, _) = get_shared_key(self.sender_ID, self.recipient_ID);
         let (shared_counter, _) = get_shared_counter(self.sender_ID, self.recipient_ID);
 
         // Verify proof
-        let (schnorr, mac) = schnorr_identification::verify_nizk_proof(pubkey,
-                                                                       sharedkey,
-                                                                       shared_counter,
-                                                                       None,
-                                                                       (self.recipient_commitment,
-                                                                        self.recipient_challenge,
-                                                                        self.recipient_response));
+        let (schnorr, mac) = verify_nizk_proof_with_keys(&pubkeys,
+                                                         &sharedkey,
+                                                         shared_counter,
+                                                         None,
+                                                         (self.recipient_commitment,
+                                                          self.recipient_challenge,
+                                                          self.recipient_response));
         // Save verification result
         let accepted = schnorr && mac;
         self.proof_accepted = accepted;
@@ -390,7 +806,10 @@ impl NIZKMutAuth {
         // Check intrusion
         if !accepted {
             file_management::manage_intrusion(self.recipient_ID, schnorr, mac);
+        } else {
+            peer_registry::record_mutual_auth(self.recipient_ID, Utc::now().timestamp_millis());
         }
+        audit_log::record_event(audit_log::AuditEvent::MutualAuth { peer_ID: self.recipient_ID, accepted });
 
         accepted
     }
@@ -427,7 +846,7 @@ impl NIZKMutAuth {
 }
 
 // Function to read shared counter from OS
-fn get_shared_counter(my_ID: u32, receiver_ID: u32) -> ([u8; 4], MyKey) {
+fn get_shared_counter(my_ID: DeviceId, receiver_ID: DeviceId) -> ([u8; 4], MyKey) {
     // Fetch Counter from OS
     let desciption = format!("SharedCounter:{}:{}", my_ID, receiver_ID);
     let mut counter_instance = get_key_instance(&desciption, 4, None).unwrap();
@@ -447,17 +866,45 @@ fn get_32byte_key(description: String) -> ([u8; 32], MyKey) {
     (key, mykey)
 }
 
-pub fn gen_nizk_proof(my_ID: u32, receiver_ID: u32, message: String, update_keys: bool) -> ([u8; 32], [u8; 32], [u8; 32]) {
+// Fetch the shared secret key from OS. It is either 16 or 32 bytes long, depending on the key size
+fn get_shared_key(my_ID: DeviceId, other_ID: DeviceId) -> (Vec<u8>, MyKey) {
+    let description = format!("SharedSecretKey:{}:{}", my_ID, other_ID);
+    let mykey = get_key_instance(&description, 32, None).unwrap();
+    let key = mykey.get_key().clone();
+
+    (key, mykey)
+}
+
+// Return the size of the shared secret key agreed with another device
+pub fn get_shared_key_size(my_ID: DeviceId, other_ID: DeviceId) -> Option<KeySize> {
+    let (key, _) = get_shared_key(my_ID, other_ID);
+    KeySize::from_bytes(key.len())
+}
+
+// Verify a NIZK proof against all accepted public keys of the sender, e.g. the old and new key during a key rotation
+fn verify_nizk_proof_with_keys(pubkeys: &[[u8; 32]], shared_secret: &[u8], shared_counter: [u8; 4],
+                               message: Option<&[u8]>, proof: ([u8; 32], [u8; 32], [u8; 32])) -> (bool, bool) {
+    let mut result = (false, false);
+    for pubkey in pubkeys {
+        result = schnorr_identification::verify_nizk_proof(*pubkey, shared_secret, shared_counter, message, proof);
+        if result.0 {
+            break;
+        }
+    }
+    result
+}
+
+pub fn gen_nizk_proof(my_ID: DeviceId, receiver_ID: DeviceId, message: String, update_keys: bool) -> ([u8; 32], [u8; 32], [u8; 32]) {
     // Fetch secret key and shared secret key
     let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", my_ID));
-    let (sharedkey, mut sk) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, receiver_ID));
+    let (sharedkey, mut sk) = get_shared_key(my_ID, receiver_ID);
 
     // Fetch shared counter value
     let (shared_counter, mut sc) = get_shared_counter(my_ID, receiver_ID);
 
     // Generate proof
     let (_, commitment, challenge, response) = schnorr_identification::nizk_proof(privkey,
-                                                                                  sharedkey,
+                                                                                  &sharedkey,
                                                                                   shared_counter,
                                                                                   Some(message.as_bytes()));
     // Update shared counter and shared secret key
@@ -469,19 +916,23 @@ pub fn gen_nizk_proof(my_ID: u32, receiver_ID: u32, message: String, update_keys
     (commitment, challenge, response)
 }
 
-pub fn verify_nizk_proof(my_ID: u32, sender_ID: u32, message: String, proof: ([u8; 32], [u8; 32], [u8; 32]), update_keys: bool) -> bool {
+pub fn verify_nizk_proof(my_ID: DeviceId, sender_ID: DeviceId, message: String, proof: ([u8; 32], [u8; 32], [u8; 32]), update_keys: bool) -> bool {
     // Fetch Public key of the sender, shared secret key, and shared counter
-    let (pubkey, _) = get_32byte_key(format!("PublicKey:{}", sender_ID));
-    let (sharedkey, _) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, sender_ID));
+    let pubkeys = peer_registry::get_peer_public_keys(sender_ID);
+    if pubkeys.is_empty() {
+        audit_log::record_event(audit_log::AuditEvent::NizkProof { peer_ID: sender_ID, accepted: false });
+        return false;
+    }
+    let (sharedkey, _) = get_shared_key(my_ID, sender_ID);
     let (shared_counter, _) = get_shared_counter(my_ID, sender_ID);
 
     // Get the commitment and the challenge response
     let (commitment, challenge, response) = proof;
-    let (schnorr, mac) = schnorr_identification::verify_nizk_proof(pubkey,
-                                                                   sharedkey,
-                                                                   shared_counter,
-                                                                   Some(message.as_bytes()),
-                                                                   proof);
+    let (schnorr, mac) = verify_nizk_proof_with_keys(&pubkeys,
+                                                     &sharedkey,
+                                                     shared_counter,
+                                                     Some(message.as_bytes()),
+                                                     proof);
     // Update shared values if proof was accepted
     let accepted = schnorr && mac;
     if accepted == true {
@@ -492,22 +943,23 @@ pub fn verify_nizk_proof(my_ID: u32, sender_ID: u32, message: String, proof: ([u
         // Check intrusion
         file_management::manage_intrusion(sender_ID, schnorr, mac);
     }
+    audit_log::record_event(audit_log::AuditEvent::NizkProof { peer_ID: sender_ID, accepted });
 
     // Return verification result
     accepted
 }
 
 // Update counter and secret key after each use
-fn update_used_values(my_ID: u32, other_ID: u32, response: [u8; 32], additional_data: Option<&[u8]>) {
+fn update_used_values(my_ID: DeviceId, other_ID: DeviceId, response: [u8; 32], additional_data: Option<&[u8]>) {
     // Fetch shared secret key and shared counter value
-    let (sharedkey, mut sharedkey_ins) = get_32byte_key(format!("SharedSecretKey:{}:{}", my_ID, other_ID));
+    let (sharedkey, mut sharedkey_ins) = get_shared_key(my_ID, other_ID);
     let (shared_counter, mut shared_counter_ins) = get_shared_counter(my_ID, other_ID);
 
     // Convert counter into u32 and increment it
     let mut counter_value: u32 = u32::from_be_bytes(shared_counter);
     counter_value = counter_value + 1;
 
-    // Calculate the new shared secret key
+    // Calculate the new shared secret key, keeping the size of the old one
     let new_key = schnorr_identification::sha3_256(&sharedkey,
                                                    Some(counter_value.to_be_bytes().as_ref()),
                                                    Some(&response),
@@ -516,7 +968,7 @@ fn update_used_values(my_ID: u32, other_ID: u32, response: [u8; 32], additional_
     // Update new key in OS
     // let mut sharedkey_ins = get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), None).unwrap();
     // let mut sharedkey_ins = get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), 32, Some(Vec::from(new_key))).unwrap();
-    sharedkey_ins.update_key_in_ring(Vec::from(new_key)).unwrap();
+    sharedkey_ins.update_key_in_ring(Vec::from(&new_key[..sharedkey.len()])).unwrap();
 
     // Update Counter in OS
     counter_value = counter_value + 1;
@@ -524,19 +976,19 @@ fn update_used_values(my_ID: u32, other_ID: u32, response: [u8; 32], additional_
 }
 
 // Check if there is a compromised key
-pub fn check_intrusion(senderID: u32) -> (bool, bool, bool) {
+pub fn check_intrusion(senderID: DeviceId) -> (bool, bool, bool) {
     file_management::check_intrusion(senderID)
 }
 
 // Init Data
-pub fn init_intrusion_counters(senderID: u32) {
+pub fn init_intrusion_counters(senderID: DeviceId) {
     file_management::init_data(senderID);
 }
 
 // Struct for interactive SIS proof
 pub struct IntSchnorrProver {
-    pub my_ID: u32,
-    pub recipient_ID: u32,
+    pub my_ID: DeviceId,
+    pub recipient_ID: DeviceId,
     my_random_int: Scalar,
     pub my_commitment: [u8; 32],
     pub my_challenge: Scalar,
@@ -546,7 +998,7 @@ pub struct IntSchnorrProver {
 // Prover for interactive Schnorr identification scheme over elliptic curves
 impl IntSchnorrProver {
     // Create a new instance of Int_mut_auth
-    pub fn new(my_ID: u32, recipient_ID: u32) -> IntSchnorrProver {
+    pub fn new(my_ID: DeviceId, recipient_ID: DeviceId) -> IntSchnorrProver {
         // Generate random secret scalar and Commitment
         let my_random_int = schnorr_identification::generate_random_scalar();
         let my_commitment = (my_random_int * &ED25519_BASEPOINT_POINT).compress().to_bytes();
@@ -601,8 +1053,8 @@ impl IntSchnorrProver {
 
 // Struct for interactive mutual authentication for secret key sharing
 pub struct IntSchnorrVerifier {
-    pub my_ID: u32,
-    pub sender_ID: u32,
+    pub my_ID: DeviceId,
+    pub sender_ID: DeviceId,
     pub commitment: [u8; 32],
     pub challenge: Scalar,
     pub response: [u8; 32],
@@ -611,7 +1063,7 @@ pub struct IntSchnorrVerifier {
 // Prover for interactive Schnorr identification scheme over elliptic curves
 impl IntSchnorrVerifier {
     // Create a new instance of Int_mut_auth
-    pub fn new(my_ID: u32, sender_ID: u32, commitment: [u8; 32]) -> IntSchnorrVerifier {
+    pub fn new(my_ID: DeviceId, sender_ID: DeviceId, commitment: [u8; 32]) -> IntSchnorrVerifier {
         // Generate random secret scalar and Commitment
         let challenge = schnorr_identification::generate_random_scalar();
 
@@ -640,20 +1092,155 @@ impl IntSchnorrVerifier {
             return false;
         }
 
-        // Fetch Public Key of the sender
-        let desciption = format!("PublicKey:{}", &self.sender_ID);
-        let sender_pubkey = get_key_instance(&desciption, 32, None).unwrap();
-
-        // Convert Key into [u8; 32] format
-        let key: &[u8] = sender_pubkey.get_key();
-        let key_bytes: [u8; 32] = <[u8; 32]>::try_from(key).unwrap();
+        // Fetch Public Keys of the sender
+        let pubkeys = peer_registry::get_peer_public_keys(self.sender_ID);
 
         // Verify proof
         let proof = (self.commitment, self.challenge, response);
-        let accepted = schnorr_identification::verify_int_proof(key_bytes, proof);
+        let accepted = pubkeys.iter().any(|key_bytes| schnorr_identification::verify_int_proof(*key_bytes, proof));
 
         // Return verification results
         accepted
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use crate::test_support;
+
+    // Request type and values of one message of the interactive mutual authentication
+    type Message = (u8, [u8; 32], Option<[u8; 32]>);
+
+    // Handle a message like the tcp examples do and return the messages to send back
+    fn respond(ins: &mut IntMutAuth, message: Message) -> Vec<Message> {
+        let (request_type, val1, val2) = message;
+        let response_state = ins.add_recipient_values(request_type, val1, val2);
+        let mut replies = Vec::new();
+
+        if response_state == CONST_RESPONSE_CANNOT_BE_VERIFIED || response_state == CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE {
+            let (val1, val2, request_type) = ins.gen_next_values();
+            replies.push((request_type, val1, val2));
+        }
+        if response_state == CONST_RESPONSE_CAN_BE_VERIFIED_AFTER_GENERATING_RESPONSE || response_state == CONST_RESPONSE_CAN_BE_VERIFIED {
+            ins.verify_proof();
+            let (val1, val2, request_type) = ins.gen_next_values();
+            replies.push((request_type, val1, val2));
+        }
+        if response_state == CONST_KEY_CONFIRMATION_CAN_BE_VERIFIED && !ins.confirm_shared_secret_key() {
+            let (val1, val2, request_type) = ins.gen_next_values();
+            replies.push((request_type, val1, val2));
+        }
+        replies.retain(|reply| reply.0 != CONST_NEXT_STEP_REQUIRED);
+        replies
+    }
+
+    // Run the key agreement between two instances in memory. Messages are passed through tamper
+    // together with the role of their receiver before delivery
+    fn run(initiator: &mut IntMutAuth, receiver: &mut IntMutAuth, tamper: &mut dyn FnMut(u8, &mut Message)) {
+        let (val1, val2, request_type) = initiator.gen_next_values();
+        let mut to_receiver = VecDeque::from([(request_type, val1, val2)]);
+        let mut to_initiator = VecDeque::new();
+        loop {
+            if let Some(mut message) = to_receiver.pop_front() {
+                tamper(CONST_RECEIVER_ROLE, &mut message);
+                to_initiator.extend(respond(receiver, message));
+            } else if let Some(mut message) = to_initiator.pop_front() {
+                tamper(CONST_INITIATOR_ROLE, &mut message);
+                to_receiver.extend(respond(initiator, message));
+            } else {
+                return;
+            }
+        }
+    }
+
+    // Two devices that know the public key of each other
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        (AID, BID)
+    }
+
+    // Complete key agreement between two devices
+    fn agree(AID: DeviceId, BID: DeviceId) {
+        let mut a_auth = get_int_mut_auth_instance(AID, BID, CONST_INITIATOR_ROLE);
+        let mut b_auth = get_int_mut_auth_instance(BID, AID, CONST_RECEIVER_ROLE);
+        run(&mut a_auth, &mut b_auth, &mut |_, _| {});
+    }
+
+    // Key agreement where the key confirmation tag of the receiver is changed on its way to the initiator
+    fn agree_with_tampered_confirmation(AID: DeviceId, BID: DeviceId) {
+        let mut a_auth = get_int_mut_auth_instance(AID, BID, CONST_INITIATOR_ROLE);
+        let mut b_auth = get_int_mut_auth_instance(BID, AID, CONST_RECEIVER_ROLE);
+        run(&mut a_auth, &mut b_auth, &mut |role, message| {
+            if role == CONST_INITIATOR_ROLE && message.0 == CONST_KEY_CONFIRMATION {
+                message.1[0] ^= 1;
+            }
+        });
+    }
+
+    fn shared_values(my_ID: DeviceId, other_ID: DeviceId) -> (Vec<u8>, Vec<u8>) {
+        (get_shared_key(my_ID, other_ID).0, get_shared_counter(my_ID, other_ID).0.to_vec())
+    }
+
+    fn has_shared_key(my_ID: DeviceId, other_ID: DeviceId) -> bool {
+        get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), 32, None).is_ok()
+    }
+
+    #[test]
+    fn agrees_on_shared_values_usable_for_proofs() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(26001, 26002);
+            agree(AID, BID);
+
+            let (a_key, a_counter) = shared_values(AID, BID);
+            assert_eq!(a_key.len(), 32);
+            assert_eq!((a_key, a_counter), shared_values(BID, AID));
+
+            let proof = gen_nizk_proof(AID, BID, String::from("message"), true);
+            assert!(verify_nizk_proof(BID, AID, String::from("message"), proof, true));
+        });
+    }
+
+    #[test]
+    fn tag_mismatch_stores_no_key() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(26011, 26012);
+            agree_with_tampered_confirmation(AID, BID);
+
+            // The initiator never stores the key, the receiver rolls it back after the signed abort
+            assert!(!has_shared_key(AID, BID));
+            assert!(!has_shared_key(BID, AID));
+        });
+    }
+
+    #[test]
+    fn tag_mismatch_keeps_previous_key() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(26021, 26022);
+            agree(AID, BID);
+            let (a_values, b_values) = (shared_values(AID, BID), shared_values(BID, AID));
+
+            agree_with_tampered_confirmation(AID, BID);
+            assert_eq!(shared_values(AID, BID), a_values);
+            assert_eq!(shared_values(BID, AID), b_values);
+        });
+    }
+
+    #[test]
+    fn unsigned_abort_is_ignored() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(26031, 26032);
+            let mut a_auth = get_int_mut_auth_instance(AID, BID, CONST_INITIATOR_ROLE);
+            let mut b_auth = get_int_mut_auth_instance(BID, AID, CONST_RECEIVER_ROLE);
+            run(&mut a_auth, &mut b_auth, &mut |_, _| {});
+            let b_values = shared_values(BID, AID);
+
+            assert_eq!(b_auth.add_recipient_values(CONST_KEY_CONFIRMATION_FAILED, [1u8; 32], Some([2u8; 32])), CONST_ABORT_NOT_AUTHENTICATED);
+            assert_eq!(shared_values(BID, AID), b_values);
+        });
+    }
+}
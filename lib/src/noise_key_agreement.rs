@@ -0,0 +1,395 @@
+use snow::{Builder, HandshakeState, TransportState};
+use crate::key_agreement::KeyAgreement;
+use crate::{peer_registry, schnorr_identification, KeySize};
+use crate::{get_32byte_key, restore_shared_values, store_shared_values};
+use crate::{CONST_INITIATOR_ROLE, CONST_RECEIVER_ROLE};
+use crate::DeviceId;
+
+// Noise pattern used for the handshake
+const CONST_NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+// Message types of the Noise key agreement
+const CONST_MSG_HANDSHAKE: u8 = 1;
+const CONST_MSG_IDENTITY: u8 = 2;
+const CONST_MSG_FAILED: u8 = 4;
+
+// Stages of the Noise key agreement
+const CONST_STAGE_SEND_HANDSHAKE: u8 = 0;
+const CONST_STAGE_SEND_IDENTITY: u8 = 1;
+const CONST_STAGE_SEND_FAILED: u8 = 2;
+const CONST_STAGE_WAIT: u8 = 3;
+
+// Labels of the Noise key agreement
+const CONST_NOISE_STATIC_KEY_LABEL: &[u8] = b"NoiseKeyAgreement static key";
+const CONST_NOISE_IDENTITY_LABEL: &[u8] = b"NoiseKeyAgreement identity";
+const CONST_NOISE_SHARED_KEY_LABEL: &[u8] = b"NoiseKeyAgreement shared key";
+
+// Max size of a Noise message
+const CONST_MAX_NOISE_MESSAGE: usize = 65535;
+
+// Key agreement with a Noise XX handshake. The Noise static key of a device is derived from its identity
+// key. After the handshake, both devices send a random contribution to the shared secret key and a
+// Schnorr signature of the handshake hash with their identity key over the encrypted Noise channel.
+// This binds the handshake to the public key of the other device in the peer registry.
+pub struct NoiseKeyAgreement {
+    pub my_ID: DeviceId,
+    pub other_ID: DeviceId,
+    pub role: u8,
+    pub key_size: KeySize,
+    stage: u8,
+    handshake: Option<HandshakeState>,
+    transport: Option<TransportState>,
+    handshake_hash: Vec<u8>,
+    my_contribution: [u8; 32],
+    other_contribution: Option<[u8; 32]>,
+    key_committed: bool,
+    previous_shared_values: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl NoiseKeyAgreement {
+    // Create a new instance. Both devices have to use the same key size
+    pub fn new(my_ID: DeviceId, other_ID: DeviceId, role: u8, key_size: KeySize) -> NoiseKeyAgreement {
+        // Derive the Noise static key from the identity key
+        let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", my_ID));
+        let static_key = schnorr_identification::sha3_256(CONST_NOISE_STATIC_KEY_LABEL, Some(&privkey), None, None);
+
+        // Prologue binds both IDs and the key size, starting with the initiator
+        let (init_ID, rec_ID) = if role == CONST_INITIATOR_ROLE {
+            (my_ID, other_ID)
+        } else {
+            (other_ID, my_ID)
+        };
+        let mut prologue = Vec::with_capacity(9);
+        prologue.extend_from_slice(&init_ID.to_bytes());
+        prologue.extend_from_slice(&rec_ID.to_bytes());
+        prologue.push(key_size.bytes() as u8);
+
+        // Build handshake state of our role
+        let builder = Builder::new(CONST_NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&static_key)
+            .prologue(&prologue);
+        let handshake = if role == CONST_INITIATOR_ROLE {
+            builder.build_initiator().unwrap()
+        } else {
+            builder.build_responder().unwrap()
+        };
+
+        // Initiator writes the first handshake message
+        let mut stage = CONST_STAGE_SEND_HANDSHAKE;
+        if role == CONST_RECEIVER_ROLE {
+            stage = CONST_STAGE_WAIT;
+        }
+
+        NoiseKeyAgreement {
+            my_ID,
+            other_ID,
+            role,
+            key_size,
+            stage,
+            handshake: Some(handshake),
+            transport: None,
+            handshake_hash: Vec::new(),
+            my_contribution: schnorr_identification::generate_random_32bytes(),
+            other_contribution: None,
+            key_committed: false,
+            previous_shared_values: None,
+        }
+    }
+
+    // Undo storing the shared secret key and counter
+    pub fn rollback_shared_secret_key(&mut self) {
+        if !self.key_committed {
+            return;
+        }
+
+        restore_shared_values(self.my_ID, self.other_ID, self.previous_shared_values.take());
+        self.key_committed = false;
+    }
+
+    // Message signed by a role with its identity key
+    fn identity_message(&self, role: u8) -> Vec<u8> {
+        let mut message = Vec::from(CONST_NOISE_IDENTITY_LABEL);
+        message.extend_from_slice(&self.handshake_hash);
+        message.push(role);
+        message
+    }
+
+    // Switch into transport mode once the handshake is finished
+    fn finish_handshake(&mut self) -> bool {
+        let handshake = self.handshake.take().unwrap();
+        self.handshake_hash = handshake.get_handshake_hash().to_vec();
+        match handshake.into_transport_mode() {
+            Ok(transport) => {
+                self.transport = Some(transport);
+                true
+            },
+            Err(e) => {
+                println!("Noise handshake with {} failed: {:?}\n", self.other_ID, e);
+                false
+            }
+        }
+    }
+
+    // Check the identity message of the other device and store the shared secret key
+    fn receive_identity(&mut self, payload: &[u8]) -> bool {
+        if payload.len() != 96 {
+            return false;
+        }
+
+        // Verify the signature of the handshake hash with the identity key of the other device
+        let other_role = if self.role == CONST_INITIATOR_ROLE {
+            CONST_RECEIVER_ROLE
+        } else {
+            CONST_INITIATOR_ROLE
+        };
+        let pubkey = match peer_registry::get_key_agreement_public_key(self.other_ID,
+                                                                       peer_registry::KeyAgreementProtocol::Noise,
+                                                                       self.key_size) {
+            Some(pubkey) => pubkey,
+            None => {
+                return false;
+            }
+        };
+        let signature = (<[u8; 32]>::try_from(&payload[32..64]).unwrap(),
+                         <[u8; 32]>::try_from(&payload[64..96]).unwrap());
+        if !schnorr_identification::verify_signature(pubkey, &self.identity_message(other_role), signature) {
+            println!("Identity of {} could not be verified, shared secret key is discarded!\n", self.other_ID);
+            return false;
+        }
+        self.other_contribution = Some(<[u8; 32]>::try_from(&payload[..32]).unwrap());
+
+        // Calculate shared secret key from the handshake hash and both contributions, starting with the initiator
+        let other_contribution = self.other_contribution.unwrap();
+        let (init_contribution, rec_contribution) = if self.role == CONST_INITIATOR_ROLE {
+            (self.my_contribution, other_contribution)
+        } else {
+            (other_contribution, self.my_contribution)
+        };
+        let key = schnorr_identification::sha3_256(CONST_NOISE_SHARED_KEY_LABEL,
+                                                   Some(&self.handshake_hash),
+                                                   Some(&init_contribution),
+                                                   Some(&rec_contribution));
+
+        // Save it in the OS
+        let key_len = self.key_size.bytes();
+        self.previous_shared_values = store_shared_values(self.my_ID, self.other_ID, &key[..key_len]);
+        self.key_committed = true;
+        true
+    }
+}
+
+impl KeyAgreement for NoiseKeyAgreement {
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let mut buffer = vec![0u8; CONST_MAX_NOISE_MESSAGE];
+        let mut message = Vec::new();
+        match self.stage {
+            CONST_STAGE_SEND_HANDSHAKE => {
+                let handshake = self.handshake.as_mut().unwrap();
+                let len = handshake.write_message(&[], &mut buffer).unwrap();
+                message.push(CONST_MSG_HANDSHAKE);
+                message.extend_from_slice(&buffer[..len]);
+
+                // Initiator sends the last handshake message and its identity right after
+                self.stage = CONST_STAGE_WAIT;
+                if handshake.is_handshake_finished() {
+                    self.stage = if self.finish_handshake() {
+                        CONST_STAGE_SEND_IDENTITY
+                    } else {
+                        CONST_STAGE_SEND_FAILED
+                    };
+                }
+            },
+            CONST_STAGE_SEND_IDENTITY => {
+                // Random contribution and signature of the handshake hash
+                let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.my_ID));
+                let (commitment, response) = schnorr_identification::sign(privkey, &self.identity_message(self.role));
+                let mut payload = Vec::with_capacity(96);
+                payload.extend_from_slice(&self.my_contribution);
+                payload.extend_from_slice(&commitment);
+                payload.extend_from_slice(&response);
+
+                let transport = self.transport.as_mut().unwrap();
+                let len = transport.write_message(&payload, &mut buffer).unwrap();
+                message.push(CONST_MSG_IDENTITY);
+                message.extend_from_slice(&buffer[..len]);
+                self.stage = CONST_STAGE_WAIT;
+            },
+            CONST_STAGE_SEND_FAILED => {
+                // Tell the other device to discard the shared secret key, over the encrypted channel if there is one
+                message.push(CONST_MSG_FAILED);
+                if let Some(transport) = self.transport.as_mut() {
+                    let len = transport.write_message(&[], &mut buffer).unwrap();
+                    message.extend_from_slice(&buffer[..len]);
+                }
+                self.stage = CONST_STAGE_WAIT;
+            },
+            _ => {
+                return None;
+            }
+        }
+
+        Some(message)
+    }
+
+    fn receive_message(&mut self, message: &[u8]) -> bool {
+        let mut buffer = vec![0u8; CONST_MAX_NOISE_MESSAGE];
+        let accepted = match (message.first().copied(), self.handshake.as_mut(), self.transport.as_mut()) {
+            // Next handshake message
+            (Some(CONST_MSG_HANDSHAKE), Some(handshake), _) => {
+                match handshake.read_message(&message[1..], &mut buffer) {
+                    Ok(_) => {
+                        if handshake.is_handshake_finished() {
+                            // Receiver read the last handshake message and waits for the identity of the initiator
+                            self.finish_handshake()
+                        } else {
+                            self.stage = CONST_STAGE_SEND_HANDSHAKE;
+                            true
+                        }
+                    },
+                    Err(e) => {
+                        println!("Noise handshake with {} failed: {:?}\n", self.other_ID, e);
+                        false
+                    }
+                }
+            },
+            // Identity of the other device over the encrypted channel
+            (Some(CONST_MSG_IDENTITY), _, Some(transport)) if self.other_contribution.is_none() => {
+                match transport.read_message(&message[1..], &mut buffer) {
+                    Ok(len) => {
+                        let payload = buffer[..len].to_vec();
+                        let accepted = self.receive_identity(&payload);
+
+                        // Receiver answers with its own identity
+                        if accepted && self.role == CONST_RECEIVER_ROLE {
+                            self.stage = CONST_STAGE_SEND_IDENTITY;
+                        }
+                        accepted
+                    },
+                    Err(_) => false,
+                }
+            },
+            // Other device discarded the key, so do the same. A key is only stored after the handshake,
+            // so the abort has to come over the encrypted channel to be authenticated
+            (Some(CONST_MSG_FAILED), _, transport) => {
+                let authenticated = match transport {
+                    Some(transport) => transport.read_message(&message[1..], &mut buffer).is_ok(),
+                    None => !self.key_committed,
+                };
+                if !authenticated {
+                    println!("Abort of {} is not authenticated and is ignored!\n", self.other_ID);
+                    return false;
+                }
+                self.rollback_shared_secret_key();
+                self.stage = CONST_STAGE_WAIT;
+                return false;
+            },
+            _ => false,
+        };
+
+        // A committed key is only discarded by an authenticated abort, never by an unexpected message
+        if !accepted && !self.key_committed {
+            self.stage = CONST_STAGE_SEND_FAILED;
+        }
+
+        accepted
+    }
+
+    fn is_complete(&self) -> bool {
+        self.key_committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Run the key agreement between two instances in memory. Messages are passed through tamper before delivery
+    fn run(initiator: &mut NoiseKeyAgreement, receiver: &mut NoiseKeyAgreement, tamper: &mut dyn FnMut(&mut Vec<u8>)) {
+        loop {
+            let mut sent = false;
+            if let Some(mut message) = initiator.next_message() {
+                tamper(&mut message);
+                receiver.receive_message(&message);
+                sent = true;
+            }
+            if let Some(mut message) = receiver.next_message() {
+                tamper(&mut message);
+                initiator.receive_message(&message);
+                sent = true;
+            }
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    // Two devices that know the public key of each other
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        (AID, BID)
+    }
+
+    fn shared_values(my_ID: DeviceId, other_ID: DeviceId) -> (Vec<u8>, Vec<u8>) {
+        (crate::get_shared_key(my_ID, other_ID).0, crate::get_shared_counter(my_ID, other_ID).0.to_vec())
+    }
+
+    #[test]
+    fn agrees_on_shared_values_usable_for_proofs() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(30001, 30002);
+            let mut a_noise = NoiseKeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits128);
+            let mut b_noise = NoiseKeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits128);
+            run(&mut a_noise, &mut b_noise, &mut |_| {});
+
+            assert!(a_noise.is_complete() && b_noise.is_complete());
+            let (a_key, a_counter) = shared_values(AID, BID);
+            assert_eq!(a_key.len(), 16);
+            assert_eq!((a_key, a_counter), shared_values(BID, AID));
+
+            let proof = crate::gen_nizk_proof(AID, BID, String::from("message"), true);
+            assert!(crate::verify_nizk_proof(BID, AID, String::from("message"), proof, true));
+            let proof = crate::gen_nizk_proof(BID, AID, String::from("answer"), true);
+            assert!(crate::verify_nizk_proof(AID, BID, String::from("answer"), proof, true));
+        });
+    }
+
+    #[test]
+    fn tampered_message_aborts() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(30003, 30004);
+            let mut a_noise = NoiseKeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits256);
+            let mut b_noise = NoiseKeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits256);
+
+            // Flip a bit of the second handshake message
+            let mut count = 0;
+            run(&mut a_noise, &mut b_noise, &mut |message| {
+                count += 1;
+                if count == 2 {
+                    let last = message.len() - 1;
+                    message[last] ^= 1;
+                }
+            });
+
+            assert!(!a_noise.is_complete() && !b_noise.is_complete());
+            assert!(crate::get_key_instance(&format!("SharedSecretKey:{}:{}", AID, BID), 32, None).is_err());
+            assert!(crate::get_key_instance(&format!("SharedSecretKey:{}:{}", BID, AID), 32, None).is_err());
+        });
+    }
+
+    #[test]
+    fn unknown_identity_key_aborts() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID) = paired_devices(30005, 30006);
+            // A uses another identity key than the one B knows
+            test_support::store_key_pair(AID);
+            let mut a_noise = NoiseKeyAgreement::new(AID, BID, CONST_INITIATOR_ROLE, KeySize::Bits256);
+            let mut b_noise = NoiseKeyAgreement::new(BID, AID, CONST_RECEIVER_ROLE, KeySize::Bits256);
+            run(&mut a_noise, &mut b_noise, &mut |_| {});
+
+            assert!(!a_noise.is_complete() && !b_noise.is_complete());
+        });
+    }
+}
@@ -0,0 +1,558 @@
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::IsIdentity;
+use crate::key_agreement::KeyAgreement;
+use crate::peer_registry::{PeerEntry, PeerRegistry, PeerStatus};
+use crate::{file_management, get_key_instance, revocation, schnorr_identification, KeySize};
+use crate::{get_32byte_key, restore_shared_values, store_shared_values};
+use crate::{CONST_INITIATOR_ROLE, CONST_RECEIVER_ROLE};
+use crate::DeviceId;
+
+// Message types of the PAKE pairing
+const CONST_MSG_SHARE: u8 = 1;
+const CONST_MSG_SHARE_AND_IDENTITY: u8 = 2;
+const CONST_MSG_IDENTITY: u8 = 3;
+const CONST_MSG_FAILED: u8 = 4;
+
+// Stages of the PAKE pairing
+const CONST_STAGE_SEND_SHARE: u8 = 0;
+const CONST_STAGE_SEND_SHARE_AND_IDENTITY: u8 = 1;
+const CONST_STAGE_SEND_IDENTITY: u8 = 2;
+const CONST_STAGE_SEND_FAILED: u8 = 3;
+const CONST_STAGE_WAIT: u8 = 4;
+
+// Labels of the PAKE pairing
+const CONST_PAKE_GENERATOR_LABEL: &[u8] = b"PakePairing generator";
+const CONST_PAKE_TRANSCRIPT_LABEL: &[u8] = b"PakePairing transcript";
+const CONST_PAKE_SESSION_KEY_LABEL: &[u8] = b"PakePairing session key";
+const CONST_PAKE_IDENTITY_LABEL: &[u8] = b"PakePairing identity";
+const CONST_PAKE_CONFIRMATION_LABEL: &[u8] = b"PakePairing key confirmation";
+const CONST_PAKE_SHARED_KEY_LABEL: &[u8] = b"PakePairing shared key";
+const CONST_PAKE_CODE_LABEL: &[u8] = b"PakePairing used code";
+const CONST_PAKE_ABORT_LABEL: &[u8] = b"PakePairing abort";
+
+// Pairing attempts without a successful pairing, until a technician has to reset the lockout
+const CONST_MAX_PAIRING_ATTEMPTS: u32 = 5;
+
+// Size of the identity part of a message: public key, signature and confirmation tag
+const CONST_IDENTITY_SIZE: usize = 128;
+
+// First contact of two devices that only share a short one-time pairing code, e.g. printed on a device label.
+// A CPace-style PAKE derives a session key from the code: both devices send a random multiple of a generator
+// that is derived from the code, so a passive attacker can not test guesses of the code offline, and an active
+// attacker can only test one guess per pairing attempt. The session key authenticates the exchange of the
+// identity public keys, which are added to the peer registry, and the first shared secret key is derived from it.
+// A pairing code is only accepted for one successful pairing, and pairing is locked after a few attempts without success.
+// A known peer keeps its key, unless re-pairing is allowed with allow_repair.
+pub struct PakePairing {
+    pub my_ID: DeviceId,
+    pub other_ID: DeviceId,
+    pub role: u8,
+    pub key_size: KeySize,
+    stage: u8,
+    my_secret: Scalar,
+    my_share: [u8; 32],
+    other_share: [u8; 32],
+    session_key: Option<[u8; 32]>,
+    code_hash: [u8; 32],
+    repair_allowed: bool,
+    key_committed: bool,
+    previous_shared_values: Option<(Vec<u8>, Vec<u8>)>,
+    previous_peer: Option<Option<PeerEntry>>,
+}
+
+impl PakePairing {
+    // Create a new instance. Both devices have to use the same pairing code and key size
+    pub fn new(my_ID: DeviceId, other_ID: DeviceId, role: u8, pairing_code: &[u8], key_size: KeySize) -> PakePairing {
+        // Derive the generator from the pairing code, both IDs and the key size
+        let mut session_id = Vec::new();
+        let (init_ID, rec_ID) = if role == CONST_INITIATOR_ROLE {
+            (my_ID, other_ID)
+        } else {
+            (other_ID, my_ID)
+        };
+        session_id.extend_from_slice(&init_ID.to_bytes());
+        session_id.extend_from_slice(&rec_ID.to_bytes());
+        session_id.push(key_size.bytes() as u8);
+
+        let mut uniform_bytes = [0u8; 64];
+        uniform_bytes[..32].copy_from_slice(&schnorr_identification::sha3_256(CONST_PAKE_GENERATOR_LABEL, Some(pairing_code), Some(&session_id), Some(&[0u8])));
+        uniform_bytes[32..].copy_from_slice(&schnorr_identification::sha3_256(CONST_PAKE_GENERATOR_LABEL, Some(pairing_code), Some(&session_id), Some(&[1u8])));
+        let generator = RistrettoPoint::from_uniform_bytes(&uniform_bytes);
+
+        // Used codes are remembered by their hash
+        let code_hash = schnorr_identification::sha3_256(CONST_PAKE_CODE_LABEL, Some(pairing_code), Some(&my_ID.to_bytes()), None);
+
+        // Random share of this device
+        let my_secret = schnorr_identification::generate_random_scalar();
+        let my_share = (my_secret * generator).compress().to_bytes();
+
+        // Initiator sends the first share
+        let mut stage = CONST_STAGE_SEND_SHARE;
+        if role == CONST_RECEIVER_ROLE {
+            stage = CONST_STAGE_WAIT;
+        }
+
+        PakePairing {
+            my_ID,
+            other_ID,
+            role,
+            key_size,
+            stage,
+            my_secret,
+            my_share,
+            other_share: [0u8; 32],
+            session_key: None,
+            code_hash,
+            repair_allowed: false,
+            key_committed: false,
+            previous_shared_values: None,
+            previous_peer: None,
+        }
+    }
+
+    // Allow replacing the public key of a known peer, e.g. after the other device was reset.
+    // The certificate of the peer is removed then, since it does not belong to the new key
+    pub fn allow_repair(&mut self) {
+        self.repair_allowed = true;
+    }
+
+    // Undo storing the shared secret key and the public key of the other device
+    pub fn rollback_shared_secret_key(&mut self) {
+        if !self.key_committed {
+            return;
+        }
+
+        restore_shared_values(self.my_ID, self.other_ID, self.previous_shared_values.take());
+        if let (Some(previous_peer), Ok(mut registry)) = (self.previous_peer.take(), PeerRegistry::load()) {
+            let _ = registry.remove_peer(self.other_ID);
+            if let Some(peer) = previous_peer {
+                registry.add_peer(peer).unwrap();
+            }
+            let _ = registry.save();
+        }
+        self.key_committed = false;
+    }
+
+    // Hash of both IDs, the key size and both shares, starting with the ones of the initiator
+    pub fn transcript_hash(&self) -> [u8; 32] {
+        let (init_values, rec_values) = if self.role == CONST_INITIATOR_ROLE {
+            ((self.my_ID, self.my_share), (self.other_ID, self.other_share))
+        } else {
+            ((self.other_ID, self.other_share), (self.my_ID, self.my_share))
+        };
+
+        let mut ids = Vec::new();
+        let mut shares = Vec::with_capacity(64);
+        for (id, share) in [init_values, rec_values] {
+            ids.extend_from_slice(&id.to_bytes());
+            shares.extend_from_slice(&share);
+        }
+        ids.push(self.key_size.bytes() as u8);
+
+        schnorr_identification::sha3_256(CONST_PAKE_TRANSCRIPT_LABEL, Some(&ids), Some(&shares), None)
+    }
+
+    // Derive the session key from the share of the other device. Returns false for invalid shares
+    fn add_other_share(&mut self, share: &[u8]) -> bool {
+        // Every session key allows one guess of the code, so the attempt is counted before it is derived
+        if is_code_used(self.my_ID, &self.code_hash) {
+            println!("Pairing code was already used for a pairing!\n");
+            return false;
+        }
+        let attempts = get_pairing_attempts(self.my_ID);
+        if attempts >= CONST_MAX_PAIRING_ATTEMPTS {
+            println!("Pairing is locked after {} attempts without success!\n", attempts);
+            return false;
+        }
+        set_pairing_attempts(self.my_ID, attempts + 1);
+
+        self.other_share = <[u8; 32]>::try_from(share).unwrap();
+        let other_point = match CompressedRistretto(self.other_share).decompress() {
+            Some(point) if !point.is_identity() => point,
+            _ => {
+                println!("Received an invalid pairing share from {}!\n", self.other_ID);
+                return false;
+            }
+        };
+
+        let shared_point = (self.my_secret * other_point).compress().to_bytes();
+        let transcript_hash = self.transcript_hash();
+        self.session_key = Some(schnorr_identification::sha3_256(CONST_PAKE_SESSION_KEY_LABEL,
+                                                                 Some(&shared_point),
+                                                                 Some(&transcript_hash),
+                                                                 None));
+        true
+    }
+
+    // Message signed by a role with its identity key
+    fn identity_message(&self, role: u8) -> Vec<u8> {
+        let mut message = Vec::from(CONST_PAKE_IDENTITY_LABEL);
+        message.extend_from_slice(&self.transcript_hash());
+        message.push(role);
+        message
+    }
+
+    // Own public key, a signature of the transcript with the identity key, and a tag with the session key
+    fn gen_identity(&self) -> Vec<u8> {
+        let (privkey, _) = get_32byte_key(format!("PrivateKey:{}", self.my_ID));
+        let pubkey = schnorr_identification::public_key(privkey);
+        let (commitment, response) = schnorr_identification::sign(privkey, &self.identity_message(self.role));
+
+        let mut identity = Vec::with_capacity(CONST_IDENTITY_SIZE);
+        identity.extend_from_slice(&pubkey);
+        identity.extend_from_slice(&commitment);
+        identity.extend_from_slice(&response);
+        let tag = schnorr_identification::kmac_256(self.session_key.unwrap(),
+                                                   CONST_PAKE_CONFIRMATION_LABEL,
+                                                   Some(&[self.role]),
+                                                   Some(&identity));
+        identity.extend_from_slice(&tag);
+        identity
+    }
+
+    // Check the identity of the other device and store its public key and the shared secret key
+    fn receive_identity(&mut self, identity: &[u8]) -> bool {
+        let session_key = match self.session_key {
+            Some(key) => key,
+            None => {
+                return false;
+            }
+        };
+        let other_role = if self.role == CONST_INITIATOR_ROLE {
+            CONST_RECEIVER_ROLE
+        } else {
+            CONST_INITIATOR_ROLE
+        };
+
+        // Tag proves that the other device knows the pairing code
+        let tag = schnorr_identification::kmac_256(session_key,
+                                                   CONST_PAKE_CONFIRMATION_LABEL,
+                                                   Some(&[other_role]),
+                                                   Some(&identity[..96]));
+        if tag[..] != identity[96..CONST_IDENTITY_SIZE] {
+            println!("Pairing with {} failed, the pairing codes do not match!\n", self.other_ID);
+            return false;
+        }
+
+        // Signature proves that the other device holds the private key
+        let pubkey = <[u8; 32]>::try_from(&identity[..32]).unwrap();
+        let signature = (<[u8; 32]>::try_from(&identity[32..64]).unwrap(),
+                         <[u8; 32]>::try_from(&identity[64..96]).unwrap());
+        if !schnorr_identification::verify_signature(pubkey, &self.identity_message(other_role), signature) {
+            println!("Identity of {} could not be verified!\n", self.other_ID);
+            return false;
+        }
+        if !self.other_ID.matches_public_key(&pubkey) || revocation::is_revoked(self.other_ID, &pubkey) {
+            println!("Public key of {} is not accepted!\n", self.other_ID);
+            return false;
+        }
+
+        // Add the other device to the peer registry. Display name and policy of a known peer are kept
+        let mut registry = match PeerRegistry::load() {
+            Ok(registry) => registry,
+            Err(_) => {
+                return false;
+            }
+        };
+        let previous_peer = registry.get_peer(self.other_ID).cloned();
+        let mut entry = match &previous_peer {
+            Some(peer) if peer.status == PeerStatus::Revoked => {
+                println!("Device {} is revoked and can not pair again!\n", self.other_ID);
+                return false;
+            },
+            Some(peer) if peer.public_key != pubkey && !self.repair_allowed => {
+                println!("Device {} is already paired with another key, re-pairing is not allowed!\n", self.other_ID);
+                return false;
+            },
+            Some(peer) => peer.clone(),
+            None => PeerEntry::new(self.other_ID, pubkey, format!("Device {}", self.other_ID)),
+        };
+        if entry.public_key != pubkey {
+            entry.certificate = None;
+            entry.previous_public_key = None;
+        }
+        entry.public_key = pubkey;
+        if registry.update_peer(entry.clone()).is_err() {
+            registry.add_peer(entry).unwrap();
+        }
+        if registry.save().is_err() {
+            return false;
+        }
+        self.previous_peer = Some(previous_peer);
+
+        // Derive the first shared secret key and save it in the OS
+        let key = schnorr_identification::sha3_256(CONST_PAKE_SHARED_KEY_LABEL, Some(&session_key), None, None);
+        let key_len = self.key_size.bytes();
+        self.previous_shared_values = store_shared_values(self.my_ID, self.other_ID, &key[..key_len]);
+        self.key_committed = true;
+
+        // The code is used up now, and the lockout starts again
+        mark_code_used(self.my_ID, self.code_hash);
+        set_pairing_attempts(self.my_ID, 0);
+        true
+    }
+}
+
+// Number of pairing attempts since the last successful pairing. It is kept in the OS,
+// so that deleting files does not reset the lockout
+fn get_pairing_attempts(my_ID: DeviceId) -> u32 {
+    match get_key_instance(&format!("PairingAttempts:{}", my_ID), 4, None) {
+        Ok(key_ins) => <[u8; 4]>::try_from(key_ins.get_key().as_slice()).map(u32::from_be_bytes).unwrap_or(u32::MAX),
+        Err(_) => 0,
+    }
+}
+
+// Save the number of pairing attempts since the last successful pairing
+fn set_pairing_attempts(my_ID: DeviceId, attempts: u32) {
+    let attempts_vec = Vec::from(attempts.to_be_bytes());
+    let mut key_ins = get_key_instance(&format!("PairingAttempts:{}", my_ID), 4, Some(attempts_vec.clone())).unwrap();
+    if key_ins.get_key() != &attempts_vec {
+        key_ins.update_key_in_ring(attempts_vec).unwrap();
+    }
+}
+
+// Allow pairing again after it was locked by too many attempts
+pub fn reset_pairing_lockout(my_ID: DeviceId) {
+    set_pairing_attempts(my_ID, 0);
+}
+
+// File path of the hashes of used pairing codes
+fn get_used_codes_file_path() -> String {
+    String::from(".nizk-auth/pairing/used_codes.json")
+}
+
+// Read the hashes of used pairing codes. None if the file can not be read
+fn read_used_codes() -> Option<Vec<[u8; 32]>> {
+    let file = match File::open(get_used_codes_file_path()) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Some(Vec::new());
+        },
+        Err(_) => {
+            return None;
+        }
+    };
+
+    serde_json::from_reader(BufReader::new(file)).ok()
+}
+
+// Check if a code was used for a pairing. Codes count as used while the file can not be read
+fn is_code_used(my_ID: DeviceId, code_hash: &[u8; 32]) -> bool {
+    match read_used_codes() {
+        Some(used_codes) => used_codes.contains(code_hash),
+        None => {
+            println!("Used pairing codes of {} can not be read!\n", my_ID);
+            true
+        }
+    }
+}
+
+// Remember a code after a successful pairing, see file_management::write_file_atomically
+fn mark_code_used(my_ID: DeviceId, code_hash: [u8; 32]) {
+    let mut used_codes = match read_used_codes() {
+        Some(used_codes) => used_codes,
+        None => {
+            println!("Used pairing codes of {} can not be read!\n", my_ID);
+            return;
+        }
+    };
+    used_codes.push(code_hash);
+
+    let json_string = serde_json::to_string(&used_codes).unwrap();
+    if let Err(e) = file_management::write_file_atomically(&get_used_codes_file_path(), json_string.as_bytes()) {
+        println!("Used pairing codes of {} can not be written: {}\n", my_ID, e);
+    }
+}
+
+impl KeyAgreement for PakePairing {
+    fn next_message(&mut self) -> Option<Vec<u8>> {
+        let mut message = Vec::with_capacity(1 + 32 + CONST_IDENTITY_SIZE);
+        match self.stage {
+            CONST_STAGE_SEND_SHARE => {
+                message.push(CONST_MSG_SHARE);
+                message.extend_from_slice(&self.my_share);
+            },
+            CONST_STAGE_SEND_SHARE_AND_IDENTITY => {
+                message.push(CONST_MSG_SHARE_AND_IDENTITY);
+                message.extend_from_slice(&self.my_share);
+                message.extend_from_slice(&self.gen_identity());
+            },
+            CONST_STAGE_SEND_IDENTITY => {
+                message.push(CONST_MSG_IDENTITY);
+                message.extend_from_slice(&self.gen_identity());
+            },
+            CONST_STAGE_SEND_FAILED => {
+                // Tell the other device to discard the shared secret key, with a tag of the session key if there is one
+                message.push(CONST_MSG_FAILED);
+                if let Some(session_key) = self.session_key {
+                    message.extend_from_slice(&schnorr_identification::kmac_256(session_key, CONST_PAKE_ABORT_LABEL, Some(&[self.role]), None));
+                }
+            },
+            _ => {
+                return None;
+            }
+        }
+
+        self.stage = CONST_STAGE_WAIT;
+        Some(message)
+    }
+
+    fn receive_message(&mut self, message: &[u8]) -> bool {
+        let accepted = match (self.role, message.first().copied(), message.len()) {
+            // Receiver got the share of the initiator
+            (CONST_RECEIVER_ROLE, Some(CONST_MSG_SHARE), 33) if self.session_key.is_none() => {
+                self.add_other_share(&message[1..33])
+            },
+            // Initiator got the share and the identity of the receiver
+            (CONST_INITIATOR_ROLE, Some(CONST_MSG_SHARE_AND_IDENTITY), 161) if self.session_key.is_none() => {
+                self.add_other_share(&message[1..33]) && self.receive_identity(&message[33..161])
+            },
+            // Receiver got the identity of the initiator
+            (CONST_RECEIVER_ROLE, Some(CONST_MSG_IDENTITY), 129) if !self.key_committed => {
+                self.receive_identity(&message[1..129])
+            },
+            // Other device discarded the key, so do the same. A stored key is only discarded if the abort
+            // has a tag of the session key
+            (_, Some(CONST_MSG_FAILED), _) => {
+                let other_role = if self.role == CONST_INITIATOR_ROLE {
+                    CONST_RECEIVER_ROLE
+                } else {
+                    CONST_INITIATOR_ROLE
+                };
+                let authenticated = !self.key_committed || match self.session_key {
+                    Some(session_key) => {
+                        let tag = schnorr_identification::kmac_256(session_key, CONST_PAKE_ABORT_LABEL, Some(&[other_role]), None);
+                        message.len() == 33 && tag[..] == message[1..33]
+                    },
+                    None => false,
+                };
+                if !authenticated {
+                    println!("Abort of {} is not authenticated and is ignored!\n", self.other_ID);
+                    return false;
+                }
+                self.rollback_shared_secret_key();
+                self.stage = CONST_STAGE_WAIT;
+                return false;
+            },
+            _ => false,
+        };
+
+        // Define the next message. A committed key is only discarded by an authenticated abort
+        self.stage = match (accepted, self.role, self.key_committed) {
+            (false, _, true) => CONST_STAGE_WAIT,
+            (false, _, false) => CONST_STAGE_SEND_FAILED,
+            (true, CONST_RECEIVER_ROLE, false) => CONST_STAGE_SEND_SHARE_AND_IDENTITY,
+            (true, CONST_INITIATOR_ROLE, true) => CONST_STAGE_SEND_IDENTITY,
+            _ => CONST_STAGE_WAIT,
+        };
+
+        accepted
+    }
+
+    fn is_complete(&self) -> bool {
+        self.key_committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support;
+
+    // Run the pairing between two instances in memory
+    fn run(initiator: &mut PakePairing, receiver: &mut PakePairing) {
+        loop {
+            let mut sent = false;
+            if let Some(message) = initiator.next_message() {
+                receiver.receive_message(&message);
+                sent = true;
+            }
+            if let Some(message) = receiver.next_message() {
+                initiator.receive_message(&message);
+                sent = true;
+            }
+            if !sent {
+                return;
+            }
+        }
+    }
+
+    // Pair two devices and return if both of them completed the pairing
+    fn pair(AID: DeviceId, BID: DeviceId, a_code: &[u8], b_code: &[u8]) -> bool {
+        let mut a_pairing = PakePairing::new(AID, BID, CONST_INITIATOR_ROLE, a_code, KeySize::Bits256);
+        let mut b_pairing = PakePairing::new(BID, AID, CONST_RECEIVER_ROLE, b_code, KeySize::Bits256);
+        run(&mut a_pairing, &mut b_pairing);
+        assert_eq!(a_pairing.is_complete(), b_pairing.is_complete());
+        a_pairing.is_complete()
+    }
+
+    // Two devices with a key pair, which do not know each other yet
+    fn unpaired_devices(first: u32, second: u32) -> (DeviceId, DeviceId, [u8; 32], [u8; 32]) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        (AID, BID, test_support::store_key_pair(AID), test_support::store_key_pair(BID))
+    }
+
+    fn has_shared_key(my_ID: DeviceId, other_ID: DeviceId) -> bool {
+        get_key_instance(&format!("SharedSecretKey:{}:{}", my_ID, other_ID), 32, None).is_ok()
+    }
+
+    #[test]
+    fn pairing_with_same_code_stores_same_key() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID, a_public_key, b_public_key) = unpaired_devices(37001, 37002);
+            assert!(pair(AID, BID, b"123456", b"123456"));
+
+            let a_key = crate::get_shared_key(AID, BID).0;
+            assert_eq!(a_key.len(), 32);
+            assert_eq!(a_key, crate::get_shared_key(BID, AID).0);
+            let registry = PeerRegistry::load().unwrap();
+            assert_eq!(registry.get_peer(AID).unwrap().public_key, a_public_key);
+            assert_eq!(registry.get_peer(BID).unwrap().public_key, b_public_key);
+        });
+    }
+
+    #[test]
+    fn wrong_code_stores_no_key() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID, _, _) = unpaired_devices(37011, 37012);
+            assert!(!pair(AID, BID, b"123456", b"654321"));
+
+            assert!(!has_shared_key(AID, BID) && !has_shared_key(BID, AID));
+            let registry = PeerRegistry::load().unwrap();
+            assert!(registry.get_peer(AID).is_none() && registry.get_peer(BID).is_none());
+        });
+    }
+
+    #[test]
+    fn pairing_is_locked_after_max_attempts() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID, _, _) = unpaired_devices(37021, 37022);
+            for _ in 0..CONST_MAX_PAIRING_ATTEMPTS {
+                assert!(!pair(AID, BID, b"123456", b"000000"));
+            }
+
+            // Even the right code is rejected until the lockout is reset
+            assert!(!pair(AID, BID, b"123456", b"123456"));
+            assert!(!has_shared_key(AID, BID) && !has_shared_key(BID, AID));
+            reset_pairing_lockout(AID);
+            reset_pairing_lockout(BID);
+            assert!(pair(AID, BID, b"123456", b"123456"));
+        });
+    }
+
+    #[test]
+    fn used_code_is_rejected() {
+        test_support::in_empty_work_dir(|| {
+            let (AID, BID, _, _) = unpaired_devices(37031, 37032);
+            assert!(pair(AID, BID, b"123456", b"123456"));
+            let a_key = crate::get_shared_key(AID, BID).0;
+
+            assert!(!pair(AID, BID, b"123456", b"123456"));
+            assert_eq!(crate::get_shared_key(AID, BID).0, a_key);
+            assert!(pair(AID, BID, b"234567", b"234567"));
+        });
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use serde::{Deserialize, Serialize};
+use chrono::Utc;
+use crate::enrollment::DeviceCertificate;
+use crate::{file_management, get_key_instance, revocation, KeySize};
+use crate::DeviceId;
+
+#[derive(Debug)]
+pub enum PeerRegistryErrors {
+    PeerAlreadyExists,
+    PeerNotFound,
+    PeerRevoked,
+    InvalidRegistryFile,
+    UnableToWriteRegistry(std::io::Error),
+}
+
+// Status of a peer. Only active peers are accepted by the verification functions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PeerStatus {
+    Active,
+    Suspended,
+    Revoked,
+}
+
+// Protocols that can agree on a shared secret key with a peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyAgreementProtocol {
+    IntMutAuth,
+    X25519,
+    Noise,
+}
+
+// Protocols a peer may use for the key agreement, and the smallest accepted key size
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerPolicy {
+    pub allowed_protocols: Vec<KeyAgreementProtocol>,
+    pub min_key_size: KeySize,
+}
+
+impl Default for PeerPolicy {
+    fn default() -> PeerPolicy {
+        PeerPolicy {
+            allowed_protocols: vec![KeyAgreementProtocol::IntMutAuth, KeyAgreementProtocol::X25519, KeyAgreementProtocol::Noise],
+            min_key_size: KeySize::Bits128,
+        }
+    }
+}
+
+impl PeerPolicy {
+    // Check if a key agreement with this protocol and key size is allowed
+    pub fn allows(&self, protocol: KeyAgreementProtocol, key_size: KeySize) -> bool {
+        self.allowed_protocols.contains(&protocol) && key_size >= self.min_key_size
+    }
+}
+
+// Entry of a peer in the registry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerEntry {
+    pub device_ID: DeviceId,
+    pub public_key: [u8; 32],
+    pub display_name: String,
+    pub enrolled_at: i64,
+    pub certificate: Option<DeviceCertificate>,
+    pub status: PeerStatus,
+    pub policy: PeerPolicy,
+    // Key before the last rotation, accepted for NIZK proofs until previous_key_valid_until
+    #[serde(default)]
+    pub previous_public_key: Option<[u8; 32]>,
+    #[serde(default)]
+    pub previous_key_valid_until: i64,
+}
+
+impl PeerEntry {
+    // Create an active entry with the default policy
+    pub fn new(device_ID: DeviceId, public_key: [u8; 32], display_name: String) -> PeerEntry {
+        PeerEntry {
+            device_ID,
+            public_key,
+            display_name,
+            enrolled_at: Utc::now().timestamp_millis(),
+            certificate: None,
+            status: PeerStatus::Active,
+            policy: PeerPolicy::default(),
+            previous_public_key: None,
+            previous_key_valid_until: 0,
+        }
+    }
+
+    // Current public key, and the previous one while its grace period lasts. Revoked keys are left out
+    pub fn accepted_public_keys(&self) -> Vec<[u8; 32]> {
+        self.keys_accepted_at(Utc::now().timestamp_millis(), |key| revocation::is_revoked(self.device_ID, key))
+    }
+
+    // Keys accepted at a time, without the keys is_revoked returns true for
+    fn keys_accepted_at(&self, timestamp: i64, is_revoked: impl Fn(&[u8; 32]) -> bool) -> Vec<[u8; 32]> {
+        let mut keys = vec![self.public_key];
+        if let Some(previous_key) = self.previous_public_key {
+            if timestamp <= self.previous_key_valid_until {
+                keys.push(previous_key);
+            }
+        }
+        keys.retain(|key| !is_revoked(key));
+        keys
+    }
+}
+
+// Directory of all known peers, saved in a json file
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct PeerRegistry {
+    peers: BTreeMap<DeviceId, PeerEntry>,
+}
+
+// File path of the peer registry
+fn get_registry_file_path() -> String {
+    String::from(".nizk-auth/peers.json")
+}
+
+impl PeerRegistry {
+    // Read the registry from its json file. Returns an empty registry if no file exists,
+    // and an error if the file can not be read, so that no peer is accepted from a broken registry
+    pub fn load() -> Result<PeerRegistry, PeerRegistryErrors> {
+        let file = match File::open(get_registry_file_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(PeerRegistry::default());
+            },
+            Err(e) => {
+                println!("Peer registry can not be opened: {}\n", e);
+                return Err(PeerRegistryErrors::InvalidRegistryFile);
+            }
+        };
+
+        let reader = BufReader::new(file);
+        serde_json::from_reader(reader).map_err(|e| {
+            println!("Peer registry can not be read: {}\n", e);
+            PeerRegistryErrors::InvalidRegistryFile
+        })
+    }
+
+    // Save the registry into its json file. The file is replaced at once, so readers never see a partial registry
+    pub fn save(&self) -> Result<(), PeerRegistryErrors> {
+        let json_string = serde_json::to_string(self).unwrap();
+        file_management::write_file_atomically(&get_registry_file_path(), json_string.as_bytes()).map_err(|e| {
+            println!("Peer registry can not be written: {}\n", e);
+            PeerRegistryErrors::UnableToWriteRegistry(e)
+        })
+    }
+
+    // Add a new peer. Peers are only added after their key was checked, by enrollment or pairing
+    pub(crate) fn add_peer(&mut self, entry: PeerEntry) -> Result<(), PeerRegistryErrors> {
+        if self.peers.contains_key(&entry.device_ID) {
+            println!("Peer {} already exists, try to update it instead!\n", entry.device_ID);
+            return Err(PeerRegistryErrors::PeerAlreadyExists);
+        }
+
+        self.peers.insert(entry.device_ID, entry);
+        Ok(())
+    }
+
+    // Replace the entry of an existing peer
+    pub(crate) fn update_peer(&mut self, entry: PeerEntry) -> Result<(), PeerRegistryErrors> {
+        match self.peers.get_mut(&entry.device_ID) {
+            Some(peer) => {
+                *peer = entry;
+                Ok(())
+            },
+            None => Err(PeerRegistryErrors::PeerNotFound),
+        }
+    }
+
+    // Remove a peer from the registry
+    pub fn remove_peer(&mut self, device_ID: DeviceId) -> Result<PeerEntry, PeerRegistryErrors> {
+        self.peers.remove(&device_ID).ok_or(PeerRegistryErrors::PeerNotFound)
+    }
+
+    // Return the entry of a peer
+    pub fn get_peer(&self, device_ID: DeviceId) -> Option<&PeerEntry> {
+        self.peers.get(&device_ID)
+    }
+
+    // Return all peers, ordered by device ID
+    pub fn list_peers(&self) -> Vec<&PeerEntry> {
+        self.peers.values().collect()
+    }
+
+    // Change the status of a peer. A revoked peer stays revoked
+    pub fn set_status(&mut self, device_ID: DeviceId, status: PeerStatus) -> Result<(), PeerRegistryErrors> {
+        let peer = self.peers.get_mut(&device_ID).ok_or(PeerRegistryErrors::PeerNotFound)?;
+        if peer.status == PeerStatus::Revoked && status != PeerStatus::Revoked {
+            println!("Peer {} is revoked and can not become {:?} again!\n", device_ID, status);
+            return Err(PeerRegistryErrors::PeerRevoked);
+        }
+        peer.status = status;
+        Ok(())
+    }
+
+    // Change the display name of a peer
+    pub fn set_display_name(&mut self, device_ID: DeviceId, display_name: String) -> Result<(), PeerRegistryErrors> {
+        let peer = self.peers.get_mut(&device_ID).ok_or(PeerRegistryErrors::PeerNotFound)?;
+        peer.display_name = display_name;
+        Ok(())
+    }
+
+    // Change the protocol policy of a peer
+    pub fn set_policy(&mut self, device_ID: DeviceId, policy: PeerPolicy) -> Result<(), PeerRegistryErrors> {
+        let peer = self.peers.get_mut(&device_ID).ok_or(PeerRegistryErrors::PeerNotFound)?;
+        peer.policy = policy;
+        Ok(())
+    }
+}
+
+// Return the entry of a peer, if it is active and not on a revocation list
+fn get_active_peer(registry: &PeerRegistry, device_ID: DeviceId) -> Option<&PeerEntry> {
+    match registry.get_peer(device_ID) {
+        Some(peer) if revocation::is_revoked(device_ID, &peer.public_key) => {
+            println!("Peer {} is on a revocation list, its proofs are not accepted!\n", device_ID);
+            None
+        },
+        Some(peer) if peer.status == PeerStatus::Active => Some(peer),
+        Some(peer) => {
+            println!("Peer {} is {:?}, its proofs are not accepted!\n", device_ID, peer.status);
+            None
+        },
+        None => {
+            println!("Peer {} is unknown!\n", device_ID);
+            None
+        }
+    }
+}
+
+// Save the time of a successful mutual authentication with a peer. A time of 0 clears it.
+// It changes with every key agreement, so it is kept in the OS like the shared counters instead of the registry file
+pub(crate) fn record_mutual_auth(device_ID: DeviceId, timestamp: i64) {
+    let description = format!("LastMutualAuth:{}", device_ID);
+    let timestamp_vec = Vec::from(timestamp.to_be_bytes());
+    let mut key_ins = get_key_instance(&description, 8, Some(timestamp_vec.clone())).unwrap();
+    if key_ins.get_key() != &timestamp_vec {
+        key_ins.update_key_in_ring(timestamp_vec).unwrap();
+    }
+}
+
+// Return the time of the last successful mutual authentication with a peer, 0 if there was none
+pub fn get_last_mutual_auth(device_ID: DeviceId) -> i64 {
+    match get_key_instance(&format!("LastMutualAuth:{}", device_ID), 8, None) {
+        Ok(key_ins) => <[u8; 8]>::try_from(key_ins.get_key().as_slice()).map(i64::from_be_bytes).unwrap_or(0),
+        Err(_) => 0,
+    }
+}
+
+// Return the public key of an active peer
+pub fn get_peer_public_key(device_ID: DeviceId) -> Option<[u8; 32]> {
+    let registry = PeerRegistry::load().ok()?;
+    get_active_peer(&registry, device_ID).map(|peer| peer.public_key)
+}
+
+// Return all public keys of an active peer that are accepted for proofs. Empty if the peer is not accepted
+pub fn get_peer_public_keys(device_ID: DeviceId) -> Vec<[u8; 32]> {
+    let registry = match PeerRegistry::load() {
+        Ok(registry) => registry,
+        Err(_) => {
+            return Vec::new();
+        }
+    };
+    match get_active_peer(&registry, device_ID) {
+        Some(peer) => peer.accepted_public_keys(),
+        None => Vec::new(),
+    }
+}
+
+// Return the public key of an active peer, if its policy allows the key agreement
+pub(crate) fn get_key_agreement_public_key(device_ID: DeviceId, protocol: KeyAgreementProtocol, key_size: KeySize) -> Option<[u8; 32]> {
+    let registry = PeerRegistry::load().ok()?;
+    let peer = get_active_peer(&registry, device_ID)?;
+    if !peer.policy.allows(protocol, key_size) {
+        println!("Policy of peer {} does not allow {:?} with {:?} keys!\n", device_ID, protocol, key_size);
+        return None;
+    }
+
+    Some(peer.public_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revoked_peer_stays_revoked() {
+        let mut registry = PeerRegistry::default();
+        registry.add_peer(PeerEntry::new(1u32.into(), [1u8; 32], String::from("Peer"))).unwrap();
+
+        registry.set_status(1u32.into(), PeerStatus::Suspended).unwrap();
+        registry.set_status(1u32.into(), PeerStatus::Revoked).unwrap();
+        assert!(matches!(registry.set_status(1u32.into(), PeerStatus::Active), Err(PeerRegistryErrors::PeerRevoked)));
+        assert_eq!(registry.get_peer(1u32.into()).unwrap().status, PeerStatus::Revoked);
+    }
+
+    #[test]
+    fn previous_key_accepted_during_grace_period() {
+        let mut entry = PeerEntry::new(1u32.into(), [1u8; 32], String::from("Peer"));
+        entry.previous_public_key = Some([2u8; 32]);
+        entry.previous_key_valid_until = 1000;
+
+        assert_eq!(entry.keys_accepted_at(1000, |_| false), vec![[1u8; 32], [2u8; 32]]);
+        assert_eq!(entry.keys_accepted_at(1001, |_| false), vec![[1u8; 32]]);
+    }
+
+    #[test]
+    fn revoked_previous_key_not_accepted_during_grace_period() {
+        let mut entry = PeerEntry::new(1u32.into(), [1u8; 32], String::from("Peer"));
+        entry.previous_public_key = Some([2u8; 32]);
+        entry.previous_key_valid_until = 1000;
+
+        assert_eq!(entry.keys_accepted_at(500, |key| *key == [2u8; 32]), vec![[1u8; 32]]);
+    }
+}
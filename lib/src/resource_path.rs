@@ -0,0 +1,163 @@
+use std::fmt;
+use std::str::FromStr;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+// Separator of the segments in the text form of a path
+const CONST_PATH_SEPARATOR: char = '/';
+
+// Separator of the segments in file names
+const CONST_FILE_NAME_SEPARATOR: &str = ".";
+
+// Identifier of a resource. Resources form a hierarchy like "building/floor2/door7", where grants of
+// a resource are inherited by all resources below it. Numeric IDs are paths with a single segment.
+// Segments may only contain ASCII letters, digits, '-' and '_'
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ResourcePath {
+    segments: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ResourcePathErrors {
+    EmptyPath,
+    InvalidSegment(String),
+}
+
+impl ResourcePath {
+    // Parse a path from its text form, with segments separated by '/'
+    pub fn parse(path: &str) -> Result<ResourcePath, ResourcePathErrors> {
+        if path.is_empty() {
+            return Err(ResourcePathErrors::EmptyPath);
+        }
+
+        let mut segments = Vec::new();
+        for segment in path.split(CONST_PATH_SEPARATOR) {
+            let valid = !segment.is_empty() &&
+                segment.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+            if !valid {
+                return Err(ResourcePathErrors::InvalidSegment(String::from(segment)));
+            }
+            segments.push(String::from(segment));
+        }
+        Ok(ResourcePath { segments })
+    }
+
+    pub fn segments(&self) -> &[String] {
+        &self.segments
+    }
+
+    // Path of the resource above this one, None for a top level resource
+    pub fn parent(&self) -> Option<ResourcePath> {
+        if self.segments.len() == 1 {
+            return None;
+        }
+        Some(ResourcePath {
+            segments: self.segments[..self.segments.len() - 1].to_vec(),
+        })
+    }
+
+    // This path followed by all paths above it, from the most to the least specific
+    pub fn ancestors(&self) -> Vec<ResourcePath> {
+        let mut ancestors = vec![self.clone()];
+        while let Some(parent) = ancestors.last().unwrap().parent() {
+            ancestors.push(parent);
+        }
+        ancestors
+    }
+
+    // Path of a resource below this one
+    pub fn join(&self, child: &ResourcePath) -> ResourcePath {
+        let mut segments = self.segments.clone();
+        segments.extend_from_slice(&child.segments);
+        ResourcePath { segments }
+    }
+
+    // Check if a path is this path or below it
+    pub fn contains(&self, other: &ResourcePath) -> bool {
+        other.segments.starts_with(&self.segments)
+    }
+
+    // Unambiguous byte form, used in hashes and signatures
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::from((self.segments.len() as u32).to_be_bytes());
+        for segment in self.segments.iter() {
+            bytes.extend_from_slice(&(segment.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(segment.as_bytes());
+        }
+        bytes
+    }
+
+    // Form used in file names. Numeric IDs keep their decimal form
+    pub(crate) fn to_file_name(&self) -> String {
+        self.segments.join(CONST_FILE_NAME_SEPARATOR)
+    }
+
+    // Numeric ID, if the path is a single number in its decimal form
+    fn as_number(&self) -> Option<u32> {
+        match self.segments.as_slice() {
+            [segment] => segment.parse::<u32>().ok().filter(|number| number.to_string() == *segment),
+            _ => None,
+        }
+    }
+}
+
+impl From<u32> for ResourcePath {
+    fn from(number: u32) -> ResourcePath {
+        ResourcePath {
+            segments: vec![number.to_string()],
+        }
+    }
+}
+
+impl fmt::Display for ResourcePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.segments.join(&CONST_PATH_SEPARATOR.to_string()))
+    }
+}
+
+impl FromStr for ResourcePath {
+    type Err = ResourcePathErrors;
+
+    fn from_str(s: &str) -> Result<ResourcePath, ResourcePathErrors> {
+        ResourcePath::parse(s)
+    }
+}
+
+// Numeric IDs are stored as json numbers, so files written with u32 resource IDs stay readable
+impl Serialize for ResourcePath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.as_number() {
+            Some(number) => serializer.serialize_u32(number),
+            None => serializer.serialize_str(&self.to_string()),
+        }
+    }
+}
+
+struct ResourcePathVisitor;
+
+impl<'de> Visitor<'de> for ResourcePathVisitor {
+    type Value = ResourcePath;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a u32 or a resource path string")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<ResourcePath, E> {
+        u32::try_from(value).map(ResourcePath::from).map_err(|_| E::custom("resource ID out of range"))
+    }
+
+    // Formats like TOML only have signed integers
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<ResourcePath, E> {
+        u32::try_from(value).map(ResourcePath::from).map_err(|_| E::custom("resource ID out of range"))
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<ResourcePath, E> {
+        value.parse().map_err(|_| E::custom(format!("invalid resource path {}", value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for ResourcePath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ResourcePath, D::Error> {
+        deserializer.deserialize_any(ResourcePathVisitor)
+    }
+}
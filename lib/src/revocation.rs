@@ -0,0 +1,344 @@
+use std::fs::File;
+use std::io::{self, BufReader, ErrorKind};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use chrono::Utc;
+use crate::enrollment::get_authority_key;
+use crate::secret_management::SecretKeyErrors;
+use crate::peer_registry::PeerRegistry;
+use crate::{file_management, get_key_instance, schnorr_identification};
+use crate::DeviceId;
+
+#[derive(Debug)]
+pub enum RevocationErrors {
+    AuthorityKeyNotFound,
+    InvalidSignature,
+    OutdatedVersion,
+    InvalidRevocationFile,
+    UnableToWriteRevocationFile(io::Error),
+    InvalidStoredVersion,
+    UnableToStoreVersion(SecretKeyErrors),
+}
+
+// List of revoked device IDs and key fingerprints, signed by the authority.
+// A list is only replaced by a list with a higher version
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RevocationList {
+    pub version: u64,
+    pub issued_at: i64,
+    pub revoked_devices: Vec<DeviceId>,
+    pub revoked_keys: Vec<[u8; 32]>,
+    pub signature: ([u8; 32], [u8; 32]),
+}
+
+impl RevocationList {
+    // Data signed by the authority
+    fn signed_data(&self) -> Vec<u8> {
+        let mut data = Vec::from(&b"RevocationList"[..]);
+        data.extend_from_slice(&self.version.to_be_bytes());
+        data.extend_from_slice(&self.issued_at.to_be_bytes());
+        data.extend_from_slice(&(self.revoked_devices.len() as u32).to_be_bytes());
+        for device_ID in self.revoked_devices.iter() {
+            data.extend_from_slice(&device_ID.to_bytes());
+        }
+        for fingerprint in self.revoked_keys.iter() {
+            data.extend_from_slice(fingerprint);
+        }
+        data
+    }
+}
+
+// Revocations of this device only, e.g. added automatically by the intrusion detection
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LocalRevocations {
+    auto_revocation: bool,
+    revoked_devices: Vec<DeviceId>,
+    revoked_keys: Vec<[u8; 32]>,
+}
+
+// File path of the revocation list of the authority
+fn get_revocation_list_file_path() -> String {
+    String::from(".nizk-auth/revocation/revocation_list.json")
+}
+
+// File path of the local revocations
+fn get_local_revocations_file_path() -> String {
+    String::from(".nizk-auth/revocation/local_revocations.json")
+}
+
+// Read json data from a file. Returns None if the file does not exist and an error if it can not be read
+fn read_json<T: DeserializeOwned>(file_path: String) -> Result<Option<T>, RevocationErrors> {
+    let file = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(None);
+        },
+        Err(e) => {
+            println!("Revocation file {} can not be opened: {}\n", file_path, e);
+            return Err(RevocationErrors::InvalidRevocationFile);
+        }
+    };
+
+    let reader = BufReader::new(file);
+    serde_json::from_reader(reader).map(Some).map_err(|e| {
+        println!("Revocation file {} can not be read: {}\n", file_path, e);
+        RevocationErrors::InvalidRevocationFile
+    })
+}
+
+// Save json data into a file, see file_management::write_file_atomically
+fn write_json<T: Serialize>(file_path: String, data: &T) -> Result<(), RevocationErrors> {
+    let json_string = serde_json::to_string(data).unwrap();
+    file_management::write_file_atomically(&file_path, json_string.as_bytes()).map_err(|e| {
+        println!("Revocation file {} can not be written: {}\n", file_path, e);
+        RevocationErrors::UnableToWriteRevocationFile(e)
+    })
+}
+
+// Highest version of a revocation list this device accepted. It is kept in the OS,
+// so that deleting or replacing the list file does not reset the version check.
+// A stored value of the wrong size is an error, no list is accepted until it is fixed
+fn get_highest_version() -> Result<u64, RevocationErrors> {
+    match get_key_instance("RevocationListVersion", 8, None) {
+        Ok(key_ins) => match <[u8; 8]>::try_from(key_ins.get_key().as_slice()) {
+            Ok(version) => Ok(u64::from_be_bytes(version)),
+            Err(_) => {
+                println!("Stored revocation list version is invalid!\n");
+                Err(RevocationErrors::InvalidStoredVersion)
+            }
+        },
+        Err(_) => Ok(0),
+    }
+}
+
+// Save the highest accepted version of a revocation list
+fn set_highest_version(version: u64) -> Result<(), RevocationErrors> {
+    let version_vec = Vec::from(version.to_be_bytes());
+    let mut key_ins = get_key_instance("RevocationListVersion", 8, Some(version_vec.clone()))
+        .map_err(RevocationErrors::UnableToStoreVersion)?;
+    if key_ins.get_key() != &version_vec {
+        key_ins.update_key_in_ring(version_vec).map_err(RevocationErrors::UnableToStoreVersion)?;
+    }
+    Ok(())
+}
+
+// Read the local revocations, None if the file is broken
+fn read_local_revocations() -> Option<LocalRevocations> {
+    read_json(get_local_revocations_file_path()).map(Option::unwrap_or_default).ok()
+}
+
+// Fingerprint of a public key, used in revocation lists
+pub fn key_fingerprint(public_key: &[u8; 32]) -> [u8; 32] {
+    schnorr_identification::sha3_256(b"Key fingerprint", Some(public_key), None, None)
+}
+
+// Sign a new revocation list. Only possible on the device holding the authority private key
+pub fn issue_revocation_list(version: u64, revoked_devices: Vec<DeviceId>, revoked_keys: Vec<[u8; 32]>) -> Result<RevocationList, RevocationErrors> {
+    let authority_key = match get_authority_key("AuthorityPrivateKey") {
+        Ok(key) => key,
+        Err(_) => {
+            return Err(RevocationErrors::AuthorityKeyNotFound);
+        }
+    };
+
+    let mut list = RevocationList {
+        version,
+        issued_at: Utc::now().timestamp_millis(),
+        revoked_devices,
+        revoked_keys,
+        signature: ([0u8; 32], [0u8; 32]),
+    };
+    list.signature = schnorr_identification::sign(authority_key, &list.signed_data());
+
+    Ok(list)
+}
+
+// Check the signature and version of a revocation list and replace the stored list with it
+pub fn update_revocation_list(list: &RevocationList) -> Result<(), RevocationErrors> {
+    let authority_key = match get_authority_key("AuthorityPublicKey") {
+        Ok(key) => key,
+        Err(_) => {
+            return Err(RevocationErrors::AuthorityKeyNotFound);
+        }
+    };
+
+    if !schnorr_identification::verify_signature(authority_key, &list.signed_data(), list.signature) {
+        println!("Revocation list {} has an invalid signature!\n", list.version);
+        return Err(RevocationErrors::InvalidSignature);
+    }
+
+    // Protect against rolling back to an older list, also if the list file was deleted.
+    // The update is refused if the stored version can not be read
+    let highest_version = get_highest_version()?;
+    if list.version <= highest_version {
+        println!("Revocation list {} is not newer than the current list {}!\n", list.version, highest_version);
+        return Err(RevocationErrors::OutdatedVersion);
+    }
+
+    write_json(get_revocation_list_file_path(), list)?;
+    set_highest_version(list.version)
+}
+
+// Return the current revocation list of the authority. Fails if the list file is broken,
+// or if it was deleted or replaced by an older list after a newer one was accepted
+pub fn get_revocation_list() -> Result<Option<RevocationList>, RevocationErrors> {
+    let list: Option<RevocationList> = read_json(get_revocation_list_file_path())?;
+    let version = list.as_ref().map(|list| list.version).unwrap_or(0);
+    let highest_version = get_highest_version()?;
+    if version < highest_version {
+        println!("Stored revocation list {} is older than the accepted list {}!\n", version, highest_version);
+        return Err(RevocationErrors::OutdatedVersion);
+    }
+
+    Ok(list)
+}
+
+// Check if a device or its public key is revoked, by the authority or locally.
+// Every device counts as revoked while the revocation data can not be read
+pub fn is_revoked(device_ID: DeviceId, public_key: &[u8; 32]) -> bool {
+    let fingerprint = key_fingerprint(public_key);
+
+    match get_revocation_list() {
+        Ok(Some(list)) if list.revoked_devices.contains(&device_ID) || list.revoked_keys.contains(&fingerprint) => {
+            return true;
+        },
+        Ok(_) => {},
+        Err(_) => {
+            return true;
+        }
+    }
+
+    match read_local_revocations() {
+        Some(local) => local.revoked_devices.contains(&device_ID) || local.revoked_keys.contains(&fingerprint),
+        None => true,
+    }
+}
+
+// Enable or disable revoking peers automatically when the intrusion detection reports a compromised key
+pub fn set_auto_revocation(enabled: bool) -> Result<(), RevocationErrors> {
+    let mut local = read_local_revocations().ok_or(RevocationErrors::InvalidRevocationFile)?;
+    local.auto_revocation = enabled;
+    write_json(get_local_revocations_file_path(), &local)
+}
+
+// Check if automatic revocation is enabled
+pub fn auto_revocation_enabled() -> bool {
+    read_local_revocations().map(|local| local.auto_revocation).unwrap_or(false)
+}
+
+// Revoke a device on this device only. The current public key of the device is revoked as well,
+// so that it can not enroll again with the same key. A broken file is not overwritten, every device is revoked then anyway
+pub fn revoke_locally(device_ID: DeviceId) -> Result<(), RevocationErrors> {
+    let mut local = read_local_revocations().ok_or(RevocationErrors::InvalidRevocationFile)?;
+    if !local.revoked_devices.contains(&device_ID) {
+        local.revoked_devices.push(device_ID);
+    }
+    let registry = PeerRegistry::load().unwrap_or_default();
+    if let Some(peer) = registry.get_peer(device_ID) {
+        let fingerprint = key_fingerprint(&peer.public_key);
+        if !local.revoked_keys.contains(&fingerprint) {
+            local.revoked_keys.push(fingerprint);
+        }
+    }
+    write_json(get_local_revocations_file_path(), &local)?;
+    println!("Device {} is revoked on this device!\n", device_ID);
+    Ok(())
+}
+
+// Remove a local revocation of a device. Revoked keys stay revoked
+pub fn clear_local_revocation(device_ID: DeviceId) -> Result<(), RevocationErrors> {
+    let mut local = read_local_revocations().ok_or(RevocationErrors::InvalidRevocationFile)?;
+    local.revoked_devices.retain(|id| *id != device_ID);
+    write_json(get_local_revocations_file_path(), &local)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{enrollment, test_support};
+
+    // Run a test with a new authority and no accepted revocation list, and reset the accepted version
+    // afterwards, as the keyring is shared by all tests
+    fn with_new_authority(test: impl FnOnce()) {
+        test_support::in_empty_work_dir(|| {
+            set_highest_version(0).unwrap();
+            enrollment::init_authority().unwrap();
+            test();
+            set_highest_version(0).unwrap();
+        });
+    }
+
+    fn paired_devices(first: u32, second: u32) -> (DeviceId, DeviceId) {
+        let (AID, BID) = (DeviceId::from(first), DeviceId::from(second));
+        test_support::add_peer(AID, test_support::store_key_pair(AID));
+        test_support::add_peer(BID, test_support::store_key_pair(BID));
+        let key = [7u8; 32];
+        crate::store_shared_values(AID, BID, &key);
+        crate::store_shared_values(BID, AID, &key);
+        (AID, BID)
+    }
+
+    #[test]
+    fn accepts_signed_newer_list() {
+        with_new_authority(|| {
+            let list = issue_revocation_list(1, vec![DeviceId::from(33001)], Vec::new()).unwrap();
+            update_revocation_list(&list).unwrap();
+
+            assert_eq!(get_revocation_list().unwrap().unwrap().version, 1);
+            assert!(is_revoked(DeviceId::from(33001), &[1u8; 32]));
+            assert!(!is_revoked(DeviceId::from(33002), &[1u8; 32]));
+        });
+    }
+
+    #[test]
+    fn rejects_version_rollback() {
+        with_new_authority(|| {
+            update_revocation_list(&issue_revocation_list(2, vec![DeviceId::from(33011)], Vec::new()).unwrap()).unwrap();
+
+            let older = issue_revocation_list(1, Vec::new(), Vec::new()).unwrap();
+            assert!(matches!(update_revocation_list(&older), Err(RevocationErrors::OutdatedVersion)));
+            let same = issue_revocation_list(2, Vec::new(), Vec::new()).unwrap();
+            assert!(matches!(update_revocation_list(&same), Err(RevocationErrors::OutdatedVersion)));
+            assert!(is_revoked(DeviceId::from(33011), &[1u8; 32]));
+        });
+    }
+
+    #[test]
+    fn rejects_bad_authority_signature() {
+        with_new_authority(|| {
+            let mut list = issue_revocation_list(1, Vec::new(), Vec::new()).unwrap();
+            list.revoked_devices.push(DeviceId::from(33021));
+            assert!(matches!(update_revocation_list(&list), Err(RevocationErrors::InvalidSignature)));
+
+            // A list signed by another authority
+            let list = issue_revocation_list(1, Vec::new(), Vec::new()).unwrap();
+            enrollment::init_authority().unwrap();
+            assert!(matches!(update_revocation_list(&list), Err(RevocationErrors::InvalidSignature)));
+            assert!(get_revocation_list().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn refuses_update_with_invalid_stored_version() {
+        with_new_authority(|| {
+            let mut key_ins = get_key_instance("RevocationListVersion", 8, None).unwrap();
+            key_ins.update_key_in_ring(vec![1, 2, 3]).unwrap();
+
+            let list = issue_revocation_list(1, Vec::new(), Vec::new()).unwrap();
+            assert!(matches!(update_revocation_list(&list), Err(RevocationErrors::InvalidStoredVersion)));
+            assert!(is_revoked(DeviceId::from(33031), &[1u8; 32]));
+        });
+    }
+
+    #[test]
+    fn revoked_peer_fails_proof_verification() {
+        with_new_authority(|| {
+            let (AID, BID) = paired_devices(33041, 33042);
+            let proof = crate::gen_nizk_proof(AID, BID, String::from("message"), false);
+            assert!(crate::verify_nizk_proof(BID, AID, String::from("message"), proof, false));
+
+            update_revocation_list(&issue_revocation_list(1, vec![AID], Vec::new()).unwrap()).unwrap();
+            assert!(!crate::verify_nizk_proof(BID, AID, String::from("message"), proof, false));
+        });
+    }
+}
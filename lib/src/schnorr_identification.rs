@@ -6,6 +6,7 @@ extern crate curve25519_dalek;
 use curve25519_dalek::scalar::Scalar;
 use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
 use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use crate::DeviceId;
 
 
 // Generate a random 32-byte value
@@ -67,10 +68,35 @@ pub fn sha3_256(arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>, arg4: Opt
     digest
 }
 
+// Bind a random challenge of the interactive protocol to both parties and their commitments,
+// so that a response can not be relayed into a session between other parties
+pub fn bind_challenge(prover_id: DeviceId, verifier_id: DeviceId, prover_commitment: &[u8; 32],
+                      verifier_commitment: &[u8; 32], challenge: &Scalar) -> Scalar {
+    let mut ids = Vec::with_capacity(8);
+    ids.extend_from_slice(&prover_id.to_bytes());
+    ids.extend_from_slice(&verifier_id.to_bytes());
+
+    let mut commitments = Vec::with_capacity(64);
+    commitments.extend_from_slice(prover_commitment);
+    commitments.extend_from_slice(verifier_commitment);
+
+    let hash = sha3_256(b"IntMutAuth challenge", Some(&ids), Some(&commitments), Some(challenge.as_bytes()));
+    Scalar::from_bytes_mod_order(hash)
+}
+
 // Generate a Kmac Tag
 pub fn kmac_256(key: [u8; 32], arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>) -> [u8; 32] {
+    kmac_tag(&key, arg1, arg2, arg3)
+}
+
+// Generate a 32-byte Kmac Tag. 128-bit keys use KMAC128, all other keys use KMAC256
+pub fn kmac_tag(key: &[u8], arg1: &[u8], arg2: Option<&[u8]>, arg3: Option<&[u8]>) -> [u8; 32] {
     // Define a kmac instance
-    let mut kmac_instance = Kmac::v256(&key, b"");
+    let mut kmac_instance = if key.len() == 16 {
+        Kmac::v128(key, b"")
+    } else {
+        Kmac::v256(key, b"")
+    };
 
     // Include main arg into data
     kmac_instance.update(arg1);
@@ -103,7 +129,7 @@ pub fn generate_proof_response(random_secret: Scalar, private_key: Scalar, chall
 }
 
 // Generate a proof that the device knows the private key, using Non-Interactive Zero-Knowledge
-pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: [u8; 32], shared_counter: [u8; 4], message: Option<&[u8]>) -> (Scalar, [u8; 32], [u8; 32], [u8; 32]) {
+pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: &[u8], shared_counter: [u8; 4], message: Option<&[u8]>) -> (Scalar, [u8; 32], [u8; 32], [u8; 32]) {
     // Turn private key into Scalar
     let private_key_sc = Scalar::from_bytes_mod_order(private_key);
 
@@ -112,7 +138,7 @@ pub fn nizk_proof(private_key: [u8; 32], shared_secret_key: [u8; 32], shared_cou
     let commitment = (r * &ED25519_BASEPOINT_POINT).compress().to_bytes();
 
     // Generate challenge using KMAC function with a random value
-    let challenge = kmac_256(shared_secret_key,
+    let challenge = kmac_tag(shared_secret_key,
                              &commitment,
                              Some(&shared_counter),
                              message);
@@ -135,9 +161,9 @@ pub fn bytes_to_edwards(bytes: &[u8; 32]) -> EdwardsPoint {
 }
 
 // Verify if the challenge is generated correctly using the MAC Tag
-fn verify_challenge(shared_secret: [u8; 32], shared_counter: [u8; 4], commitment: [u8; 32], challenge: [u8; 32], message: Option<&[u8]>) -> bool {
+fn verify_challenge(shared_secret: &[u8], shared_counter: [u8; 4], commitment: [u8; 32], challenge: [u8; 32], message: Option<&[u8]>) -> bool {
     // Generate expected challenge using KMAC function with a random value
-    let expected_challenge = kmac_256(shared_secret,
+    let expected_challenge = kmac_tag(shared_secret,
                                       &commitment,
                                       Some(&shared_counter),
                                       message);
@@ -146,7 +172,7 @@ fn verify_challenge(shared_secret: [u8; 32], shared_counter: [u8; 4], commitment
 }
 
 // Verify the proof
-pub fn verify_nizk_proof(public_key: [u8; 32], shared_secret: [u8; 32], shared_counter: [u8; 4],
+pub fn verify_nizk_proof(public_key: [u8; 32], shared_secret: &[u8], shared_counter: [u8; 4],
                          message: Option<&[u8]>, proof: ([u8; 32], [u8; 32], [u8; 32])) -> (bool, bool) {
 
     // Convert compressed public key into an Edwards point
@@ -192,3 +218,41 @@ pub fn verify_int_proof(public_key: [u8; 32], proof: ([u8; 32], Scalar, [u8; 32]
     return lhs == rhs;
 }
 
+// Calculate the public key of a private key
+pub fn public_key(private_key: [u8; 32]) -> [u8; 32] {
+    let private_key_sc = Scalar::from_bytes_mod_order(private_key);
+    (private_key_sc * ED25519_BASEPOINT_POINT).compress().to_bytes()
+}
+
+// Sign a message with a private key, using a Schnorr signature over Ed25519. Returns commitment and response
+pub fn sign(private_key: [u8; 32], message: &[u8]) -> ([u8; 32], [u8; 32]) {
+    // Turn private key into Scalar and calculate the public key
+    let private_key_sc = Scalar::from_bytes_mod_order(private_key);
+    let public_key = public_key(private_key);
+
+    // Generate a random number and the commitment
+    let r = generate_random_scalar();
+    let commitment = (r * ED25519_BASEPOINT_POINT).compress().to_bytes();
+
+    // Challenge is the hash of the commitment, the public key and the message
+    let challenge = sha3_256(b"Schnorr signature", Some(&commitment), Some(&public_key), Some(message));
+    let c = Scalar::from_bytes_mod_order(challenge);
+
+    // Return commitment and response
+    (commitment, generate_proof_response(r, private_key_sc, c))
+}
+
+// Verify a Schnorr signature of a message
+pub fn verify_signature(public_key: [u8; 32], message: &[u8], signature: ([u8; 32], [u8; 32])) -> bool {
+    let (commitment, response) = signature;
+
+    // Signatures are received from other devices, reject values that are not on the elliptic curve
+    if CompressedEdwardsY(commitment).decompress().is_none() || CompressedEdwardsY(public_key).decompress().is_none() {
+        return false;
+    }
+
+    // Recalculate the challenge and verify the proof
+    let challenge = sha3_256(b"Schnorr signature", Some(&commitment), Some(&public_key), Some(message));
+    let c = Scalar::from_bytes_mod_order(challenge);
+    verify_int_proof(public_key, (commitment, c, response))
+}
@@ -4,6 +4,11 @@ use linux_keyutils::{KeyPermissionsBuilder, Permission};
 use rand::RngCore;
 use hex;
 
+// Keyring of the keys. Tests use the keyring of their own process, so they never read or change the keys of the user
+#[cfg(not(test))]
+const CONST_KEYRING: (KeyRingIdentifier, bool) = (KeyRingIdentifier::User, false);
+#[cfg(test)]
+const CONST_KEYRING: (KeyRingIdentifier, bool) = (KeyRingIdentifier::Process, true);
 
 #[derive(Debug)]
 pub enum SecretKeyErrors {
@@ -29,7 +34,7 @@ impl MyKey {
         // Define keyring of current key
         // See [KeyRingIdentifier] and `man 2 keyctl` for more information on default
         // keyrings for processes.
-        match KeyRing::from_special_id(KeyRingIdentifier::User, false) {
+        match KeyRing::from_special_id(CONST_KEYRING.0, CONST_KEYRING.1) {
             Ok(ring) => {
                 // Save key description as Vec<u8> and use description as it's encoded value
                 let key_description = key_description_str.as_bytes().to_vec();
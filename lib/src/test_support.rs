@@ -0,0 +1,51 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+// Tests that read or write files under .nizk-auth run one after the other, each in a new directory
+static WORK_DIR_LOCK: Mutex<()> = Mutex::new(());
+static WORK_DIR_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+// Changes back into the previous working directory and removes the test directory, also if the test panics
+struct WorkDir {
+    previous_dir: PathBuf,
+    test_dir: PathBuf,
+}
+
+impl Drop for WorkDir {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.previous_dir);
+        let _ = fs::remove_dir_all(&self.test_dir);
+    }
+}
+
+// Run a test in a new empty working directory
+pub(crate) fn in_empty_work_dir<T>(test: impl FnOnce() -> T) -> T {
+    let _lock = WORK_DIR_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let test_dir = env::temp_dir().join(format!("nizk-auth-test-{}-{}", std::process::id(), WORK_DIR_COUNTER.fetch_add(1, Ordering::SeqCst)));
+    fs::create_dir_all(&test_dir).unwrap();
+    let _work_dir = WorkDir {
+        previous_dir: env::current_dir().unwrap(),
+        test_dir: test_dir.clone(),
+    };
+    env::set_current_dir(&test_dir).unwrap();
+    test()
+}
+
+// Store a new key pair of a device in the keyring and return its public key
+pub(crate) fn store_key_pair(device_ID: crate::DeviceId) -> [u8; 32] {
+    let (public_key, private_key) = crate::gen_random_key_pair();
+    let description = format!("PrivateKey:{}", device_ID);
+    let mut key_ins = crate::get_key_instance(&description, 32, Some(private_key.to_vec())).unwrap();
+    key_ins.update_key_in_ring(private_key.to_vec()).unwrap();
+    public_key
+}
+
+// Add an active peer with the default policy to the peer registry of the working directory
+pub(crate) fn add_peer(device_ID: crate::DeviceId, public_key: [u8; 32]) {
+    let mut registry = crate::peer_registry::PeerRegistry::load().unwrap();
+    registry.add_peer(crate::peer_registry::PeerEntry::new(device_ID, public_key, format!("Device {}", device_ID))).unwrap();
+    registry.save().unwrap();
+}